@@ -15,17 +15,38 @@ pub struct AllocMap {
     root: RwLock<Root>,
 }
 
+/// The root table. `leaves` is kept read-only except while [`RootWriter`]
+/// mutates it, and is checked for consistency before every mutation, so that
+/// a wild write (which is what fatalloc is here to survive) breaking it is
+/// caught and repaired instead of making the lookups panic.
 struct Root {
+    /// `leaves[..num_leaves]` is sorted by root index without duplicates,
+    /// and the rest is `None`
     leaves: Option<&'static mut [Option<(usize, &'static mut Leaf)>]>,
     num_leaves: usize,
+    /// Whether `leaves` is currently writable
+    writable: bool,
 }
 
 /// Leaf table size
 const LEAF_LEN: usize = 1 << 23;
 
+/// Sub-leaf table size. Sub-leaves are allocated on demand, so this
+/// determines the granularity of the memory committed for sparse address
+/// usage (one page).
+const SUBLEAF_LEN: usize = 1 << 15;
+
+/// The number of `Leaf::bitmap` elements in a sub-leaf
+const SUBLEAF_WORDS: usize = SUBLEAF_LEN / usize::BITS as usize;
+
 #[repr(C)]
 struct Leaf {
-    bitmap: [AtomicUsize; LEAF_LEN / usize::BITS as usize],
+    subleaves: [Option<&'static mut SubLeaf>; LEAF_LEN / SUBLEAF_LEN],
+}
+
+#[repr(C)]
+struct SubLeaf {
+    bitmap: [AtomicUsize; SUBLEAF_WORDS],
 }
 
 impl AllocMap {
@@ -33,9 +54,18 @@ impl AllocMap {
         root: RwLock::new(Root {
             leaves: None,
             num_leaves: 0,
+            writable: false,
         }),
     };
 
+    /// Split `i` into a root index, an index into the leaf's bitmap, and a bit
+    /// position.
+    ///
+    /// Any `i` is accepted. On a 64-bit target, `root_i` ranges up to
+    /// `2^41` (`2^(64 - 23)`), e.g., `2^22` for a 48-bit address space
+    /// (`i = ptr / 8`) and `2^31` for a 57-bit one. This doesn't affect the
+    /// size of `Root::leaves`, which only holds root indices that are
+    /// actually present.
     #[inline]
     fn expand_index(i: usize) -> (usize, usize, u32) {
         let bit = i as u32 % usize::BITS;
@@ -47,6 +77,20 @@ impl AllocMap {
         (root_i, bitmap_i, bit)
     }
 
+    /// Take the write lock, repairing `Root` first if it's inconsistent.
+    fn write_root(self: Pin<&Self>) -> RootWriter<impl ops::DerefMut<Target = Root> + '_> {
+        let mut root = self.project_ref().root.write();
+        if let Some(e) = root.repair() {
+            warn!("the allocation map's root table was corrupted ({e}); repaired it");
+        }
+        RootWriter(root)
+    }
+
+    /// Get the lock of the root table for the `fork` handlers.
+    pub fn fork_rwlock(self: Pin<&Self>) -> *mut libc::pthread_rwlock_t {
+        self.project_ref().root.rwlock.get()
+    }
+
     #[inline]
     pub fn get(self: Pin<&Self>, i: usize) -> bool {
         let this = self.project_ref();
@@ -65,6 +109,16 @@ impl AllocMap {
         (bitmap.load(Ordering::Acquire) & mask) != 0
     }
 
+    /// Call `f` if the bit `i` is set, holding the lock of the root table
+    /// meanwhile so that its leaves can't be unmapped, and return its result.
+    pub fn if_set<R>(self: Pin<&Self>, i: usize, f: impl FnOnce() -> R) -> Option<R> {
+        let this = self.project_ref();
+        let (root_i, bitmap_i, bit) = Self::expand_index(i);
+        let root_read = this.root.read();
+        let bitmap = root_read.get_bitmap(root_i, bitmap_i)?;
+        (bitmap.load(Ordering::Acquire) & (1usize << bit) != 0).then(f)
+    }
+
     #[inline]
     pub fn test_and_clear(self: Pin<&Self>, i: usize) -> bool {
         let this = self.project_ref();
@@ -80,11 +134,134 @@ impl AllocMap {
 
         // Perform the bit operation
         let mask = 1usize << bit;
-        (bitmap.fetch_nand(mask, Ordering::AcqRel) & mask) != 0
+        (bitmap.fetch_and(!mask, Ordering::AcqRel) & mask) != 0
     }
 
+    /// Clear the bits `indices` like [`Self::test_and_clear`], taking the
+    /// lock once for all of them instead of once per bit. `cleared` is called
+    /// with the position in `indices` of each bit that was set, with the lock
+    /// held.
+    pub fn test_and_clear_many(
+        self: Pin<&Self>,
+        indices: impl IntoIterator<Item = usize>,
+        mut cleared: impl FnMut(usize),
+    ) {
+        let this = self.project_ref();
+        let root_read = this.root.read();
+        for (pos, i) in indices.into_iter().enumerate() {
+            let (root_i, bitmap_i, bit) = Self::expand_index(i);
+            let bitmap = match root_read.get_bitmap(root_i, bitmap_i) {
+                Some(bitmap) => bitmap,
+                None => continue,
+            };
+            let mask = 1usize << bit;
+            if (bitmap.fetch_and(!mask, Ordering::AcqRel) & mask) != 0 {
+                cleared(pos);
+            }
+        }
+    }
+
+    /// Find the first set bit in the specified range. Absent leaves and
+    /// sub-leaves are skipped as a whole, so a sparse range is cheap to scan.
+    pub fn find_next(self: Pin<&Self>, range: ops::Range<usize>) -> Option<usize> {
+        let this = self.project_ref();
+        let root_read = this.root.read();
+
+        let mut i = range.start;
+        while i < range.end {
+            let (root_i, bitmap_i, bit) = Self::expand_index(i);
+            let word_start = i - bit as usize;
+
+            let leaf = match root_read.find_leaf(root_i) {
+                Ok(leaf) => leaf,
+                Err(Some(next_root_i)) if next_root_i > root_i => {
+                    i = next_root_i.checked_mul(LEAF_LEN)?;
+                    continue;
+                }
+                // No more leaves, or a corrupted root table (which would
+                // send us back)
+                Err(_) => return None,
+            };
+
+            let subleaf = match leaf.subleaves[bitmap_i / SUBLEAF_WORDS].as_deref() {
+                Some(subleaf) => subleaf,
+                None => {
+                    i = (i & !(SUBLEAF_LEN - 1)).checked_add(SUBLEAF_LEN)?;
+                    continue;
+                }
+            };
+
+            let word =
+                subleaf.bitmap[bitmap_i % SUBLEAF_WORDS].load(Ordering::Acquire) & (!0usize << bit);
+            if word != 0 {
+                let found = word_start + word.trailing_zeros() as usize;
+                return Some(found).filter(|&found| found < range.end);
+            }
+
+            i = word_start.checked_add(usize::BITS as usize)?;
+        }
+
+        None
+    }
+
+    /// Find the last set bit in the specified range, skipping absent leaves
+    /// and sub-leaves like [`Self::find_next`].
+    pub fn find_prev(self: Pin<&Self>, range: ops::Range<usize>) -> Option<usize> {
+        let this = self.project_ref();
+        let root_read = this.root.read();
+
+        // The exclusive end of the part left to scan
+        let mut end = range.end;
+        while end > range.start {
+            let i = end - 1;
+            let (root_i, bitmap_i, bit) = Self::expand_index(i);
+            let word_start = i - bit as usize;
+
+            let leaf = match root_read.find_leaf(root_i) {
+                Ok(leaf) => leaf,
+                Err(_) => {
+                    end = i & !(LEAF_LEN - 1);
+                    continue;
+                }
+            };
+
+            let subleaf = match leaf.subleaves[bitmap_i / SUBLEAF_WORDS].as_deref() {
+                Some(subleaf) => subleaf,
+                None => {
+                    end = i & !(SUBLEAF_LEN - 1);
+                    continue;
+                }
+            };
+
+            let word = subleaf.bitmap[bitmap_i % SUBLEAF_WORDS].load(Ordering::Acquire)
+                & (!0usize >> (usize::BITS - 1 - bit));
+            if word != 0 {
+                let found = word_start + (usize::BITS - 1 - word.leading_zeros()) as usize;
+                return Some(found).filter(|&found| found >= range.start);
+            }
+
+            end = word_start;
+        }
+
+        None
+    }
+
+    /// Set a bit whose leaf and sub-leaf are known to exist, e.g., because it
+    /// was just cleared.
     #[inline]
     pub fn set(self: Pin<&Self>, i: usize) {
+        let result = self.set_bounded(i, usize::MAX);
+        debug_assert!(result.is_ok());
+    }
+
+    /// Set a bit, creating at most `max_leaves` leaves in total. If a new
+    /// leaf is needed at the limit, an empty leaf is recycled; if there's
+    /// none, or memory for a new leaf can't be mapped, this fails without
+    /// setting the bit.
+    ///
+    /// Returns whether the bit was already set.
+    #[inline]
+    pub fn set_bounded(self: Pin<&Self>, i: usize, max_leaves: usize) -> Result<bool, SetError> {
         let this = self.project_ref();
         let (root_i, bitmap_i, bit) = Self::expand_index(i);
 
@@ -96,34 +273,456 @@ impl AllocMap {
         } else {
             // Upgrade the lock
             drop(root_read);
-            root_write = this.root.write();
-            root_write.get_or_insert_bitmap(root_i, bitmap_i)
+            root_write = self.write_root();
+            root_write.get_or_insert_bitmap(root_i, bitmap_i, max_leaves)?
         };
 
         // Perform the bit operation
         let mask = 1usize << bit;
-        bitmap.fetch_or(mask, Ordering::Release);
+        Ok((bitmap.fetch_or(mask, Ordering::Release) & mask) != 0)
+    }
+
+    /// Set the bits `indices` like [`Self::set_bounded`], taking the lock
+    /// once for all of them instead of once per bit. The lock is upgraded
+    /// at most once, at the first bit needing a new leaf or sub-leaf.
+    ///
+    /// `already_set` is called with the position in `indices` of each bit
+    /// that was already set. On failure, returns the number of leading bits
+    /// that were set.
+    pub fn set_many(
+        self: Pin<&Self>,
+        indices: impl IntoIterator<Item = usize>,
+        max_leaves: usize,
+        mut already_set: impl FnMut(usize),
+    ) -> Result<(), (usize, SetError)> {
+        let this = self.project_ref();
+        let mut indices = indices.into_iter().peekable();
+        let mut num_set = 0;
+
+        {
+            let root_read = this.root.read();
+            while let Some(&i) = indices.peek() {
+                let (root_i, bitmap_i, bit) = Self::expand_index(i);
+                let bitmap = match root_read.get_bitmap(root_i, bitmap_i) {
+                    Some(bitmap) => bitmap,
+                    None => break,
+                };
+                let mask = 1usize << bit;
+                if (bitmap.fetch_or(mask, Ordering::Release) & mask) != 0 {
+                    already_set(num_set);
+                }
+                num_set += 1;
+                indices.next();
+            }
+        }
+
+        if indices.peek().is_none() {
+            return Ok(());
+        }
+
+        // Upgrade the lock
+        let mut root_write = self.write_root();
+        for i in indices {
+            let (root_i, bitmap_i, bit) = Self::expand_index(i);
+            let bitmap = root_write
+                .get_or_insert_bitmap(root_i, bitmap_i, max_leaves)
+                .map_err(|e| (num_set, e))?;
+            let mask = 1usize << bit;
+            if (bitmap.fetch_or(mask, Ordering::Release) & mask) != 0 {
+                already_set(num_set);
+            }
+            num_set += 1;
+        }
+        Ok(())
+    }
+
+    /// Unmap every sub-leaf with no bits set, and every leaf left without
+    /// sub-leaves. Returns the number of bytes unmapped.
+    ///
+    /// This takes the write lock and scans every sub-leaf, so it blocks all
+    /// other operations for a while.
+    pub fn release_empty(self: Pin<&Self>) -> usize {
+        let mut root = self.write_root();
+        let root = &mut *root;
+        root.unprotect();
+        let leaves = match &mut root.leaves {
+            Some(leaves) => leaves,
+            None => return 0,
+        };
+
+        let mut num_bytes = 0;
+        let mut num_kept = 0;
+        for leaf_i in 0..root.num_leaves {
+            let (_, leaf) = leaves[leaf_i].as_mut().unwrap();
+            for subleaf in leaf.subleaves.iter_mut() {
+                if subleaf.as_deref().map_or(false, SubLeaf::is_empty) {
+                    let subleaf = subleaf.take().unwrap();
+                    unsafe {
+                        libc::munmap((subleaf as *mut SubLeaf).cast(), mem::size_of::<SubLeaf>())
+                    };
+                    num_bytes += mem::size_of::<SubLeaf>();
+                }
+            }
+
+            if leaf.subleaves.iter().all(Option::is_none) {
+                let (_, leaf) = leaves[leaf_i].take().unwrap();
+                unsafe { libc::munmap((leaf as *mut Leaf).cast(), mem::size_of::<Leaf>()) };
+                num_bytes += mem::size_of::<Leaf>();
+            } else {
+                // Compact the remaining leaves, which stay sorted
+                leaves.swap(num_kept, leaf_i);
+                num_kept += 1;
+            }
+        }
+        root.num_leaves = num_kept;
+
+        num_bytes
+    }
+
+    /// Count the set bits.
+    ///
+    /// This takes the read lock and scans every sub-leaf.
+    pub fn count_set(self: Pin<&Self>) -> usize {
+        let this = self.project_ref();
+        let root = this.root.read();
+        root.live_leaves()
+            .iter()
+            .flatten()
+            .flat_map(|(_, leaf)| leaf.subleaves.iter().flatten())
+            .flat_map(|subleaf| subleaf.bitmap.iter())
+            .map(|word| word.load(Ordering::Relaxed).count_ones() as usize)
+            .sum()
+    }
+
+    /// Get the memory mapped for `Root::leaves`, leaves, and sub-leaves.
+    ///
+    /// This takes the read lock and scans every leaf.
+    pub fn num_mapped_bytes(self: Pin<&Self>) -> usize {
+        let this = self.project_ref();
+        let root = this.root.read();
+        let leaves = root.leaves.as_deref().unwrap_or(&[]);
+        let live_leaves = root.live_leaves();
+        let num_subleaves: usize = live_leaves
+            .iter()
+            .flatten()
+            .map(|(_, leaf)| leaf.subleaves.iter().flatten().count())
+            .sum();
+        mem::size_of_val(leaves)
+            + mem::size_of::<Leaf>() * live_leaves.len()
+            + mem::size_of::<SubLeaf>() * num_subleaves
+    }
+}
+
+/// Break a scratch map's root table in the ways a wild write could, and check
+/// that lookups survive it and that it's repaired, for `FATALLOC_SELF_TEST`
+pub fn self_test() -> Result<(), &'static str> {
+    static MAP: AllocMap = AllocMap::INIT;
+    let map = Pin::static_ref(&MAP);
+    let indices = [LEAF_LEN, LEAF_LEN * 3, LEAF_LEN * 5];
+    for &i in &indices {
+        map.set(i);
+    }
+
+    let corruptions: [fn(&mut Root); 3] = [
+        |root| root.num_leaves = usize::MAX,
+        |root| root.num_leaves = 1,
+        |root| {
+            root.unprotect();
+            root.leaves.as_mut().unwrap().swap(0, 2);
+        },
+    ];
+    for corrupt in corruptions {
+        corrupt(&mut map.write_root());
+
+        // Wrong, but no panic
+        for &i in &indices {
+            map.get(i);
+        }
+        map.find_next(0..usize::MAX);
+
+        let caught = map.project_ref().root.read().find_corruption().is_some();
+        if !caught {
+            return Err("a corrupted root table goes unnoticed");
+        }
+
+        // Repair it the way the next mutation would, warning about it
+        drop(map.write_root());
+        let root = map.project_ref().root.read();
+        if root.find_corruption().is_some() {
+            return Err("a corrupted root table isn't repaired");
+        }
+        drop(root);
+        if !indices.iter().all(|&i| map.get(i)) {
+            return Err("a corrupted root table isn't fully repaired");
+        }
+    }
+
+    for &i in &indices {
+        map.test_and_clear(i);
+    }
+    map.release_empty();
+    Ok(())
+}
+
+/// The structure of an [`AllocMap`], for tuning `LEAF_LEN` and diagnosing
+/// the map itself
+#[cfg(feature = "map-stats")]
+pub struct Stats {
+    pub num_leaves: usize,
+    /// The capacity of `Root::leaves`
+    pub leaves_cap: usize,
+    /// The memory mapped for `Root::leaves`, leaves, and sub-leaves
+    pub num_bytes: usize,
+}
+
+/// The state of a leaf, for [`AllocMap::stats`]
+#[cfg(feature = "map-stats")]
+pub struct LeafStats {
+    /// The first index covered by the leaf
+    pub start: usize,
+    pub num_subleaves: usize,
+    pub num_set: usize,
+}
+
+#[cfg(feature = "map-stats")]
+impl AllocMap {
+    /// The number of indices a leaf covers
+    pub const LEAF_LEN: usize = LEAF_LEN;
+
+    /// Inspect the structure, calling `each_leaf` for each leaf in the index
+    /// order.
+    ///
+    /// This takes the read lock and scans every sub-leaf, so `each_leaf`
+    /// must not reenter the allocator.
+    pub fn stats(self: Pin<&Self>, mut each_leaf: impl FnMut(LeafStats)) -> Stats {
+        let this = self.project_ref();
+        let root = this.root.read();
+        let leaves = root.leaves.as_deref().unwrap_or(&[]);
+        let live_leaves = root.live_leaves();
+
+        let mut stats = Stats {
+            num_leaves: live_leaves.len(),
+            leaves_cap: leaves.len(),
+            num_bytes: mem::size_of_val(leaves) + mem::size_of::<Leaf>() * live_leaves.len(),
+        };
+        for (root_i, leaf) in live_leaves.iter().flatten() {
+            let mut leaf_stats = LeafStats {
+                start: root_i * LEAF_LEN,
+                num_subleaves: 0,
+                num_set: 0,
+            };
+            for subleaf in leaf.subleaves.iter().flatten() {
+                leaf_stats.num_subleaves += 1;
+                leaf_stats.num_set += subleaf
+                    .bitmap
+                    .iter()
+                    .map(|word| word.load(Ordering::Relaxed).count_ones() as usize)
+                    .sum::<usize>();
+            }
+            stats.num_bytes += mem::size_of::<SubLeaf>() * leaf_stats.num_subleaves;
+            each_leaf(leaf_stats);
+        }
+
+        stats
+    }
+}
+
+/// The reason [`AllocMap::set_bounded`] failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetError {
+    /// `max_leaves` was reached, and there was no empty leaf to recycle.
+    MaxLeaves,
+    /// `mmap` failed.
+    OutOfMemory,
+    /// The bit was already set, i.e., the backend returned a block in use by
+    /// a live allocation. Reported by the caller, which sets nothing.
+    AlreadyTracked,
+}
+
+impl SetError {
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::MaxLeaves => "FATALLOC_MAX_LEAVES reached",
+            Self::OutOfMemory => "out of memory for the allocation map",
+            Self::AlreadyTracked => "the backend returned an already-tracked address",
+        }
     }
 }
 
 impl Root {
-    /// Find an element of `Leaf::bitmap`.
+    /// Get `leaves[..num_leaves]`, or nothing if `num_leaves` is out of
+    /// bounds.
+    #[inline]
+    fn live_leaves(&self) -> &[Option<(usize, &'static mut Leaf)>] {
+        let leaves = self.leaves.as_deref().unwrap_or(&[]);
+        leaves.get(..self.num_leaves).unwrap_or(&[])
+    }
+
+    /// Check the invariants of `leaves` and `num_leaves`. Returns what's
+    /// broken.
+    fn find_corruption(&self) -> Option<&'static str> {
+        let key = |e: &Option<(usize, &mut Leaf)>| e.as_ref().map(|e| e.0);
+        let leaves = self.leaves.as_deref().unwrap_or(&[]);
+        Some(if self.num_leaves > leaves.len() {
+            "the leaf count exceeds the capacity"
+        } else if leaves[..self.num_leaves].iter().any(Option::is_none) {
+            "a leaf is missing"
+        } else if leaves[self.num_leaves..].iter().any(Option::is_some) {
+            "a leaf is past the count"
+        } else if leaves[..self.num_leaves]
+            .windows(2)
+            .any(|w| key(&w[0]) >= key(&w[1]))
+        {
+            "the leaves are out of order"
+        } else {
+            return None;
+        })
+    }
+
+    /// Check the invariants of `leaves` and `num_leaves`, and restore them
+    /// if broken. Returns what was broken.
+    ///
+    /// The leaves are re-sorted, and of those with the same root index, only
+    /// the first is kept (and the rest leaked). Any of them might be the
+    /// bogus one, so the allocations in the dropped ones become unknown.
+    #[cold]
+    fn repair(&mut self) -> Option<&'static str> {
+        let key = |e: &Option<(usize, &mut Leaf)>| e.as_ref().map(|e| e.0);
+        let e = self.find_corruption()?;
+
+        self.unprotect();
+        let leaves = match &mut self.leaves {
+            Some(leaves) => &mut **leaves,
+            None => {
+                self.num_leaves = 0;
+                return Some(e);
+            }
+        };
+
+        // Move the leaves to the front
+        let mut num_leaves = 0;
+        for leaf_i in 0..leaves.len() {
+            if leaves[leaf_i].is_some() {
+                leaves.swap(num_leaves, leaf_i);
+                num_leaves += 1;
+            }
+        }
+
+        // Insertion-sort them. There are few of them, and no memory to
+        // spare for anything fancier.
+        for leaf_i in 1..num_leaves {
+            let mut i = leaf_i;
+            while i > 0 && key(&leaves[i - 1]) > key(&leaves[i]) {
+                leaves.swap(i - 1, i);
+                i -= 1;
+            }
+        }
+
+        // Drop the duplicates
+        let mut num_kept = 0;
+        for leaf_i in 0..num_leaves {
+            if num_kept > 0 && key(&leaves[num_kept - 1]) == key(&leaves[leaf_i]) {
+                leaves[leaf_i] = None;
+            } else {
+                leaves.swap(num_kept, leaf_i);
+                num_kept += 1;
+            }
+        }
+        self.num_leaves = num_kept;
+
+        Some(e)
+    }
+
+    /// Make `leaves` writable until [`Self::protect`].
+    fn unprotect(&mut self) {
+        if !self.writable {
+            self.set_leaves_prot(libc::PROT_READ | libc::PROT_WRITE);
+            self.writable = true;
+        }
+    }
+
+    /// Make `leaves` read-only.
+    fn protect(&mut self) {
+        if self.writable {
+            self.set_leaves_prot(libc::PROT_READ);
+            self.writable = false;
+        }
+    }
+
+    fn set_leaves_prot(&self, prot: libc::c_int) {
+        match self.leaves.as_deref() {
+            // The protection is a safety net; on failure, `leaves` is simply
+            // left as it is
+            Some(leaves) if !leaves.is_empty() => unsafe {
+                libc::mprotect(leaves.as_ptr() as *mut _, mem::size_of_val(leaves), prot);
+            },
+            _ => {}
+        }
+    }
+
+    /// Find an element of `Leaf`'s bitmap, which is split into `SubLeaf`s.
     #[inline]
     fn get_bitmap(&self, root_i: usize, bitmap_i: usize) -> Option<&AtomicUsize> {
-        let leaves = self.leaves.as_deref()?;
-        let leaf_i = leaves[..self.num_leaves]
-            .binary_search_by_key(&root_i, |e| e.as_ref().unwrap().0)
-            .ok()?;
-        Some(&leaves[leaf_i].as_ref().unwrap().1.bitmap[bitmap_i])
+        let leaf = self.find_leaf(root_i).ok()?;
+        let subleaf = leaf.subleaves[bitmap_i / SUBLEAF_WORDS].as_deref()?;
+        Some(&subleaf.bitmap[bitmap_i % SUBLEAF_WORDS])
     }
 
+    /// Find the `Leaf` for `root_i`. If it's absent, return the next root
+    /// index having a `Leaf` instead.
+    ///
+    /// This doesn't trust `leaves` to be consistent (that's checked by
+    /// [`Self::repair`] under the write lock). A broken one only makes the
+    /// result wrong.
+    #[inline]
+    fn find_leaf(&self, root_i: usize) -> Result<&Leaf, Option<usize>> {
+        let leaves = self.live_leaves();
+        // A missing leaf sorts last
+        match leaves.binary_search_by_key(&root_i, |e| e.as_ref().map_or(usize::MAX, |e| e.0)) {
+            Ok(leaf_i) => leaves[leaf_i].as_ref().map(|e| &*e.1).ok_or(None),
+            Err(next_leaf_i) => Err(leaves
+                .get(next_leaf_i)
+                .and_then(|e| e.as_ref())
+                .map(|e| e.0)),
+        }
+    }
+
+    /// Must be called through [`RootWriter`], which has checked the
+    /// invariants.
     #[cold]
-    fn get_or_insert_bitmap(&mut self, root_i: usize, bitmap_i: usize) -> &mut AtomicUsize {
-        let mut leaves = self.leaves.get_or_insert(&mut []);
-        let leaf_i =
-            leaves[..self.num_leaves].binary_search_by_key(&root_i, |e| e.as_ref().unwrap().0);
+    fn get_or_insert_bitmap(
+        &mut self,
+        root_i: usize,
+        bitmap_i: usize,
+        max_leaves: usize,
+    ) -> Result<&mut AtomicUsize, SetError> {
+        let leaf_i = self.leaves.get_or_insert(&mut [])[..self.num_leaves]
+            .binary_search_by_key(&root_i, |e| e.as_ref().unwrap().0);
+        if leaf_i.is_err() {
+            // `leaves` is about to change
+            self.unprotect();
+        }
+        let mut leaves = self.leaves.as_mut().unwrap();
         let leaf_i = match leaf_i {
             Ok(leaf_i) => leaf_i,
+            Err(insert_at_leaf_i) if self.num_leaves >= max_leaves => {
+                // Recycle an empty leaf. Its sub-leaves are all zero, so
+                // they can be kept as they are.
+                let empty_leaf_i = leaves[..self.num_leaves]
+                    .iter()
+                    .position(|e| e.as_ref().unwrap().1.is_empty())
+                    .ok_or(SetError::MaxLeaves)?;
+                leaves[empty_leaf_i].as_mut().unwrap().0 = root_i;
+
+                // Move it to keep `leaves` sorted
+                if empty_leaf_i < insert_at_leaf_i {
+                    leaves[empty_leaf_i..insert_at_leaf_i].rotate_left(1);
+                    insert_at_leaf_i - 1
+                } else {
+                    leaves[insert_at_leaf_i..=empty_leaf_i].rotate_right(1);
+                    insert_at_leaf_i
+                }
+            }
             Err(insert_at_leaf_i) => {
                 // Reserve a space
                 if self.num_leaves == leaves.len() {
@@ -132,8 +731,10 @@ impl Root {
                         .len()
                         .max(8)
                         .checked_mul(2)
-                        .expect("capacity overflow");
-                    let new_leaves = unsafe { alloc_zeroed(new_cap) };
+                        .expect("capacity overflow")
+                        .min(max_leaves);
+                    let new_leaves =
+                        unsafe { alloc_zeroed(new_cap) }.ok_or(SetError::OutOfMemory)?;
                     for new_leaf in new_leaves.iter_mut() {
                         mem::forget(mem::replace(new_leaf, None));
                     }
@@ -144,7 +745,11 @@ impl Root {
                     }
 
                     // Deallocate `leaves[..]`
-                    unsafe { libc::munmap(leaves.as_mut_ptr().cast(), leaves.len()) };
+                    if !leaves.is_empty() {
+                        unsafe {
+                            libc::munmap(leaves.as_mut_ptr().cast(), mem::size_of_val(leaves))
+                        };
+                    }
 
                     // Replace `leaves `with `new_leaves`
                     leaves = self.leaves.insert(new_leaves);
@@ -153,7 +758,7 @@ impl Root {
                 assert!(self.num_leaves < leaves.len());
 
                 // Construct a new zero-initialized `Leaf`
-                let leaf = &mut unsafe { alloc_zeroed::<Leaf>(1) }[0];
+                let leaf = &mut unsafe { alloc_zeroed::<Leaf>(1) }.ok_or(SetError::OutOfMemory)?[0];
 
                 // Insert the new `Leaf`
                 self.num_leaves += 1;
@@ -167,7 +772,60 @@ impl Root {
 
         // Reborrow (NLL Problem Case #2)
         let (_root_i, leaf) = self.leaves.as_mut().unwrap()[leaf_i].as_mut().unwrap();
-        &mut leaf.bitmap[bitmap_i]
+
+        // Construct a new zero-initialized `SubLeaf` if needed. A new leaf
+        // is left empty on failure, which is harmless.
+        let subleaf = &mut leaf.subleaves[bitmap_i / SUBLEAF_WORDS];
+        if subleaf.is_none() {
+            let new_subleaf = unsafe { alloc_zeroed::<SubLeaf>(1) }.ok_or(SetError::OutOfMemory)?;
+            *subleaf = Some(&mut new_subleaf[0]);
+        }
+        Ok(&mut subleaf.as_mut().unwrap().bitmap[bitmap_i % SUBLEAF_WORDS])
+    }
+}
+
+impl Leaf {
+    /// Check if no bits are set. This is slow.
+    fn is_empty(&self) -> bool {
+        self.subleaves
+            .iter()
+            .flatten()
+            .all(|subleaf| subleaf.is_empty())
+    }
+}
+
+impl SubLeaf {
+    /// Check if no bits are set.
+    fn is_empty(&self) -> bool {
+        self.bitmap
+            .iter()
+            .all(|word| word.load(Ordering::Relaxed) == 0)
+    }
+}
+
+/// A write lock on [`Root`], which restores the protection of
+/// `Root::leaves` when it's released
+struct RootWriter<G: ops::DerefMut<Target = Root>>(G);
+
+impl<G: ops::DerefMut<Target = Root>> Drop for RootWriter<G> {
+    fn drop(&mut self) {
+        self.0.protect();
+    }
+}
+
+impl<G: ops::DerefMut<Target = Root>> ops::Deref for RootWriter<G> {
+    type Target = Root;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<G: ops::DerefMut<Target = Root>> ops::DerefMut for RootWriter<G> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
     }
 }
 
@@ -253,8 +911,9 @@ impl<T> RwLock<T> {
     }
 }
 
-/// Allocate memory for a zeroed slice of the specified size.
-unsafe fn alloc_zeroed<T>(len: usize) -> &'static mut [T] {
+/// Allocate memory for a zeroed slice of the specified size. Returns `None`
+/// if `mmap` fails.
+unsafe fn alloc_zeroed<T>(len: usize) -> Option<&'static mut [T]> {
     let num_bytes = mem::size_of::<T>().checked_mul(len).expect("too large");
     // Memory pages should be sufficiently aligned at least for `usize`, I hope!
     assert!(core::mem::align_of::<T>() <= core::mem::align_of::<usize>());
@@ -267,7 +926,9 @@ unsafe fn alloc_zeroed<T>(len: usize) -> &'static mut [T] {
         -1,
         0,
     );
-    assert_ne!(p, libc::MAP_FAILED, "mmap {num_bytes} bytes failed");
+    if p == libc::MAP_FAILED {
+        return None;
+    }
 
-    core::slice::from_raw_parts_mut(p.cast(), len)
+    Some(core::slice::from_raw_parts_mut(p.cast(), len))
 }
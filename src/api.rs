@@ -0,0 +1,749 @@
+//! fatalloc-specific functions for programs that know they are running under
+//! fatalloc (look them up by `dlsym` to stay loadable without it)
+use core::{alloc::GlobalAlloc, ptr::NonNull};
+use libc::{c_int, c_void};
+
+use crate::ovrride::ALLOC;
+
+/// Pin the live allocation `ptr` for debugging use-after-free. A pinned
+/// allocation is never reused: when it's freed, it's filled with a poison
+/// pattern and retained until [`fatalloc_unpin_allocation`] is called, which
+/// reports any modification made after the deallocation.
+///
+/// This intentionally leaks memory. Returns `false` if `ptr` isn't a live
+/// allocation.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_pin_allocation(ptr: *mut c_void) -> bool {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr.cast(),
+        None => return false,
+    };
+    match ALLOC.pin_allocation(ptr) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("rejecting the pin request for {ptr:p}: {e}");
+            false
+        }
+    }
+}
+
+/// Unpin an allocation pinned by [`fatalloc_pin_allocation`]. If it has
+/// already been freed, it's checked for use-after-free and released.
+///
+/// `ptr` must not be used concurrently by other threads. Returns `false` if
+/// `ptr` isn't a pinned allocation.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_unpin_allocation(ptr: *mut c_void) -> bool {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr.cast(),
+        None => return false,
+    };
+    match ALLOC.unpin_allocation(ptr) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("rejecting the unpin request for {ptr:p}: {e}");
+            false
+        }
+    }
+}
+
+/// Write-protect the live allocation `ptr` so that writing to it faults, e.g.,
+/// for data that is immutable after initialization. The protection is lifted
+/// when it's freed or reallocated.
+///
+/// Only the whole pages inside the allocation (including its margins) are
+/// protected, so `ptr` should be page-aligned and its size a multiple of the
+/// page size for complete coverage. Returns `false` if `ptr` isn't a live
+/// allocation or no page could be protected.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_make_readonly(ptr: *mut c_void) -> bool {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr.cast(),
+        None => return false,
+    };
+    match ALLOC.make_readonly(ptr) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("rejecting the write-protection request for {ptr:p}: {e}");
+            false
+        }
+    }
+}
+
+/// Report every access to the live allocation `ptr` until it's unwatched,
+/// freed, or reallocated, e.g., to find out what touches a buffer. Each
+/// access is logged with the offset and the address of the instruction.
+///
+/// The access faults and is single-stepped by signal handlers for `SIGSEGV`
+/// and `SIGTRAP`, which pass other signals to the handlers installed before.
+/// This costs two signals per access. Only the whole pages inside the
+/// allocation are watched, so `ptr` should be page-aligned. A system call
+/// accessing a watched allocation fails with `EFAULT` instead of being
+/// reported. Returns `false` if `ptr` isn't a live allocation or can't be
+/// watched.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_watch(ptr: *mut c_void) -> bool {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr.cast(),
+        None => return false,
+    };
+    match ALLOC.watch(ptr) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("rejecting the watch request for {ptr:p}: {e}");
+            false
+        }
+    }
+}
+
+/// Record the calling thread as the owner of the live allocation `ptr`,
+/// which is believed to be used by one thread only, so that freeing,
+/// reallocating, or verifying it from another thread (or accessing it while
+/// it's watched) is reported as a cross-thread access, e.g., to catch a data
+/// race on it. It stays marked across reallocations. Marking it again hands
+/// it over to the calling thread. Returns `false` if `ptr` isn't a live
+/// allocation or has a token from [`fatalloc_malloc_checked`], whose room in
+/// the metadata the owner takes. `ptr` must not be freed concurrently.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_mark_single_threaded(ptr: *mut c_void) -> bool {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr.cast(),
+        None => return false,
+    };
+    match ALLOC.mark_single_threaded(ptr) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("rejecting the single-thread marking request for {ptr:p}: {e}");
+            false
+        }
+    }
+}
+
+/// Stop watching the allocation `ptr` watched by [`fatalloc_watch`]. Returns
+/// `false` if `ptr` isn't a live, watched allocation.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_unwatch(ptr: *mut c_void) -> bool {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr.cast(),
+        None => return false,
+    };
+    match ALLOC.unwatch(ptr) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("rejecting the unwatch request for {ptr:p}: {e}");
+            false
+        }
+    }
+}
+
+/// Get the start of the block fatalloc got from its backend for the live
+/// allocation `ptr`, which is `ptr` minus the margin. The margin is written
+/// to `margin` unless it's null. Returns a null pointer if `ptr` isn't a live
+/// allocation.
+///
+/// The block belongs to fatalloc: freeing it or writing to the margins
+/// bypasses fatalloc and corrupts its state. Free `ptr` instead.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_outer_pointer(
+    ptr: *mut c_void,
+    margin: *mut usize,
+) -> *mut c_void {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr.cast(),
+        None => return core::ptr::null_mut(),
+    };
+    match ALLOC.outer_pointer(ptr) {
+        Ok((outer_ptr, outer_margin)) => {
+            if let Some(margin) = margin.as_mut() {
+                *margin = outer_margin;
+            }
+            outer_ptr.as_ptr().cast()
+        }
+        Err(e) => {
+            warn!("rejecting the outer pointer request for {ptr:p}: {e}");
+            core::ptr::null_mut()
+        }
+    }
+}
+
+/// Write the size requested for the live allocation `ptr` to `requested`
+/// and the size granted to it (which `malloc_usable_size` returns) to
+/// `granted`, each unless it's null. They only differ with
+/// `FATALLOC_ROUND_SIZES=1`. Returns `false` if `ptr` isn't a live
+/// allocation.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_allocation_sizes(
+    ptr: *mut c_void,
+    requested: *mut usize,
+    granted: *mut usize,
+) -> bool {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr.cast(),
+        None => return false,
+    };
+    match ALLOC.allocation_sizes(ptr) {
+        Ok((requested_size, granted_size)) => {
+            if let Some(requested) = requested.as_mut() {
+                *requested = requested_size;
+            }
+            if let Some(granted) = granted.as_mut() {
+                *granted = granted_size;
+            }
+            true
+        }
+        Err(e) => {
+            warn!("rejecting the size query for {ptr:p}: {e}");
+            false
+        }
+    }
+}
+
+/// Check if `ptr` is a live allocation made by fatalloc. This doesn't log
+/// anything, so it's fine to call on any pointer, e.g., to decide which
+/// allocator to free a block with. `ptr` must not be freed concurrently.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_owns(ptr: *mut c_void) -> bool {
+    match NonNull::new(ptr) {
+        #[cfg(feature = "pointer-tag")]
+        Some(ptr) => ALLOC.owns(crate::ptrtag::split(ptr.cast()).0),
+        #[cfg(not(feature = "pointer-tag"))]
+        Some(ptr) => ALLOC.owns(ptr.cast()),
+        None => false,
+    }
+}
+
+/// Check the live allocation `ptr` for heap corruption with every check,
+/// regardless of `FATALLOC_CHECKS`, e.g., to assert that a buffer is intact
+/// before a critical operation. Nothing is logged or changed, except that a
+/// call from a thread not owning a single-threaded allocation
+/// ([`fatalloc_mark_single_threaded`]) is reported.
+///
+/// Returns `0` if it's intact, or what's wrong: `1` = not a live allocation,
+/// `2` = the metadata checksum doesn't match, `3` = the guard below the
+/// metadata is broken (an underflow), `4` = the canary is broken (an overrun
+/// from below), `5` = the poisoned tail left by a shrinking `realloc` was
+/// modified, `6` = the metadata is inconsistent. `ptr` must not be freed
+/// concurrently.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_verify(ptr: *mut c_void) -> c_int {
+    let ptr = match NonNull::new(ptr) {
+        #[cfg(feature = "pointer-tag")]
+        Some(ptr) => crate::ptrtag::split(ptr.cast()).0,
+        #[cfg(not(feature = "pointer-tag"))]
+        Some(ptr) => ptr.cast(),
+        None => return crate::CorruptionKind::NotAllocated as c_int,
+    };
+    match ALLOC.verify(ptr) {
+        Ok(()) => 0,
+        Err(kind) => kind as c_int,
+    }
+}
+
+/// Allocate `count` blocks of `size` bytes like `malloc`, write their
+/// pointers to `out[..count]`, and return the number of successful
+/// allocations, which come first in `out`. The rest of `out` is null or left
+/// untouched. This tracks all of them with one lock operation, which is
+/// cheaper than `count` calls to `malloc` in an allocation-heavy loop. Each
+/// block is freed individually by `free`.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_malloc_batch(
+    size: usize,
+    count: usize,
+    out: *mut *mut c_void,
+) -> usize {
+    let layout = match core::alloc::Layout::from_size_align(size, crate::MIN_ALIGN) {
+        Ok(layout) => layout,
+        Err(_) => return 0,
+    };
+    if out.is_null() {
+        return 0;
+    }
+    let out = core::slice::from_raw_parts_mut(out.cast(), count);
+    ALLOC.allocate_batch(layout, out)
+}
+
+/// Free the `count` pointers in `ptrs` like `free`, e.g., the objects of an
+/// arena all at once. They are untracked with one lock operation per 64
+/// pointers (32 on a 32-bit target), which is cheaper than `count` calls to
+/// `free`. Each pointer is validated on its own, and null pointers are
+/// skipped.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_free_batch(ptrs: *const *mut c_void, count: usize) {
+    if ptrs.is_null() {
+        return;
+    }
+    let ptrs: &[*mut u8] = core::slice::from_raw_parts(ptrs.cast(), count);
+
+    // The tags are stripped without being checked
+    #[cfg(feature = "pointer-tag")]
+    for chunk in ptrs.chunks(usize::BITS as usize) {
+        let mut untagged = [core::ptr::null_mut(); usize::BITS as usize];
+        for (out, &ptr) in untagged.iter_mut().zip(chunk) {
+            *out = NonNull::new(ptr).map_or(ptr, |ptr| crate::ptrtag::split(ptr).0.as_ptr());
+        }
+        ALLOC.deallocate_batch(&untagged[..chunk.len()]);
+    }
+    #[cfg(not(feature = "pointer-tag"))]
+    ALLOC.deallocate_batch(ptrs);
+}
+
+/// Allocate `size` bytes aligned to `align` whose pages are preferably
+/// placed on the NUMA node `node` (`mbind` with `MPOL_PREFERRED`). The
+/// alignment is raised to two pages, which makes fatalloc `mmap` the
+/// allocation directly. Returns a null pointer on failure, but only logs a
+/// failure to apply the placement (once), as it's a hint. Only nodes below
+/// `usize::BITS` can be placed on.
+///
+/// Free it by `free` as usual. A reallocation doesn't keep the placement.
+#[no_mangle]
+pub extern "C" fn fatalloc_aligned_alloc_on_node(
+    align: usize,
+    size: usize,
+    node: c_int,
+) -> *mut c_void {
+    let layout = match core::alloc::Layout::from_size_align(size, align) {
+        Ok(layout) => layout,
+        Err(_) => return core::ptr::null_mut(),
+    };
+    let node = match u32::try_from(node) {
+        Ok(node) => node,
+        Err(_) => return core::ptr::null_mut(),
+    };
+    ALLOC
+        .allocate_on_node(layout, node)
+        .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr().cast())
+}
+
+/// Allocate memory through fatalloc's `GlobalAlloc` implementation, for a
+/// Rust program's `#[global_allocator]` forwarding to fatalloc. Its other
+/// methods may keep their default implementations. Returns a null pointer if
+/// `size` and `align` don't make a valid `Layout` or the allocation fails.
+///
+/// Free it by [`fatalloc_rust_dealloc`].
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_rust_alloc(size: usize, align: usize) -> *mut c_void {
+    match core::alloc::Layout::from_size_align(size, align) {
+        Ok(layout) => GlobalAlloc::alloc(&ALLOC, layout).cast(),
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+/// [`fatalloc_rust_alloc`], but zero-initialized, for `GlobalAlloc::alloc_zeroed`
+/// to forward to. A large allocation is cleared without writing to the whole
+/// of it.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_rust_alloc_zeroed(size: usize, align: usize) -> *mut c_void {
+    match core::alloc::Layout::from_size_align(size, align) {
+        Ok(layout) => GlobalAlloc::alloc_zeroed(&ALLOC, layout).cast(),
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+/// Deallocate `ptr` through fatalloc's `GlobalAlloc` implementation, which
+/// reports a `size` or `align` other than the allocation's, e.g., from a
+/// `Box` of the wrong type. The allocation is left alone with
+/// `FATALLOC_STRICT_SIZED_FREE=1`.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_rust_dealloc(ptr: *mut c_void, size: usize, align: usize) {
+    match core::alloc::Layout::from_size_align(size, align) {
+        Ok(layout) => GlobalAlloc::dealloc(&ALLOC, ptr.cast(), layout),
+        Err(_) => warn!("ignoring the deallocation request for {ptr:p}: invalid layout"),
+    }
+}
+
+/// Allocate `size` bytes for secrets such as keys and passwords. The
+/// allocation is page-aligned, and its pages are locked in memory (`mlock`)
+/// to keep them out of swap. When it's freed (or moved by `realloc`), it's
+/// zeroed before anything else happens to it. Returns a null pointer if the
+/// pages can't be locked, e.g., because of `RLIMIT_MEMLOCK`.
+///
+/// Free it by `free` as usual.
+#[no_mangle]
+pub extern "C" fn fatalloc_malloc_secure(size: usize) -> *mut c_void {
+    let layout = match core::alloc::Layout::from_size_align(size, crate::MIN_ALIGN) {
+        Ok(layout) => layout,
+        Err(_) => return core::ptr::null_mut(),
+    };
+    ALLOC
+        .allocate_secure(layout)
+        .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr().cast())
+}
+
+/// Allocate `size` bytes like `malloc` and write a token for the allocation
+/// to `token`, which [`fatalloc_free_checked`] requires to free it. Returns a
+/// null pointer on failure or if `token` is null.
+///
+/// The token is a number unique among the allocations made by this function
+/// (until it wraps around after 2^64 of them, or 2^32 on a 32-bit target).
+/// Unlike the heuristics `free` relies on, it tells a stale pointer to reused
+/// memory apart from the allocation now there.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_malloc_checked(size: usize, token: *mut u64) -> *mut c_void {
+    let layout = match core::alloc::Layout::from_size_align(size, crate::MIN_ALIGN) {
+        Ok(layout) => layout,
+        Err(_) => return core::ptr::null_mut(),
+    };
+    if token.is_null() {
+        return core::ptr::null_mut();
+    }
+    match ALLOC.allocate_checked(layout) {
+        Some((ptr, new_token)) => {
+            *token = new_token;
+            ptr.as_ptr().cast()
+        }
+        None => core::ptr::null_mut(),
+    }
+}
+
+/// Free `ptr` if `token` is the one [`fatalloc_malloc_checked`] returned for
+/// it. Otherwise, e.g., if it was freed already or the address was reused by
+/// another allocation since, it's reported as an invalid pointer and left
+/// alone. Returns whether it was freed.
+///
+/// A `realloc` changing the size ends the allocation, so its token is
+/// rejected afterwards, too.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_free_checked(ptr: *mut c_void, token: u64) -> bool {
+    match NonNull::new(ptr) {
+        Some(ptr) => ALLOC.deallocate_checked(ptr.cast(), token),
+        None => false,
+    }
+}
+
+/// Make every subsequent request for new memory fail until
+/// [`fatalloc_unfreeze`] is called, e.g., to enforce that a service doesn't
+/// allocate after its initialization. `malloc` and its friends return null
+/// with `errno = ENOMEM`, and so does `realloc` unless it shrinks or keeps the
+/// size. Deallocation keeps working. The first denial is logged.
+#[no_mangle]
+pub extern "C" fn fatalloc_freeze() {
+    crate::set_frozen(true);
+}
+
+/// Allow new memory again after [`fatalloc_freeze`].
+#[no_mangle]
+pub extern "C" fn fatalloc_unfreeze() {
+    crate::set_frozen(false);
+}
+
+/// Set the log level threshold (`0` = off, `1` = warn, `2` = info), which
+/// overrides `FATALLOC_LOG_LEVEL`, and return the previous one, e.g., to
+/// restore it after logging allocations made by a specific operation.
+///
+/// The threshold is process-wide, so other threads' messages are affected
+/// too. Returns `-1` without changing anything if `level` is invalid.
+#[no_mangle]
+pub extern "C" fn fatalloc_set_log_level(level: c_int) -> c_int {
+    use crate::logger::Level;
+    if !(Level::Off as c_int..=Level::Info as c_int).contains(&level) {
+        return -1;
+    }
+    crate::logger::set_level(Level::from_u8(level as u8)) as c_int
+}
+
+/// Call `f(arg)` with the log level threshold set to `level` like
+/// [`fatalloc_set_log_level`], and restore the previous one when it returns,
+/// e.g., to log only the allocations made by one operation. The previous
+/// threshold is also restored if the thread exits in `f` by `pthread_exit` or
+/// cancellation.
+///
+/// The threshold is still process-wide while `f` runs, so other threads'
+/// messages are affected too. Returns `-1` without calling `f` if `level` is
+/// invalid, and `0` otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_with_log_level(
+    level: c_int,
+    f: unsafe extern "C" fn(*mut c_void),
+    arg: *mut c_void,
+) -> c_int {
+    use crate::logger::Level;
+    if !(Level::Off as c_int..=Level::Info as c_int).contains(&level) {
+        return -1;
+    }
+    crate::logger::with_level(Level::from_u8(level as u8), || f(arg));
+    0
+}
+
+/// Redirect fatalloc's messages to the file descriptor `fd`, which is
+/// `STDERR_FILENO` initially, and return the previous one, e.g., to follow a
+/// log file reopened for rotation.
+///
+/// A message isn't split between the two, and none is being written to the
+/// previous one once this returns, so the caller can close it right away.
+/// Returns `-1` without changing anything if `fd` is negative.
+#[no_mangle]
+pub extern "C" fn fatalloc_set_log_fd(fd: c_int) -> c_int {
+    if fd < 0 {
+        return -1;
+    }
+    crate::logger::set_fd(fd)
+}
+
+/// Get the total size of live allocations, excluding margins.
+#[no_mangle]
+pub extern "C" fn fatalloc_bytes_live() -> usize {
+    crate::stats::bytes_live()
+}
+
+/// Get the total size of the margins of live allocations, i.e., the memory
+/// fatalloc spends on top of [`fatalloc_bytes_live`].
+#[no_mangle]
+pub extern "C" fn fatalloc_overhead_bytes() -> usize {
+    crate::stats::overhead_bytes()
+}
+
+/// Get the number of live allocations as counted on allocation and
+/// deallocation. It's counted like [`fatalloc_bytes_live`].
+#[no_mangle]
+pub extern "C" fn fatalloc_live_allocations() -> usize {
+    crate::stats::num_live_allocations()
+}
+
+/// Count the live allocations in the bitmap tracking them, which is what
+/// `free` checks pointers against. A difference from
+/// [`fatalloc_live_allocations`] while no other thread is allocating means
+/// that the accounting is broken.
+///
+/// This scans the whole bitmap, taking time proportional to
+/// [`fatalloc_map_memory_bytes`] and blocking the allocations and
+/// deallocations that need a new leaf or sub-leaf meanwhile.
+#[no_mangle]
+pub extern "C" fn fatalloc_count_tracked() -> usize {
+    crate::alloc_map().count_set()
+}
+
+/// Get the memory fatalloc has mapped for the bitmap tracking the
+/// allocations, which isn't included in [`fatalloc_overhead_bytes`]. It grows
+/// by a leaf for each new 64 MiB range (on a 64-bit target) holding an
+/// allocation, and by a sub-leaf for each 256 KiB range in it.
+#[no_mangle]
+pub extern "C" fn fatalloc_map_memory_bytes() -> usize {
+    crate::alloc_map().num_mapped_bytes()
+}
+
+/// Get the freed allocation a word read from freed memory came from, if it
+/// has the pattern `FATALLOC_IDENTITY_FILL=1` fills freed allocations with,
+/// e.g., to trace a stale value that turned up elsewhere. Returns a null
+/// pointer otherwise. The word must be read at an offset into the freed
+/// allocation that's a multiple of its size. On a 32-bit target, any word
+/// aligned like an allocation decodes.
+#[no_mangle]
+pub extern "C" fn fatalloc_decode_fill(word: usize) -> *mut c_void {
+    crate::decode_identity_fill(word).map_or(core::ptr::null_mut(), |ptr| ptr as *mut c_void)
+}
+
+/// Get the number of allocations per second over the last full interval of
+/// one second, e.g., to poll for allocation-heavy phases of a program. An
+/// interval is completed by the call that ends it, so polling more often
+/// than once a second returns the same rate. Returns `0` until an interval
+/// is complete; the first call starts one.
+#[no_mangle]
+pub extern "C" fn fatalloc_churn_rate() -> usize {
+    crate::stats::churn_rate()
+}
+
+/// Get the quarantine's byte budget, which is `FATALLOC_QUARANTINE_BYTES` or
+/// the one sized by `FATALLOC_QUARANTINE_FRACTION` when the configuration is
+/// read.
+#[no_mangle]
+pub extern "C" fn fatalloc_quarantine_budget() -> usize {
+    crate::config::get().quarantine_bytes
+}
+
+/// Give memory back now, e.g., after a spike in memory usage subsides: flush
+/// the quarantine (discarding the pages of the flushed blocks), unmap the
+/// empty parts of the allocation bitmap, and call the system allocator's
+/// `malloc_trim` if it's the backend. Returns the number of bytes flushed
+/// and unmapped.
+///
+/// Flushing the quarantine stops it from catching use-after-free of the
+/// blocks freed so far.
+#[no_mangle]
+pub extern "C" fn fatalloc_compact() -> usize {
+    ALLOC.compact()
+}
+
+/// Write allocator statistics to `FATALLOC_METRICS_FILE` in the Prometheus
+/// text exposition format. The file is replaced atomically. Returns `false`
+/// if `FATALLOC_METRICS_FILE` is unset or writing failed.
+///
+/// Only available with the `stats` feature.
+#[cfg(feature = "stats")]
+#[no_mangle]
+pub extern "C" fn fatalloc_export_metrics() -> bool {
+    crate::metrics::export()
+}
+
+/// Write the call stacks of live allocations to `FATALLOC_FOLDED_FILE` in
+/// the folded-stack format (one line per allocation site with the total size
+/// of the allocations made there), which flame graph tools take. The file is
+/// replaced atomically, and it's also written when the process exits.
+/// Returns `false` if `FATALLOC_FOLDED_FILE` is unset or writing failed.
+///
+/// Only available with the `backtrace` feature.
+#[cfg(feature = "backtrace")]
+#[no_mangle]
+pub extern "C" fn fatalloc_dump_folded() -> bool {
+    ALLOC.export_folded()
+}
+
+/// Calls [`fatalloc_dump_folded`] on exit if `FATALLOC_FOLDED_FILE` is set
+#[cfg(feature = "backtrace")]
+#[used]
+#[link_section = ".fini_array"]
+static DUMP_FOLDED_AT_EXIT: extern "C" fn() = {
+    extern "C" fn dump_folded_at_exit() {
+        if crate::config::get().folded_file.is_some() {
+            fatalloc_dump_folded();
+        }
+    }
+    dump_folded_at_exit
+};
+
+/// Write up to `len` pointers of live allocations to `out`, from the oldest
+/// (or the newest if `newest_first` is set), and return the number of written
+/// pointers. A reallocated allocation counts as the newest.
+///
+/// Requires `FATALLOC_REGISTRY=1`; allocations made without it aren't
+/// included.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_registered_allocations(
+    out: *mut *mut c_void,
+    len: usize,
+    newest_first: bool,
+) -> usize {
+    if out.is_null() {
+        return 0;
+    }
+    let out = core::slice::from_raw_parts_mut(out.cast(), len);
+    ALLOC.registered_allocations(out, newest_first)
+}
+
+/// Report live allocations made at least `min_age` seconds ago as possible
+/// leaks and return their number. A reallocation doesn't reset the age.
+///
+/// Requires `FATALLOC_REGISTRY=1`; allocations made without it aren't
+/// included.
+#[no_mangle]
+pub extern "C" fn fatalloc_report_old_allocations(min_age: usize) -> usize {
+    ALLOC.report_old_allocations(min_age)
+}
+
+/// Call `callback(ptr, size, ctx)` for every live allocation whose pointer
+/// falls in `start..end`, in the address order. Only the parts of the
+/// allocation bitmap covering the range are scanned, so this is cheap for a
+/// small range, e.g., around a faulting address in a crash handler.
+///
+/// Concurrent deallocations aren't blocked, so `callback` may be given an
+/// allocation being freed.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_allocations_in_range(
+    start: usize,
+    end: usize,
+    callback: unsafe extern "C" fn(ptr: *mut c_void, size: usize, ctx: *mut c_void),
+    ctx: *mut c_void,
+) {
+    ALLOC.allocations_in_range(start..end, |ptr, size| {
+        callback(ptr.as_ptr().cast(), size, ctx)
+    });
+}
+
+/// Check that fatalloc's metadata handling works on this platform, which
+/// `FATALLOC_SELF_TEST=1` does at startup, and log the result. Returns
+/// `true` if the check passed.
+#[no_mangle]
+pub extern "C" fn fatalloc_self_test() -> bool {
+    crate::selftest::run()
+}
+
+/// Check that no two live allocations (including their margins) overlap,
+/// which would mean a bug in fatalloc or its backend, and report every
+/// collision. Returns the number of collisions.
+///
+/// This scans the whole allocation bitmap, so it's slow with a sparse heap.
+#[no_mangle]
+pub extern "C" fn fatalloc_check_consistency() -> usize {
+    ALLOC.check_consistency()
+}
+
+/// Move up to `len` recent heap corruption events, the oldest first, to
+/// `out` and return their number. Up to 64 events are kept; older ones are
+/// dropped and counted by [`fatalloc_dropped_corruptions`].
+///
+/// The events are recorded even with `FATALLOC_ON_CORRUPTION=ignore`.
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_recent_corruptions(
+    out: *mut crate::corruptions::CorruptionEvent,
+    len: usize,
+) -> usize {
+    if out.is_null() {
+        return 0;
+    }
+    crate::corruptions::drain(core::slice::from_raw_parts_mut(out, len))
+}
+
+/// Get the number of corruption events dropped before
+/// [`fatalloc_recent_corruptions`] collected them.
+#[no_mangle]
+pub extern "C" fn fatalloc_dropped_corruptions() -> usize {
+    crate::corruptions::num_dropped()
+}
+
+/// Get the number of repeated corruption events suppressed by
+/// `FATALLOC_REPORT_ONCE`.
+#[no_mangle]
+pub extern "C" fn fatalloc_suppressed_corruptions() -> usize {
+    crate::corruptions::num_suppressed()
+}
+
+/// Log the structure of the allocation bitmap: the number of leaves and the
+/// capacity of the array holding them, the memory mapped for it, and the
+/// number of sub-leaves and allocations in each leaf. Returns the number of
+/// leaves.
+///
+/// This is for debugging fatalloc itself and tuning its leaf size. Only
+/// available with the `map-stats` feature.
+#[cfg(feature = "map-stats")]
+#[no_mangle]
+pub extern "C" fn fatalloc_dump_map_stats() -> usize {
+    let leaf_bytes = crate::allocmap::AllocMap::LEAF_LEN * crate::MIN_ALIGN;
+    let stats = crate::alloc_map().stats(|leaf| {
+        warn!(
+            "allocation map leaf {:#x}..{:#x}: {} sub-leaves, {} allocations",
+            leaf.start * crate::MIN_ALIGN,
+            (leaf.start * crate::MIN_ALIGN).wrapping_add(leaf_bytes),
+            leaf.num_subleaves,
+            leaf.num_set
+        );
+    });
+    warn!(
+        "allocation map: {} leaves (capacity {}), {} bytes mapped",
+        stats.num_leaves, stats.leaves_cap, stats.num_bytes
+    );
+    stats.num_leaves
+}
+
+/// Tag the live allocation `ptr` with `tag`, which identifies its type. The
+/// returned pointer has `tag` in its top byte; freeing, reallocating, or
+/// querying the allocation through a pointer with a different non-zero tag
+/// is reported as possible type confusion. Untagged pointers are still
+/// accepted. `tag = 0` removes the tag.
+///
+/// Only available with the `pointer-tag` feature. The returned pointer can
+/// only be dereferenced on AArch64. Returns a null pointer if `ptr` isn't a
+/// live allocation.
+#[cfg(feature = "pointer-tag")]
+#[no_mangle]
+pub unsafe extern "C" fn fatalloc_tag_pointer(ptr: *mut c_void, tag: u8) -> *mut c_void {
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => crate::ovrride::untag(ptr).0,
+        None => return core::ptr::null_mut(),
+    };
+    match ALLOC.set_tag(ptr, tag) {
+        Ok(()) => crate::ptrtag::apply(ptr, tag).as_ptr().cast(),
+        Err(e) => {
+            warn!("rejecting the tag request for {ptr:p}: {e}");
+            core::ptr::null_mut()
+        }
+    }
+}
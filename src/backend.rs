@@ -0,0 +1,373 @@
+//! The backend allocator selected by `FATALLOC_BACKEND`
+//!
+//! The selection is made on the first use and fixed afterwards, so every
+//! block is returned to the allocator it came from. Dispatching costs a load
+//! and a well-predicted branch per backend call, which is small next to the
+//! cost of the allocator itself.
+use core::{
+    alloc::Layout,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering},
+};
+use rlsf::CAlloc;
+
+use crate::{config, emergency, CAllocBlockSize, CAllocZeroed};
+
+/// A backend choice
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Kind {
+    /// `rlsf`: `rlsf::GlobalTlsf`
+    Tlsf = 1,
+    /// `system`: The allocator `LD_PRELOAD` took over, usually glibc's
+    System = 2,
+    /// `bump`: `bump::Bump`
+    #[cfg(feature = "bump-backend")]
+    Bump = 3,
+}
+
+pub struct Backend {
+    /// Read-locked around every call into `tlsf` and `bump`, so that the
+    /// `fork` handlers can wait for them to finish by write-locking it.
+    /// `rlsf`'s own lock is out of their reach. The calls still only
+    /// serialize on `rlsf`'s and `bump`'s locks.
+    lock: RwLock,
+    tlsf: rlsf::GlobalTlsf,
+    #[cfg(feature = "bump-backend")]
+    bump: crate::bump::Bump,
+    /// `Kind`, or `0` if not selected yet
+    kind: AtomicU8,
+}
+
+impl Backend {
+    pub const INIT: Self = Self {
+        lock: RwLock::INIT,
+        tlsf: rlsf::GlobalTlsf::INIT,
+        #[cfg(feature = "bump-backend")]
+        bump: crate::bump::Bump::INIT,
+        kind: AtomicU8::new(0),
+    };
+
+    #[inline]
+    fn kind(&self) -> Kind {
+        match self.kind.load(Ordering::Relaxed) {
+            0 => self.select(),
+            1 => Kind::Tlsf,
+            #[cfg(feature = "bump-backend")]
+            3 => Kind::Bump,
+            _ => Kind::System,
+        }
+    }
+
+    #[cold]
+    fn select(&self) -> Kind {
+        let mut kind = config::get().backend;
+        if kind == Kind::System && is_looking_up() {
+            // Called back by `dlsym` in the middle of the selection. Don't
+            // settle on anything yet. The block comes from `bootstrap`.
+            return Kind::System;
+        }
+        if kind == Kind::System && system_fns().is_none() {
+            warn!("the system allocator is unavailable; falling back to rlsf");
+            kind = Kind::Tlsf;
+        }
+        match self
+            .kind
+            .compare_exchange(0, kind as u8, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => kind,
+            // Another thread was first
+            Err(_) => self.kind(),
+        }
+    }
+}
+
+impl Backend {
+    /// Run `f` with `self.lock` read-locked.
+    #[inline]
+    fn locked<R>(&self, f: impl FnOnce() -> R) -> R {
+        unsafe {
+            libc::pthread_rwlock_rdlock(self.lock.0.get());
+            let result = f();
+            libc::pthread_rwlock_unlock(self.lock.0.get());
+            result
+        }
+    }
+
+    /// Get the lock read-locked around the calls into `rlsf` and the bump
+    /// allocator for the `fork` handlers.
+    pub fn fork_rwlock(&self) -> *mut libc::pthread_rwlock_t {
+        self.lock.0.get()
+    }
+
+    /// Ask the backend to return its free memory to the OS.
+    pub fn trim(&self) {
+        match self.kind() {
+            // `rlsf` never gives back its pools
+            Kind::Tlsf => {}
+            Kind::System => unsafe { system_trim() },
+            #[cfg(feature = "bump-backend")]
+            Kind::Bump => {}
+        }
+    }
+}
+
+unsafe impl CAlloc for Backend {
+    #[inline]
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        match self.kind() {
+            Kind::Tlsf => self.locked(|| self.tlsf.allocate(layout)),
+            Kind::System if is_looking_up() => bootstrap::allocate(layout),
+            Kind::System => unsafe { system_allocate(layout) },
+            #[cfg(feature = "bump-backend")]
+            Kind::Bump => self.locked(|| self.bump.allocate(layout)),
+        }
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>) {
+        if bootstrap::owns(ptr) {
+            return bootstrap::deallocate(ptr);
+        }
+        match self.kind() {
+            Kind::Tlsf => self.locked(|| self.tlsf.deallocate(ptr)),
+            Kind::System => (system_fns().unwrap().free)(ptr.as_ptr().cast()),
+            // Blocks are never reused
+            #[cfg(feature = "bump-backend")]
+            Kind::Bump => {}
+        }
+    }
+
+    #[inline]
+    unsafe fn reallocate(&self, ptr: NonNull<u8>, new_layout: Layout) -> Option<NonNull<u8>> {
+        if bootstrap::owns(ptr) {
+            let new_ptr = self.allocate(new_layout)?;
+            core::ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.as_ptr(),
+                emergency::usable_size(ptr).min(new_layout.size()),
+            );
+            bootstrap::deallocate(ptr);
+            return Some(new_ptr);
+        }
+        match self.kind() {
+            Kind::Tlsf => self.locked(|| self.tlsf.reallocate(ptr, new_layout)),
+            Kind::System => system_reallocate(ptr, new_layout),
+            #[cfg(feature = "bump-backend")]
+            Kind::Bump => self.locked(|| self.bump.reallocate(ptr, new_layout)),
+        }
+    }
+}
+
+unsafe impl CAllocZeroed for Backend {
+    #[inline]
+    fn allocate_zeroed(&self, layout: Layout) -> Option<(NonNull<u8>, bool)> {
+        match self.kind() {
+            Kind::Tlsf => self.locked(|| self.tlsf.allocate_zeroed(layout)),
+            // Freshly mapped
+            Kind::System if is_looking_up() => bootstrap::allocate(layout).map(|ptr| (ptr, true)),
+            // `calloc` can skip clearing fresh memory
+            Kind::System if layout.align() <= SYSTEM_MIN_ALIGN => {
+                let p = unsafe { (system_fns()?.calloc)(1, layout.size()) };
+                NonNull::new(p.cast()).map(|ptr| (ptr, true))
+            }
+            Kind::System => self.allocate(layout).map(|ptr| (ptr, false)),
+            // Blocks are never reused, so they're freshly mapped
+            #[cfg(feature = "bump-backend")]
+            Kind::Bump => self
+                .locked(|| self.bump.allocate(layout))
+                .map(|ptr| (ptr, true)),
+        }
+    }
+}
+
+unsafe impl CAllocBlockSize for Backend {
+    #[inline]
+    unsafe fn block_size(&self, ptr: NonNull<u8>) -> Option<usize> {
+        if bootstrap::owns(ptr) {
+            return Some(emergency::usable_size(ptr));
+        }
+        match self.kind() {
+            // `rlsf` doesn't expose it
+            Kind::Tlsf => None,
+            Kind::System => Some((system_fns()?.malloc_usable_size)(ptr.as_ptr().cast())),
+            #[cfg(feature = "bump-backend")]
+            Kind::Bump => Some(self.bump.block_size(ptr)),
+        }
+    }
+}
+
+/// Blocks allocated while [`system_fns`] looks up the system allocator,
+/// which `dlsym` may call back for. The system allocator can't serve them,
+/// and neither can `rlsf` because the system allocator may end up being
+/// selected and would be handed them back. They are mapped by `emergency`
+/// instead, which every free and size query checks first while any of them
+/// is alive.
+mod bootstrap {
+    use core::{
+        alloc::Layout,
+        ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use crate::emergency;
+
+    /// The number of live blocks
+    static NUM_BLOCKS: AtomicUsize = AtomicUsize::new(0);
+
+    pub fn allocate(layout: Layout) -> Option<NonNull<u8>> {
+        let ptr = emergency::allocate(layout)?;
+        NUM_BLOCKS.fetch_add(1, Ordering::Relaxed);
+        Some(ptr)
+    }
+
+    /// Check if `ptr` is a block made by [`allocate`]. It's only a load and
+    /// a predictable branch unless one is alive.
+    #[inline]
+    pub fn owns(ptr: NonNull<u8>) -> bool {
+        NUM_BLOCKS.load(Ordering::Relaxed) != 0 && emergency::owns(ptr)
+    }
+
+    /// Release the block `ptr`, which must satisfy [`owns`].
+    pub unsafe fn deallocate(ptr: NonNull<u8>) {
+        emergency::deallocate(ptr);
+        NUM_BLOCKS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The alignment the system `malloc` guarantees (glibc's `MALLOC_ALIGNMENT`)
+const SYSTEM_MIN_ALIGN: usize = core::mem::size_of::<usize>() * 2;
+
+unsafe fn system_allocate(layout: Layout) -> Option<NonNull<u8>> {
+    let mut p = null_mut();
+    let align = layout.align().max(core::mem::size_of::<usize>());
+    if (system_fns()?.posix_memalign)(&mut p, align, layout.size()) != 0 {
+        return None;
+    }
+    NonNull::new(p.cast())
+}
+
+unsafe fn system_reallocate(ptr: NonNull<u8>, new_layout: Layout) -> Option<NonNull<u8>> {
+    let fns = system_fns()?;
+    if new_layout.align() <= SYSTEM_MIN_ALIGN {
+        return NonNull::new((fns.realloc)(ptr.as_ptr().cast(), new_layout.size()).cast());
+    }
+
+    // `realloc` doesn't preserve larger alignments
+    let new_ptr = system_allocate(new_layout)?;
+    let old_size = (fns.malloc_usable_size)(ptr.as_ptr().cast());
+    core::ptr::copy_nonoverlapping(
+        ptr.as_ptr(),
+        new_ptr.as_ptr(),
+        old_size.min(new_layout.size()),
+    );
+    (fns.free)(ptr.as_ptr().cast());
+    Some(new_ptr)
+}
+
+/// Call the system allocator's `malloc_trim` if it has one, which glibc does.
+unsafe fn system_trim() {
+    static MALLOC_TRIM: AtomicPtr<libc::c_void> = AtomicPtr::new(null_mut());
+
+    let mut p = MALLOC_TRIM.load(Ordering::Relaxed);
+    if p.is_null() {
+        p = libc::dlsym(libc::RTLD_NEXT, b"malloc_trim\0".as_ptr().cast());
+        MALLOC_TRIM.store(p, Ordering::Relaxed);
+    }
+    if !p.is_null() {
+        let malloc_trim: unsafe extern "C" fn(usize) -> libc::c_int = core::mem::transmute(p);
+        malloc_trim(0);
+    }
+}
+
+/// The functions of the system allocator
+pub struct SystemFns {
+    pub malloc: unsafe extern "C" fn(usize) -> *mut libc::c_void,
+    pub posix_memalign: unsafe extern "C" fn(*mut *mut libc::c_void, usize, usize) -> libc::c_int,
+    pub calloc: unsafe extern "C" fn(usize, usize) -> *mut libc::c_void,
+    pub realloc: unsafe extern "C" fn(*mut libc::c_void, usize) -> *mut libc::c_void,
+    pub free: unsafe extern "C" fn(*mut libc::c_void),
+    pub malloc_usable_size: unsafe extern "C" fn(*mut libc::c_void) -> usize,
+}
+
+/// Look up the system allocator's functions by `dlsym(RTLD_NEXT, ...)` on
+/// the first call. Returns `None` if any of them is missing, or if called
+/// by `dlsym` itself during the lookup.
+pub fn system_fns() -> Option<&'static SystemFns> {
+    static FNS: AtomicPtr<SystemFns> = AtomicPtr::new(null_mut());
+    static mut FNS_STORAGE: Option<SystemFns> = None;
+    let p = FNS.load(Ordering::Acquire);
+    if !p.is_null() {
+        return Some(unsafe { &*p });
+    }
+
+    // `dlsym` may allocate memory, which comes back here
+    if is_looking_up() {
+        return None;
+    }
+    let this_thread = unsafe { libc::pthread_self() } as usize;
+
+    unsafe {
+        libc::pthread_mutex_lock(LOOKUP_MUTEX.0.get());
+        OWNER.store(this_thread, Ordering::Relaxed);
+        if FNS.load(Ordering::Relaxed).is_null() {
+            let lookup = |name: &[u8]| {
+                let p = libc::dlsym(libc::RTLD_NEXT, name.as_ptr().cast());
+                (!p.is_null()).then(|| p)
+            };
+            let fns = (|| {
+                Some(SystemFns {
+                    malloc: core::mem::transmute(lookup(b"malloc\0")?),
+                    posix_memalign: core::mem::transmute(lookup(b"posix_memalign\0")?),
+                    calloc: core::mem::transmute(lookup(b"calloc\0")?),
+                    realloc: core::mem::transmute(lookup(b"realloc\0")?),
+                    free: core::mem::transmute(lookup(b"free\0")?),
+                    malloc_usable_size: core::mem::transmute(lookup(b"malloc_usable_size\0")?),
+                })
+            })();
+            if let Some(fns) = fns {
+                let storage = &mut *core::ptr::addr_of_mut!(FNS_STORAGE);
+                FNS.store(storage.insert(fns), Ordering::Release);
+            }
+        }
+        OWNER.store(0, Ordering::Relaxed);
+        libc::pthread_mutex_unlock(LOOKUP_MUTEX.0.get());
+    }
+
+    let p = FNS.load(Ordering::Acquire);
+    (!p.is_null()).then(|| unsafe { &*p })
+}
+
+/// Serializes [`system_fns`]'s lookup
+static LOOKUP_MUTEX: Mutex = Mutex::INIT;
+
+/// Get [`system_fns`]'s lookup lock for the `fork` handlers.
+pub fn lookup_mutex() -> *mut libc::pthread_mutex_t {
+    LOOKUP_MUTEX.0.get()
+}
+
+/// The thread running [`system_fns`]'s lookup
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+
+/// Check if this thread is in [`system_fns`]'s lookup.
+pub fn is_looking_up() -> bool {
+    OWNER.load(Ordering::Relaxed) == unsafe { libc::pthread_self() } as usize
+}
+
+struct Mutex(core::cell::UnsafeCell<libc::pthread_mutex_t>);
+
+unsafe impl Sync for Mutex {}
+
+impl Mutex {
+    const INIT: Self = Self(core::cell::UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER));
+}
+
+struct RwLock(core::cell::UnsafeCell<libc::pthread_rwlock_t>);
+
+unsafe impl Sync for RwLock {}
+
+impl RwLock {
+    const INIT: Self = Self(core::cell::UnsafeCell::new(
+        libc::PTHREAD_RWLOCK_INITIALIZER,
+    ));
+}
@@ -0,0 +1,155 @@
+//! Call stack capturing by the DWARF unwinder in `libgcc_s`
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use libc::{c_int, c_void};
+
+use crate::registry::SITE_DEPTH;
+
+/// The maximum number of frames a [`Backtrace`] holds
+pub const DEPTH: usize = 6;
+
+/// Return addresses of the innermost frames, outermost last. Unused entries
+/// are zero.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Backtrace {
+    pub frames: [usize; DEPTH],
+}
+
+#[repr(C)]
+struct UnwindContext {
+    _private: [u8; 0],
+}
+
+const URC_NO_REASON: c_int = 0;
+const URC_END_OF_STACK: c_int = 5;
+
+type UnwindTraceFn = extern "C" fn(*mut UnwindContext, *mut c_void) -> c_int;
+
+#[link(name = "gcc_s")]
+extern "C" {
+    fn _Unwind_Backtrace(trace: UnwindTraceFn, arg: *mut c_void) -> c_int;
+    fn _Unwind_GetIP(ctx: *mut UnwindContext) -> usize;
+}
+
+struct State {
+    skip: usize,
+    /// Frames in this range are skipped, too, until one outside it is found
+    skip_range: (usize, usize),
+    len: usize,
+    bt: Backtrace,
+}
+
+impl Backtrace {
+    /// Capture the current call stack, skipping the `skip` innermost frames
+    /// in addition to this function's.
+    #[inline(never)]
+    pub fn capture(skip: usize) -> Self {
+        unwind(skip + 1, (0, 0))
+    }
+
+    /// Make one from the frames of a `registry::Origin::site`.
+    pub fn from_site(site: &[usize; SITE_DEPTH]) -> Self {
+        let mut this = Self::default();
+        this.frames[..SITE_DEPTH].copy_from_slice(site);
+        this
+    }
+}
+
+/// Capture the innermost frames of the current call stack outside fatalloc's
+/// own code, i.e., where fatalloc was called, for `registry::Origin::site`.
+#[inline(never)]
+pub fn capture_site() -> [usize; SITE_DEPTH] {
+    let bt = unwind(0, own_code());
+    let mut site = [0; SITE_DEPTH];
+    site.copy_from_slice(&bt.frames[..SITE_DEPTH]);
+    site
+}
+
+/// Walk the call stack from the caller, skipping `skip` frames and then the
+/// ones in `skip_range`. Inlined so that the caller's frame is the first one.
+#[inline(always)]
+fn unwind(skip: usize, skip_range: (usize, usize)) -> Backtrace {
+    extern "C" fn trace(ctx: *mut UnwindContext, arg: *mut c_void) -> c_int {
+        let state = unsafe { &mut *arg.cast::<State>() };
+        if state.skip > 0 {
+            state.skip -= 1;
+            return URC_NO_REASON;
+        }
+        let ip = unsafe { _Unwind_GetIP(ctx) };
+        let (start, end) = state.skip_range;
+        if state.len == 0 && (start..end).contains(&ip) {
+            return URC_NO_REASON;
+        }
+        state.bt.frames[state.len] = ip;
+        state.len += 1;
+        if state.len == DEPTH {
+            URC_END_OF_STACK
+        } else {
+            URC_NO_REASON
+        }
+    }
+
+    let mut state = State {
+        skip,
+        skip_range,
+        len: 0,
+        bt: Backtrace::default(),
+    };
+    unsafe { _Unwind_Backtrace(trace, (&mut state as *mut State).cast()) };
+    state.bt
+}
+
+/// The address range of fatalloc's code, found on the first use. The end is
+/// `0` until then.
+static OWN_CODE: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+fn own_code() -> (usize, usize) {
+    let end = OWN_CODE[1].load(Ordering::Acquire);
+    if end != 0 {
+        return (OWN_CODE[0].load(Ordering::Relaxed), end);
+    }
+    // An empty range if it's not found
+    let (start, end) = crate::procmaps::range(own_code as usize).unwrap_or((1, 1));
+    OWN_CODE[0].store(start, Ordering::Relaxed);
+    OWN_CODE[1].store(end, Ordering::Release);
+    (start, end)
+}
+
+impl fmt::Display for Backtrace {
+    /// Format as a comma-separated list of [`Frame`]s.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.frames[0] == 0 {
+            return f.write_str("(unknown)");
+        }
+        let frames = self.frames.iter().take_while(|&&ip| ip != 0);
+        for (i, &ip) in frames.enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", Frame(ip))?;
+        }
+        Ok(())
+    }
+}
+
+/// A code address
+pub struct Frame(pub usize);
+
+impl fmt::Display for Frame {
+    /// Format in the `module+0xaddress` form if `FATALLOC_SYMBOLIZE` is enabled
+    /// and the module is found, or as a plain hex address otherwise.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let location = if crate::config::get().symbolize {
+            crate::procmaps::locate(self.0)
+        } else {
+            None
+        };
+        match location {
+            Some(location) => write!(f, "{location}"),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
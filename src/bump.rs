@@ -0,0 +1,123 @@
+//! A bump allocator backend (`FATALLOC_BACKEND=bump`) that places blocks
+//! deterministically, so that heap-layout-dependent bugs can be reproduced
+//!
+//! Blocks are carved out of chunks in request order and never reused, so the
+//! same sequence of requests gets the same offsets on every run. The chunks
+//! are mapped back to back from `FATALLOC_FIXED_BASE` if it's set, making the
+//! addresses themselves the same. Freed memory is never given back, which is
+//! only affordable in testing.
+use core::{alloc::Layout, cell::UnsafeCell, ptr::NonNull};
+
+use crate::config;
+
+/// The size of a chunk. Larger blocks get a chunk of their own.
+const CHUNK_SIZE: usize = 64 << 20;
+
+/// The room for a block's size in front of it
+const HEADER_LEN: usize = core::mem::size_of::<usize>();
+
+struct Inner {
+    /// The next free byte in the current chunk, or `0` if none is mapped
+    next: usize,
+    /// The end of the current chunk
+    end: usize,
+}
+
+pub struct Bump {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    inner: UnsafeCell<Inner>,
+}
+
+unsafe impl Sync for Bump {}
+
+impl Bump {
+    pub const INIT: Self = Self {
+        mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+        inner: UnsafeCell::new(Inner { next: 0, end: 0 }),
+    };
+
+    /// Run `f` with the allocator locked.
+    fn with_inner<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        unsafe {
+            libc::pthread_mutex_lock(self.mutex.get());
+            let result = f(&mut *self.inner.get());
+            libc::pthread_mutex_unlock(self.mutex.get());
+            result
+        }
+    }
+
+    /// Allocate a block of zero-filled memory.
+    pub fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let align = layout.align().max(HEADER_LEN);
+        self.with_inner(|inner| {
+            let fits = |next: usize, end: usize| {
+                let start = next.checked_add(HEADER_LEN + align - 1)? & !(align - 1);
+                Some(start).filter(|&start| {
+                    start
+                        .checked_add(layout.size())
+                        .map_or(false, |block_end| block_end <= end)
+                })
+            };
+            let start = match fits(inner.next, inner.end) {
+                Some(start) => start,
+                None => {
+                    let page_size = crate::page_size();
+                    let len = layout
+                        .size()
+                        .checked_add(HEADER_LEN + align + page_size - 1)?
+                        & !(page_size - 1);
+                    let len = len.max(CHUNK_SIZE);
+                    let chunk = map_chunk(inner.end, len)?;
+                    inner.next = chunk;
+                    inner.end = chunk + len;
+                    fits(inner.next, inner.end)?
+                }
+            };
+            inner.next = start + layout.size();
+            unsafe { *((start - HEADER_LEN) as *mut usize) = layout.size() };
+            NonNull::new(start as *mut u8)
+        })
+    }
+
+    /// Get the size of the live block `ptr`.
+    pub unsafe fn block_size(&self, ptr: NonNull<u8>) -> usize {
+        *(ptr.as_ptr().sub(HEADER_LEN) as *const usize)
+    }
+
+    /// Move the live block `ptr` to a new block of `new_layout`. The old one
+    /// is left in place like a freed one.
+    pub unsafe fn reallocate(&self, ptr: NonNull<u8>, new_layout: Layout) -> Option<NonNull<u8>> {
+        let new_ptr = self.allocate(new_layout)?;
+        core::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.as_ptr(),
+            self.block_size(ptr).min(new_layout.size()),
+        );
+        Some(new_ptr)
+    }
+}
+
+/// Map a chunk of `len` bytes right after the previous one ending at `end`,
+/// or at `FATALLOC_FIXED_BASE` (or anywhere if unset) for the first one.
+///
+/// With `FATALLOC_FIXED_BASE`, the chunks are mapped with `MAP_FIXED`, which
+/// silently replaces whatever was mapped there.
+fn map_chunk(end: usize, len: usize) -> Option<usize> {
+    let fixed_base = config::get().fixed_base;
+    let addr = if end != 0 { end } else { fixed_base };
+    let fixed = if fixed_base != 0 { libc::MAP_FIXED } else { 0 };
+    let mapped = unsafe {
+        libc::mmap(
+            addr as *mut _,
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | fixed,
+            -1,
+            0,
+        )
+    };
+    if mapped == libc::MAP_FAILED {
+        return None;
+    }
+    Some(mapped as usize)
+}
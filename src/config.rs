@@ -0,0 +1,639 @@
+//! Runtime configuration read from environment variables
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::logger::{Format, Level};
+
+pub struct Config {
+    /// `FATALLOC_REALLOC_ERROR`
+    pub realloc_error: ReallocErrorAction,
+    /// `FATALLOC_QUARANTINE_BYTES`
+    pub quarantine_bytes: usize,
+    /// `FATALLOC_QUARANTINE_MADV_FREE`
+    pub quarantine_madv_free: bool,
+    /// `FATALLOC_LOG_LEVEL`
+    pub log_level: Level,
+    /// `FATALLOC_LOG_FORMAT`
+    pub log_format: Format,
+    /// `FATALLOC_LOG_THREAD`
+    pub log_thread: bool,
+    /// `FATALLOC_FAIL_NTH` (`0` = disabled)
+    pub fail_nth: usize,
+    /// `FATALLOC_FAIL_REPEAT`
+    pub fail_repeat: bool,
+    /// `FATALLOC_CHECKS` (`CHECK_*`)
+    pub checks: usize,
+    /// `FATALLOC_CHECK_SAMPLE` (`(n, d)`: `n` in `d` calls; `n >= d` means
+    /// every call)
+    pub check_sample: (usize, usize),
+    /// `FATALLOC_CONTENT_HASH`
+    pub content_hash: bool,
+    /// `FATALLOC_FREE_BACKTRACE` (no effect without the `backtrace` feature)
+    pub free_backtrace: bool,
+    /// `FATALLOC_CLASSIFY_DOUBLE_FREE`
+    pub classify_double_free: bool,
+    /// `FATALLOC_DOUBLE_FREE_GRACE` (implies `classify_double_free`)
+    pub double_free_grace: bool,
+    /// `FATALLOC_PROTECT_METADATA`
+    pub protect_metadata: bool,
+    /// `FATALLOC_IDENTITY_FILL`
+    pub identity_fill: bool,
+    /// `FATALLOC_DETECT_UNWRITTEN`
+    pub detect_unwritten: bool,
+    /// `FATALLOC_STRICT_ALIGN`
+    pub strict_align: bool,
+    /// `FATALLOC_METADATA_FREE`
+    pub metadata_free: bool,
+    /// `FATALLOC_SYMBOLIZE` (no effect without the `backtrace` feature)
+    pub symbolize: bool,
+    /// `FATALLOC_MIN_ALIGN` (always a power of two)
+    pub min_align: usize,
+    /// `FATALLOC_MIN_SIZE`
+    pub min_size: usize,
+    /// `FATALLOC_MARGIN` (a power of two, at least `MIN_MARGIN`)
+    pub margin: usize,
+    /// `FATALLOC_MEMORY_LIMIT`
+    pub memory_limit: usize,
+    /// `FATALLOC_REGISTRY`
+    pub registry: bool,
+    /// `FATALLOC_MAX_LEAVES`
+    pub max_leaves: usize,
+    /// `FATALLOC_WARN_FREE_NULL`
+    pub warn_free_null: bool,
+    /// `FATALLOC_ROUND_SIZES`
+    pub round_sizes: bool,
+    /// `FATALLOC_LEAK_CHECK_SIGNAL` (`0` = disabled)
+    pub leak_check_signal: libc::c_int,
+    /// `FATALLOC_LEAK_AGE` (in seconds)
+    pub leak_age: usize,
+    /// `FATALLOC_METRICS_FILE` (no effect without the `stats` feature)
+    pub metrics_file: Option<&'static [u8]>,
+    /// `FATALLOC_FOLDED_FILE` (no effect without the `backtrace` feature)
+    pub folded_file: Option<&'static [u8]>,
+    /// `FATALLOC_LEAK_SITES` (no effect without the `backtrace` feature)
+    pub leak_sites: bool,
+    /// `FATALLOC_REALLOC_SITE`
+    pub realloc_site: ReallocSite,
+    /// `FATALLOC_BACKEND`
+    pub backend: crate::backend::Kind,
+    /// `FATALLOC_FIXED_BASE` (`0` = unset; no effect without the
+    /// `bump-backend` feature)
+    pub fixed_base: usize,
+    /// `FATALLOC_PASSTHROUGH` (`PASSTHROUGH_*`)
+    pub passthrough: usize,
+    /// `FATALLOC_SELF_TEST`
+    pub self_test: bool,
+    /// `FATALLOC_ON_CORRUPTION`
+    pub on_corruption: ErrorAction,
+    /// `FATALLOC_ON_INVALID_POINTER`
+    pub on_invalid_pointer: ErrorAction,
+    /// `FATALLOC_REPORT_ONCE`
+    pub report_once: bool,
+    /// `FATALLOC_STRICT_SIZED_FREE`
+    pub strict_sized_free: bool,
+}
+
+/// `Config::checks`: Verify the canary of an allocation on deallocation and
+/// reallocation.
+pub const CHECK_CANARY: usize = 1 << 0;
+/// `Config::checks`: Verify the metadata checksum of an allocation on
+/// deallocation and reallocation.
+pub const CHECK_CHECKSUM: usize = 1 << 1;
+/// `Config::checks`: Verify the poison in the tail released by a shrinking
+/// reallocation when it's re-exposed.
+pub const CHECK_REDZONE: usize = 1 << 2;
+/// `Config::checks`: Verify the next allocation in memory on deallocation.
+pub const CHECK_NEIGHBOR: usize = 1 << 3;
+
+/// `Config::passthrough`: Forward `malloc` to the system allocator.
+pub const PASSTHROUGH_MALLOC: usize = 1 << 0;
+/// `Config::passthrough`: Forward `calloc` to the system allocator.
+pub const PASSTHROUGH_CALLOC: usize = 1 << 1;
+/// `Config::passthrough`: Forward `realloc` of anything but fatalloc's live
+/// allocations to the system allocator.
+pub const PASSTHROUGH_REALLOC: usize = 1 << 2;
+
+/// What to do when `realloc` is called with an invalid pointer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReallocErrorAction {
+    /// `null`: Warn and return a null pointer
+    Null,
+    /// `abort`: Warn and abort the process
+    Abort,
+}
+
+/// Which call site a reallocated allocation is attributed to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReallocSite {
+    /// `first`: Where it was first allocated
+    First,
+    /// `last`: Where it was last reallocated
+    Last,
+}
+
+/// What to do when a heap error of a category is detected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorAction {
+    /// `warn`: Log it and carry on
+    Warn,
+    /// `abort`: Log it and abort the process
+    Abort,
+    /// `ignore`: Only count it in `fatalloc_events_total`
+    Ignore,
+}
+
+impl Config {
+    const DEFAULT: Self = Self {
+        realloc_error: ReallocErrorAction::Null,
+        quarantine_bytes: crate::quarantine::QUARANTINE_BYTES,
+        quarantine_madv_free: false,
+        log_level: Level::Warn,
+        log_format: Format::Text,
+        log_thread: false,
+        fail_nth: 0,
+        fail_repeat: false,
+        checks: CHECK_CANARY | CHECK_CHECKSUM | CHECK_REDZONE,
+        check_sample: (1, 1),
+        content_hash: false,
+        free_backtrace: false,
+        classify_double_free: false,
+        double_free_grace: false,
+        protect_metadata: false,
+        identity_fill: false,
+        detect_unwritten: false,
+        strict_align: false,
+        metadata_free: false,
+        symbolize: true,
+        min_align: 1,
+        min_size: 0,
+        margin: crate::MIN_MARGIN,
+        memory_limit: usize::MAX,
+        registry: false,
+        max_leaves: usize::MAX,
+        warn_free_null: false,
+        round_sizes: false,
+        leak_check_signal: 0,
+        leak_age: 60,
+        metrics_file: None,
+        folded_file: None,
+        leak_sites: false,
+        realloc_site: ReallocSite::First,
+        backend: crate::backend::Kind::Tlsf,
+        fixed_base: 0,
+        passthrough: 0,
+        self_test: false,
+        on_corruption: ErrorAction::Warn,
+        on_invalid_pointer: ErrorAction::Warn,
+        report_once: false,
+        strict_sized_free: false,
+    };
+
+    fn from_env() -> Self {
+        let mut this = Self::DEFAULT;
+
+        match env(b"FATALLOC_REALLOC_ERROR\0") {
+            None | Some(b"") => {}
+            Some(b"null") => this.realloc_error = ReallocErrorAction::Null,
+            Some(b"abort") => this.realloc_error = ReallocErrorAction::Abort,
+            Some(_) => warn!("ignoring an unrecognized value of FATALLOC_REALLOC_ERROR"),
+        }
+
+        match env(b"FATALLOC_BACKEND\0") {
+            None | Some(b"") => {}
+            Some(b"rlsf") => this.backend = crate::backend::Kind::Tlsf,
+            Some(b"system") => this.backend = crate::backend::Kind::System,
+            #[cfg(feature = "bump-backend")]
+            Some(b"bump") => this.backend = crate::backend::Kind::Bump,
+            #[cfg(not(feature = "bump-backend"))]
+            Some(b"bump") => {
+                warn!("ignoring FATALLOC_BACKEND=bump, which requires the `bump-backend` feature")
+            }
+            Some(_) => warn!("ignoring an unrecognized value of FATALLOC_BACKEND"),
+        }
+
+        match env(b"FATALLOC_FIXED_BASE\0") {
+            None | Some(b"") => {}
+            Some(value) => match parse_hex(value) {
+                Some(x) if x % crate::page_size() == 0 => this.fixed_base = x,
+                _ => warn!(
+                    "ignoring FATALLOC_FIXED_BASE, which isn't a page-aligned hexadecimal address"
+                ),
+            },
+        }
+
+        match env(b"FATALLOC_LOG_LEVEL\0") {
+            None | Some(b"") => {}
+            Some(b"off") => this.log_level = Level::Off,
+            Some(b"warn") => this.log_level = Level::Warn,
+            Some(b"info") => this.log_level = Level::Info,
+            Some(_) => warn!("ignoring an unrecognized value of FATALLOC_LOG_LEVEL"),
+        }
+
+        match env(b"FATALLOC_LOG_FORMAT\0") {
+            None | Some(b"") => {}
+            Some(b"text") => this.log_format = Format::Text,
+            Some(b"json") => this.log_format = Format::Json,
+            Some(_) => warn!("ignoring an unrecognized value of FATALLOC_LOG_FORMAT"),
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_LOG_THREAD\0") {
+            this.log_thread = x;
+        }
+
+        if let Some(x) = env_usize(b"FATALLOC_QUARANTINE_BYTES\0") {
+            this.quarantine_bytes = x;
+        }
+
+        if let Some(value) = env(b"FATALLOC_QUARANTINE_FRACTION\0") {
+            let max = env_usize(b"FATALLOC_QUARANTINE_MAX_BYTES\0")
+                .unwrap_or(crate::quarantine::QUARANTINE_MAX_BYTES);
+            match (parse_fraction(value), phys_mem_bytes()) {
+                (None, _) => {
+                    warn!("ignoring FATALLOC_QUARANTINE_FRACTION, which isn't in the form `n/d`")
+                }
+                (Some(_), None) => warn!(
+                    "couldn't get the size of the physical memory; sizing the quarantine by \
+                    FATALLOC_QUARANTINE_BYTES instead of FATALLOC_QUARANTINE_FRACTION"
+                ),
+                (Some((n, d)), Some(total)) => {
+                    let budget = total as u128 * n as u128 / d as u128;
+                    this.quarantine_bytes = budget.min(max as u128) as usize;
+                }
+            }
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_QUARANTINE_MADV_FREE\0") {
+            this.quarantine_madv_free = x;
+        }
+
+        if let Some(x) = env_usize(b"FATALLOC_FAIL_NTH\0") {
+            this.fail_nth = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_FAIL_REPEAT\0") {
+            this.fail_repeat = x;
+        }
+
+        if let Some(value) = env(b"FATALLOC_CHECKS\0") {
+            this.checks = 0;
+            for name in value.split(|&ch| ch == b',' || ch == b'|') {
+                this.checks |= match name {
+                    b"" | b"none" => 0,
+                    b"canary" => CHECK_CANARY,
+                    b"checksum" => CHECK_CHECKSUM,
+                    b"redzone" => CHECK_REDZONE,
+                    b"neighbor" => CHECK_NEIGHBOR,
+                    b"all" => CHECK_CANARY | CHECK_CHECKSUM | CHECK_REDZONE | CHECK_NEIGHBOR,
+                    _ => {
+                        let name = core::str::from_utf8(name).unwrap_or("?");
+                        warn!("ignoring an unrecognized check `{name}` in FATALLOC_CHECKS");
+                        0
+                    }
+                };
+            }
+        }
+
+        if let Some(value) = env(b"FATALLOC_PASSTHROUGH\0") {
+            for name in value.split(|&ch| ch == b',') {
+                this.passthrough |= match name {
+                    b"" => 0,
+                    b"malloc" => PASSTHROUGH_MALLOC,
+                    b"calloc" => PASSTHROUGH_CALLOC,
+                    b"realloc" => PASSTHROUGH_REALLOC,
+                    _ => {
+                        let name = core::str::from_utf8(name).unwrap_or("?");
+                        warn!("ignoring an unsupported function `{name}` in FATALLOC_PASSTHROUGH");
+                        0
+                    }
+                };
+            }
+        }
+
+        // A shorthand for `FATALLOC_CHECKS=...,neighbor`
+        if let Some(x) = env_bool(b"FATALLOC_CHECK_NEIGHBORS\0") {
+            if x {
+                this.checks |= CHECK_NEIGHBOR;
+            } else {
+                this.checks &= !CHECK_NEIGHBOR;
+            }
+        }
+
+        if let Some(value) = env(b"FATALLOC_CHECK_SAMPLE\0") {
+            match parse_fraction(value) {
+                Some(x) => this.check_sample = x,
+                None => warn!("ignoring FATALLOC_CHECK_SAMPLE, which isn't in the form `n/d`"),
+            }
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_CONTENT_HASH\0") {
+            this.content_hash = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_FREE_BACKTRACE\0") {
+            this.free_backtrace = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_CLASSIFY_DOUBLE_FREE\0") {
+            this.classify_double_free = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_DOUBLE_FREE_GRACE\0") {
+            this.double_free_grace = x;
+            this.classify_double_free |= x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_PROTECT_METADATA\0") {
+            this.protect_metadata = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_IDENTITY_FILL\0") {
+            this.identity_fill = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_DETECT_UNWRITTEN\0") {
+            this.detect_unwritten = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_STRICT_ALIGN\0") {
+            this.strict_align = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_METADATA_FREE\0") {
+            this.metadata_free = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_SYMBOLIZE\0") {
+            this.symbolize = x;
+        }
+
+        match env_usize(b"FATALLOC_MIN_ALIGN\0") {
+            Some(x) if x.is_power_of_two() => this.min_align = x,
+            Some(_) => warn!("ignoring FATALLOC_MIN_ALIGN, which isn't a power of two"),
+            None => {}
+        }
+
+        if let Some(x) = env_usize(b"FATALLOC_MIN_SIZE\0") {
+            this.min_size = x;
+        }
+
+        if let Some(x) = env_usize(b"FATALLOC_MARGIN\0") {
+            // A smaller margin would have the metadata overwrite the user
+            // region. The margin must be a power of two to be validated.
+            let min_margin = crate::MIN_MARGIN;
+            if x < min_margin {
+                warn!(
+                    "FATALLOC_MARGIN={x} is too small for the metadata and the canary; \
+                    using {min_margin}"
+                );
+            }
+            match x.max(min_margin).checked_next_power_of_two() {
+                Some(margin) => this.margin = margin,
+                None => warn!("ignoring FATALLOC_MARGIN, which is too large"),
+            }
+        }
+
+        if let Some(x) = env_usize(b"FATALLOC_MEMORY_LIMIT\0") {
+            this.memory_limit = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_REGISTRY\0") {
+            this.registry = x;
+        }
+
+        if let Some(x) = env_usize(b"FATALLOC_MAX_LEAVES\0") {
+            this.max_leaves = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_WARN_FREE_NULL\0") {
+            this.warn_free_null = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_ROUND_SIZES\0") {
+            this.round_sizes = x;
+        }
+
+        match env_usize(b"FATALLOC_LEAK_CHECK_SIGNAL\0") {
+            // `sigaction` will reject other invalid numbers
+            Some(x) if (1..=libc::c_int::MAX as usize).contains(&x) => {
+                this.leak_check_signal = x as libc::c_int;
+                // Allocation times are recorded in the registry
+                this.registry = true;
+            }
+            Some(_) => warn!("ignoring FATALLOC_LEAK_CHECK_SIGNAL, which isn't a signal number"),
+            None => {}
+        }
+
+        if let Some(x) = env_usize(b"FATALLOC_LEAK_AGE\0") {
+            this.leak_age = x;
+        }
+
+        match env(b"FATALLOC_METRICS_FILE\0") {
+            None | Some(b"") => {}
+            Some(x) => this.metrics_file = Some(x),
+        }
+
+        match env(b"FATALLOC_FOLDED_FILE\0") {
+            None | Some(b"") => {}
+            Some(x) => {
+                this.folded_file = Some(x);
+                // Allocation sites are recorded in the registry
+                this.registry = true;
+            }
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_LEAK_SITES\0") {
+            this.leak_sites = x;
+            // Allocation sites are recorded in the registry
+            this.registry |= x;
+        }
+
+        match env(b"FATALLOC_REALLOC_SITE\0") {
+            None | Some(b"") => {}
+            Some(b"first") => this.realloc_site = ReallocSite::First,
+            Some(b"last") => this.realloc_site = ReallocSite::Last,
+            Some(_) => warn!("ignoring an unrecognized value of FATALLOC_REALLOC_SITE"),
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_SELF_TEST\0") {
+            this.self_test = x;
+        }
+
+        if let Some(x) = env_error_action(b"FATALLOC_ON_CORRUPTION\0") {
+            this.on_corruption = x;
+        }
+
+        if let Some(x) = env_error_action(b"FATALLOC_ON_INVALID_POINTER\0") {
+            this.on_invalid_pointer = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_REPORT_ONCE\0") {
+            this.report_once = x;
+        }
+
+        if let Some(x) = env_bool(b"FATALLOC_STRICT_SIZED_FREE\0") {
+            this.strict_sized_free = x;
+        }
+
+        this
+    }
+}
+
+struct ConfigCell {
+    state: AtomicU8,
+    config: UnsafeCell<Config>,
+}
+
+unsafe impl Sync for ConfigCell {}
+
+const UNINIT: u8 = 0;
+const BUSY: u8 = 1;
+const READY: u8 = 2;
+
+static CONFIG: ConfigCell = ConfigCell {
+    state: AtomicU8::new(UNINIT),
+    config: UnsafeCell::new(Config::DEFAULT),
+};
+
+/// Get the configuration, reading it from the environment on first use.
+///
+/// The default configuration is returned while the configuration is being
+/// read by another thread (or by this thread, which can happen if reading it
+/// logs a warning).
+#[inline]
+pub fn get() -> &'static Config {
+    if CONFIG.state.load(Ordering::Acquire) == READY {
+        unsafe { &*CONFIG.config.get() }
+    } else {
+        init()
+    }
+}
+
+#[cold]
+fn init() -> &'static Config {
+    match CONFIG
+        .state
+        .compare_exchange(UNINIT, BUSY, Ordering::Acquire, Ordering::Acquire)
+    {
+        Ok(_) => {
+            let config = Config::from_env();
+            unsafe { *CONFIG.config.get() = config };
+            CONFIG.state.store(READY, Ordering::Release);
+            unsafe { &*CONFIG.config.get() }
+        }
+        Err(READY) => unsafe { &*CONFIG.config.get() },
+        Err(_) => &Config::DEFAULT,
+    }
+}
+
+/// Get the value of the specified environment variable. `name` must be
+/// null-terminated.
+fn env(name: &[u8]) -> Option<&'static [u8]> {
+    debug_assert_eq!(name.last(), Some(&0));
+    let p = unsafe { libc::getenv(name.as_ptr().cast()) };
+    if p.is_null() {
+        return None;
+    }
+
+    // `core::ffi::CStr` is unavailable in our toolchain
+    let mut len = 0;
+    while unsafe { *p.add(len) } != 0 {
+        len += 1;
+    }
+    Some(unsafe { core::slice::from_raw_parts(p.cast(), len) })
+}
+
+/// Get the value of the specified environment variable as a boolean (`0` or
+/// `1`). `name` must be null-terminated.
+fn env_bool(name: &[u8]) -> Option<bool> {
+    match env(name)? {
+        b"" => None,
+        b"0" => Some(false),
+        b"1" => Some(true),
+        _ => {
+            let name = core::str::from_utf8(&name[..name.len() - 1]).unwrap_or("?");
+            warn!("ignoring an invalid value of {name}");
+            None
+        }
+    }
+}
+
+/// Get the value of the specified environment variable as an
+/// [`ErrorAction`]. `name` must be null-terminated.
+fn env_error_action(name: &[u8]) -> Option<ErrorAction> {
+    match env(name)? {
+        b"" => None,
+        b"warn" => Some(ErrorAction::Warn),
+        b"abort" => Some(ErrorAction::Abort),
+        b"ignore" => Some(ErrorAction::Ignore),
+        _ => {
+            let name = core::str::from_utf8(&name[..name.len() - 1]).unwrap_or("?");
+            warn!("ignoring an unrecognized value of {name}");
+            None
+        }
+    }
+}
+
+/// Get the value of the specified environment variable as a decimal integer.
+/// `name` must be null-terminated.
+fn env_usize(name: &[u8]) -> Option<usize> {
+    let parsed = parse_usize(env(name)?);
+    if parsed.is_none() {
+        let name = core::str::from_utf8(&name[..name.len() - 1]).unwrap_or("?");
+        warn!("ignoring an invalid value of {name}");
+    }
+    parsed
+}
+
+/// Parse `n/d` with a non-zero `d`.
+fn parse_fraction(value: &[u8]) -> Option<(usize, usize)> {
+    let mut parts = value.splitn(2, |&ch| ch == b'/');
+    match (
+        parts.next().and_then(parse_usize),
+        parts.next().and_then(parse_usize),
+    ) {
+        (Some(n), Some(d)) if d > 0 => Some((n, d)),
+        _ => None,
+    }
+}
+
+/// Get the size of the physical memory in bytes.
+fn phys_mem_bytes() -> Option<usize> {
+    let (num_pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    if num_pages <= 0 || page_size <= 0 {
+        return None;
+    }
+    Some((num_pages as usize).saturating_mul(page_size as usize))
+}
+
+/// Parse a non-empty hexadecimal integer with an optional `0x` prefix.
+fn parse_hex(value: &[u8]) -> Option<usize> {
+    let value = value.strip_prefix(b"0x").unwrap_or(value);
+    if value.is_empty() {
+        return None;
+    }
+    value.iter().try_fold(0usize, |acc, &ch| {
+        let digit = (ch as char).to_digit(16)?;
+        acc.checked_mul(16)?.checked_add(digit as usize)
+    })
+}
+
+/// Parse a non-empty decimal integer.
+fn parse_usize(value: &[u8]) -> Option<usize> {
+    if value.is_empty() {
+        return None;
+    }
+    value.iter().try_fold(0usize, |acc, &ch| {
+        if ch.is_ascii_digit() {
+            acc.checked_mul(10)?.checked_add((ch - b'0') as usize)
+        } else {
+            None
+        }
+    })
+}
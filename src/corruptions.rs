@@ -0,0 +1,185 @@
+//! Keeps the recent heap corruption events for
+//! `fatalloc_recent_corruptions`, so that a watchdog can collect them
+//! without parsing the log
+//!
+//! The events are kept in a bounded ring buffer. When it's full, the oldest
+//! event is dropped and counted.
+//!
+//! With `FATALLOC_REPORT_ONCE`, the kinds and the pointers of the events
+//! seen so far are kept in a bounded hash set, and a repeat of one is
+//! suppressed and counted instead of being recorded and logged again. Once
+//! the set is full, new pairs are reported every time.
+use core::{cell::UnsafeCell, ptr::null};
+
+use crate::{config, logger::Event};
+
+/// The number of events kept at most
+const CAPACITY: usize = 64;
+
+/// The number of `(kind, ptr)` pairs `FATALLOC_REPORT_ONCE` remembers at
+/// most (a power of two)
+const SEEN_CAPACITY: usize = 1024;
+
+/// [`Event::Overrun`]
+pub const KIND_OVERRUN: u32 = 1;
+/// [`Event::NeighborOverrun`]
+pub const KIND_NEIGHBOR_OVERRUN: u32 = 2;
+/// [`Event::ShrunkTailOverrun`]
+pub const KIND_SHRUNK_TAIL_OVERRUN: u32 = 3;
+/// [`Event::UseAfterFree`]
+pub const KIND_USE_AFTER_FREE: u32 = 4;
+/// [`Event::ReallocMismatch`]
+pub const KIND_REALLOC_MISMATCH: u32 = 5;
+/// [`Event::AlreadyTracked`]
+pub const KIND_ALREADY_TRACKED: u32 = 6;
+
+/// A corruption event as returned by `fatalloc_recent_corruptions`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CorruptionEvent {
+    /// `KIND_*`
+    pub kind: u32,
+    /// The corrupted allocation
+    pub ptr: *const u8,
+    /// The culprit's size for an overrun (`0` if unknown), the offset of the
+    /// modified byte for a shrunk tail overrun or a use-after-free, or the
+    /// number of bytes that should have been preserved for a reallocation
+    /// mismatch
+    pub size: usize,
+    /// When it was detected, in seconds of `CLOCK_MONOTONIC`
+    pub time: usize,
+}
+
+struct Inner {
+    events: [CorruptionEvent; CAPACITY],
+    /// The index of the oldest event in `events`
+    start: usize,
+    len: usize,
+    num_dropped: usize,
+    /// An open-addressing hash set of `(kind, ptr)`, where `kind == 0` is
+    /// vacant
+    seen: [(u32, usize); SEEN_CAPACITY],
+    num_suppressed: usize,
+}
+
+struct Ring {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    inner: UnsafeCell<Inner>,
+}
+
+unsafe impl Sync for Ring {}
+
+static RING: Ring = Ring {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    inner: UnsafeCell::new(Inner {
+        events: [CorruptionEvent {
+            kind: 0,
+            ptr: null(),
+            size: 0,
+            time: 0,
+        }; CAPACITY],
+        start: 0,
+        len: 0,
+        num_dropped: 0,
+        seen: [(0, 0); SEEN_CAPACITY],
+        num_suppressed: 0,
+    }),
+};
+
+/// Run `f` with the ring buffer locked.
+fn with_inner<R>(f: impl FnOnce(&mut Inner) -> R) -> R {
+    unsafe {
+        libc::pthread_mutex_lock(RING.mutex.get());
+        let result = f(&mut *RING.inner.get());
+        libc::pthread_mutex_unlock(RING.mutex.get());
+        result
+    }
+}
+
+/// Get the ring buffer's lock for the `fork` handlers.
+pub fn fork_mutex() -> *mut libc::pthread_mutex_t {
+    RING.mutex.get()
+}
+
+/// Record `event` if it's a corruption event. Returns `false` if it's to be
+/// suppressed as a repeat by `FATALLOC_REPORT_ONCE`.
+pub fn record(event: &Event) -> bool {
+    let (kind, ptr, size) = match *event {
+        Event::Overrun { ptr, ref culprit } => {
+            (KIND_OVERRUN, ptr, culprit.as_ref().map_or(0, |c| c.size))
+        }
+        Event::NeighborOverrun { ptr, .. } => (KIND_NEIGHBOR_OVERRUN, ptr, 0),
+        Event::ShrunkTailOverrun { ptr, offset } => (KIND_SHRUNK_TAIL_OVERRUN, ptr, offset),
+        Event::UseAfterFree { ptr, offset } => (KIND_USE_AFTER_FREE, ptr, offset),
+        Event::ReallocMismatch { ptr, len } => (KIND_REALLOC_MISMATCH, ptr, len),
+        Event::AlreadyTracked { ptr } => (KIND_ALREADY_TRACKED, ptr, 0),
+        _ => return true,
+    };
+    let event = CorruptionEvent {
+        kind,
+        ptr,
+        size,
+        time: crate::registry::now(),
+    };
+
+    let report_once = config::get().report_once;
+    with_inner(|inner| {
+        if report_once && !inner.insert_seen(kind, ptr as usize) {
+            inner.num_suppressed += 1;
+            return false;
+        }
+        if inner.len == CAPACITY {
+            // Drop the oldest one
+            inner.start = (inner.start + 1) % CAPACITY;
+            inner.len -= 1;
+            inner.num_dropped += 1;
+        }
+        inner.events[(inner.start + inner.len) % CAPACITY] = event;
+        inner.len += 1;
+        true
+    })
+}
+
+impl Inner {
+    /// Add `(kind, ptr)` to `seen`. Returns `false` if it's already there.
+    fn insert_seen(&mut self, kind: u32, ptr: usize) -> bool {
+        let hash = (ptr ^ kind as usize).wrapping_mul(0x9e3779b97f4a7c15u64 as usize);
+        let start = hash >> (usize::BITS - SEEN_CAPACITY.trailing_zeros());
+        for i in 0..SEEN_CAPACITY {
+            let slot = &mut self.seen[(start + i) % SEEN_CAPACITY];
+            if *slot == (kind, ptr) {
+                return false;
+            }
+            if slot.0 == 0 {
+                *slot = (kind, ptr);
+                return true;
+            }
+        }
+        // Full
+        true
+    }
+}
+
+/// Move up to `out.len()` events, the oldest first, to `out`. Returns the
+/// number of moved events.
+pub fn drain(out: &mut [CorruptionEvent]) -> usize {
+    with_inner(|inner| {
+        let len = inner.len.min(out.len());
+        for (i, out) in out[..len].iter_mut().enumerate() {
+            *out = inner.events[(inner.start + i) % CAPACITY];
+        }
+        inner.start = (inner.start + len) % CAPACITY;
+        inner.len -= len;
+        len
+    })
+}
+
+/// Get the number of events dropped because the ring buffer was full.
+pub fn num_dropped() -> usize {
+    with_inner(|inner| inner.num_dropped)
+}
+
+/// Get the number of repeats suppressed by `FATALLOC_REPORT_ONCE`.
+pub fn num_suppressed() -> usize {
+    with_inner(|inner| inner.num_suppressed)
+}
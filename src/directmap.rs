@@ -0,0 +1,108 @@
+//! Direct `mmap` for outer allocations aligned to more than a page
+//!
+//! The margin of such an allocation is at least its alignment, so a 2 MiB
+//! aligned buffer would take 4 MiB of backend memory in margins alone. Mapping
+//! it directly keeps the untouched parts of the margins virtual.
+//!
+//! A mapping can also be given a NUMA node to place its pages on, which is
+//! why `fatalloc_aligned_alloc_on_node` always takes this path.
+use core::{
+    alloc::Layout,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// `MPOL_PREFERRED` from `<linux/mempolicy.h>`
+const MPOL_PREFERRED: libc::c_int = 1;
+
+/// Check if an outer allocation of `layout` is mapped directly.
+#[inline]
+pub fn is_direct(layout: Layout) -> bool {
+    layout.align() > crate::page_size()
+}
+
+/// Map zero-filled memory for `layout`, where [`is_direct`]`(layout)`, with
+/// its pages preferably placed on the NUMA node `node`.
+///
+/// An over-sized region is mapped, and the parts outside the aligned block
+/// are unmapped.
+pub fn map(layout: Layout, node: Option<u32>) -> Option<NonNull<u8>> {
+    debug_assert!(is_direct(layout));
+    let page_size = crate::page_size();
+    let len = layout.size().checked_add(page_size - 1)? & !(page_size - 1);
+    let mapped_len = len.checked_add(layout.align() - page_size)?;
+
+    let mapped = unsafe {
+        libc::mmap(
+            core::ptr::null_mut(),
+            mapped_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if mapped == libc::MAP_FAILED {
+        return None;
+    }
+
+    // Trim the region to the aligned block. The alignment is a multiple of
+    // the page size, so the remainders are whole pages.
+    let mapped = mapped as usize;
+    let start = (mapped + layout.align() - 1) & !(layout.align() - 1);
+    let end = start + len;
+    unsafe {
+        if start > mapped {
+            libc::munmap(mapped as *mut _, start - mapped);
+        }
+        if mapped + mapped_len > end {
+            libc::munmap(end as *mut _, mapped + mapped_len - end);
+        }
+    }
+
+    // Before any page is touched
+    if let Some(node) = node {
+        prefer_node(start, len, node);
+    }
+
+    NonNull::new(start as *mut u8)
+}
+
+/// Set the memory policy of the pages in `start..start + len` to prefer the
+/// NUMA node `node`. The placement is only a hint, so the failure is merely
+/// logged (once; a kernel without NUMA support rejects every call).
+fn prefer_node(start: usize, len: usize, node: u32) {
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+
+    let ret = if node < usize::BITS {
+        let mask = 1usize << node;
+        // The kernel ignores the last bit of `maxnode`
+        unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                start,
+                len,
+                MPOL_PREFERRED,
+                &mask as *const usize,
+                usize::BITS as usize + 1,
+                0,
+            )
+        }
+    } else {
+        -1
+    };
+    if ret != 0 && !REPORTED.swap(true, Ordering::Relaxed) {
+        warn!("couldn't place the allocation {start:#x} on NUMA node {node}; mbind failed");
+    }
+}
+
+/// Unmap the memory returned by [`map`]`(layout)`.
+///
+/// # Safety
+///
+/// `ptr` must be a live allocation made by [`map`] for `layout`.
+pub unsafe fn unmap(ptr: NonNull<u8>, layout: Layout) {
+    let page_size = crate::page_size();
+    let len = (layout.size() + page_size - 1) & !(page_size - 1);
+    libc::munmap(ptr.as_ptr().cast(), len);
+}
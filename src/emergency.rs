@@ -0,0 +1,120 @@
+//! Lock-free allocation for a reentered allocator, e.g., a crash handler
+//! interrupting an allocation on the same thread
+//!
+//! The interrupted call might be holding the `AllocMap` lock, the backend's
+//! lock, or the quarantine's, so none of them can be taken. Blocks are mapped
+//! by `mmap` instead and remembered in a fixed table of atomic slots, so that
+//! they can be recognized and unmapped later in any context.
+use core::{
+    alloc::Layout,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The maximum number of blocks alive at once
+const NUM_SLOTS: usize = 64;
+
+/// The user pointers of the blocks, or `0` for a free slot
+static SLOTS: [AtomicUsize; NUM_SLOTS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+    [FREE; NUM_SLOTS]
+};
+
+/// The distance from the start of a mapping to the user pointer, which is
+/// at least a word to hold the mapping's length
+fn header_len(align: usize) -> usize {
+    align.max(core::mem::size_of::<usize>() * 2)
+}
+
+/// Map a block for `layout`. Returns `None` if `layout` is aligned to more
+/// than a page, the table is full, or `mmap` fails.
+pub fn allocate(layout: Layout) -> Option<NonNull<u8>> {
+    let page_size = crate::page_size();
+    if layout.align() > page_size {
+        return None;
+    }
+    let len = layout
+        .size()
+        .checked_add(header_len(layout.align()) + page_size - 1)?
+        & !(page_size - 1);
+
+    let base = unsafe {
+        libc::mmap(
+            core::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if base == libc::MAP_FAILED {
+        return None;
+    }
+    let user_ptr = base.cast::<u8>().wrapping_add(header_len(layout.align()));
+    unsafe { user_ptr.cast::<usize>().wrapping_sub(1).write(len) };
+
+    let claimed = SLOTS.iter().any(|slot| {
+        slot.compare_exchange(0, user_ptr as usize, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    });
+    if !claimed {
+        unsafe { libc::munmap(base, len) };
+        return None;
+    }
+    NonNull::new(user_ptr)
+}
+
+/// Check if `ptr` is a block made by [`allocate`].
+pub fn owns(ptr: NonNull<u8>) -> bool {
+    SLOTS
+        .iter()
+        .any(|slot| slot.load(Ordering::Relaxed) == ptr.as_ptr() as usize)
+}
+
+/// Get the start and the length of the mapping of the block `ptr`.
+unsafe fn mapping(ptr: NonNull<u8>) -> (*mut libc::c_void, usize) {
+    // The header is at most a page long
+    let page_size = crate::page_size();
+    let base = (ptr.as_ptr() as usize - 1) & !(page_size - 1);
+    let len = ptr.as_ptr().cast::<usize>().wrapping_sub(1).read();
+    (base as *mut libc::c_void, len)
+}
+
+/// Get the usable size of the block `ptr`, which must satisfy [`owns`].
+pub unsafe fn usable_size(ptr: NonNull<u8>) -> usize {
+    let (base, len) = mapping(ptr);
+    base as usize + len - ptr.as_ptr() as usize
+}
+
+/// Unmap the block `ptr`, which must satisfy [`owns`]. Nothing happens if
+/// another thread has unmapped it first.
+pub unsafe fn deallocate(ptr: NonNull<u8>) {
+    let released = SLOTS.iter().any(|slot| {
+        slot.compare_exchange(
+            ptr.as_ptr() as usize,
+            0,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+    });
+    if released {
+        let (base, len) = mapping(ptr);
+        libc::munmap(base, len);
+    }
+}
+
+/// Move the block `ptr`, which must satisfy [`owns`], to a new block for
+/// `new_layout`. The original block remains valid on failure.
+pub unsafe fn reallocate(ptr: NonNull<u8>, new_layout: Layout) -> Option<NonNull<u8>> {
+    let new_ptr = allocate(new_layout)?;
+    core::ptr::copy_nonoverlapping(
+        ptr.as_ptr(),
+        new_ptr.as_ptr(),
+        usable_size(ptr).min(new_layout.size()),
+    );
+    deallocate(ptr);
+    Some(new_ptr)
+}
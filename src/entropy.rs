@@ -0,0 +1,114 @@
+//! The per-process seed of metadata mangling
+//!
+//! The seed is mixed into every mangled metadata word so that a program (or
+//! an attacker) can't forge valid-looking metadata by reproducing the fixed
+//! keys. It's obtained on the first use from the first available source of
+//! the following:
+//!
+//!  1. `getrandom(GRND_NONBLOCK)`, which fails instead of blocking if the
+//!     entropy pool isn't initialized yet
+//!  2. `/dev/urandom`
+//!  3. `AT_RANDOM`, the random bytes the kernel passes to every process in
+//!     the auxiliary vector, which needs no system calls
+//!  4. The time, the process ID, and addresses (subject to ASLR), which is
+//!     predictable and therefore warned about
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The seed, or `0` if it's not obtained yet
+static SEED: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the seed.
+#[inline]
+pub fn seed() -> usize {
+    match SEED.load(Ordering::Relaxed) {
+        0 => init(),
+        seed => seed,
+    }
+}
+
+#[cold]
+fn init() -> usize {
+    let (seed, source) = if let Some(seed) = from_getrandom() {
+        (seed, "getrandom")
+    } else if let Some(seed) = from_urandom() {
+        (seed, "/dev/urandom")
+    } else if let Some(seed) = from_auxv() {
+        (seed, "AT_RANDOM")
+    } else {
+        (from_clock(), "the clock")
+    };
+    // `0` means unset
+    let seed = seed.max(1);
+
+    // Another thread might have raced us. The first seed must stick because
+    // it might be already in use.
+    match SEED.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => {
+            if source == "the clock" {
+                warn!("no entropy source is available; the metadata keys are predictable");
+            }
+            info!("seeded the metadata keys from {source}");
+            seed
+        }
+        Err(seed) => seed,
+    }
+}
+
+fn from_getrandom() -> Option<usize> {
+    let mut seed = 0usize;
+    let len = core::mem::size_of_val(&seed);
+    let num_read = unsafe {
+        libc::syscall(
+            libc::SYS_getrandom,
+            (&mut seed as *mut usize).cast::<libc::c_void>(),
+            len,
+            libc::GRND_NONBLOCK,
+        )
+    };
+    (num_read == len as libc::c_long).then(|| seed)
+}
+
+fn from_urandom() -> Option<usize> {
+    let fd = unsafe {
+        libc::open(
+            b"/dev/urandom\0".as_ptr().cast(),
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+    let mut seed = 0usize;
+    let len = core::mem::size_of_val(&seed);
+    let num_read = unsafe { libc::read(fd, (&mut seed as *mut usize).cast(), len) };
+    unsafe { libc::close(fd) };
+    (num_read == len as isize).then(|| seed)
+}
+
+fn from_auxv() -> Option<usize> {
+    // Points to 16 random bytes
+    let p = unsafe { libc::getauxval(libc::AT_RANDOM) } as *const u8;
+    if p.is_null() {
+        return None;
+    }
+    // glibc uses the leading bytes for the stack protector and the pointer
+    // guard. Take the trailing ones so as not to duplicate them.
+    let p = p.wrapping_add(16 - core::mem::size_of::<usize>());
+    Some(unsafe { p.cast::<usize>().read_unaligned() })
+}
+
+fn from_clock() -> usize {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    let x = (ts.tv_sec as u64).wrapping_mul(1_000_000_000) ^ ts.tv_nsec as u64;
+    let x = x ^ (unsafe { libc::getpid() } as u64) << 40;
+    let x = x ^ (&ts as *const _ as u64).rotate_left(17);
+    let x = x ^ (from_clock as usize as u64).rotate_left(31);
+    // SplitMix64's finalizer
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (x ^ (x >> 31)) as usize
+}
@@ -0,0 +1,116 @@
+//! Writes the call stacks of live allocations to `FATALLOC_FOLDED_FILE` in
+//! the folded-stack format of flame graph tools (`frame1;frame2;frame3
+//! bytes`, outermost first), so that live memory can be viewed by
+//! allocation site
+//!
+//! Allocations made at the same site are aggregated in a bounded hash table.
+//! Once it's full, the rest are summed up under `(other)`.
+use core::{cell::UnsafeCell, fmt::Write};
+
+use crate::{backtrace::Frame, config, metrics, registry::SITE_DEPTH};
+
+/// The number of distinct sites aggregated at most (a power of two)
+const CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    site: [usize; SITE_DEPTH],
+    /// `0` if vacant
+    bytes: usize,
+}
+
+struct Inner {
+    entries: [Entry; CAPACITY],
+    other_bytes: usize,
+}
+
+struct Table {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    inner: UnsafeCell<Inner>,
+}
+
+unsafe impl Sync for Table {}
+
+/// Static rather than on the stack because it's large
+static TABLE: Table = Table {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    inner: UnsafeCell::new(Inner {
+        entries: [Entry {
+            site: [0; SITE_DEPTH],
+            bytes: 0,
+        }; CAPACITY],
+        other_bytes: 0,
+    }),
+};
+
+/// Get the table's lock for the `fork` handlers. It's held while the
+/// registry is iterated.
+pub fn fork_mutex() -> *mut libc::pthread_mutex_t {
+    TABLE.mutex.get()
+}
+
+/// Aggregate the allocations `for_each_allocation` passes to its argument,
+/// each with its site and size, and write them to `FATALLOC_FOLDED_FILE`.
+/// Returns `false` if it's unset or the file couldn't be written.
+pub fn export(
+    for_each_allocation: impl FnOnce(&mut dyn FnMut(&[usize; SITE_DEPTH], usize)),
+) -> bool {
+    let path = match config::get().folded_file {
+        Some(path) => path,
+        None => return false,
+    };
+    unsafe {
+        libc::pthread_mutex_lock(TABLE.mutex.get());
+        let inner = &mut *TABLE.inner.get();
+        inner.entries.iter_mut().for_each(|entry| entry.bytes = 0);
+        inner.other_bytes = 0;
+
+        for_each_allocation(&mut |site, size| inner.add(site, size));
+        let ok = metrics::write_file(path, "FATALLOC_FOLDED_FILE", |out| {
+            for entry in inner.entries.iter().filter(|entry| entry.bytes != 0) {
+                let mut frames = entry.site.iter().rev().filter(|&&ip| ip != 0);
+                match frames.next() {
+                    Some(&ip) => write!(out, "{}", Frame(ip))?,
+                    None => out.write_str("(unknown)")?,
+                }
+                for &ip in frames {
+                    write!(out, ";{}", Frame(ip))?;
+                }
+                writeln!(out, " {}", entry.bytes)?;
+            }
+            if inner.other_bytes != 0 {
+                writeln!(out, "(other) {}", inner.other_bytes)?;
+            }
+            Ok(())
+        });
+
+        libc::pthread_mutex_unlock(TABLE.mutex.get());
+        ok
+    }
+}
+
+impl Inner {
+    fn add(&mut self, site: &[usize; SITE_DEPTH], size: usize) {
+        // A zero-sized allocation would look vacant
+        let size = size.max(1);
+        let hash = site.iter().fold(0usize, |acc, &ip| {
+            (acc ^ ip).wrapping_mul(0x9e3779b97f4a7c15u64 as usize)
+        });
+        let start = hash >> (usize::BITS - CAPACITY.trailing_zeros());
+        for i in 0..CAPACITY {
+            let entry = &mut self.entries[(start + i) % CAPACITY];
+            if entry.bytes == 0 {
+                *entry = Entry {
+                    site: *site,
+                    bytes: size,
+                };
+                return;
+            }
+            if entry.site == *site {
+                entry.bytes += size;
+                return;
+            }
+        }
+        self.other_bytes += size;
+    }
+}
@@ -0,0 +1,101 @@
+//! Keeps the allocator usable in the child of a `fork`
+//!
+//! The child gets only the thread that called `fork`, so a lock another
+//! thread was holding stays locked with nobody to release it, and the child
+//! deadlocks on its first allocation. The `pthread_atfork` handlers take every
+//! lock before `fork`, so that none is held midway through an update, release
+//! them in the parent, and reset them to their initializers in the child,
+//! where they are owned by a thread that no longer exists.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    alloc_map, backend, corruptions, logger, ovrride, pinned, quarantine, registry, stats, watch,
+};
+
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Register the handlers if they aren't yet.
+///
+/// This must be called outside the allocator because `pthread_atfork`
+/// allocates memory.
+#[inline]
+pub fn register() {
+    if !REGISTERED.load(Ordering::Relaxed) {
+        register_slow();
+    }
+}
+
+#[cold]
+fn register_slow() {
+    if REGISTERED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let result = unsafe { libc::pthread_atfork(Some(prepare), Some(parent), Some(child)) };
+    if result != 0 {
+        warn!("failed to register the fork handlers; a forked child may deadlock");
+    }
+}
+
+/// The mutexes taken before the allocation map's lock, the outermost first.
+/// A lock is never taken while holding one that comes after it.
+fn outer_mutexes() -> [*mut libc::pthread_mutex_t; 4 + cfg!(feature = "backtrace") as usize] {
+    [
+        #[cfg(feature = "backtrace")]
+        crate::folded::fork_mutex(),
+        backend::lookup_mutex(),
+        registry::fork_mutex(),
+        pinned::fork_mutex(),
+        quarantine::fork_mutex(),
+    ]
+}
+
+/// The mutexes taken after the allocation map's and the backend's locks, the
+/// outermost first
+fn inner_mutexes() -> [*mut libc::pthread_mutex_t; 4] {
+    [
+        stats::churn_fork_mutex(),
+        corruptions::fork_mutex(),
+        watch::fork_mutex(),
+        logger::fork_mutex(),
+    ]
+}
+
+extern "C" fn prepare() {
+    unsafe {
+        for mutex in outer_mutexes() {
+            libc::pthread_mutex_lock(mutex);
+        }
+        libc::pthread_rwlock_wrlock(alloc_map().fork_rwlock());
+        libc::pthread_rwlock_wrlock(ovrride::ALLOC.alloc.fork_rwlock());
+        for mutex in inner_mutexes() {
+            libc::pthread_mutex_lock(mutex);
+        }
+    }
+}
+
+extern "C" fn parent() {
+    unsafe {
+        for mutex in inner_mutexes().into_iter().rev() {
+            libc::pthread_mutex_unlock(mutex);
+        }
+        libc::pthread_rwlock_unlock(ovrride::ALLOC.alloc.fork_rwlock());
+        libc::pthread_rwlock_unlock(alloc_map().fork_rwlock());
+        for mutex in outer_mutexes().into_iter().rev() {
+            libc::pthread_mutex_unlock(mutex);
+        }
+    }
+}
+
+extern "C" fn child() {
+    unsafe {
+        for mutex in outer_mutexes().into_iter().chain(inner_mutexes()) {
+            mutex.write(libc::PTHREAD_MUTEX_INITIALIZER);
+        }
+        for rwlock in [
+            alloc_map().fork_rwlock(),
+            ovrride::ALLOC.alloc.fork_rwlock(),
+        ] {
+            rwlock.write(libc::PTHREAD_RWLOCK_INITIALIZER);
+        }
+    }
+}
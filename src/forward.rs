@@ -0,0 +1,60 @@
+//! Forwards the functions selected by `FATALLOC_PASSTHROUGH` to the system
+//! allocator at runtime, as if they weren't interposed
+//!
+//! Forwarded allocations are foreign to fatalloc, so they have no margins or
+//! canaries, and `free` only releases them with `backend-passthrough`. A
+//! forwarded `realloc` still handles fatalloc's live allocations itself
+//! because the system allocator would take them for heap corruption.
+use core::ptr::NonNull;
+use libc::c_void;
+
+use crate::{
+    backend::{system_fns, SystemFns},
+    config,
+};
+
+/// Get the system allocator's functions if `function` (`PASSTHROUGH_*`) is
+/// forwarded. Returns `None` while they are being looked up, which `dlsym`
+/// may allocate memory for.
+#[inline]
+fn forwarded(function: usize) -> Option<&'static SystemFns> {
+    if config::get().passthrough & function == 0 {
+        return None;
+    }
+    system_fns()
+}
+
+/// Call the system `malloc` if it's forwarded.
+#[inline]
+pub unsafe fn malloc(size: usize) -> Option<*mut c_void> {
+    let fns = forwarded(config::PASSTHROUGH_MALLOC)?;
+    Some((fns.malloc)(size))
+}
+
+/// Call the system `calloc` if it's forwarded.
+#[inline]
+pub unsafe fn calloc(number: usize, size: usize) -> Option<*mut c_void> {
+    let fns = forwarded(config::PASSTHROUGH_CALLOC)?;
+    Some((fns.calloc)(number, size))
+}
+
+/// Call the system `realloc` if it's forwarded and `ptr` isn't a live
+/// allocation of fatalloc.
+///
+/// A pointer fatalloc has already freed is forwarded too, which the system
+/// allocator will likely reject by aborting.
+#[inline]
+pub unsafe fn realloc(ptr: *mut c_void, size: usize) -> Option<*mut c_void> {
+    let fns = forwarded(config::PASSTHROUGH_REALLOC)?;
+    let ptr = match NonNull::new(ptr) {
+        Some(ptr) => ptr,
+        // glibc's `realloc(NULL, ...)` calls back our `__libc_malloc`
+        None => return Some((fns.malloc)(size)),
+    };
+    #[cfg(feature = "pointer-tag")]
+    let ptr = crate::ptrtag::split(ptr.cast()).0;
+    if crate::ovrride::ALLOC.owns(ptr.cast()) {
+        return None;
+    }
+    Some((fns.realloc)(ptr.as_ptr().cast(), size))
+}
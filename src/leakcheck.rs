@@ -0,0 +1,46 @@
+//! Reports old allocations as possible leaks on a signal
+//! (`FATALLOC_LEAK_CHECK_SIGNAL`)
+//!
+//! Walking the registry in the signal handler could deadlock (the handler
+//! might interrupt a thread holding its lock), so the handler only raises a
+//! flag, and the next allocation does the work.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config;
+
+static PENDING: AtomicBool = AtomicBool::new(false);
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_: libc::c_int) {
+    PENDING.store(true, Ordering::Relaxed);
+}
+
+/// Install the signal handler if it's not yet, and return `true` if a
+/// leak check was requested since the last call.
+#[inline]
+pub fn poll() -> bool {
+    let signal = config::get().leak_check_signal;
+    if signal == 0 {
+        return false;
+    }
+    if !INSTALLED.load(Ordering::Relaxed) {
+        install(signal);
+    }
+    PENDING.load(Ordering::Relaxed) && PENDING.swap(false, Ordering::Relaxed)
+}
+
+#[cold]
+fn install(signal: libc::c_int) {
+    if INSTALLED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    unsafe {
+        let mut action: libc::sigaction = core::mem::zeroed();
+        action.sa_sigaction = handle_signal as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, core::ptr::null_mut()) != 0 {
+            warn!("failed to install the handler for FATALLOC_LEAK_CHECK_SIGNAL={signal}");
+        }
+    }
+}
@@ -1,13 +1,55 @@
 #![doc = include_str!("../README.md")]
 #![no_std]
-use core::{alloc, pin::Pin, ptr::NonNull};
+use core::{
+    alloc,
+    cell::Cell,
+    ops,
+    pin::Pin,
+    ptr,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
 
 use rlsf::CAlloc;
 
 #[macro_use]
 mod logger;
 mod allocmap;
+pub mod api;
+mod backend;
+#[cfg(feature = "backtrace")]
+mod backtrace;
+#[cfg(feature = "bump-backend")]
+mod bump;
+mod config;
+mod corruptions;
+mod directmap;
+mod emergency;
+mod entropy;
+#[cfg(feature = "backtrace")]
+mod folded;
+mod fork;
+mod forward;
+mod leakcheck;
+mod meta;
+#[cfg(any(feature = "stats", feature = "backtrace"))]
+mod metrics;
 pub mod ovrride;
+#[cfg(feature = "backend-passthrough")]
+mod passthrough;
+mod pinned;
+mod procmaps;
+#[cfg(feature = "pointer-tag")]
+mod ptrtag;
+mod quarantine;
+mod reentrancy;
+mod registry;
+mod sample;
+mod selftest;
+mod stack;
+mod stats;
+mod tls;
+mod watch;
 
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo) -> ! {
@@ -19,21 +61,275 @@ struct FatAlloc<T> {
     alloc: T,
 }
 
-const MIN_MARGIN: usize = core::mem::size_of::<usize>() * 64;
+/// The smallest margin, enough to hold the metadata guard, the metadata, and
+/// the canary. The guard is always located at `user_ptr - MIN_MARGIN`, and
+/// the metadata follows it at `user_ptr - META_OFFSET`.
+const MIN_MARGIN: usize =
+    (meta::GUARD_SIZE + META_WORDS * core::mem::size_of::<usize>() + meta::CANARY_SIZE)
+        .next_power_of_two();
+/// The distance from `user_ptr` back to the metadata
+const META_OFFSET: usize = MIN_MARGIN - meta::GUARD_SIZE;
+/// The upper bound of the size-proportional part of a margin
+const MAX_MARGIN: usize = 1 << 16;
+/// The size-proportional part of a margin is `size / MARGIN_RATIO`.
+const MARGIN_RATIO: usize = 8;
 const MIN_ALIGN: usize = core::mem::align_of::<usize>();
+/// The unit `FATALLOC_ROUND_SIZES` rounds allocation sizes up to, which is
+/// glibc's `MALLOC_ALIGNMENT`
+const SIZE_GRANULARITY: usize = core::mem::size_of::<usize>() * 2;
+/// How far past an allocation's trailing margin `AllocInfo::check_neighbor`
+/// looks for the next allocation
+const NEIGHBOR_SCAN_LEN: usize = MAX_MARGIN * 2;
+/// The most [`scan_back_range`] looks back, even after larger allocations
+const CULPRIT_SCAN_LEN: usize = 1 << 20;
+/// The smallest zero-initialized allocation whose whole pages are discarded
+/// instead of cleared, which is cheaper from about glibc's default
+/// `M_MMAP_THRESHOLD` up
+const DISCARD_ZEROED_MIN: usize = 128 << 10;
 
-const KEY_MARGIN: usize = 0x123456789abcdefu64 as usize;
-const KEY_CANARY: usize = 0x23435243643547au64 as usize;
-const KEY_SIZE: usize = 0x1ae9deaf526c83du64 as usize;
+const KEY_GENERATION: usize = 0x2f8b6e07c4d91a5u64 as usize;
 
+/// `AllocInfo::flags`: Retain the allocation forever when it's freed.
+const FLAG_PINNED: usize = 1 << 0;
+/// `AllocInfo::flags`: The allocation's pages are write-protected by
+/// `FatAlloc::make_readonly`.
+const FLAG_READONLY: usize = 1 << 1;
+/// `AllocInfo::flags`: The allocation is in the `registry`.
+const FLAG_REGISTERED: usize = 1 << 2;
+/// `AllocInfo::flags`: The allocation's pages are locked in memory, and it's
+/// zeroed when freed (`fatalloc_malloc_secure`).
+const FLAG_SECURE: usize = 1 << 3;
+/// `AllocInfo::flags`: The allocation's pages are watched by
+/// `FatAlloc::watch`.
+const FLAG_WATCHED: usize = 1 << 4;
+/// `AllocInfo::flags`: The allocation's pages were write-protected by
+/// `FATALLOC_DETECT_UNWRITTEN` so that its first write is seen. It might have
+/// been written since.
+const FLAG_UNWRITTEN: usize = 1 << 5;
+/// `AllocInfo::flags`: The allocation was freed. Only written with
+/// `FATALLOC_METADATA_FREE`, so that the stale metadata of a freed block
+/// doesn't pass for that of an allocation the map misses.
+const FLAG_FREED: usize = 1 << 6;
+/// `AllocInfo::flags`: The allocation's `registry::Origin::site` is where it
+/// was last reallocated (`FATALLOC_REALLOC_SITE=last`).
+#[cfg(feature = "backtrace")]
+const FLAG_REALLOC_SITE: usize = 1 << 7;
+/// `AllocInfo::flags`: The bit position of the pointer tag, which occupies
+/// eight bits
+#[cfg(feature = "pointer-tag")]
+const FLAG_TAG_SHIFT: u32 = 8;
+/// The bit position of `user_size - requested_size` in the flags metadata
+/// word, which is at most `MAX_SLACK`. `AllocInfo::flags` doesn't include it.
+const META_SLACK_SHIFT: u32 = 16;
+/// The largest `user_size - requested_size` the flags metadata word can
+/// hold. `compact-meta` leaves it four bits, enough for the rounding only.
+#[cfg(not(feature = "compact-meta"))]
+const MAX_SLACK: usize = usize::MAX >> META_SLACK_SHIFT;
+#[cfg(feature = "compact-meta")]
+const MAX_SLACK: usize = SIZE_GRANULARITY - 1;
+
+/// The byte pattern freed memory is filled with where it matters
+const FREE_FILL: u8 = 0xdf;
+
+/// The bits set in the address of a freed allocation to make the word
+/// `FATALLOC_IDENTITY_FILL` fills it with. On a 64-bit target, they make the
+/// word a non-canonical address, so a stale pointer read from the freed
+/// memory faults when dereferenced.
+const IDENTITY_FILL_MARK: usize = 0xfd << (usize::BITS - 8);
+
+/// Get the word `FATALLOC_IDENTITY_FILL` fills the freed allocation
+/// `user_ptr` with.
+#[inline]
+fn identity_fill_word(user_ptr: *const u8) -> usize {
+    user_ptr as usize ^ IDENTITY_FILL_MARK
+}
+
+/// Get the allocation whose freed memory `word` was read from if it looks
+/// like the fill of `FATALLOC_IDENTITY_FILL`. Only a 64-bit target's user
+/// addresses leave the top byte free to tell the fill apart.
+#[inline]
+fn decode_identity_fill(word: usize) -> Option<*const u8> {
+    let user_ptr = word ^ IDENTITY_FILL_MARK;
+    let plausible = user_ptr != 0
+        && user_ptr % MIN_ALIGN == 0
+        && (usize::BITS < 64 || user_ptr >> (usize::BITS - 8) == 0);
+    plausible.then(|| user_ptr as *const u8)
+}
+
+/// The word offset (from `user_ptr - META_OFFSET`) of the `quarantine::Node`
+/// written when the allocation is freed. It's placed after the fields
+/// covered by the checksum so that a quarantined allocation still has intact
+/// metadata.
+const META_QUARANTINE_NODE: usize = meta::HEADER_WORDS;
+
+/// The word offset of the `registry::Node` of a live allocation. It shares
+/// the space with `quarantine::Node`, which is only written after the
+/// allocation is removed from the registry.
+const META_REGISTRY_NODE: usize = META_QUARANTINE_NODE;
+
+/// The word offset of the generation of a live allocation, which
+/// `fatalloc_malloc_checked` assigns (`0` if none), or its owner thread
+/// marked by [`GENERATION_OWNER_MARK`]. It follows `registry::Node`, and a
+/// larger `quarantine::Node` may overwrite it once the allocation is freed.
+/// It's not covered by the checksum because it's only checked against a
+/// token, and a corrupted one fails that anyway.
+const META_GENERATION: usize = META_REGISTRY_NODE + registry::NODE_WORDS;
+
+/// The bit set in the generation word of a single-threaded allocation
+/// (`fatalloc_mark_single_threaded`), which holds the owner's thread ID
+/// instead. The generations `fatalloc_malloc_checked` assigns never reach it.
+const GENERATION_OWNER_MARK: usize = 1 << (usize::BITS - 1);
+
+/// The number of words in the metadata area, excluding the canary. Any new
+/// metadata word must be accounted for here.
+const META_WORDS: usize = if META_QUARANTINE_NODE + quarantine::NODE_WORDS > META_GENERATION + 1 {
+    META_QUARANTINE_NODE + quarantine::NODE_WORDS
+} else {
+    META_GENERATION + 1
+};
+
+/// Obfuscate a metadata word. The per-process seed is mixed into `key` so
+/// that the keys here and in `meta` can't be used to forge metadata.
 #[inline]
 fn mangle(x: usize, key: usize) -> usize {
-    x.rotate_left(13) ^ key
+    x.rotate_left(13) ^ key ^ entropy::seed()
 }
 
 #[inline]
 fn demangle(x: usize, key: usize) -> usize {
-    (x ^ key).rotate_right(13)
+    (x ^ key ^ entropy::seed()).rotate_right(13)
+}
+
+/// Hash the contents of an allocation for `FATALLOC_CONTENT_HASH` (64-bit
+/// FNV-1a).
+fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Read and demangle the `i`-th metadata word of the allocation at
+/// `user_ptr`.
+#[inline]
+unsafe fn meta_word(user_ptr: NonNull<u8>, i: usize, key: usize) -> usize {
+    let meta_ptr = user_ptr.as_ptr().wrapping_sub(META_OFFSET);
+    demangle(
+        meta_ptr.cast::<usize>().wrapping_add(i).read(),
+        user_ptr.as_ptr() as usize ^ key,
+    )
+}
+
+/// Get the thread ID of the caller.
+#[inline]
+fn current_thread_id() -> usize {
+    unsafe { libc::syscall(libc::SYS_gettid) as usize }
+}
+
+/// Report the caller's `operation` on the live allocation at `user_ptr` if
+/// it's single-threaded (`fatalloc_mark_single_threaded`) and owned by
+/// another thread.
+unsafe fn check_owner(user_ptr: NonNull<u8>, operation: &'static str) {
+    let word = meta_word(user_ptr, META_GENERATION, KEY_GENERATION);
+    if word & GENERATION_OWNER_MARK == 0 {
+        return;
+    }
+    let (owner, thread) = (word & !GENERATION_OWNER_MARK, current_thread_id());
+    if owner != thread {
+        logger::report(logger::Event::CrossThreadAccess {
+            ptr: user_ptr.as_ptr(),
+            operation,
+            thread,
+            owner,
+        });
+    }
+}
+
+/// Get the location of the `quarantine::Node` of the allocation at `user_ptr`.
+#[inline]
+fn quarantine_node(user_ptr: NonNull<u8>) -> NonNull<u8> {
+    let node = user_ptr
+        .as_ptr()
+        .wrapping_sub(META_OFFSET)
+        .cast::<usize>()
+        .wrapping_add(META_QUARANTINE_NODE);
+    NonNull::new(node.cast()).unwrap()
+}
+
+/// Get the location of the `registry::Node` of the allocation at `user_ptr`.
+#[inline]
+fn registry_node(user_ptr: NonNull<u8>) -> NonNull<u8> {
+    let node = user_ptr
+        .as_ptr()
+        .wrapping_sub(META_OFFSET)
+        .cast::<usize>()
+        .wrapping_add(META_REGISTRY_NODE);
+    NonNull::new(node.cast()).unwrap()
+}
+
+/// The inverse of [`registry_node`]
+#[inline]
+fn user_ptr_of_registry_node(node: NonNull<u8>) -> NonNull<u8> {
+    let user_ptr = node
+        .as_ptr()
+        .cast::<usize>()
+        .wrapping_sub(META_REGISTRY_NODE)
+        .cast::<u8>()
+        .wrapping_add(META_OFFSET);
+    NonNull::new(user_ptr).unwrap()
+}
+
+/// `mlock` or `munlock` the pages overlapping `ptr..ptr + len`. Returns
+/// `false` on failure.
+unsafe fn set_locked(ptr: *mut u8, len: usize, locked: bool) -> bool {
+    let f = if locked { libc::mlock } else { libc::munlock };
+    f(ptr.cast(), len) == 0
+}
+
+#[inline]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Check if all pages overlapping with `start..end` are mapped. Spans of
+/// more than two pages are reported as unmapped.
+fn is_mapped(start: usize, end: usize) -> bool {
+    let page_size = page_size();
+    let start = start & !(page_size - 1);
+    // `mincore` writes one byte per page
+    let mut residency = [0u8; 2];
+    let len = end - start;
+    if len > residency.len() * page_size {
+        return false;
+    }
+    unsafe { libc::mincore(start as *mut libc::c_void, len, residency.as_mut_ptr()) == 0 }
+}
+
+/// Capture the call site for `registry::Origin::site` if
+/// `FATALLOC_FOLDED_FILE` or `FATALLOC_LEAK_SITES` wants it.
+#[cfg(feature = "backtrace")]
+fn allocation_site() -> [usize; registry::SITE_DEPTH] {
+    let config = config::get();
+    if config.folded_file.is_some() || config.leak_sites {
+        backtrace::capture_site()
+    } else {
+        [0; registry::SITE_DEPTH]
+    }
+}
+
+/// Get the flags and the `registry::Origin` of the allocation replacing
+/// `alloc`, made at `origin`, in a reallocation: the same unless
+/// `FATALLOC_REALLOC_SITE=last` attributes it to the current call.
+fn reallocated_origin(alloc: &AllocInfo, origin: registry::Origin) -> (usize, registry::Origin) {
+    #[cfg(feature = "backtrace")]
+    if alloc.flags & FLAG_REGISTERED != 0 && config::get().realloc_site == config::ReallocSite::Last
+    {
+        let origin = registry::Origin {
+            site: allocation_site(),
+            ..origin
+        };
+        return (alloc.flags | FLAG_REALLOC_SITE, origin);
+    }
+    (alloc.flags, origin)
 }
 
 #[inline]
@@ -42,10 +338,184 @@ fn alloc_map() -> Pin<&'static allocmap::AllocMap> {
     Pin::static_ref(&ALLOC_MAP)
 }
 
+/// Check if the current allocation request should fail according to
+/// `FATALLOC_FAIL_NTH`.
+#[inline]
+fn should_inject_failure() -> bool {
+    static NUM_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    let config = config::get();
+    if config.fail_nth == 0 {
+        return false;
+    }
+
+    let n = NUM_REQUESTS.fetch_add(1, Ordering::Relaxed) + 1;
+    if config.fail_repeat {
+        n % config.fail_nth == 0
+    } else {
+        n == config.fail_nth
+    }
+}
+
+/// How far past its user pointer the block of an allocation marked so far
+/// extends at most
+static MAX_REACH: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the `AllocMap` indices where the user pointer of an allocation whose
+/// block extends to `addr` can be, up to `end` (exclusive). Like
+/// [`AllocInfo::check_neighbor`], this allows for [`NEIGHBOR_SCAN_LEN`]
+/// bytes of the backend's headers and rounding between blocks.
+///
+/// Bounding the backward scans by [`MAX_REACH`] keeps a wild pointer from
+/// scanning far and being attributed to an allocation that can't reach it.
+fn scan_back_range(addr: usize, end: usize) -> ops::Range<usize> {
+    let reach = MAX_REACH.load(Ordering::Relaxed) + NEIGHBOR_SCAN_LEN;
+    addr.saturating_sub(reach.min(CULPRIT_SCAN_LEN)) / MIN_ALIGN..end / MIN_ALIGN
+}
+
+/// `FROZEN_*`, set by `fatalloc_freeze`
+static FROZEN: AtomicU8 = AtomicU8::new(FROZEN_NO);
+
+const FROZEN_NO: u8 = 0;
+/// Frozen, and no request has been denied yet
+const FROZEN_YES: u8 = 1;
+/// Frozen, and the first denial has been logged
+const FROZEN_REPORTED: u8 = 2;
+
+/// Set whether new memory is denied.
+fn set_frozen(frozen: bool) {
+    FROZEN.store(
+        if frozen { FROZEN_YES } else { FROZEN_NO },
+        Ordering::Relaxed,
+    );
+}
+
+/// Check if a request for new memory (`layout`) should fail because the
+/// allocator is frozen. Only the first denial after freezing is logged.
+#[inline]
+fn is_frozen(layout: alloc::Layout) -> bool {
+    match FROZEN.load(Ordering::Relaxed) {
+        FROZEN_NO => false,
+        _ => {
+            if FROZEN
+                .compare_exchange(
+                    FROZEN_YES,
+                    FROZEN_REPORTED,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                warn!(
+                    "allocation denied: the allocator is frozen (requested {layout:?}); \
+                    further denials aren't logged"
+                );
+            }
+            true
+        }
+    }
+}
+
+/// Report a rejected deallocation request for `ptr`, telling what kind of
+/// double free it is if possible.
+#[cold]
+unsafe fn report_invalid_free(ptr: NonNull<u8>, reason: &'static str) {
+    #[cfg(feature = "backtrace")]
+    if config::get().free_backtrace {
+        if let Some(site) = quarantine::find_free_site(quarantine_node(ptr)) {
+            logger::report(logger::Event::DoubleFree {
+                ptr: ptr.as_ptr(),
+                reason,
+                first_freed: site,
+                freed_again: backtrace::Backtrace::capture(0),
+            });
+            return;
+        }
+    }
+
+    if config::get().strict_align {
+        if let Some((owner, _)) = AllocInfo::find_owner(ptr) {
+            let align = meta::read_field(owner, meta::FIELD_ALIGN);
+            if align > MIN_ALIGN && ptr.as_ptr() as usize % align != 0 {
+                logger::report(logger::Event::MisalignedFree {
+                    ptr: ptr.as_ptr(),
+                    owner: owner.as_ptr(),
+                    align,
+                });
+                return;
+            }
+        }
+    }
+
+    if config::get().classify_double_free {
+        if quarantine::contains(quarantine_node(ptr)) {
+            logger::report(logger::Event::FreedTwice { ptr: ptr.as_ptr() });
+            return;
+        }
+        let owner = AllocInfo::find_owner(ptr);
+        if config::get().double_free_grace {
+            // Out of the quarantine window; record it without escalating
+            crate::stats::count_event();
+            match owner {
+                Some((owner, owner_size)) => info!(
+                    "double free grace: {ptr:p} is a stale pointer into memory reused by \
+                     the allocation {owner:p} of {owner_size} bytes; ignoring it"
+                ),
+                None => info!("double free grace: {ptr:p} is {reason}; ignoring it"),
+            }
+            return;
+        }
+        if let Some((owner, owner_size)) = owner {
+            logger::report(logger::Event::StaleFree {
+                ptr: ptr.as_ptr(),
+                owner: owner.as_ptr(),
+                owner_size,
+            });
+            return;
+        }
+    }
+
+    logger::report(logger::Event::InvalidFree {
+        ptr: ptr.as_ptr(),
+        reason,
+    });
+}
+
+/// What [`AllocInfo::verify`] found wrong with an allocation. The values are
+/// returned by `fatalloc_verify`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CorruptionKind {
+    /// Not a live allocation
+    NotAllocated = 1,
+    /// The metadata checksum doesn't match.
+    Checksum = 2,
+    /// The guard below the metadata is broken.
+    Guard = 3,
+    /// The heap canary is broken.
+    Canary = 4,
+    /// The poisoned tail left by a shrinking reallocation was modified.
+    PoisonedTail = 5,
+    /// The metadata holds impossible values despite the checksum.
+    Metadata = 6,
+}
+
 #[derive(Debug, PartialEq)]
 struct AllocInfo {
     margin: usize,
+    /// The size granted to the caller, which `malloc_usable_size` reports
+    /// and the trailing margin starts at
     user_size: usize,
+    /// The size the caller asked for, before `FATALLOC_ROUND_SIZES`. It's
+    /// smaller than `user_size` by at most `MAX_SLACK`.
+    requested_size: usize,
+    /// The alignment requested by the caller, which can't be derived from
+    /// `margin` anymore
+    align: usize,
+    /// `FLAG_*`
+    flags: usize,
+    /// The number of bytes after the user region filled with `FREE_FILL`
+    /// by a shrinking reallocation. Never exceeds `margin`.
+    poisoned_tail: usize,
     outer_ptr: NonNull<u8>,
 }
 
@@ -77,21 +547,118 @@ impl AllocInfo {
         })
     }
 
+    /// Validate a supposed allocation at `user_ptr` that the `AllocMap`
+    /// misses by its metadata alone (`FATALLOC_METADATA_FREE`), running every
+    /// check regardless of `FATALLOC_CHECKS`, and claim it by invalidating
+    /// the checksum, so that a racing free of the same pointer fails. The
+    /// claimed allocation's metadata must be rewritten.
+    unsafe fn from_metadata_and_claim(user_ptr: NonNull<u8>) -> Option<Self> {
+        let addr = user_ptr.as_ptr() as usize;
+        let meta_start = addr.wrapping_sub(MIN_MARGIN);
+        if addr % MIN_ALIGN != 0 || meta_start > addr || !is_mapped(meta_start, addr) {
+            return None;
+        }
+        let checksum = &*meta::checksum_ptr(user_ptr).cast::<meta::AtomicChecksum>();
+        let expected = checksum.load(Ordering::Relaxed);
+
+        // Checked before `from_user_ptr_unchecked`, which would report it
+        if !Self::has_intact_metadata(user_ptr)
+            || !Self::has_intact_guard(user_ptr)
+            || !Self::has_intact_canary(user_ptr)
+        {
+            return None;
+        }
+        let this = Self::from_user_ptr_unchecked(user_ptr).ok()?;
+        if this.flags & FLAG_FREED != 0 {
+            return None;
+        }
+
+        this.unprotect_meta();
+        checksum
+            .compare_exchange(expected, !expected, Ordering::Relaxed, Ordering::Relaxed)
+            .ok()?;
+        Some(this)
+    }
+
+    /// Check the metadata guard of a supposed allocation at `user_ptr`.
+    #[inline]
+    unsafe fn has_intact_guard(user_ptr: NonNull<u8>) -> bool {
+        meta::has_intact_guard(user_ptr)
+    }
+
+    /// Report if the allocation was passed through a pointer with a non-zero
+    /// tag other than its own.
+    #[cfg(feature = "pointer-tag")]
+    fn check_tag(&self, tag: u8) {
+        let expected = (self.flags >> FLAG_TAG_SHIFT) as u8;
+        if tag != 0 && tag != expected {
+            logger::report(logger::Event::TypeConfusion {
+                ptr: self.user_ptr(),
+                tag,
+                expected,
+            });
+        }
+    }
+
+    /// Read the requested size, the granted size, and the alignment of the
+    /// live allocation at `user_ptr` without logging anything. Returns `None`
+    /// if it's not one or its metadata is corrupted.
+    unsafe fn peek_layout(user_ptr: NonNull<u8>) -> Option<(usize, usize, usize)> {
+        let addr = user_ptr.as_ptr() as usize;
+        if addr % MIN_ALIGN != 0
+            || !alloc_map().get(addr / MIN_ALIGN)
+            || !Self::has_intact_metadata(user_ptr)
+        {
+            return None;
+        }
+        let user_size = meta::read_field(user_ptr, meta::FIELD_SIZE);
+        let slack = meta::read_field(user_ptr, meta::FIELD_FLAGS) >> META_SLACK_SHIFT;
+        let align = meta::read_field(user_ptr, meta::FIELD_ALIGN);
+        Some((user_size.wrapping_sub(slack), user_size, align))
+    }
+
+    /// Check the metadata checksum of a supposed allocation at `user_ptr`.
+    unsafe fn has_intact_metadata(user_ptr: NonNull<u8>) -> bool {
+        meta::has_intact_fields(user_ptr)
+    }
+
     unsafe fn from_user_ptr_unchecked(user_ptr: NonNull<u8>) -> Result<Self, &'static str> {
+        // A large underflow can reach the metadata without touching the
+        // canary, so validate the metadata words as a whole first
+        let checks = config::get().checks;
+        if checks & config::CHECK_CHECKSUM != 0 && !Self::has_intact_metadata(user_ptr) {
+            return Err("metadata overwritten by underflow");
+        }
+
+        // Even if the metadata happens to be intact, a write reaching the
+        // guard means it can't be trusted
+        if checks & config::CHECK_CANARY != 0 && !Self::has_intact_guard(user_ptr) {
+            return Err("metadata region overwritten");
+        }
+
         // Read the metadata
-        let meta_ptr = user_ptr.as_ptr().wrapping_sub(MIN_MARGIN);
-        let margin = demangle(
-            meta_ptr.cast::<usize>().read(),
-            user_ptr.as_ptr() as usize ^ KEY_MARGIN,
-        );
+        let [margin, user_size, align, flags, poisoned_tail] = meta::read_fields(user_ptr);
         if !margin.is_power_of_two() || margin < MIN_MARGIN {
             return Err("metadata corrupted");
         }
 
-        let user_size = demangle(
-            meta_ptr.cast::<usize>().wrapping_add(1).read(),
-            user_ptr.as_ptr() as usize ^ KEY_SIZE,
-        );
+        if !align.is_power_of_two() || align > margin {
+            return Err("metadata corrupted");
+        }
+        if config::get().strict_align && user_ptr.as_ptr() as usize % align != 0 {
+            return Err("misaligned for the allocation's alignment");
+        }
+
+        if poisoned_tail > margin {
+            return Err("metadata corrupted");
+        }
+
+        let slack = flags >> META_SLACK_SHIFT;
+        let flags = flags & !(!0 << META_SLACK_SHIFT);
+        if slack > MAX_SLACK || slack > user_size {
+            return Err("metadata corrupted");
+        }
+        let requested_size = user_size - slack;
 
         // Find the outer allocation
         let outer_ptr = user_ptr.as_ptr().wrapping_sub(margin);
@@ -101,132 +668,2120 @@ impl AllocInfo {
             margin,
             outer_ptr,
             user_size,
+            requested_size,
+            align,
+            flags,
+            poisoned_tail,
         };
 
         // Check round-trip conversion
         debug_assert_eq!(this.user_ptr(), user_ptr.as_ptr());
 
         // Check the heap canary
-        let canary = demangle(
-            user_ptr.as_ptr().cast::<usize>().wrapping_sub(1).read(),
-            KEY_CANARY,
-        );
-        if canary != user_ptr.as_ptr() as usize {
-            warn!("heap overrun detected at allocation {user_ptr:p}");
+        if checks & config::CHECK_CANARY != 0 && !Self::has_intact_canary(user_ptr) {
+            logger::report(logger::Event::Overrun {
+                ptr: user_ptr.as_ptr(),
+                culprit: Self::find_culprit(user_ptr, margin),
+            });
         }
 
         Ok(this)
     }
 
+    /// Check the heap canary of a supposed allocation at `user_ptr`.
+    #[inline]
+    unsafe fn has_intact_canary(user_ptr: NonNull<u8>) -> bool {
+        meta::has_intact_canary(user_ptr)
+    }
+
+    /// Run every check on the allocation at `user_ptr` regardless of
+    /// `FATALLOC_CHECKS`, without logging or changing anything.
+    unsafe fn verify(user_ptr: NonNull<u8>) -> Result<(), CorruptionKind> {
+        if user_ptr.as_ptr() as usize % MIN_ALIGN != 0
+            || !alloc_map().get(user_ptr.as_ptr() as usize / MIN_ALIGN)
+        {
+            return Err(CorruptionKind::NotAllocated);
+        }
+        if !Self::has_intact_metadata(user_ptr) {
+            return Err(CorruptionKind::Checksum);
+        }
+        if !Self::has_intact_guard(user_ptr) {
+            return Err(CorruptionKind::Guard);
+        }
+        // Checked before `from_user_ptr_unchecked`, which would report it
+        if !Self::has_intact_canary(user_ptr) {
+            return Err(CorruptionKind::Canary);
+        }
+        let alloc =
+            Self::from_user_ptr_unchecked(user_ptr).map_err(|_| CorruptionKind::Metadata)?;
+
+        let tail = core::slice::from_raw_parts(
+            alloc.user_ptr().wrapping_add(alloc.user_size),
+            alloc.poisoned_tail,
+        );
+        if tail.iter().any(|&b| b != FREE_FILL) {
+            return Err(CorruptionKind::PoisonedTail);
+        }
+        Ok(())
+    }
+
+    /// Find the live allocation preceding `user_ptr` in the address order,
+    /// which an overflow reaching the canary of `user_ptr` most likely came
+    /// from.
+    ///
+    /// Like [`Self::check_neighbor`], this may be misled by a concurrent
+    /// deallocation.
+    #[cold]
+    unsafe fn find_culprit(user_ptr: NonNull<u8>, margin: usize) -> Option<logger::Culprit> {
+        let canary = user_ptr.as_ptr() as usize - core::mem::size_of::<usize>();
+        let outer_start = (user_ptr.as_ptr() as usize).saturating_sub(margin);
+        let culprit =
+            alloc_map().find_prev(scan_back_range(outer_start, user_ptr.as_ptr() as usize))?;
+        let culprit = NonNull::new_unchecked((culprit * MIN_ALIGN) as *mut u8);
+        if !Self::has_intact_metadata(culprit) {
+            return None;
+        }
+
+        let size = meta::read_field(culprit, meta::FIELD_SIZE);
+        let culprit_end = (culprit.as_ptr() as usize).checked_add(size)?;
+        if culprit_end > canary {
+            return None;
+        }
+        Some(logger::Culprit {
+            ptr: culprit.as_ptr(),
+            size,
+            overflow: canary - culprit_end + 1,
+        })
+    }
+
+    /// Find the live allocation containing `ptr`, returning its user
+    /// pointer and size.
+    ///
+    /// Like [`Self::find_culprit`], this may be misled by a concurrent
+    /// deallocation.
+    #[cold]
+    unsafe fn find_owner(ptr: NonNull<u8>) -> Option<(NonNull<u8>, usize)> {
+        let addr = ptr.as_ptr() as usize;
+        let owner = alloc_map().find_prev(scan_back_range(addr, addr))?;
+        let owner = NonNull::new_unchecked((owner * MIN_ALIGN) as *mut u8);
+        if !Self::has_intact_metadata(owner) {
+            return None;
+        }
+
+        let size = meta::read_field(owner, meta::FIELD_SIZE);
+        let owner_end = (owner.as_ptr() as usize).checked_add(size)?;
+        if owner_end <= ptr.as_ptr() as usize {
+            return None;
+        }
+        Some((owner, size))
+    }
+
+    /// Verify the metadata and the canary of the next allocation in the
+    /// address order, which an overflow from `self` would hit first.
+    ///
+    /// The neighbor might be freed concurrently, in which case this may
+    /// misreport corruption.
+    unsafe fn check_neighbor(&self) {
+        let user_end = self.user_ptr() as usize + self.user_size;
+        let start_i = (user_end + MIN_ALIGN - 1) / MIN_ALIGN;
+        let end_i = start_i.saturating_add((self.margin + NEIGHBOR_SCAN_LEN) / MIN_ALIGN);
+        let neighbor_i = match alloc_map().find_next(start_i..end_i) {
+            Some(i) => i,
+            None => return,
+        };
+        let neighbor = NonNull::new_unchecked((neighbor_i * MIN_ALIGN) as *mut u8);
+
+        // Another thread may have freed the neighbor since, and unmapped it
+        // if it was mapped directly or by the system allocator. Withdrawn
+        // from the map or unmapped, it's skipped.
+        let meta_start = neighbor.as_ptr() as usize - MIN_MARGIN;
+        let intact = alloc_map().if_set(neighbor_i, || {
+            !is_mapped(meta_start, neighbor.as_ptr() as usize)
+                || (Self::has_intact_guard(neighbor)
+                    && Self::has_intact_metadata(neighbor)
+                    && Self::has_intact_canary(neighbor))
+        });
+        if intact == Some(false) {
+            logger::report(logger::Event::NeighborOverrun {
+                ptr: neighbor.as_ptr(),
+                culprit: self.user_ptr(),
+            });
+        }
+    }
+
+    /// Get the range of whole pages inside the outer allocation, which can be
+    /// protected without affecting other allocations. It starts at the user
+    /// region so that the metadata, which the `registry` might update,
+    /// stays writable.
+    fn inner_pages(&self) -> ops::Range<usize> {
+        let page_size = page_size();
+        let outer_start = self.outer_ptr.as_ptr() as usize;
+        let start = (self.user_ptr() as usize + page_size - 1) & !(page_size - 1);
+        let end = (outer_start + self.outer_size()) & !(page_size - 1);
+        start..end.max(start)
+    }
+
+    /// Get the part of [`Self::inner_pages`] that overlaps the user region,
+    /// which [`FatAlloc::watch`] protects.
+    fn watched_pages(&self) -> ops::Range<usize> {
+        let page_size = page_size();
+        let pages = self.inner_pages();
+        let user_end =
+            (self.user_ptr() as usize + self.user_size + page_size - 1) & !(page_size - 1);
+        pages.start..pages.end.min(user_end).max(pages.start)
+    }
+
+    /// Get the pages `FATALLOC_DETECT_UNWRITTEN` write-protects, or `None` if
+    /// they don't cover the entire user region, in which case a write to the
+    /// rest would go unseen.
+    fn unwritten_pages(&self) -> Option<ops::Range<usize>> {
+        let pages = self.watched_pages();
+        let user_start = self.user_ptr() as usize;
+        (!pages.is_empty() && pages.start == user_start && pages.end >= user_start + self.user_size)
+            .then(|| pages)
+    }
+
+    /// Write-protect [`Self::inner_pages`].
+    unsafe fn protect(&self) -> bool {
+        let pages = self.inner_pages();
+        libc::mprotect(
+            pages.start as *mut libc::c_void,
+            pages.end - pages.start,
+            libc::PROT_READ,
+        ) == 0
+    }
+
+    /// Let the kernel reclaim [`Self::inner_pages`] under memory pressure
+    /// (`MADV_FREE`). Until it does, their contents stay readable; after
+    /// that, they read as zero. Any write cancels it for the page.
+    unsafe fn advise_free(&self) {
+        static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+        let pages = self.inner_pages();
+        if pages.is_empty() || UNSUPPORTED.load(Ordering::Relaxed) {
+            return;
+        }
+        let ret = libc::madvise(
+            pages.start as *mut libc::c_void,
+            pages.end - pages.start,
+            libc::MADV_FREE,
+        );
+        // Linux < 4.5 doesn't know `MADV_FREE`
+        if ret != 0 && !UNSUPPORTED.swap(true, Ordering::Relaxed) {
+            warn!("MADV_FREE is unavailable; FATALLOC_QUARANTINE_MADV_FREE has no effect");
+        }
+    }
+
+    /// Make the allocation's pages writable again if it's read-only,
+    /// watched, or not written yet.
+    unsafe fn make_writable(&self) {
+        if self.flags & FLAG_WATCHED != 0 {
+            watch::remove(self.user_ptr() as usize);
+        }
+        if self.flags & FLAG_UNWRITTEN != 0 {
+            watch::remove_unwritten(self.user_ptr() as usize);
+        }
+        if self.flags & FLAG_READONLY != 0 {
+            let pages = self.inner_pages();
+            let ret = libc::mprotect(
+                pages.start as *mut libc::c_void,
+                pages.end - pages.start,
+                libc::PROT_READ | libc::PROT_WRITE,
+            );
+            assert_eq!(ret, 0, "failed to unprotect a read-only allocation");
+        }
+        self.unprotect_meta();
+    }
+
+    /// Get the page holding the metadata if `FATALLOC_PROTECT_METADATA`
+    /// write-protects it. Only a page-aligned allocation has its metadata on
+    /// a page of its own (its leading margin), and the `registry` updates the
+    /// metadata of registered allocations.
+    fn protected_meta_page(&self) -> Option<usize> {
+        let page_size = page_size();
+        let user_ptr = self.user_ptr() as usize;
+        (config::get().protect_metadata
+            && self.flags & FLAG_REGISTERED == 0
+            && self.margin >= page_size
+            && user_ptr % page_size == 0)
+            .then(|| user_ptr - page_size)
+    }
+
+    /// Write-protect [`Self::protected_meta_page`] of a live allocation. It's
+    /// left writable if `mprotect` fails.
+    unsafe fn protect_meta(&self) {
+        if let Some(page) = self.protected_meta_page() {
+            libc::mprotect(page as *mut libc::c_void, page_size(), libc::PROT_READ);
+        }
+    }
+
+    /// Undo [`Self::protect_meta`].
+    unsafe fn unprotect_meta(&self) {
+        if let Some(page) = self.protected_meta_page() {
+            let ret = libc::mprotect(
+                page as *mut libc::c_void,
+                page_size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+            );
+            assert_eq!(ret, 0, "failed to unprotect the metadata");
+        }
+    }
+
+    /// Rewrite the metadata of a live allocation, which might be
+    /// write-protected.
+    unsafe fn update_meta(&self) {
+        self.unprotect_meta();
+        self.write_meta();
+        self.protect_meta();
+    }
+
+    /// Zero the user region of a secure allocation in a way the compiler
+    /// can't elide, and unlock its pages.
+    unsafe fn wipe_if_secure(&self) {
+        if self.flags & FLAG_SECURE == 0 {
+            return;
+        }
+        let user_ptr = self.user_ptr();
+        // The user region is page-aligned
+        let num_words = self.user_size / core::mem::size_of::<usize>();
+        for i in 0..num_words {
+            ptr::write_volatile(user_ptr.cast::<usize>().add(i), 0);
+        }
+        for i in num_words * core::mem::size_of::<usize>()..self.user_size {
+            ptr::write_volatile(user_ptr.add(i), 0);
+        }
+        core::sync::atomic::compiler_fence(Ordering::SeqCst);
+        set_locked(user_ptr, self.user_size, false);
+    }
+
+    /// Fill the user region with [`identity_fill_word`], in the native byte
+    /// order, so that the bytes at any offset identify the allocation.
+    unsafe fn fill_identity(&self) {
+        let user_ptr = self.user_ptr();
+        let word = identity_fill_word(user_ptr);
+        let num_words = self.user_size / core::mem::size_of::<usize>();
+        for i in 0..num_words {
+            user_ptr.cast::<usize>().add(i).write(word);
+        }
+        for (i, &b) in word.to_ne_bytes()[..self.user_size % core::mem::size_of::<usize>()]
+            .iter()
+            .enumerate()
+        {
+            user_ptr
+                .add(num_words * core::mem::size_of::<usize>() + i)
+                .write(b);
+        }
+    }
+
     #[inline]
     fn user_ptr(&self) -> *mut u8 {
         self.outer_ptr.as_ptr().wrapping_add(self.margin)
     }
 
     #[inline]
-    unsafe fn mark(&self) {
-        assert!(self.margin.is_power_of_two() && self.margin >= MIN_MARGIN);
+    fn outer_size(&self) -> usize {
+        padded_size(self.user_size) + self.margin * 2
+    }
 
-        // Mark the allocation
-        let user_ptr = self.user_ptr();
-        assert_eq!(user_ptr as usize % MIN_ALIGN, 0);
-        alloc_map().set(user_ptr as usize / MIN_ALIGN);
+    /// Get the bytes the outer allocation takes beyond the user region.
+    #[inline]
+    fn overhead(&self) -> usize {
+        self.outer_size() - self.user_size
+    }
 
-        // Write the metadata
-        let meta_ptr = user_ptr.wrapping_sub(MIN_MARGIN);
-        meta_ptr
-            .cast::<usize>()
-            .write(mangle(self.margin, user_ptr as usize ^ KEY_MARGIN));
-        meta_ptr
-            .cast::<usize>()
-            .wrapping_add(1)
-            .write(mangle(self.user_size, user_ptr as usize ^ KEY_SIZE));
+    /// Fill the padding added by `FATALLOC_MIN_SIZE` with `FREE_FILL` as a
+    /// poisoned tail (up to `margin` bytes), so that an overflow past the
+    /// requested end into it is caught like one into a shrunk tail.
+    unsafe fn poison_padding(&mut self) {
+        let len = (padded_size(self.user_size) - self.user_size).min(self.margin);
+        if len > self.poisoned_tail {
+            self.user_ptr()
+                .wrapping_add(self.user_size + self.poisoned_tail)
+                .write_bytes(FREE_FILL, len - self.poisoned_tail);
+            self.poisoned_tail = len;
+        }
+    }
+
+    /// Get the layout the outer allocation was made with.
+    #[inline]
+    fn outer_layout(&self) -> alloc::Layout {
+        unsafe {
+            alloc::Layout::from_size_align_unchecked(self.outer_size(), self.align.max(MIN_ALIGN))
+        }
+    }
+
+    /// Mark the allocation as valid and write its metadata. Fails if the
+    /// `AllocMap` would exceed `max_leaves` leaves or can't grow.
+    #[inline]
+    unsafe fn mark(&self, max_leaves: usize) -> Result<(), allocmap::SetError> {
+        // Leave a live allocation's metadata alone. `check_backend_ptr` has
+        // checked this already unless another thread got the same block.
+        if alloc_map().get(self.user_ptr() as usize / MIN_ALIGN) {
+            logger::report(logger::Event::AlreadyTracked {
+                ptr: self.user_ptr(),
+            });
+            return Err(allocmap::SetError::AlreadyTracked);
+        }
+        self.prepare_mark();
+
+        // Publish the allocation last. A racing `free` or `realloc` through
+        // a stale pointer (e.g., to the block an in-place reallocation is
+        // resizing) may win the bit as soon as it's set, and then it must see
+        // the new metadata. Conversely, exactly one of racing callers wins
+        // `AllocMap::test_and_clear`, and the rest are rejected before
+        // touching the block.
+        if alloc_map().set_bounded(self.user_ptr() as usize / MIN_ALIGN, max_leaves)? {
+            // `check_backend_ptr` rejects such a block unless it was handed
+            // out again before being marked, e.g., twice in one batch
+            logger::report(logger::Event::AlreadyTracked {
+                ptr: self.user_ptr(),
+            });
+        }
 
         // Check round-trip conversion
         debug_assert_eq!(
-            Self::from_user_ptr(NonNull::new(user_ptr).unwrap()).unwrap(),
+            Self::from_user_ptr(NonNull::new(self.user_ptr()).unwrap()).unwrap(),
             *self
         );
+        Ok(())
+    }
+
+    /// Write the metadata and the canary, leaving the allocation unpublished
+    /// in `AllocMap`.
+    #[inline]
+    unsafe fn prepare_mark(&self) {
+        assert!(self.margin.is_power_of_two() && self.margin >= MIN_MARGIN);
+
+        let user_ptr = self.user_ptr();
+        assert_eq!(user_ptr as usize % MIN_ALIGN, 0);
+
+        // The guard and the metadata must lie between the outer allocation's
+        // start and the canary
+        debug_assert!(
+            user_ptr as usize - MIN_MARGIN >= self.outer_ptr.as_ptr() as usize
+                && user_ptr as usize - META_OFFSET + META_WORDS * core::mem::size_of::<usize>()
+                    <= user_ptr as usize - meta::CANARY_SIZE
+        );
 
-        // Place a heap canary
+        // Before the allocation is published, so that the scans from
+        // addresses it covers reach it
+        let reach = self.outer_size() - self.margin;
+        if reach > MAX_REACH.load(Ordering::Relaxed) {
+            MAX_REACH.fetch_max(reach, Ordering::Relaxed);
+        }
+
+        // Write the metadata. The generation left by a previous allocation at
+        // the same address must not survive.
+        self.write_meta();
+        self.write_generation(0);
+
+        // Guard the metadata and place a heap canary
         // TODO: Place another one on the other size
+        meta::write_guard_and_canary(NonNull::new_unchecked(user_ptr));
+    }
+
+    /// Get the flags metadata word, which also holds the rounding slack.
+    #[inline]
+    fn meta_flags(&self) -> usize {
+        debug_assert!(self.flags >> META_SLACK_SHIFT == 0);
+        debug_assert!(self.user_size - self.requested_size <= MAX_SLACK);
+        self.flags | (self.user_size - self.requested_size) << META_SLACK_SHIFT
+    }
+
+    /// Get the generation assigned by [`FatAlloc::allocate_checked`], or `0`.
+    unsafe fn generation(&self) -> usize {
+        meta_word(
+            NonNull::new_unchecked(self.user_ptr()),
+            META_GENERATION,
+            KEY_GENERATION,
+        )
+    }
+
+    unsafe fn write_generation(&self, generation: usize) {
+        let user_ptr = self.user_ptr();
         user_ptr
+            .wrapping_sub(META_OFFSET)
             .cast::<usize>()
-            .wrapping_sub(1)
-            .write(mangle(user_ptr as usize, KEY_CANARY));
+            .wrapping_add(META_GENERATION)
+            .write(mangle(generation, user_ptr as usize ^ KEY_GENERATION));
+    }
+
+    /// Rewrite the metadata of an allocation being freed with [`FLAG_FREED`]
+    /// if `FATALLOC_METADATA_FREE` is enabled. The metadata must be writable.
+    unsafe fn retire_meta(&self) {
+        if config::get().metadata_free {
+            AllocInfo {
+                flags: self.flags | FLAG_FREED,
+                ..*self
+            }
+            .write_meta();
+        }
+    }
+
+    /// Write the metadata fields and their checksum.
+    #[inline]
+    unsafe fn write_meta(&self) {
+        let user_ptr = NonNull::new_unchecked(self.user_ptr());
+        meta::write_fields(
+            user_ptr,
+            &[
+                self.margin,
+                self.user_size,
+                self.align,
+                self.meta_flags(),
+                self.poisoned_tail,
+            ],
+        );
+    }
+}
+
+/// Choose the margin for an allocation of the specified layout.
+///
+/// Tiny allocations only get room for the metadata and the canary (or
+/// `FATALLOC_MARGIN`), while larger ones get a redzone proportional to their
+/// size (capped at [`MAX_MARGIN`]). The result is always a power of two so
+/// that it can be validated on deallocation.
+#[inline]
+fn margin_for(layout: alloc::Layout) -> usize {
+    let proportional = (layout.size() / MARGIN_RATIO).clamp(MIN_MARGIN, MAX_MARGIN);
+    proportional
+        .next_power_of_two()
+        .max(config::get().margin)
+        .max(layout.align())
+}
+
+/// Get the size to actually give an allocation of `size` bytes, which
+/// `malloc_usable_size` reports and the trailing margin starts at.
+#[inline]
+fn user_size_for(size: usize) -> Option<usize> {
+    if config::get().round_sizes {
+        Some(size.checked_add(SIZE_GRANULARITY - 1)? & !(SIZE_GRANULARITY - 1))
+    } else {
+        Some(size)
     }
 }
 
+/// Get the size of the part of an outer allocation between the margins for
+/// a user region of `user_size` bytes, padded to `FATALLOC_MIN_SIZE`.
+#[inline]
+fn padded_size(user_size: usize) -> usize {
+    user_size.max(config::get().min_size)
+}
+
 #[inline]
 fn outer_layout_and_margin(layout: alloc::Layout) -> Option<(alloc::Layout, usize)> {
-    let margin = MIN_MARGIN.max(layout.align());
-    let outer_size = layout.size().checked_add(margin.checked_mul(2)?)?;
+    let margin = margin_for(layout);
+    // However small `layout` is (e.g., `realloc(p, 0)`), the leading margin
+    // holds the metadata and the canary, and the user pointer stays aligned.
+    // `margin_for` ensures this by construction: it's the largest of powers
+    // of two including `MIN_MARGIN` and the alignment (`FATALLOC_MARGIN` is
+    // rounded up to one, too), so this is only asserted in debug builds to
+    // keep it off the allocation path.
+    debug_assert!(margin >= MIN_MARGIN && margin % layout.align() == 0);
+    let outer_size = padded_size(layout.size()).checked_add(margin.checked_mul(2)?)?;
     let outer_layout =
         alloc::Layout::from_size_align(outer_size, layout.align().max(MIN_ALIGN)).ok()?;
     Some((outer_layout, margin))
 }
 
+/// Check that `outer_ptr`, returned by the backend for `outer_layout`, is
+/// usable. A misaligned one would misalign the user pointer and the metadata,
+/// and one overlapping with a live allocation would corrupt it.
+#[inline]
+fn check_backend_ptr(outer_ptr: NonNull<u8>, outer_layout: alloc::Layout) -> Option<()> {
+    let addr = outer_ptr.as_ptr() as usize;
+    let e = if addr % outer_layout.align() != 0 {
+        "misaligned"
+    } else if let Some(end) = addr.checked_add(outer_layout.size()) {
+        let range = addr / MIN_ALIGN..(end - 1) / MIN_ALIGN + 1;
+        match alloc_map().find_next(range) {
+            Some(_) => "overlapping with a live allocation",
+            None => return Some(()),
+        }
+    } else {
+        "wrapping around the address space"
+    };
+    logger::report(logger::Event::BadBackendPtr {
+        ptr: outer_ptr.as_ptr(),
+        layout: outer_layout,
+        reason: e,
+    });
+    None
+}
+
 impl<T> FatAlloc<T> {
     const fn new(alloc: T) -> Self {
         Self { alloc }
     }
 }
 
-unsafe impl<T: CAlloc> CAlloc for FatAlloc<T> {
+unsafe impl<T: CAllocBlockSize> CAlloc for FatAlloc<T> {
     fn allocate(&self, layout: alloc::Layout) -> Option<NonNull<u8>> {
-        // Add margins
-        let (outer_layout, margin) = outer_layout_and_margin(layout)?;
-
-        // Allocate memory
-        let outer_ptr = CAlloc::allocate(&self.alloc, outer_layout)?;
-        let alloc = AllocInfo {
-            margin,
-            outer_ptr,
-            user_size: layout.size(),
-        };
+        let (ptr, _) = self.allocate_inner(layout, None, |layout| {
+            CAlloc::allocate(&self.alloc, layout).map(|ptr| (ptr, false))
+        })?;
+        if config::get().detect_unwritten {
+            unsafe { self.track_unwritten(ptr) };
+        }
+        Some(ptr)
+    }
 
-        // Write metadata to one of the margins
-        unsafe { alloc.mark() };
+    unsafe fn deallocate(&self, ptr: NonNull<u8>) {
+        self.deallocate_tagged(
+            ptr,
+            #[cfg(feature = "pointer-tag")]
+            0,
+        )
+    }
 
-        Some(NonNull::new(alloc.user_ptr()).unwrap())
+    unsafe fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        new_layout: alloc::Layout,
+    ) -> Option<NonNull<u8>> {
+        self.reallocate_tagged(
+            ptr,
+            new_layout,
+            #[cfg(feature = "pointer-tag")]
+            0,
+        )
     }
+}
+
+impl<T: CAllocBlockSize> FatAlloc<T> {
+    /// [`CAlloc::deallocate`] through a pointer that had the tag `tag`
+    /// (`0` = untagged). The tag is checked once the allocation is claimed,
+    /// when a racing deallocation can no longer release it.
+    unsafe fn deallocate_tagged(&self, ptr: NonNull<u8>, #[cfg(feature = "pointer-tag")] tag: u8) {
+        let _guard = match reentrancy::Guard::enter() {
+            Some(guard) => guard,
+            None if emergency::owns(ptr) => {
+                emergency::deallocate(ptr);
+                return;
+            }
+            None => {
+                warn!("allocator reentered; leaking {ptr:p}");
+                return;
+            }
+        };
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>) {
         match AllocInfo::from_user_ptr_and_unmark(ptr) {
-            Ok(AllocInfo { outer_ptr, .. }) => CAlloc::deallocate(&self.alloc, outer_ptr),
-            Err(e) => warn!("ignoring the deallocation request for {ptr:p}: {e}"),
+            Ok(alloc) => {
+                #[cfg(feature = "pointer-tag")]
+                alloc.check_tag(tag);
+                self.deallocate_unmarked(ptr, &alloc)
+            }
+            Err(_) if self.deallocate_by_metadata(ptr) => {}
+            Err(e) => self.reject_deallocation(ptr, e),
         }
     }
 
-    unsafe fn reallocate(
+    /// [`CAlloc::reallocate`] through a pointer that had the tag `tag`, like
+    /// [`Self::deallocate_tagged`]
+    unsafe fn reallocate_tagged(
         &self,
         ptr: NonNull<u8>,
         new_layout: alloc::Layout,
+        #[cfg(feature = "pointer-tag")] tag: u8,
     ) -> Option<NonNull<u8>> {
+        let _guard = match reentrancy::Guard::enter() {
+            Some(guard) => guard,
+            None if emergency::owns(ptr) => return emergency::reallocate(ptr, new_layout),
+            None => {
+                warn!("allocator reentered; rejecting the reallocation request for {ptr:p}");
+                return None;
+            }
+        };
+
+        if should_inject_failure() {
+            info!("injecting a reallocation failure for {ptr:p}");
+            return None;
+        }
+
         match AllocInfo::from_user_ptr_and_unmark(ptr) {
-            Ok(AllocInfo {
-                outer_ptr, margin, ..
-            }) => {
-                let new_layout = alloc::Layout::from_size_align(new_layout.size(), margin).ok()?;
-                let (new_outer_layout, new_margin) = outer_layout_and_margin(new_layout)?;
-                assert_eq!(margin, new_margin);
-                let new_outer_ptr = CAlloc::reallocate(&self.alloc, outer_ptr, new_outer_layout)?;
-                let alloc = AllocInfo {
-                    outer_ptr: new_outer_ptr,
-                    margin: new_margin,
-                    user_size: new_layout.size(),
+            Ok(alloc) => {
+                #[cfg(feature = "pointer-tag")]
+                alloc.check_tag(tag);
+                check_owner(ptr, "realloc");
+                let new_size = match user_size_for(new_layout.size()) {
+                    Some(new_size) => new_size,
+                    None => {
+                        alloc_map().set(ptr.as_ptr() as usize / MIN_ALIGN);
+                        return None;
+                    }
                 };
-                alloc.mark();
-                Some(NonNull::new(alloc.user_ptr()).unwrap())
+
+                // Nothing to do if the size is unchanged (the original
+                // alignment is kept anyway). Read-only and watched
+                // allocations take the slow path to become writable.
+                if new_size == alloc.user_size && alloc.flags & (FLAG_READONLY | FLAG_WATCHED) == 0
+                {
+                    if new_layout.size() != alloc.requested_size {
+                        AllocInfo {
+                            requested_size: new_layout.size(),
+                            ..alloc
+                        }
+                        .update_meta();
+                    }
+                    alloc_map().set(ptr.as_ptr() as usize / MIN_ALIGN);
+                    return Some(ptr);
+                }
+
+                // Shrinking is allowed while frozen
+                if new_size > alloc.user_size && is_frozen(new_layout) {
+                    alloc_map().set(ptr.as_ptr() as usize / MIN_ALIGN);
+                    return None;
+                }
+
+                // Enforce `FATALLOC_MEMORY_LIMIT` on the growth only
+                let growth = new_size.saturating_sub(alloc.user_size);
+                if !stats::try_add_live(growth, config::get().memory_limit) {
+                    logger::report(logger::Event::MemoryLimit { layout: new_layout });
+                    alloc_map().set(ptr.as_ptr() as usize / MIN_ALIGN);
+                    return None;
+                }
+
+                alloc.make_writable();
+                // The old block is freed unless the reallocation fails
+                alloc.retire_meta();
+                let mut origin = registry::Origin::default();
+                if alloc.flags & FLAG_REGISTERED != 0 {
+                    // The allocation might move. Re-insert it as the newest one
+                    // afterwards.
+                    origin = registry::remove(registry_node(ptr));
+                }
+                // Verify that the backend preserves the contents
+                let preserved_len = alloc.requested_size.min(new_layout.size());
+                let old_hash = config::get().content_hash.then(|| {
+                    content_hash(core::slice::from_raw_parts(alloc.user_ptr(), preserved_len))
+                });
+                let generation = alloc.generation();
+                let (new_flags, new_origin) = reallocated_origin(&alloc, origin);
+                let new_ptr = self.reallocate_inner(
+                    &AllocInfo {
+                        flags: new_flags,
+                        ..alloc
+                    },
+                    new_layout.size(),
+                    new_size,
+                );
+                if let (Some(old_hash), Some(new_ptr)) = (old_hash, new_ptr) {
+                    let contents = core::slice::from_raw_parts(new_ptr.as_ptr(), preserved_len);
+                    if content_hash(contents) != old_hash {
+                        logger::report(logger::Event::ReallocMismatch {
+                            ptr: new_ptr.as_ptr(),
+                            len: preserved_len,
+                        });
+                    }
+                }
+                if let Some(new_ptr) = new_ptr.filter(|_| alloc.flags & FLAG_REGISTERED != 0) {
+                    registry::insert(registry_node(new_ptr), new_origin);
+                }
+                // The new allocation is single-threaded, too
+                if let Some(new_ptr) = new_ptr.filter(|_| generation & GENERATION_OWNER_MARK != 0) {
+                    if let Ok(new_alloc) = AllocInfo::from_user_ptr(new_ptr) {
+                        new_alloc.unprotect_meta();
+                        new_alloc.write_generation(generation);
+                        new_alloc.protect_meta();
+                    }
+                }
+                if new_ptr.is_none() {
+                    // The original allocation remains valid
+                    alloc_map().set(ptr.as_ptr() as usize / MIN_ALIGN);
+                    if alloc.flags & FLAG_REGISTERED != 0 {
+                        registry::insert(registry_node(ptr), origin);
+                    }
+                    stats::sub_live(growth);
+                    if alloc.flags & FLAG_READONLY != 0 {
+                        alloc.protect();
+                    }
+                    if alloc.flags & FLAG_WATCHED != 0 {
+                        let pages = alloc.watched_pages();
+                        let _ = watch::add(
+                            ptr.as_ptr() as usize,
+                            alloc.user_size,
+                            pages.start,
+                            pages.end,
+                        );
+                    }
+                    if config::get().metadata_free {
+                        // Undo `retire_meta`
+                        alloc.write_meta();
+                    }
+                    alloc.protect_meta();
+                } else {
+                    stats::sub_live(alloc.user_size.saturating_sub(new_size));
+                }
+                new_ptr
+            }
+            Err(_) if emergency::owns(ptr) => {
+                drop(_guard);
+                self.reallocate_emergency(ptr, new_layout)
+            }
+            #[cfg(feature = "backend-passthrough")]
+            Err(_) if passthrough::is_foreign(ptr) => {
+                drop(_guard);
+                self.reallocate_foreign(ptr, new_layout)
             }
             Err(e) => {
-                warn!("rejecting the reallocation request for {ptr:p}: {e}");
+                logger::report(logger::Event::InvalidRealloc {
+                    ptr: ptr.as_ptr(),
+                    reason: e,
+                });
+                // The caller may well assume the original allocation is still
+                // valid and keep using it
+                if config::get().realloc_error == config::ReallocErrorAction::Abort {
+                    libc::abort();
+                }
                 None
             }
         }
     }
+
+    /// Check that the backend's block under `alloc` is large enough for its
+    /// outer allocation, if the backend can tell. If it isn't, report it;
+    /// either the pointer doesn't point where it was allocated from, or the
+    /// backend's bookkeeping is corrupt, and releasing the block could only
+    /// make it worse.
+    unsafe fn has_consistent_block(&self, alloc: &AllocInfo) -> bool {
+        if directmap::is_direct(alloc.outer_layout()) {
+            return true;
+        }
+        match self.alloc.block_size(alloc.outer_ptr) {
+            Some(block_size) if block_size < alloc.outer_size() => {
+                logger::report(logger::Event::BlockSizeMismatch {
+                    ptr: alloc.user_ptr(),
+                    outer_ptr: alloc.outer_ptr.as_ptr(),
+                    size: alloc.outer_size(),
+                    block_size,
+                });
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Move the block `ptr` mapped while the allocator was reentered to a
+    /// tracked allocation.
+    unsafe fn reallocate_emergency(
+        &self,
+        ptr: NonNull<u8>,
+        new_layout: alloc::Layout,
+    ) -> Option<NonNull<u8>> {
+        let new_ptr = CAlloc::allocate(self, new_layout)?;
+        core::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.as_ptr(),
+            emergency::usable_size(ptr).min(new_layout.size()),
+        );
+        emergency::deallocate(ptr);
+        Some(new_ptr)
+    }
+
+    /// Move the foreign allocation `ptr` to a new allocation of ours, and
+    /// release it by the real `free`.
+    #[cfg(feature = "backend-passthrough")]
+    unsafe fn reallocate_foreign(
+        &self,
+        ptr: NonNull<u8>,
+        new_layout: alloc::Layout,
+    ) -> Option<NonNull<u8>> {
+        let old_size = match passthrough::usable_size(ptr) {
+            Some(old_size) => old_size,
+            None => {
+                warn!(
+                    "rejecting the reallocation request for the foreign allocation {ptr:p}: \
+                    the real `malloc_usable_size` is unavailable"
+                );
+                return None;
+            }
+        };
+        let new_ptr = CAlloc::allocate(self, new_layout)?;
+        core::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.as_ptr(),
+            old_size.min(new_layout.size()),
+        );
+        passthrough::deallocate(ptr);
+        Some(new_ptr)
+    }
+
+    /// Allocate memory. `backend_allocate` is `CAlloc::allocate` or
+    /// [`CAllocZeroed::allocate_zeroed`], whose returned flag is passed
+    /// through. `node` is the preferred NUMA node of a direct mapping.
+    fn allocate_inner(
+        &self,
+        layout: alloc::Layout,
+        node: Option<u32>,
+        backend_allocate: impl FnOnce(alloc::Layout) -> Option<(NonNull<u8>, bool)>,
+    ) -> Option<(NonNull<u8>, bool)> {
+        // Before the guard, as registering allocates
+        fork::register();
+        let _guard = match reentrancy::Guard::enter() {
+            Some(guard) => guard,
+            None => {
+                // Bypass tracking and the backend, either of which might be
+                // what we were interrupted in. The system allocator's lookup
+                // is expected to allocate.
+                if !backend::is_looking_up() {
+                    warn!("allocator reentered; mapping {layout:?} directly");
+                }
+                return emergency::allocate(layout).map(|ptr| (ptr, true));
+            }
+        };
+
+        selftest::poll();
+        if leakcheck::poll() {
+            self.report_old_allocations(config::get().leak_age);
+        }
+
+        // Fail before touching anything
+        if should_inject_failure() {
+            info!("injecting an allocation failure for {layout:?}");
+            return None;
+        }
+
+        let (alloc, is_zeroed) = self.allocate_unmarked(layout, node, backend_allocate)?;
+
+        // Write metadata to one of the margins
+        if let Err(e) = unsafe { self.mark(&alloc, config::get().max_leaves) } {
+            warn!("allocation denied: {} (requested {layout:?})", e.message());
+            if e == allocmap::SetError::AlreadyTracked {
+                // The block belongs to the live allocation
+                stats::sub_live(alloc.user_size);
+            } else {
+                unsafe { self.discard_unmarked(&alloc) };
+            }
+            return None;
+        }
+        self.finish_allocation(&alloc);
+
+        Some((NonNull::new(alloc.user_ptr()).unwrap(), is_zeroed))
+    }
+
+    /// Allocate memory whose pages are preferably placed on the NUMA node
+    /// `node`. The alignment is raised to two pages so that the allocation
+    /// is mapped directly, which is the only path a placement applies to.
+    fn allocate_on_node(&self, layout: alloc::Layout, node: u32) -> Option<NonNull<u8>> {
+        let layout = layout.align_to(page_size() * 2).ok()?;
+        self.allocate_inner(layout, Some(node), |layout| {
+            CAlloc::allocate(&self.alloc, layout).map(|ptr| (ptr, false))
+        })
+        .map(|(ptr, _)| ptr)
+    }
+
+    /// Allocate memory for secrets: its pages are locked in memory, and it's
+    /// zeroed when freed. The alignment is raised to a page so that the
+    /// locked pages aren't shared with other allocations, which could unlock
+    /// them. Fails if the pages can't be locked.
+    fn allocate_secure(&self, layout: alloc::Layout) -> Option<NonNull<u8>> {
+        let layout = layout.align_to(page_size()).ok()?;
+        let ptr = CAlloc::allocate(self, layout)?;
+        unsafe {
+            // An emergency block has no room for the flag, so it wouldn't be
+            // wiped. Looking it up would take the `AllocMap` lock the
+            // interrupted call might be holding.
+            let alloc = if emergency::owns(ptr) {
+                Err("made while the allocator was reentered")
+            } else {
+                AllocInfo::from_user_ptr(ptr)
+            };
+            let mut alloc = match alloc {
+                Ok(alloc) => alloc,
+                Err(e) => {
+                    warn!("allocation denied: can't secure {ptr:p}: {e} (requested {layout:?})");
+                    CAlloc::deallocate(self, ptr);
+                    return None;
+                }
+            };
+            if !set_locked(ptr.as_ptr(), alloc.user_size, true) {
+                warn!("allocation denied: mlock failed (requested {layout:?})");
+                CAlloc::deallocate(self, ptr);
+                return None;
+            }
+            alloc.flags |= FLAG_SECURE;
+            alloc.update_meta();
+        }
+        Some(ptr)
+    }
+
+    /// Allocate memory like [`CAlloc::allocate`] and assign it a new
+    /// generation, which is returned as the token
+    /// [`Self::deallocate_checked`] requires.
+    fn allocate_checked(&self, layout: alloc::Layout) -> Option<(NonNull<u8>, u64)> {
+        static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+        let ptr = CAlloc::allocate(self, layout)?;
+        unsafe {
+            // An emergency block has no room for the generation. Looking it
+            // up would take the `AllocMap` lock the interrupted call might
+            // be holding.
+            let alloc = if emergency::owns(ptr) {
+                None
+            } else {
+                AllocInfo::from_user_ptr(ptr).ok()
+            };
+            let alloc = match alloc {
+                Some(alloc) => alloc,
+                None => {
+                    CAlloc::deallocate(self, ptr);
+                    return None;
+                }
+            };
+            // `0` means none, so skip it when the counter wraps around
+            let generation = loop {
+                let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+                if generation != 0 {
+                    break generation;
+                }
+            };
+            alloc.unprotect_meta();
+            alloc.write_generation(generation);
+            alloc.protect_meta();
+            Some((ptr, generation as u64))
+        }
+    }
+
+    /// Deallocate `ptr` like [`CAlloc::deallocate`] if `token` is the one
+    /// [`Self::allocate_checked`] returned for it. Otherwise, it's reported
+    /// and left alone. Returns whether it was deallocated.
+    ///
+    /// The token is compared after the allocation is unmarked, so a
+    /// concurrent free and reuse of the address can't slip in between.
+    unsafe fn deallocate_checked(&self, ptr: NonNull<u8>, token: u64) -> bool {
+        let _guard = match reentrancy::Guard::enter() {
+            Some(guard) => guard,
+            None => {
+                warn!("allocator reentered; leaking {ptr:p}");
+                return false;
+            }
+        };
+
+        let alloc = match AllocInfo::from_user_ptr_and_unmark(ptr) {
+            Ok(alloc) => alloc,
+            Err(reason) => {
+                logger::report(logger::Event::InvalidFree {
+                    ptr: ptr.as_ptr(),
+                    reason,
+                });
+                return false;
+            }
+        };
+        let generation = alloc.generation();
+        if generation == 0 || generation as u64 != token {
+            alloc_map().set(ptr.as_ptr() as usize / MIN_ALIGN);
+            logger::report(logger::Event::InvalidFree {
+                ptr: ptr.as_ptr(),
+                reason: "the token doesn't match the allocation",
+            });
+            return false;
+        }
+        self.deallocate_unmarked(ptr, &alloc);
+        true
+    }
+
+    /// Make `out.len()` allocations of `layout`, write their pointers to
+    /// `out`, and return the number of successful allocations, which are at
+    /// the front of `out`. All allocations are marked by one `AllocMap`
+    /// operation.
+    ///
+    /// None of the pointers is returned before it's marked, so it can be
+    /// freed (by any thread) as soon as this returns.
+    fn allocate_batch(&self, layout: alloc::Layout, out: &mut [*mut u8]) -> usize {
+        fork::register();
+        let _guard = match reentrancy::Guard::enter() {
+            Some(guard) => guard,
+            None => {
+                warn!("allocator reentered; allocating {layout:?} untracked");
+                return 0;
+            }
+        };
+
+        selftest::poll();
+        if leakcheck::poll() {
+            self.report_old_allocations(config::get().leak_age);
+        }
+
+        // Stop at the first failure, leaving the rest of `out` untouched
+        let mut num_allocated = 0;
+        for slot in out.iter_mut() {
+            if should_inject_failure() {
+                info!("injecting an allocation failure for {layout:?}");
+                break;
+            }
+            match self.allocate_unmarked(layout, None, |layout| {
+                CAlloc::allocate(&self.alloc, layout).map(|ptr| (ptr, false))
+            }) {
+                Some((alloc, _)) => *slot = alloc.user_ptr(),
+                None => break,
+            }
+            num_allocated += 1;
+        }
+        let allocated = Cell::from_mut(&mut out[..num_allocated]).as_slice_of_cells();
+
+        // `out` is the only room for the batch, so the allocations are read
+        // back from the metadata `allocate_unmarked` has just written
+        unsafe fn read_new_allocation(ptr: *mut u8) -> AllocInfo {
+            AllocInfo::from_user_ptr_unchecked(NonNull::new_unchecked(ptr)).unwrap()
+        }
+
+        // A block handed out twice in the batch is only returned once. Its
+        // metadata, written again for the same layout, is unchanged.
+        let mark_all = |ptrs: &[Cell<*mut u8>]| {
+            alloc_map().set_many(
+                ptrs.iter().map(|ptr| ptr.get() as usize / MIN_ALIGN),
+                config::get().max_leaves,
+                |i| {
+                    logger::report(logger::Event::AlreadyTracked { ptr: ptrs[i].get() });
+                    stats::sub_live(unsafe { read_new_allocation(ptrs[i].get()) }.user_size);
+                    ptrs[i].set(ptr::null_mut());
+                },
+            )
+        };
+        let result = match mark_all(allocated) {
+            Err((num_marked, allocmap::SetError::OutOfMemory)) => {
+                for outer_ptr in quarantine::drain() {
+                    unsafe { CAlloc::deallocate(&self.alloc, outer_ptr) };
+                }
+                mark_all(&allocated[num_marked..])
+                    .map_err(|(num_marked2, e)| (num_marked + num_marked2, e))
+            }
+            result => result,
+        };
+        let num_marked = match result {
+            Ok(()) => num_allocated,
+            Err((num_marked, e)) => {
+                warn!(
+                    "allocation denied: {} (requested {} of {layout:?})",
+                    e.message(),
+                    num_allocated - num_marked,
+                );
+                // Free the unmarked ones, nulling them so that the caller
+                // never sees their freed pointers
+                for ptr in &allocated[num_marked..] {
+                    unsafe { self.discard_unmarked(&read_new_allocation(ptr.get())) };
+                    ptr.set(ptr::null_mut());
+                }
+                num_marked
+            }
+        };
+
+        // Move the returned ones to the front
+        let mut num_returned = 0;
+        for i in 0..num_marked {
+            let ptr = allocated[i].get();
+            if !ptr.is_null() {
+                self.finish_allocation(&unsafe { read_new_allocation(ptr) });
+                allocated[i].set(ptr::null_mut());
+                allocated[num_returned].set(ptr);
+                num_returned += 1;
+            }
+        }
+        num_returned
+    }
+
+    /// Deallocate every non-null pointer in `ptrs` like
+    /// [`CAlloc::deallocate`]. The allocations are unmarked by one `AllocMap`
+    /// operation per `usize::BITS` pointers. Each pointer is still validated
+    /// on its own, so a pointer appearing twice is rejected the second time.
+    unsafe fn deallocate_batch(&self, ptrs: &[*mut u8]) {
+        let _guard = match reentrancy::Guard::enter() {
+            Some(guard) => guard,
+            None => {
+                // Each is leaked or returned to the emergency allocator
+                for ptr in ptrs.iter().filter_map(|&ptr| NonNull::new(ptr)) {
+                    CAlloc::deallocate(self, ptr);
+                }
+                return;
+            }
+        };
+
+        let is_aligned = |ptr: *mut u8| ptr as usize % MIN_ALIGN == 0;
+        for chunk in ptrs.chunks(usize::BITS as usize) {
+            // Bit `k` is set if the `k`-th aligned non-null pointer was marked
+            let mut cleared = 0usize;
+            alloc_map().test_and_clear_many(
+                chunk
+                    .iter()
+                    .filter(|&&ptr| !ptr.is_null() && is_aligned(ptr))
+                    .map(|&ptr| ptr as usize / MIN_ALIGN),
+                |k| cleared |= 1 << k,
+            );
+
+            let mut k = 0;
+            for ptr in chunk.iter().filter_map(|&ptr| NonNull::new(ptr)) {
+                if !is_aligned(ptr.as_ptr()) {
+                    self.reject_deallocation(ptr, "misaligned");
+                    continue;
+                }
+                let was_marked = cleared & (1 << k) != 0;
+                k += 1;
+                if !was_marked {
+                    if !self.deallocate_by_metadata(ptr) {
+                        self.reject_deallocation(ptr, "not a known valid allocation");
+                    }
+                    continue;
+                }
+                match AllocInfo::from_user_ptr_unchecked(ptr) {
+                    Ok(alloc) => self.deallocate_unmarked(ptr, &alloc),
+                    Err(e) => self.reject_deallocation(ptr, e),
+                }
+            }
+        }
+    }
+
+    /// Deallocate `ptr` like [`CAlloc::deallocate`], reporting it first if
+    /// `size` is neither the requested size nor up to the usable size, which
+    /// a sized deallocation (`sdallocx`) must pass. The allocation is freed
+    /// anyway, as its real size is known, unless `FATALLOC_STRICT_SIZED_FREE`
+    /// leaves it allocated.
+    unsafe fn deallocate_sized(
+        &self,
+        ptr: NonNull<u8>,
+        size: usize,
+        #[cfg(feature = "pointer-tag")] tag: u8,
+    ) {
+        // Leave reporting an invalid or corrupted allocation to `deallocate`
+        if let Some((requested_size, user_size, _)) = AllocInfo::peek_layout(ptr) {
+            if !(requested_size..=user_size).contains(&size) {
+                let refused = config::get().strict_sized_free;
+                logger::report(logger::Event::SizedFreeMismatch {
+                    ptr: ptr.as_ptr(),
+                    size,
+                    allocated_size: requested_size,
+                    refused,
+                });
+                if refused {
+                    return;
+                }
+            }
+        }
+        self.deallocate_tagged(
+            ptr,
+            #[cfg(feature = "pointer-tag")]
+            tag,
+        );
+    }
+
+    /// The rest of [`CAlloc::deallocate`] once `alloc` was unmarked
+    unsafe fn deallocate_unmarked(&self, ptr: NonNull<u8>, alloc: &AllocInfo) {
+        stats::sub_live(alloc.user_size);
+        stats::sub_overhead(alloc.overhead());
+        stats::count_deallocation();
+        check_owner(ptr, "free");
+        if alloc.flags & FLAG_UNWRITTEN != 0 && watch::remove_unwritten(ptr.as_ptr() as usize) {
+            warn!(
+                "freeing {ptr:p}: allocated but never written {} bytes",
+                alloc.requested_size
+            );
+        }
+        alloc.make_writable();
+        alloc.retire_meta();
+        if alloc.flags & FLAG_REGISTERED != 0 {
+            registry::remove(registry_node(ptr));
+        }
+        if config::get().checks & config::CHECK_NEIGHBOR != 0 && sample::should_check() {
+            alloc.check_neighbor();
+        }
+        if config::get().content_hash {
+            let contents = core::slice::from_raw_parts(alloc.user_ptr(), alloc.requested_size);
+            info!(
+                "deallocate size={} ptr={ptr:p} hash={:016x}",
+                alloc.requested_size,
+                content_hash(contents),
+            );
+        }
+        if alloc.flags & FLAG_PINNED != 0 {
+            // Keep it until `unpin_allocation`
+            alloc.user_ptr().write_bytes(FREE_FILL, alloc.user_size);
+            pinned::insert(quarantine_node(ptr));
+            info!("retaining the freed pinned allocation {ptr:p}");
+            return;
+        }
+        if !self.has_consistent_block(alloc) {
+            // Leaked, but not with the secrets in it
+            alloc.wipe_if_secure();
+            return;
+        }
+        self.release(alloc);
+    }
+
+    /// Free `ptr`, which the `AllocMap` misses, by its metadata alone if
+    /// `FATALLOC_METADATA_FREE` is enabled and it passes every check. Returns
+    /// `false` if it's not freed.
+    unsafe fn deallocate_by_metadata(&self, ptr: NonNull<u8>) -> bool {
+        if !config::get().metadata_free {
+            return false;
+        }
+        match AllocInfo::from_metadata_and_claim(ptr) {
+            Some(alloc) => {
+                info!("freeing {ptr:p}, which the allocation map misses, by its metadata");
+                self.deallocate_unmarked(ptr, &alloc);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The rest of [`CAlloc::deallocate`] for a pointer that isn't one of
+    /// our live allocations for the reason `e`
+    unsafe fn reject_deallocation(&self, ptr: NonNull<u8>, e: &'static str) {
+        if emergency::owns(ptr) {
+            emergency::deallocate(ptr);
+        } else if stack::contains(ptr.as_ptr()) {
+            // Checked before the system allocator could take it for its own
+            logger::report(logger::Event::StackFree { ptr: ptr.as_ptr() });
+        } else {
+            #[cfg(feature = "backend-passthrough")]
+            if passthrough::is_foreign(ptr) {
+                passthrough::deallocate(ptr);
+                return;
+            }
+            report_invalid_free(ptr, e);
+        }
+    }
+
+    /// Make an outer allocation for `layout` and write its metadata, leaving
+    /// it unmarked. The returned allocation must be either marked and passed
+    /// to [`Self::finish_allocation`], or passed to
+    /// [`Self::discard_unmarked`].
+    fn allocate_unmarked(
+        &self,
+        layout: alloc::Layout,
+        node: Option<u32>,
+        backend_allocate: impl FnOnce(alloc::Layout) -> Option<(NonNull<u8>, bool)>,
+    ) -> Option<(AllocInfo, bool)> {
+        // Apply `FATALLOC_MIN_ALIGN` and `FATALLOC_ROUND_SIZES`
+        let requested_size = layout.size();
+        let layout = layout.align_to(config::get().min_align).ok()?;
+        let layout =
+            alloc::Layout::from_size_align(user_size_for(layout.size())?, layout.align()).ok()?;
+
+        // Add margins
+        let (outer_layout, margin) = outer_layout_and_margin(layout)?;
+
+        if is_frozen(layout) {
+            return None;
+        }
+
+        // Enforce `FATALLOC_MEMORY_LIMIT`
+        if !stats::try_add_live(layout.size(), config::get().memory_limit) {
+            logger::report(logger::Event::MemoryLimit { layout });
+            return None;
+        }
+
+        // Allocate memory. Fresh mappings are zero-filled.
+        let allocated = if directmap::is_direct(outer_layout) {
+            directmap::map(outer_layout, node).map(|outer_ptr| (outer_ptr, true))
+        } else {
+            backend_allocate(outer_layout)
+        };
+        let (outer_ptr, is_zeroed) = match allocated {
+            Some((outer_ptr, is_zeroed))
+                if check_backend_ptr(outer_ptr, outer_layout).is_some() =>
+            {
+                (outer_ptr, is_zeroed)
+            }
+            _ => {
+                stats::sub_live(layout.size());
+                return None;
+            }
+        };
+        let user_size = unsafe {
+            self.granted_size(
+                outer_ptr,
+                outer_layout,
+                margin,
+                layout.size(),
+                requested_size,
+            )
+        };
+        stats::try_add_live(user_size - layout.size(), usize::MAX);
+        let mut alloc = AllocInfo {
+            margin,
+            outer_ptr,
+            user_size,
+            requested_size,
+            align: layout.align(),
+            flags: if config::get().registry {
+                FLAG_REGISTERED
+            } else {
+                0
+            },
+            poisoned_tail: 0,
+        };
+
+        unsafe {
+            alloc.poison_padding();
+            alloc.prepare_mark();
+        }
+        Some((alloc, is_zeroed))
+    }
+
+    /// Get the size to grant an allocation of `size` bytes (`requested_size`
+    /// before rounding) in the fresh outer block `outer_ptr` of
+    /// `outer_layout`: with `FATALLOC_ROUND_SIZES`, the block's whole usable
+    /// size minus the margins if the backend can tell, so that the trailing
+    /// margin starts where the backend's block ends. The metadata can't
+    /// record more than `MAX_SLACK` bytes of slack, though.
+    unsafe fn granted_size(
+        &self,
+        outer_ptr: NonNull<u8>,
+        outer_layout: alloc::Layout,
+        margin: usize,
+        size: usize,
+        requested_size: usize,
+    ) -> usize {
+        if !config::get().round_sizes || directmap::is_direct(outer_layout) {
+            return size;
+        }
+        match self.alloc.block_size(outer_ptr) {
+            Some(block_size) if block_size > outer_layout.size() => {
+                (block_size - margin * 2).min(requested_size.saturating_add(MAX_SLACK))
+            }
+            _ => size,
+        }
+    }
+
+    /// Release an allocation made by [`Self::allocate_unmarked`] that failed
+    /// to be marked.
+    unsafe fn discard_unmarked(&self, alloc: &AllocInfo) {
+        alloc.retire_meta();
+        self.deallocate_outer(alloc.outer_ptr, alloc.outer_layout());
+        stats::sub_live(alloc.user_size);
+    }
+
+    /// Account for, protect, and log a newly marked allocation.
+    fn finish_allocation(&self, alloc: &AllocInfo) {
+        stats::add_overhead(alloc.overhead());
+        stats::count_allocation();
+        unsafe { alloc.protect_meta() };
+        if alloc.flags & FLAG_REGISTERED != 0 {
+            unsafe {
+                registry::insert(
+                    registry_node(NonNull::new_unchecked(alloc.user_ptr())),
+                    registry::Origin {
+                        time: registry::now(),
+                        #[cfg(feature = "backtrace")]
+                        site: allocation_site(),
+                    },
+                )
+            };
+        }
+
+        let outer_layout = alloc.outer_layout();
+        info!(
+            "allocate size={} align={} outer_size={} outer_align={} margin={} ptr={:p}",
+            alloc.user_size,
+            alloc.align,
+            outer_layout.size(),
+            outer_layout.align(),
+            alloc.margin,
+            alloc.user_ptr(),
+        );
+    }
+
+    /// Get the requested and granted sizes of the live allocation at `ptr`.
+    unsafe fn allocation_sizes(&self, ptr: NonNull<u8>) -> Result<(usize, usize), &'static str> {
+        let alloc = AllocInfo::from_user_ptr(ptr)?;
+        Ok((alloc.requested_size, alloc.user_size))
+    }
+
+    /// Check if `ptr` is a live allocation made by `self`, without reporting
+    /// anything. Only the allocation bitmap is consulted (under the read
+    /// lock) unless `ptr` is found there, in which case the metadata is
+    /// validated too.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must not be freed concurrently.
+    pub unsafe fn owns(&self, ptr: NonNull<u8>) -> bool {
+        let addr = ptr.as_ptr() as usize;
+        addr % MIN_ALIGN == 0
+            && alloc_map().get(addr / MIN_ALIGN)
+            && AllocInfo::has_intact_metadata(ptr)
+    }
+
+    /// Get the outer allocation and the margin of the live allocation at
+    /// `ptr`.
+    unsafe fn outer_pointer(&self, ptr: NonNull<u8>) -> Result<(NonNull<u8>, usize), &'static str> {
+        let alloc = AllocInfo::from_user_ptr(ptr)?;
+        Ok((alloc.outer_ptr, alloc.margin))
+    }
+
+    /// Check the allocation at `ptr` for corruption without logging.
+    unsafe fn verify(&self, ptr: NonNull<u8>) -> Result<(), CorruptionKind> {
+        AllocInfo::verify(ptr)?;
+        check_owner(ptr, "verify");
+        Ok(())
+    }
+
+    /// Record the calling thread as the owner of the live allocation at
+    /// `ptr`, so that [`check_owner`] reports other threads.
+    unsafe fn mark_single_threaded(&self, ptr: NonNull<u8>) -> Result<(), &'static str> {
+        let alloc = AllocInfo::from_user_ptr(ptr)?;
+        let generation = alloc.generation();
+        if generation != 0 && generation & GENERATION_OWNER_MARK == 0 {
+            return Err("it has a token from fatalloc_malloc_checked");
+        }
+        alloc.unprotect_meta();
+        alloc.write_generation(GENERATION_OWNER_MARK | current_thread_id());
+        alloc.protect_meta();
+        Ok(())
+    }
+
+    /// Make the live allocation at `ptr` retained forever when it's freed.
+    unsafe fn pin_allocation(&self, ptr: NonNull<u8>) -> Result<(), &'static str> {
+        let mut alloc = AllocInfo::from_user_ptr(ptr)?;
+        if alloc.flags & FLAG_READONLY != 0 {
+            return Err("read-only");
+        }
+        alloc.flags |= FLAG_PINNED;
+        alloc.update_meta();
+        Ok(())
+    }
+
+    /// Undo [`Self::pin_allocation`]. If the allocation has already been
+    /// freed, check it for use-after-free and release it.
+    unsafe fn unpin_allocation(&self, ptr: NonNull<u8>) -> Result<(), &'static str> {
+        if ptr.as_ptr() as usize % MIN_ALIGN != 0 {
+            return Err("misaligned");
+        }
+        let is_live = alloc_map().get(ptr.as_ptr() as usize / MIN_ALIGN);
+
+        // A freed one must be retained to be trusted, let alone read
+        if !is_live && !pinned::remove(quarantine_node(ptr)) {
+            return Err("not a known valid allocation");
+        }
+        let mut alloc = if is_live {
+            AllocInfo::from_user_ptr(ptr)?
+        } else {
+            AllocInfo::from_user_ptr_unchecked(ptr)?
+        };
+        if alloc.flags & FLAG_PINNED == 0 {
+            return Err("not pinned");
+        }
+        if is_live && alloc.flags & FLAG_READONLY != 0 {
+            // The protection is lifted when it's freed
+            return Err("read-only");
+        }
+        alloc.flags &= !FLAG_PINNED;
+        if is_live {
+            alloc.update_meta();
+        } else {
+            // Freed, and thus writable
+            alloc.write_meta();
+        }
+
+        if !is_live {
+            let user = core::slice::from_raw_parts(alloc.user_ptr(), alloc.user_size);
+            if let Some(offset) = user.iter().position(|&b| b != FREE_FILL) {
+                logger::report(logger::Event::UseAfterFree {
+                    ptr: ptr.as_ptr(),
+                    offset,
+                });
+            }
+            self.release(&alloc);
+        }
+
+        Ok(())
+    }
+
+    /// Write-protect the user region of the new allocation at `ptr` to see
+    /// whether it's written before it's freed (`FATALLOC_DETECT_UNWRITTEN`).
+    /// Only a page-aligned allocation not sharing its last page with another
+    /// allocation can be tracked, and only so many at once.
+    unsafe fn track_unwritten(&self, ptr: NonNull<u8>) {
+        let mut alloc = match AllocInfo::from_user_ptr(ptr) {
+            Ok(alloc) => alloc,
+            // An emergency block
+            Err(_) => return,
+        };
+        let pages = match alloc.unwritten_pages() {
+            Some(pages) => pages,
+            None => return,
+        };
+        alloc.flags |= FLAG_UNWRITTEN;
+        alloc.update_meta();
+        if let Err(e) = watch::add_unwritten(
+            ptr.as_ptr() as usize,
+            alloc.user_size,
+            pages.start,
+            pages.end,
+        ) {
+            info!("not tracking whether {ptr:p} is written: {e}");
+            alloc.flags &= !FLAG_UNWRITTEN;
+            alloc.update_meta();
+        }
+    }
+
+    /// Write-protect the pages of the live allocation at `ptr` until it's
+    /// freed or reallocated. Only the whole pages inside the outer
+    /// allocation are protected.
+    unsafe fn make_readonly(&self, ptr: NonNull<u8>) -> Result<(), &'static str> {
+        let mut alloc = AllocInfo::from_user_ptr(ptr)?;
+        if alloc.flags & FLAG_READONLY != 0 {
+            return Ok(());
+        }
+        if alloc.flags & FLAG_WATCHED != 0 {
+            return Err("the allocation is watched");
+        }
+        // Its first write would make it writable
+        if alloc.flags & FLAG_UNWRITTEN != 0 {
+            watch::remove_unwritten(ptr.as_ptr() as usize);
+        }
+
+        let pages = alloc.inner_pages();
+        let user_start = alloc.user_ptr() as usize;
+        if pages.end <= user_start || pages.start >= user_start + alloc.user_size {
+            return Err("no whole pages to protect");
+        }
+        if pages.start > user_start || pages.end < user_start + alloc.user_size {
+            info!("{ptr:p} is only partially write-protected because it's not page-aligned");
+        }
+
+        alloc.flags |= FLAG_READONLY;
+        alloc.update_meta();
+        if !alloc.protect() {
+            alloc.flags &= !FLAG_READONLY;
+            alloc.update_meta();
+            return Err("mprotect failed");
+        }
+        Ok(())
+    }
+
+    /// Report every access to the live allocation at `ptr` until it's
+    /// unwatched, freed, or reallocated. Only the whole pages inside the outer
+    /// allocation are watched.
+    unsafe fn watch(&self, ptr: NonNull<u8>) -> Result<(), &'static str> {
+        let mut alloc = AllocInfo::from_user_ptr(ptr)?;
+        if alloc.flags & FLAG_WATCHED != 0 {
+            return Ok(());
+        }
+        if alloc.flags & FLAG_READONLY != 0 {
+            return Err("the allocation is read-only");
+        }
+        if alloc.flags & FLAG_UNWRITTEN != 0 {
+            watch::remove_unwritten(ptr.as_ptr() as usize);
+        }
+
+        let pages = alloc.watched_pages();
+        let user_start = alloc.user_ptr() as usize;
+        if pages.is_empty() {
+            return Err("no whole pages to watch");
+        }
+        if pages.start > user_start || pages.end < user_start + alloc.user_size {
+            info!("{ptr:p} is only partially watched because it's not page-aligned");
+        }
+
+        alloc.flags |= FLAG_WATCHED;
+        alloc.update_meta();
+        if let Err(e) = watch::add(user_start, alloc.user_size, pages.start, pages.end) {
+            alloc.flags &= !FLAG_WATCHED;
+            alloc.update_meta();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Stop reporting accesses to the live allocation at `ptr`.
+    unsafe fn unwatch(&self, ptr: NonNull<u8>) -> Result<(), &'static str> {
+        let mut alloc = AllocInfo::from_user_ptr(ptr)?;
+        if alloc.flags & FLAG_WATCHED == 0 {
+            return Err("not watched");
+        }
+        watch::remove(ptr.as_ptr() as usize);
+        alloc.flags &= !FLAG_WATCHED;
+        alloc.update_meta();
+        Ok(())
+    }
+
+    /// Write up to `out.len()` pointers of live allocations in the
+    /// allocation order (reversed if `newest_first` is set) to `out`, and
+    /// return the number of written pointers. Only allocations made with
+    /// `FATALLOC_REGISTRY` are included.
+    fn registered_allocations(&self, out: &mut [*mut u8], newest_first: bool) -> usize {
+        let mut len = 0;
+        registry::for_each(newest_first, |node, _| {
+            if len == out.len() {
+                return false;
+            }
+            out[len] = user_ptr_of_registry_node(node).as_ptr();
+            len += 1;
+            true
+        });
+        len
+    }
+
+    /// Report live registered allocations made at least `min_age` seconds
+    /// ago as possible leaks. Returns the number of reported allocations.
+    fn report_old_allocations(&self, min_age: usize) -> usize {
+        let now = registry::now();
+        let mut count = 0;
+        registry::for_each(false, |node, origin| {
+            let age = now.saturating_sub(origin.time);
+            if age >= min_age {
+                let ptr = user_ptr_of_registry_node(node);
+                logger::report(logger::Event::PossibleLeak {
+                    ptr: ptr.as_ptr(),
+                    size: unsafe { meta::read_field(ptr, meta::FIELD_SIZE) },
+                    age,
+                    #[cfg(feature = "backtrace")]
+                    site: config::get().leak_sites.then(|| logger::LeakSite {
+                        frames: backtrace::Backtrace::from_site(&origin.site),
+                        reallocated: unsafe { meta::read_field(ptr, meta::FIELD_FLAGS) }
+                            & FLAG_REALLOC_SITE
+                            != 0,
+                    }),
+                });
+                count += 1;
+            }
+            true
+        });
+        count
+    }
+
+    /// Write the call stacks of live registered allocations, each with the
+    /// total size of the allocations made there, to `FATALLOC_FOLDED_FILE`.
+    /// Returns `false` if it's unset or writing failed.
+    #[cfg(feature = "backtrace")]
+    fn export_folded(&self) -> bool {
+        folded::export(|f| {
+            registry::for_each(false, |node, origin| {
+                let ptr = user_ptr_of_registry_node(node);
+                f(&origin.site, unsafe {
+                    meta::read_field(ptr, meta::FIELD_SIZE)
+                });
+                true
+            })
+        })
+    }
+
+    /// Set the pointer tag of the live allocation at `ptr`.
+    #[cfg(feature = "pointer-tag")]
+    unsafe fn set_tag(&self, ptr: NonNull<u8>, tag: u8) -> Result<(), &'static str> {
+        let mut alloc = AllocInfo::from_user_ptr(ptr)?;
+        if alloc.flags & FLAG_READONLY != 0 {
+            return Err("read-only");
+        }
+        alloc.flags = alloc.flags & !(0xff << FLAG_TAG_SHIFT) | (tag as usize) << FLAG_TAG_SHIFT;
+        alloc.update_meta();
+        Ok(())
+    }
+
+    /// Get the pointer tag of the live allocation at `ptr`.
+    #[cfg(feature = "pointer-tag")]
+    unsafe fn tag(&self, ptr: NonNull<u8>) -> Result<u8, &'static str> {
+        // This takes the `AllocMap` lock, so a signal handler interrupting
+        // it must see the allocator as reentered
+        let _guard = reentrancy::Guard::enter().ok_or("allocator reentered")?;
+
+        // Don't use `AllocInfo::from_user_ptr`, which would report a broken
+        // canary before the actual request does
+        if ptr.as_ptr() as usize % MIN_ALIGN != 0
+            || !alloc_map().get(ptr.as_ptr() as usize / MIN_ALIGN)
+            || !AllocInfo::has_intact_metadata(ptr)
+        {
+            return Err("not a known valid allocation");
+        }
+        Ok((meta::read_field(ptr, meta::FIELD_FLAGS) >> FLAG_TAG_SHIFT) as u8)
+    }
+
+    /// Call `f` with the pointer and size of every live allocation whose
+    /// pointer falls in `range`, in the address order.
+    ///
+    /// This doesn't lock out concurrent deallocations, so `f` may see an
+    /// allocation being freed. `f` may allocate or deallocate memory.
+    fn allocations_in_range(
+        &self,
+        range: ops::Range<usize>,
+        mut f: impl FnMut(NonNull<u8>, usize),
+    ) {
+        let end_i = (range.end / MIN_ALIGN).saturating_add((range.end % MIN_ALIGN != 0) as usize);
+        let mut i =
+            (range.start / MIN_ALIGN).saturating_add((range.start % MIN_ALIGN != 0) as usize);
+        while let Some(found_i) = alloc_map().find_next(i..end_i) {
+            let ptr = unsafe { NonNull::new_unchecked((found_i * MIN_ALIGN) as *mut u8) };
+            // Skip ones whose metadata is unreliable without reporting; this
+            // might be called in an awkward situation such as a crash handler
+            if unsafe { AllocInfo::has_intact_metadata(ptr) } {
+                f(ptr, unsafe { meta::read_field(ptr, meta::FIELD_SIZE) });
+            }
+            i = found_i + 1;
+        }
+    }
+
+    /// Verify that no two live allocations, including their margins, overlap,
+    /// and report every collision. Returns the number of collisions.
+    ///
+    /// This walks the whole allocation bitmap. The allocations are visited in
+    /// the address order, so each is only compared to the one reaching the
+    /// furthest among the preceding ones. Concurrent allocations and
+    /// deallocations may be missed but don't cause false positives.
+    fn check_consistency(&self) -> usize {
+        let mut num_collisions = 0;
+        // The preceding allocation whose outer allocation ends last
+        let mut furthest: Option<(NonNull<u8>, usize)> = None;
+        self.allocations_in_range(0..usize::MAX, |ptr, size| {
+            let margin = unsafe { meta::read_field(ptr, meta::FIELD_MARGIN) };
+            let start = (ptr.as_ptr() as usize).wrapping_sub(margin);
+            let end = (ptr.as_ptr() as usize)
+                .saturating_add(size)
+                .saturating_add(margin);
+            match furthest {
+                Some((other, other_end)) if start < other_end => {
+                    logger::report(logger::Event::OverlappingAllocations {
+                        ptr: ptr.as_ptr(),
+                        other: other.as_ptr(),
+                    });
+                    num_collisions += 1;
+                    if end > other_end {
+                        furthest = Some((ptr, end));
+                    }
+                }
+                _ => furthest = Some((ptr, end)),
+            }
+        });
+        num_collisions
+    }
+
+    /// Return the outer allocation of the unmarked allocation `alloc` to the
+    /// backend by way of the quarantine.
+    unsafe fn release(&self, alloc: &AllocInfo) {
+        alloc.wipe_if_secure();
+        if config::get().identity_fill {
+            alloc.fill_identity();
+        }
+
+        // The quarantine couldn't tell a direct mapping from a backend
+        // allocation on eviction, so unmap it right away
+        if directmap::is_direct(alloc.outer_layout()) {
+            directmap::unmap(alloc.outer_ptr, alloc.outer_layout());
+            return;
+        }
+
+        let config = config::get();
+        // This must precede the insertion, after which the block may be
+        // evicted and reused at any time
+        if config.quarantine_madv_free
+            && quarantine::admits(alloc.outer_size(), config.quarantine_bytes)
+        {
+            alloc.advise_free();
+        }
+        let evicted = quarantine::insert(
+            quarantine_node(NonNull::new_unchecked(alloc.user_ptr())),
+            alloc.outer_ptr,
+            alloc.outer_size(),
+            config.quarantine_bytes,
+            #[cfg(feature = "backtrace")]
+            if config.free_backtrace {
+                backtrace::Backtrace::capture(1)
+            } else {
+                backtrace::Backtrace::default()
+            },
+        );
+        for outer_ptr in evicted {
+            CAlloc::deallocate(&self.alloc, outer_ptr);
+        }
+    }
+
+    /// [`AllocInfo::mark`], but on memory exhaustion, flush the quarantine to
+    /// free memory and retry once.
+    unsafe fn mark(&self, alloc: &AllocInfo, max_leaves: usize) -> Result<(), allocmap::SetError> {
+        match alloc.mark(max_leaves) {
+            Err(allocmap::SetError::OutOfMemory) => {
+                for outer_ptr in quarantine::drain() {
+                    CAlloc::deallocate(&self.alloc, outer_ptr);
+                }
+                alloc.mark(max_leaves)
+            }
+            result => result,
+        }
+    }
+
+    /// Make an outer allocation, mapping it directly if it's aligned to more
+    /// than a page.
+    fn allocate_outer(&self, layout: alloc::Layout) -> Option<NonNull<u8>> {
+        if directmap::is_direct(layout) {
+            directmap::map(layout, None)
+        } else {
+            CAlloc::allocate(&self.alloc, layout)
+        }
+    }
+
+    /// Free an outer allocation made by [`Self::allocate_outer`] or the
+    /// backend for `layout`.
+    unsafe fn deallocate_outer(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        if directmap::is_direct(layout) {
+            directmap::unmap(ptr, layout);
+        } else {
+            CAlloc::deallocate(&self.alloc, ptr);
+        }
+    }
+
+    /// Move or resize the allocation `alloc`, which has already been unmarked,
+    /// to `requested_size` bytes, granting `new_size` bytes.
+    unsafe fn reallocate_inner(
+        &self,
+        alloc: &AllocInfo,
+        requested_size: usize,
+        new_size: usize,
+    ) -> Option<NonNull<u8>> {
+        // `realloc` can't change the alignment, so reuse the original one.
+        // The program might still rely on it, so the margin isn't reduced
+        // even though `realloc` only has to return natural alignment.
+        let new_layout = alloc::Layout::from_size_align(new_size, alloc.align).ok()?;
+        let (new_outer_layout, new_margin) = outer_layout_and_margin(new_layout)?;
+
+        // The part of the poisoned tail re-exposed by growing must be intact
+        let reexposed_len = new_size.min(alloc.user_size + alloc.poisoned_tail);
+        let reexposed_len =
+            if config::get().checks & config::CHECK_REDZONE != 0 && sample::should_check() {
+                reexposed_len.saturating_sub(alloc.user_size)
+            } else {
+                0
+            };
+        let reexposed = core::slice::from_raw_parts(
+            alloc.user_ptr().wrapping_add(alloc.user_size),
+            reexposed_len,
+        );
+        if let Some(i) = reexposed.iter().position(|&b| b != FREE_FILL) {
+            logger::report(logger::Event::ShrunkTailOverrun {
+                ptr: alloc.user_ptr(),
+                offset: alloc.user_size + i,
+            });
+        }
+
+        let mut poisoned_tail = 0;
+        let mut granted_size = new_size;
+        // The backend wouldn't wipe the original block of a secure allocation
+        let new_outer_ptr = if new_margin == alloc.margin
+            && !directmap::is_direct(new_outer_layout)
+            && alloc.flags & FLAG_SECURE == 0
+        {
+            let new_outer_ptr = CAlloc::reallocate(&self.alloc, alloc.outer_ptr, new_outer_layout)?;
+            if check_backend_ptr(new_outer_ptr, new_outer_layout).is_none() {
+                // The original allocation is gone, so we can neither fail
+                // the request nor use the new one
+                libc::abort();
+            }
+
+            // The trailing margin is preserved, so is the poison in it.
+            // Poison the part released by shrinking, too, if any.
+            let new_user_ptr = new_outer_ptr.as_ptr().wrapping_add(new_margin);
+            let old_tail_end = alloc.user_size + alloc.poisoned_tail;
+            let released_end = alloc.user_size.min(new_size + new_margin);
+            if released_end > new_size {
+                new_user_ptr
+                    .wrapping_add(new_size)
+                    .write_bytes(FREE_FILL, released_end - new_size);
+            }
+            poisoned_tail = old_tail_end.saturating_sub(new_size).min(new_margin);
+
+            new_outer_ptr
+        } else {
+            // The user region moves relative to the outer allocation (or
+            // it's directly mapped), so `CAlloc::reallocate` wouldn't
+            // preserve its contents
+            let new_outer_ptr = self.allocate_outer(new_outer_layout)?;
+            check_backend_ptr(new_outer_ptr, new_outer_layout)?;
+            granted_size = self.granted_size(
+                new_outer_ptr,
+                new_outer_layout,
+                new_margin,
+                new_size,
+                requested_size,
+            );
+            let new_user_ptr = new_outer_ptr.as_ptr().wrapping_add(new_margin);
+            if alloc.flags & FLAG_SECURE != 0 && !set_locked(new_user_ptr, new_size, true) {
+                self.deallocate_outer(new_outer_ptr, new_outer_layout);
+                return None;
+            }
+            ptr::copy_nonoverlapping(
+                alloc.user_ptr(),
+                new_user_ptr,
+                alloc.user_size.min(new_size),
+            );
+            self.release(alloc);
+            new_outer_ptr
+        };
+
+        stats::try_add_live(granted_size - new_size, usize::MAX);
+        let mut new_alloc = AllocInfo {
+            outer_ptr: new_outer_ptr,
+            margin: new_margin,
+            user_size: granted_size,
+            requested_size,
+            align: alloc.align,
+            // The new allocation is writable, unwatched, and untracked by
+            // `FATALLOC_DETECT_UNWRITTEN`
+            flags: alloc.flags & !(FLAG_READONLY | FLAG_WATCHED | FLAG_UNWRITTEN),
+            poisoned_tail,
+        };
+        new_alloc.poison_padding();
+        // Replacing an allocation is allowed to exceed `FATALLOC_MAX_LEAVES`
+        // because it's too late to fail. If even that fails, the allocation
+        // is returned untracked; freeing it will be rejected (i.e., leak)
+        // unless `FATALLOC_METADATA_FREE` frees it by its metadata.
+        if let Err(e) = self.mark(&new_alloc, usize::MAX) {
+            warn!(
+                "reallocated {:p} without tracking it: {}",
+                new_alloc.user_ptr(),
+                e.message()
+            );
+        }
+        new_alloc.protect_meta();
+        stats::sub_overhead(alloc.overhead());
+        stats::add_overhead(new_alloc.overhead());
+        Some(NonNull::new(new_alloc.user_ptr()).unwrap())
+    }
+}
+
+impl FatAlloc<backend::Backend> {
+    /// Give memory back now, e.g., after a spike in memory usage: flush the
+    /// quarantine, unmap the empty parts of the allocation bitmap, and make
+    /// the backend trim itself. Returns the number of bytes flushed and
+    /// unmapped; what the backend trims isn't known.
+    ///
+    /// The pages inside each flushed block are discarded before it's returned
+    /// to the backend, which would otherwise keep them resident as free
+    /// memory indefinitely.
+    fn compact(&self) -> usize {
+        let page_size = page_size();
+        let mut num_bytes = 0;
+        for (outer_ptr, size) in quarantine::drain().with_sizes() {
+            // Spare the start of the block, where the backend might keep its
+            // free list links
+            let start = outer_ptr.as_ptr() as usize + MIN_MARGIN;
+            let start = (start + page_size - 1) & !(page_size - 1);
+            let end = (outer_ptr.as_ptr() as usize + size) & !(page_size - 1);
+            unsafe {
+                if start < end {
+                    libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_DONTNEED);
+                }
+                CAlloc::deallocate(&self.alloc, outer_ptr);
+            }
+            num_bytes += size;
+        }
+        num_bytes += alloc_map().release_empty();
+        self.alloc.trim();
+        num_bytes
+    }
+}
+
+/// The `GlobalAlloc` interface, for Rust programs by way of
+/// `fatalloc_rust_alloc`, `fatalloc_rust_alloc_zeroed`, and
+/// `fatalloc_rust_dealloc`. Unlike the C interface, deallocation carries the
+/// layout, which is checked against the allocation's.
+unsafe impl<T: CAllocZeroed + CAllocBlockSize> alloc::GlobalAlloc for FatAlloc<T> {
+    unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 {
+        CAlloc::allocate(self, layout).map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: alloc::Layout) -> *mut u8 {
+        self.allocate_zeroed(layout)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: alloc::Layout) {
+        let ptr = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => return,
+        };
+        // The recorded alignment reflects `FATALLOC_MIN_ALIGN`. Leave
+        // reporting an invalid or corrupted allocation to `deallocate`.
+        if let Some((size, _, align)) = AllocInfo::peek_layout(ptr) {
+            if layout.size() != size || layout.align().max(config::get().min_align) != align {
+                let refused = config::get().strict_sized_free;
+                logger::report(logger::Event::LayoutMismatch {
+                    ptr: ptr.as_ptr(),
+                    layout,
+                    size,
+                    align,
+                    refused,
+                });
+                if refused {
+                    return;
+                }
+            }
+        }
+        CAlloc::deallocate(self, ptr);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: alloc::Layout, new_size: usize) -> *mut u8 {
+        let new_layout = match alloc::Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => new_layout,
+            Err(_) => return ptr::null_mut(),
+        };
+        match NonNull::new(ptr) {
+            Some(ptr) => CAlloc::reallocate(self, ptr, new_layout),
+            None => CAlloc::allocate(self, new_layout),
+        }
+        .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
 }
 
+impl<T: CAllocZeroed + CAllocBlockSize> FatAlloc<T> {
+    /// Allocate zero-initialized memory.
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Option<NonNull<u8>> {
+        let (ptr, is_zeroed) =
+            self.allocate_inner(layout, None, |layout| self.alloc.allocate_zeroed(layout))?;
+        // Only the margins are written by us, so the user region is still
+        // zero if the backend says the whole block was. Clear the granted
+        // slack too; it's as usable as the rest.
+        if !is_zeroed {
+            let size = unsafe { self.allocation_usable_size(ptr) };
+            unsafe { clear(ptr, size) };
+        }
+        Some(ptr)
+    }
+}
+
+/// Fill `ptr..ptr + size` with zeros. The whole pages of a large region are
+/// discarded instead (`MADV_DONTNEED`), after which they read as zero, so
+/// that a recycled backend block is cleared without writing to all of it.
+/// The backends' memory is private and anonymous, which this relies on.
+unsafe fn clear(ptr: NonNull<u8>, size: usize) {
+    let page_size = page_size();
+    let start = ptr.as_ptr() as usize;
+    let end = start + size;
+    let pages = ((start + page_size - 1) & !(page_size - 1))..(end & !(page_size - 1));
+    if size < DISCARD_ZEROED_MIN
+        || libc::madvise(
+            pages.start as *mut libc::c_void,
+            pages.end - pages.start,
+            libc::MADV_DONTNEED,
+        ) != 0
+    {
+        ptr.as_ptr().write_bytes(0, size);
+        return;
+    }
+    ptr.as_ptr().write_bytes(0, pages.start - start);
+    (pages.end as *mut u8).write_bytes(0, end - pages.end);
+}
+
+/// A backend that may know its allocation is already zero-initialized
+unsafe trait CAllocZeroed: CAlloc {
+    /// Allocate memory, returning `true` as well if it's known to be filled
+    /// with zeros, e.g., because it was freshly mapped.
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Option<(NonNull<u8>, bool)> {
+        self.allocate(layout).map(|ptr| (ptr, false))
+    }
+}
+
+// `rlsf` recycles freed blocks, so the memory is never known to be zero
+unsafe impl CAllocZeroed for rlsf::GlobalTlsf {}
+
+/// A backend that may be able to tell the size of its blocks
+unsafe trait CAllocBlockSize: CAlloc {
+    /// Get the usable size of the live block `ptr` if known.
+    unsafe fn block_size(&self, _ptr: NonNull<u8>) -> Option<usize> {
+        None
+    }
+}
+
+unsafe impl CAllocBlockSize for rlsf::GlobalTlsf {}
+
 unsafe trait CAllocUsableSize {
     /// `malloc_usable_size`, which is [lacked][1] by `rlsf`
     ///
@@ -238,8 +2793,14 @@ unsafe impl<T: CAlloc> CAllocUsableSize for FatAlloc<T> {
     unsafe fn allocation_usable_size(&self, ptr: NonNull<u8>) -> usize {
         match AllocInfo::from_user_ptr(ptr) {
             Ok(AllocInfo { user_size, .. }) => user_size,
+            Err(_) if emergency::owns(ptr) => emergency::usable_size(ptr),
+            #[cfg(feature = "backend-passthrough")]
+            Err(_) if passthrough::is_foreign(ptr) => passthrough::usable_size(ptr).unwrap_or(0),
             Err(e) => {
-                warn!("rejecting the size query for {ptr:p}: {e}");
+                logger::report(logger::Event::InvalidSizeQuery {
+                    ptr: ptr.as_ptr(),
+                    reason: e,
+                });
                 0
             }
         }
@@ -1,23 +1,915 @@
-use core::fmt;
+use core::{
+    alloc::Layout,
+    fmt,
+    fmt::Write,
+    sync::atomic::{AtomicI32, AtomicU8, Ordering},
+};
+
+use crate::{config::ErrorAction, tls};
 
 static mut MUTEX: libc::pthread_mutex_t = libc::PTHREAD_MUTEX_INITIALIZER;
+/// Non-zero while the current thread is writing a message, including while
+/// it's waiting for `MUTEX`
+static WRITING: tls::Key = tls::Key::INIT;
+
+/// Get `MUTEX` for the `fork` handlers.
+pub fn fork_mutex() -> *mut libc::pthread_mutex_t {
+    unsafe { core::ptr::addr_of_mut!(MUTEX) }
+}
+
+/// Holds `MUTEX`, unless the current thread was already writing a message
+/// when it was interrupted, e.g., by a signal handler. The output then
+/// interleaves with the interrupted message instead of deadlocking.
+///
+/// `WRITING` is set before `MUTEX` is taken and cleared after it's released,
+/// so an interruption anywhere in between sees it.
+struct Lock {
+    locked: bool,
+}
+
+impl Lock {
+    fn acquire() -> Self {
+        if WRITING.get() != 0 {
+            return Self { locked: false };
+        }
+        WRITING.set(1);
+        unsafe { libc::pthread_mutex_lock(core::ptr::addr_of_mut!(MUTEX)) };
+        Self { locked: true }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if self.locked {
+            unsafe { libc::pthread_mutex_unlock(core::ptr::addr_of_mut!(MUTEX)) };
+            WRITING.set(0);
+        }
+    }
+}
+
+/// The file descriptor messages are written to
+static FD: AtomicI32 = AtomicI32::new(libc::STDERR_FILENO);
+
+/// The log level threshold, or [`LEVEL_UNSET`] to use `Config::log_level`
+static LEVEL: AtomicU8 = AtomicU8::new(LEVEL_UNSET);
+const LEVEL_UNSET: u8 = u8::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Off = 0,
+    Warn = 1,
+    Info = 2,
+}
+
+impl Level {
+    pub fn from_u8(x: u8) -> Self {
+        match x {
+            0 => Self::Off,
+            1 => Self::Warn,
+            _ => Self::Info,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Warn => "warn",
+            Self::Info => "info",
+        }
+    }
+}
+
+/// The log message format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `text`: `fatalloc: <prose>`
+    Text,
+    /// `json`: One JSON object per line, e.g.,
+    /// `{"lvl":"warn","event":"overrun","ptr":"0x..."}`
+    Json,
+}
+
+/// The allocation an overrun probably came from
+#[derive(Clone, Copy)]
+pub struct Culprit {
+    pub ptr: *const u8,
+    pub size: usize,
+    /// How many bytes past its end the overflow must have reached at least
+    pub overflow: usize,
+}
+
+/// A heap error or another notable event detected by the allocator, which
+/// is logged either in prose or as a structured record depending on
+/// `FATALLOC_LOG_FORMAT`.
+pub enum Event {
+    /// The canary of an allocation is broken. `culprit` is the preceding
+    /// allocation in the address order if there's one nearby.
+    Overrun {
+        ptr: *const u8,
+        culprit: Option<Culprit>,
+    },
+    /// The metadata or the canary of the next allocation `ptr` is broken
+    /// when `culprit` is freed.
+    NeighborOverrun { ptr: *const u8, culprit: *const u8 },
+    /// The tail released by a shrinking reallocation was modified before the
+    /// allocation was grown again.
+    ShrunkTailOverrun { ptr: *const u8, offset: usize },
+    /// A freed pinned allocation was modified.
+    UseAfterFree { ptr: *const u8, offset: usize },
+    /// The first `len` bytes of an allocation, which were to be preserved,
+    /// changed across a reallocation to `ptr`.
+    ReallocMismatch { ptr: *const u8, len: usize },
+    /// The backend returned a block whose user pointer `ptr` is already
+    /// marked as a live allocation.
+    AlreadyTracked { ptr: *const u8 },
+    /// A deallocation request was rejected.
+    InvalidFree {
+        ptr: *const u8,
+        reason: &'static str,
+    },
+    /// A deallocation request for a pointer into the current thread's stack
+    /// was rejected.
+    StackFree { ptr: *const u8 },
+    /// A deallocation request was rejected, and the allocation was found in
+    /// the quarantine, i.e., it was freed twice before its memory was reused.
+    FreedTwice { ptr: *const u8 },
+    /// A deallocation request was rejected, and the pointer is inside the
+    /// live allocation `owner`. It's likely a stale pointer to an allocation
+    /// whose memory `owner` has reused (or a pointer into `owner`).
+    StaleFree {
+        ptr: *const u8,
+        owner: *const u8,
+        owner_size: usize,
+    },
+    /// A deallocation request was rejected, and the pointer is inside the
+    /// live allocation `owner` but not aligned to its alignment `align`
+    /// (`FATALLOC_STRICT_ALIGN`), which suggests a pointer corrupted into
+    /// another `MIN_ALIGN`-aligned value rather than an offset one.
+    MisalignedFree {
+        ptr: *const u8,
+        owner: *const u8,
+        align: usize,
+    },
+    /// A deallocation request was rejected, and the allocation was found in
+    /// the quarantine.
+    #[cfg(feature = "backtrace")]
+    DoubleFree {
+        ptr: *const u8,
+        reason: &'static str,
+        first_freed: crate::backtrace::Backtrace,
+        freed_again: crate::backtrace::Backtrace,
+    },
+    /// A reallocation request was rejected.
+    InvalidRealloc {
+        ptr: *const u8,
+        reason: &'static str,
+    },
+    /// A `malloc_usable_size` request was rejected.
+    InvalidSizeQuery {
+        ptr: *const u8,
+        reason: &'static str,
+    },
+    /// An allocation was accessed through a pointer with a different tag.
+    #[cfg(feature = "pointer-tag")]
+    TypeConfusion {
+        ptr: *const u8,
+        tag: u8,
+        expected: u8,
+    },
+    /// The size of a `calloc` request, `number * size`, overflowed.
+    CallocOverflow { number: usize, size: usize },
+    /// An allocation request was denied by `FATALLOC_MEMORY_LIMIT`.
+    MemoryLimit { layout: Layout },
+    /// The backend returned an unusable allocation.
+    BadBackendPtr {
+        ptr: *const u8,
+        layout: Layout,
+        reason: &'static str,
+    },
+    /// The backend reported a block smaller than the outer allocation of
+    /// `ptr` when it was freed.
+    BlockSizeMismatch {
+        ptr: *const u8,
+        outer_ptr: *const u8,
+        size: usize,
+        block_size: usize,
+    },
+    /// The outer allocations of `ptr` and a preceding allocation `other`
+    /// overlap.
+    OverlappingAllocations { ptr: *const u8, other: *const u8 },
+    /// A Rust allocation was deallocated with a layout different from the
+    /// one it was allocated with. `refused` is set if it was left allocated
+    /// by `FATALLOC_STRICT_SIZED_FREE`.
+    LayoutMismatch {
+        ptr: *const u8,
+        layout: Layout,
+        size: usize,
+        align: usize,
+        refused: bool,
+    },
+    /// A sized deallocation (`sdallocx`) passed a size outside the range from
+    /// the requested size to the usable size of the allocation. `refused` is
+    /// set if it was left allocated by `FATALLOC_STRICT_SIZED_FREE`.
+    SizedFreeMismatch {
+        ptr: *const u8,
+        size: usize,
+        allocated_size: usize,
+        refused: bool,
+    },
+    /// A thread other than the owner of a single-threaded allocation
+    /// (`fatalloc_mark_single_threaded`) performed `operation` on it.
+    CrossThreadAccess {
+        ptr: *const u8,
+        operation: &'static str,
+        thread: usize,
+        owner: usize,
+    },
+    /// A live allocation is older than the leak check threshold.
+    PossibleLeak {
+        ptr: *const u8,
+        size: usize,
+        age: usize,
+        /// Where it was made if `FATALLOC_LEAK_SITES` is enabled
+        #[cfg(feature = "backtrace")]
+        site: Option<LeakSite>,
+    },
+}
+
+/// Where a possibly leaked allocation was made
+#[cfg(feature = "backtrace")]
+pub struct LeakSite {
+    pub frames: crate::backtrace::Backtrace,
+    /// The frames are where it was last reallocated
+    /// (`FATALLOC_REALLOC_SITE=last`), not first allocated.
+    pub reallocated: bool,
+}
+
+/// A field value of an [`Event`]
+enum Value<'a> {
+    Ptr(*const u8),
+    Num(usize),
+    Str(&'a dyn fmt::Display),
+}
+
+impl Event {
+    /// Get the action configured for the event's category.
+    fn action(&self) -> ErrorAction {
+        let config = crate::config::get();
+        match self {
+            // The heap is being corrupted
+            Self::Overrun { .. }
+            | Self::NeighborOverrun { .. }
+            | Self::ShrunkTailOverrun { .. }
+            | Self::UseAfterFree { .. }
+            | Self::ReallocMismatch { .. }
+            | Self::AlreadyTracked { .. } => config.on_corruption,
+            // A bad pointer was rejected before it could do harm
+            Self::InvalidFree { .. }
+            | Self::InvalidRealloc { .. }
+            | Self::InvalidSizeQuery { .. }
+            | Self::StackFree { .. }
+            | Self::StaleFree { .. }
+            | Self::MisalignedFree { .. }
+            | Self::LayoutMismatch { .. }
+            | Self::SizedFreeMismatch { .. }
+            | Self::CallocOverflow { .. } => config.on_invalid_pointer,
+            // Freed twice within the quarantine window, which
+            // `FATALLOC_DOUBLE_FREE_GRACE` escalates
+            Self::FreedTwice { .. } if config.double_free_grace => config.on_corruption,
+            Self::FreedTwice { .. } => config.on_invalid_pointer,
+            #[cfg(feature = "backtrace")]
+            Self::DoubleFree { .. } => config.on_invalid_pointer,
+            #[cfg(feature = "pointer-tag")]
+            Self::TypeConfusion { .. } => config.on_invalid_pointer,
+            Self::MemoryLimit { .. }
+            | Self::BadBackendPtr { .. }
+            | Self::BlockSizeMismatch { .. }
+            | Self::OverlappingAllocations { .. }
+            | Self::CrossThreadAccess { .. }
+            | Self::PossibleLeak { .. } => ErrorAction::Warn,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Overrun { .. } => "overrun",
+            Self::NeighborOverrun { .. } => "neighbor_overrun",
+            Self::ShrunkTailOverrun { .. } => "shrunk_tail_overrun",
+            Self::UseAfterFree { .. } => "use_after_free",
+            Self::ReallocMismatch { .. } => "realloc_mismatch",
+            Self::AlreadyTracked { .. } => "already_tracked",
+            Self::InvalidFree { .. } => "invalid_free",
+            Self::StackFree { .. } => "stack_free",
+            Self::FreedTwice { .. } => "freed_twice",
+            Self::StaleFree { .. } => "stale_free",
+            Self::MisalignedFree { .. } => "misaligned_free",
+            #[cfg(feature = "backtrace")]
+            Self::DoubleFree { .. } => "double_free",
+            Self::InvalidRealloc { .. } => "invalid_realloc",
+            Self::InvalidSizeQuery { .. } => "invalid_size_query",
+            #[cfg(feature = "pointer-tag")]
+            Self::TypeConfusion { .. } => "type_confusion",
+            Self::CallocOverflow { .. } => "calloc_overflow",
+            Self::MemoryLimit { .. } => "memory_limit",
+            Self::BadBackendPtr { .. } => "bad_backend_ptr",
+            Self::BlockSizeMismatch { .. } => "block_size_mismatch",
+            Self::OverlappingAllocations { .. } => "overlapping_allocations",
+            Self::LayoutMismatch { .. } => "layout_mismatch",
+            Self::SizedFreeMismatch { .. } => "sized_free_mismatch",
+            Self::CrossThreadAccess { .. } => "cross_thread_access",
+            Self::PossibleLeak { .. } => "possible_leak",
+        }
+    }
+
+    /// Call `f` for each field.
+    fn for_each_field(&self, f: &mut dyn FnMut(&str, Value<'_>)) {
+        match *self {
+            Self::Overrun { ptr, culprit } => {
+                f("ptr", Value::Ptr(ptr));
+                if let Some(culprit) = culprit {
+                    f("culprit", Value::Ptr(culprit.ptr));
+                    f("culprit_size", Value::Num(culprit.size));
+                    f("overflow", Value::Num(culprit.overflow));
+                }
+            }
+            Self::NeighborOverrun { ptr, culprit } => {
+                f("ptr", Value::Ptr(ptr));
+                f("culprit", Value::Ptr(culprit));
+            }
+            Self::ShrunkTailOverrun { ptr, offset } | Self::UseAfterFree { ptr, offset } => {
+                f("ptr", Value::Ptr(ptr));
+                f("offset", Value::Num(offset));
+            }
+            Self::ReallocMismatch { ptr, len } => {
+                f("ptr", Value::Ptr(ptr));
+                f("len", Value::Num(len));
+            }
+            Self::AlreadyTracked { ptr } | Self::StackFree { ptr } | Self::FreedTwice { ptr } => {
+                f("ptr", Value::Ptr(ptr))
+            }
+            Self::StaleFree {
+                ptr,
+                owner,
+                owner_size,
+            } => {
+                f("ptr", Value::Ptr(ptr));
+                f("owner", Value::Ptr(owner));
+                f("owner_size", Value::Num(owner_size));
+            }
+            Self::MisalignedFree { ptr, owner, align } => {
+                f("ptr", Value::Ptr(ptr));
+                f("owner", Value::Ptr(owner));
+                f("align", Value::Num(align));
+            }
+            Self::InvalidFree { ptr, reason }
+            | Self::InvalidRealloc { ptr, reason }
+            | Self::InvalidSizeQuery { ptr, reason } => {
+                f("ptr", Value::Ptr(ptr));
+                f("reason", Value::Str(&reason));
+            }
+            #[cfg(feature = "backtrace")]
+            Self::DoubleFree {
+                ptr,
+                reason,
+                ref first_freed,
+                ref freed_again,
+            } => {
+                f("ptr", Value::Ptr(ptr));
+                f("reason", Value::Str(&reason));
+                f("first_freed", Value::Str(first_freed));
+                f("freed_again", Value::Str(freed_again));
+            }
+            #[cfg(feature = "pointer-tag")]
+            Self::TypeConfusion { ptr, tag, expected } => {
+                f("ptr", Value::Ptr(ptr));
+                f("tag", Value::Num(tag as usize));
+                f("expected", Value::Num(expected as usize));
+            }
+            Self::CallocOverflow { number, size } => {
+                f("number", Value::Num(number));
+                f("size", Value::Num(size));
+            }
+            Self::MemoryLimit { layout } => {
+                f("size", Value::Num(layout.size()));
+                f("align", Value::Num(layout.align()));
+            }
+            Self::BadBackendPtr {
+                ptr,
+                layout,
+                reason,
+            } => {
+                f("ptr", Value::Ptr(ptr));
+                f("size", Value::Num(layout.size()));
+                f("align", Value::Num(layout.align()));
+                f("reason", Value::Str(&reason));
+            }
+            Self::BlockSizeMismatch {
+                ptr,
+                outer_ptr,
+                size,
+                block_size,
+            } => {
+                f("ptr", Value::Ptr(ptr));
+                f("outer_ptr", Value::Ptr(outer_ptr));
+                f("size", Value::Num(size));
+                f("block_size", Value::Num(block_size));
+            }
+            Self::OverlappingAllocations { ptr, other } => {
+                f("ptr", Value::Ptr(ptr));
+                f("other", Value::Ptr(other));
+            }
+            Self::LayoutMismatch {
+                ptr,
+                layout,
+                size,
+                align,
+                refused,
+            } => {
+                f("ptr", Value::Ptr(ptr));
+                f("size", Value::Num(layout.size()));
+                f("align", Value::Num(layout.align()));
+                f("allocated_size", Value::Num(size));
+                f("allocated_align", Value::Num(align));
+                f("refused", Value::Num(refused as usize));
+            }
+            Self::SizedFreeMismatch {
+                ptr,
+                size,
+                allocated_size,
+                refused,
+            } => {
+                f("ptr", Value::Ptr(ptr));
+                f("size", Value::Num(size));
+                f("allocated_size", Value::Num(allocated_size));
+                f("refused", Value::Num(refused as usize));
+            }
+            Self::CrossThreadAccess {
+                ptr,
+                operation,
+                thread,
+                owner,
+            } => {
+                f("ptr", Value::Ptr(ptr));
+                f("operation", Value::Str(&operation));
+                f("thread", Value::Num(thread));
+                f("owner", Value::Num(owner));
+            }
+            Self::PossibleLeak {
+                ptr,
+                size,
+                age,
+                #[cfg(feature = "backtrace")]
+                ref site,
+            } => {
+                f("ptr", Value::Ptr(ptr));
+                f("size", Value::Num(size));
+                f("age", Value::Num(age));
+                #[cfg(feature = "backtrace")]
+                if let Some(site) = site {
+                    f("site", Value::Str(&site.frames));
+                    f("reallocated", Value::Num(site.reallocated as usize));
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    /// Format in prose.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Overrun { ptr, culprit: None } => {
+                write!(f, "heap overrun detected at allocation {ptr:p}")
+            }
+            Self::Overrun {
+                ptr,
+                culprit: Some(culprit),
+            } => write!(
+                f,
+                "heap overrun detected at allocation {ptr:p}: likely overflow by {} or more \
+                bytes from the allocation at {:p} ({} bytes)",
+                culprit.overflow, culprit.ptr, culprit.size,
+            ),
+            Self::NeighborOverrun { ptr, culprit } => write!(
+                f,
+                "heap overrun detected at allocation {ptr:p}: likely overflow from the \
+                allocation freed at {culprit:p}"
+            ),
+            Self::ShrunkTailOverrun { ptr, offset } => write!(
+                f,
+                "heap overrun detected at offset {offset} of allocation {ptr:p}, in the part \
+                released by an earlier shrinking reallocation"
+            ),
+            Self::UseAfterFree { ptr, offset } => write!(
+                f,
+                "use after free detected at offset {offset} of the pinned allocation {ptr:p}"
+            ),
+            Self::ReallocMismatch { ptr, len } => write!(
+                f,
+                "the first {len} bytes of allocation {ptr:p} changed while being reallocated; \
+                the backend might be faulty"
+            ),
+            Self::AlreadyTracked { ptr } => {
+                write!(f, "the backend returned an already-tracked address {ptr:p}")
+            }
+            Self::InvalidFree { ptr, reason } => {
+                write!(f, "ignoring the deallocation request for {ptr:p}: {reason}")
+            }
+            Self::StackFree { ptr } => write!(
+                f,
+                "ignoring the deallocation request for {ptr:p}: free of a pointer into the \
+                current thread's stack"
+            ),
+            Self::FreedTwice { ptr } => write!(
+                f,
+                "ignoring the deallocation request for {ptr:p}: freed twice before reuse (the \
+                allocation is still in the quarantine)"
+            ),
+            Self::StaleFree {
+                ptr,
+                owner,
+                owner_size,
+            } => write!(
+                f,
+                "ignoring the deallocation request for {ptr:p}: stale pointer into memory \
+                reused by the allocation {owner:p} of {owner_size} bytes"
+            ),
+            Self::MisalignedFree { ptr, owner, align } => write!(
+                f,
+                "ignoring the deallocation request for {ptr:p}: not aligned to {align}, the \
+                alignment of the allocation {owner:p} it points into"
+            ),
+            #[cfg(feature = "backtrace")]
+            Self::DoubleFree {
+                ptr,
+                reason,
+                ref first_freed,
+                ref freed_again,
+            } => write!(
+                f,
+                "ignoring the deallocation request for {ptr:p}: {reason} (double free; first \
+                freed at [{first_freed}], freed again at [{freed_again}])"
+            ),
+            Self::InvalidRealloc { ptr, reason } => {
+                write!(
+                    f,
+                    "rejecting the reallocation request for {ptr:p}: {reason}"
+                )
+            }
+            Self::InvalidSizeQuery { ptr, reason } => {
+                write!(f, "rejecting the size query for {ptr:p}: {reason}")
+            }
+            #[cfg(feature = "pointer-tag")]
+            Self::TypeConfusion { ptr, tag, expected } => write!(
+                f,
+                "allocation {ptr:p} (tag {expected:#04x}) accessed through a pointer tagged \
+                {tag:#04x}: possible type confusion"
+            ),
+            Self::CallocOverflow { number, size } => {
+                write!(f, "calloc integer overflow: {number} * {size}")
+            }
+            Self::MemoryLimit { layout } => write!(
+                f,
+                "allocation denied: memory limit reached (requested {layout:?})"
+            ),
+            Self::BadBackendPtr {
+                ptr,
+                layout,
+                reason,
+            } => write!(
+                f,
+                "the backend returned a bad allocation {ptr:p} for {layout:?}: {reason}"
+            ),
+            Self::BlockSizeMismatch {
+                ptr,
+                outer_ptr,
+                size,
+                block_size,
+            } => write!(
+                f,
+                "leaking allocation {ptr:p}: the backend reports only {block_size} usable bytes \
+                for its block {outer_ptr:p} of {size} bytes; the block or the backend's \
+                bookkeeping is corrupt"
+            ),
+            Self::OverlappingAllocations { ptr, other } => write!(
+                f,
+                "allocations {other:p} and {ptr:p} overlap, including their margins"
+            ),
+            Self::LayoutMismatch {
+                ptr,
+                layout,
+                size,
+                align,
+                refused,
+            } => {
+                write!(
+                    f,
+                    "allocation {ptr:p} (size {size}, align {align}) deallocated with a \
+                    different layout {layout:?}"
+                )?;
+                if refused {
+                    f.write_str("; leaving it allocated")?;
+                }
+                Ok(())
+            }
+            Self::SizedFreeMismatch {
+                ptr,
+                size,
+                allocated_size,
+                refused,
+            } => {
+                write!(
+                    f,
+                    "allocation {ptr:p} of {allocated_size} bytes deallocated with a different \
+                    size {size}"
+                )?;
+                if refused {
+                    f.write_str("; leaving it allocated")?;
+                }
+                Ok(())
+            }
+            Self::CrossThreadAccess {
+                ptr,
+                operation,
+                thread,
+                owner,
+            } => write!(
+                f,
+                "cross-thread access to single-threaded allocation {ptr:p}: {operation} by \
+                thread {thread}, but it's owned by thread {owner}"
+            ),
+            Self::PossibleLeak {
+                ptr,
+                size,
+                age,
+                #[cfg(feature = "backtrace")]
+                ref site,
+            } => {
+                write!(
+                    f,
+                    "allocation {ptr:p} of {size} bytes possibly leaked (live for {age} seconds)"
+                )?;
+                #[cfg(feature = "backtrace")]
+                if let Some(site) = site {
+                    let verb = if site.reallocated {
+                        "reallocated"
+                    } else {
+                        "allocated"
+                    };
+                    write!(f, "; {verb} at [{}]", site.frames)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Get the current log level threshold.
+#[inline]
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        LEVEL_UNSET => crate::config::get().log_level,
+        x => Level::from_u8(x),
+    }
+}
+
+/// Override the log level threshold for the whole process. Returns the
+/// previous threshold.
+pub fn set_level(level: Level) -> Level {
+    match LEVEL.swap(level as u8, Ordering::Relaxed) {
+        LEVEL_UNSET => crate::config::get().log_level,
+        x => Level::from_u8(x),
+    }
+}
+
+/// The threshold to restore if the current thread exits inside the outermost
+/// [`with_level`] scope, plus one, or `0` outside it
+static SCOPE: tls::Key = tls::Key::with_destructor(restore_level);
 
-pub fn warn(args: fmt::Arguments<'_>) {
-    unsafe { libc::pthread_mutex_lock(core::ptr::addr_of_mut!(MUTEX)) };
-    let _ = fmt::Write::write_str(&mut Stderr, "fatalloc: ");
-    let _ = fmt::Write::write_fmt(&mut Stderr, args);
-    let _ = fmt::Write::write_str(&mut Stderr, "\n");
-    unsafe { libc::pthread_mutex_unlock(core::ptr::addr_of_mut!(MUTEX)) };
+unsafe extern "C" fn restore_level(saved: *mut libc::c_void) {
+    LEVEL.store(saved as usize as u8 - 1, Ordering::Relaxed);
 }
 
-struct Stderr;
+/// Run `f` with the log level threshold overridden by `level`, and restore
+/// the previous one afterward, even if `f` unwinds or the thread exits in it.
+/// The threshold is still process-wide while `f` runs.
+pub fn with_level<R>(level: Level, f: impl FnOnce() -> R) -> R {
+    struct Restore {
+        previous: Level,
+        outermost: bool,
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            set_level(self.previous);
+            if self.outermost {
+                SCOPE.set(0);
+            }
+        }
+    }
+
+    let previous = set_level(level);
+    let outermost = SCOPE.get() == 0 && SCOPE.set(previous as usize + 1);
+    let _restore = Restore {
+        previous,
+        outermost,
+    };
+    f()
+}
+
+/// Redirect messages to the file descriptor `fd` for the whole process.
+/// Returns the previous one, which no message is being written to anymore,
+/// so it can be closed right away. A message being written when this is
+/// called is finished first.
+pub fn set_fd(fd: i32) -> i32 {
+    let _lock = Lock::acquire();
+    FD.swap(fd, Ordering::Relaxed)
+}
+
+/// Check if messages of the specified level should be emitted.
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level <= self::level()
+}
+
+fn format() -> Format {
+    crate::config::get().log_format
+}
+
+/// The calling thread, which `FATALLOC_LOG_THREAD` adds to messages
+struct Thread {
+    pid: libc::pid_t,
+    tid: libc::c_long,
+    /// The null-terminated name set by `pthread_setname_np`, `prctl`, etc.
+    name: [u8; 16],
+}
+
+impl Thread {
+    /// Identify the calling thread if `FATALLOC_LOG_THREAD` is enabled. The
+    /// name isn't cached because it can change.
+    fn current() -> Option<Self> {
+        if !crate::config::get().log_thread {
+            return None;
+        }
+        let mut this = Self {
+            pid: unsafe { libc::getpid() },
+            tid: unsafe { libc::syscall(libc::SYS_gettid) },
+            name: [0; 16],
+        };
+        // Reads at most 16 bytes, including the terminator
+        unsafe { libc::prctl(libc::PR_GET_NAME, this.name.as_mut_ptr()) };
+        Some(this)
+    }
+
+    fn name(&self) -> &str {
+        let len = self.name.iter().position(|&ch| ch == 0).unwrap_or(16);
+        let name = &self.name[..len];
+        // Truncation might have split a character
+        match core::str::from_utf8(name) {
+            Ok(name) => name,
+            Err(e) => core::str::from_utf8(&name[..e.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+/// Formats as the text prefix `[pid/tid name]`, or nothing.
+struct TextPrefix<'a>(&'a Option<Thread>);
+
+impl fmt::Display for TextPrefix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(thread) => write!(f, "[{}/{} {}]", thread.pid, thread.tid, thread.name()),
+            None => Ok(()),
+        }
+    }
+}
 
-impl fmt::Write for Stderr {
+/// Formats as the JSON fields `,"pid":...,"tid":...,"thread":"..."`, or
+/// nothing.
+struct JsonFields<'a>(&'a Option<Thread>);
+
+impl fmt::Display for JsonFields<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(thread) => write!(
+                f,
+                ",\"pid\":{},\"tid\":{},\"thread\":{}",
+                thread.pid,
+                thread.tid,
+                JsonStr(thread.name())
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Emit a free-form message.
+pub fn log(level: Level, args: fmt::Arguments<'_>) {
+    let thread = Thread::current();
+    let _lock = Lock::acquire();
+    let mut out = Output::current();
+    let _ = match format() {
+        Format::Text => writeln!(out, "fatalloc{}: {args}", TextPrefix(&thread)),
+        Format::Json => {
+            writeln!(
+                out,
+                "{{\"lvl\":\"{}\"{},\"msg\":{}}}",
+                level.name(),
+                JsonFields(&thread),
+                JsonStr(&args)
+            )
+        }
+    };
+}
+
+/// Emit an [`Event`] at the warning level, and take the action configured
+/// for its category.
+pub fn report(event: Event) {
+    crate::stats::count_event();
+    let is_first = crate::corruptions::record(&event);
+    let action = event.action();
+    if action != ErrorAction::Ignore && is_first && enabled(Level::Warn) {
+        write_event(&event);
+    }
+    if action == ErrorAction::Abort {
+        unsafe { libc::abort() };
+    }
+}
+
+fn write_event(event: &Event) {
+    if format() == Format::Text {
+        return log(Level::Warn, format_args!("{event}"));
+    }
+
+    let thread = Thread::current();
+    let _lock = Lock::acquire();
+    let mut out = Output::current();
+    let _ = write!(
+        out,
+        "{{\"lvl\":\"{}\"{},\"event\":\"{}\"",
+        Level::Warn.name(),
+        JsonFields(&thread),
+        event.name()
+    );
+    event.for_each_field(&mut |key, value| {
+        let _ = match value {
+            Value::Ptr(ptr) => write!(out, ",\"{key}\":\"{ptr:p}\""),
+            Value::Num(x) => write!(out, ",\"{key}\":{x}"),
+            Value::Str(x) => write!(out, ",\"{key}\":{}", JsonStr(x)),
+        };
+    });
+    let _ = out.write_str("}\n");
+}
+
+/// Formats the inner value as a JSON string literal.
+struct JsonStr<'a, T: ?Sized>(&'a T);
+
+impl<T: fmt::Display + ?Sized> fmt::Display for JsonStr<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Escape<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+        impl fmt::Write for Escape<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for ch in s.chars() {
+                    match ch {
+                        '"' => self.0.write_str("\\\"")?,
+                        '\\' => self.0.write_str("\\\\")?,
+                        '\n' => self.0.write_str("\\n")?,
+                        '\0'..='\x1f' => write!(self.0, "\\u{:04x}", ch as u32)?,
+                        _ => self.0.write_char(ch)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        f.write_char('"')?;
+        write!(Escape(f), "{}", self.0)?;
+        f.write_char('"')
+    }
+}
+
+/// The destination of a message, which is loaded once so that a message
+/// written while [`set_fd`] redirects them isn't split between the file
+/// descriptors. Get it while holding `Lock`.
+struct Output {
+    fd: i32,
+}
+
+impl Output {
+    fn current() -> Self {
+        Self {
+            fd: FD.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Write for Output {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let stderr = 2;
         let mut s = s.as_bytes();
         while !s.is_empty() {
-            let written = unsafe { libc::write(stderr, s.as_ptr().cast(), s.len()) };
+            let written = unsafe { libc::write(self.fd, s.as_ptr().cast(), s.len()) };
             if written < 0 {
                 break;
             }
@@ -29,6 +921,16 @@ impl fmt::Write for Stderr {
 
 macro_rules! warn {
     ($($tt:tt)*) => {
-        crate::logger::warn(format_args!($($tt)*))
+        if crate::logger::enabled(crate::logger::Level::Warn) {
+            crate::logger::log(crate::logger::Level::Warn, format_args!($($tt)*))
+        }
+    }
+}
+
+macro_rules! info {
+    ($($tt:tt)*) => {
+        if crate::logger::enabled(crate::logger::Level::Info) {
+            crate::logger::log(crate::logger::Level::Info, format_args!($($tt)*))
+        }
     }
 }
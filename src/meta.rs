@@ -0,0 +1,365 @@
+//! The encoding of the metadata fields covered by the checksum, the checksum
+//! itself, the metadata guard, and the heap canary
+//!
+//! By default, each of them takes a mangled word. The `compact-meta` feature
+//! keeps only the granted size a full word. The margin and the alignment,
+//! both powers of two, are stored as their base-2 logarithms and packed
+//! with the flags word into 32 bits, and the poisoned tail, the checksum,
+//! the guard, and the canary take 32 bits each. This halves
+//! [`crate::MIN_MARGIN`] on a 64-bit target without the `backtrace` feature,
+//! whose larger `registry::Node` and `quarantine::Node` need the room anyway.
+//!
+//! A 32-bit target doesn't benefit: its fields are already 32 bits wide, so
+//! packing only saves the words of the margin and the alignment. The guard,
+//! the metadata, and the canary shrink from 48 to 40 bytes, which round up
+//! to the same 64-byte `MIN_MARGIN`. Reaching 32 bytes would take moving the pointers of
+//! the nodes out of the margin.
+use core::ptr::NonNull;
+
+pub use self::layout::*;
+use crate::{demangle, mangle, META_OFFSET, MIN_MARGIN};
+
+/// The number of metadata fields covered by the checksum
+pub const NUM_FIELDS: usize = 5;
+
+/// The metadata fields covered by the checksum, demangled. They are
+/// indexed by the `FIELD_*` constants.
+pub type Fields = [usize; NUM_FIELDS];
+
+/// [`Fields`]: The margin, a power of two
+pub const FIELD_MARGIN: usize = 0;
+/// [`Fields`]: The granted size
+pub const FIELD_SIZE: usize = 1;
+/// [`Fields`]: The alignment, a power of two
+pub const FIELD_ALIGN: usize = 2;
+/// [`Fields`]: The flags word (`AllocInfo::meta_flags`), which also holds
+/// the rounding slack
+pub const FIELD_FLAGS: usize = 3;
+/// [`Fields`]: The length of the poisoned trailing margin
+pub const FIELD_POISONED_TAIL: usize = 4;
+
+const KEY_MARGIN: usize = 0x123456789abcdefu64 as usize;
+const KEY_CANARY: usize = 0x23435243643547au64 as usize;
+const KEY_GUARD: usize = 0x71e3b58c2d90f46u64 as usize;
+const KEY_SIZE: usize = 0x1ae9deaf526c83du64 as usize;
+#[cfg(not(feature = "compact-meta"))]
+const KEY_ALIGN: usize = 0x3b1f5e27d09a6c4u64 as usize;
+const KEY_CHECKSUM: usize = 0x5d2c8e4f19b7a03u64 as usize;
+#[cfg(not(feature = "compact-meta"))]
+const KEY_FLAGS: usize = 0x6e94d1c3a57f20bu64 as usize;
+const KEY_POISONED_TAIL: usize = 0x47c0a9e13f6b5d2u64 as usize;
+
+/// Calculate the checksum of the (mangled) metadata words.
+#[inline]
+fn checksum(words: &[usize]) -> usize {
+    words
+        .iter()
+        .fold(KEY_CHECKSUM, |acc, &x| mangle(acc.wrapping_add(x), x))
+}
+
+/// Get the start of the metadata of the allocation at `user_ptr`.
+#[inline]
+fn meta_ptr(user_ptr: NonNull<u8>) -> *mut usize {
+    user_ptr.as_ptr().wrapping_sub(META_OFFSET).cast()
+}
+
+#[cfg(not(feature = "compact-meta"))]
+mod layout {
+    use core::{mem::size_of, ptr::NonNull, sync::atomic::AtomicUsize};
+
+    use super::*;
+
+    /// The size of the metadata guard, a canary occupying the word before
+    /// the metadata. It only catches writes reaching that word, e.g., a long
+    /// underflow; one landing on the metadata in between is left to the
+    /// checksum.
+    pub const GUARD_SIZE: usize = size_of::<usize>();
+
+    /// The size of the heap canary, which occupies the last word before
+    /// `user_ptr`
+    pub const CANARY_SIZE: usize = size_of::<usize>();
+
+    /// The number of words the fields and the checksum occupy at the start
+    /// of the metadata. The nodes follow them.
+    pub const HEADER_WORDS: usize = NUM_FIELDS + 1;
+
+    /// The distance from `user_ptr` back to the bytes covered by the
+    /// checksum, the checksum included
+    pub const CHECKED_OFFSET: usize = META_OFFSET;
+
+    /// The number of bytes covered by the checksum, the checksum included
+    pub const CHECKED_LEN: usize = HEADER_WORDS * size_of::<usize>();
+
+    /// The type of the checksum
+    pub type AtomicChecksum = AtomicUsize;
+
+    /// The key of each field, indexed by the `FIELD_*` constants
+    const KEYS: [usize; NUM_FIELDS] = {
+        let mut keys = [0; NUM_FIELDS];
+        keys[FIELD_MARGIN] = KEY_MARGIN;
+        keys[FIELD_SIZE] = KEY_SIZE;
+        keys[FIELD_ALIGN] = KEY_ALIGN;
+        keys[FIELD_FLAGS] = KEY_FLAGS;
+        keys[FIELD_POISONED_TAIL] = KEY_POISONED_TAIL;
+        keys
+    };
+
+    /// Read and demangle the metadata field `i` (`FIELD_*`) of the
+    /// allocation at `user_ptr`.
+    #[inline]
+    pub unsafe fn read_field(user_ptr: NonNull<u8>, i: usize) -> usize {
+        crate::meta_word(user_ptr, i, KEYS[i])
+    }
+
+    /// Write the metadata fields of the allocation at `user_ptr` and their
+    /// checksum.
+    #[inline]
+    pub unsafe fn write_fields(user_ptr: NonNull<u8>, fields: &Fields) {
+        let key = user_ptr.as_ptr() as usize;
+        let mut words = [0; NUM_FIELDS];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = mangle(fields[i], key ^ KEYS[i]);
+            meta_ptr(user_ptr).wrapping_add(i).write(*word);
+        }
+        checksum_ptr(user_ptr).write(mangle(checksum(&words), key ^ KEY_CHECKSUM));
+    }
+
+    /// Check the checksum of the metadata fields of a supposed allocation at
+    /// `user_ptr`.
+    #[inline]
+    pub unsafe fn has_intact_fields(user_ptr: NonNull<u8>) -> bool {
+        let mut words = [0; NUM_FIELDS];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = meta_ptr(user_ptr).wrapping_add(i).read();
+        }
+        let expected = demangle(
+            checksum_ptr(user_ptr).read(),
+            user_ptr.as_ptr() as usize ^ KEY_CHECKSUM,
+        );
+        expected == checksum(&words)
+    }
+
+    /// Get the location of the (mangled) checksum of the allocation at
+    /// `user_ptr`.
+    #[inline]
+    pub fn checksum_ptr(user_ptr: NonNull<u8>) -> *mut usize {
+        meta_ptr(user_ptr).wrapping_add(NUM_FIELDS)
+    }
+
+    /// Get the location of the metadata guard of the allocation at
+    /// `user_ptr`.
+    #[inline]
+    pub fn guard_ptr(user_ptr: NonNull<u8>) -> *mut usize {
+        user_ptr.as_ptr().wrapping_sub(MIN_MARGIN).cast()
+    }
+
+    /// Get the location of the heap canary of the allocation at `user_ptr`.
+    #[inline]
+    pub fn canary_ptr(user_ptr: NonNull<u8>) -> *mut usize {
+        user_ptr.as_ptr().cast::<usize>().wrapping_sub(1)
+    }
+
+    /// Get the expected metadata guard of the allocation at `user_ptr`.
+    #[inline]
+    pub fn guard(user_ptr: NonNull<u8>) -> usize {
+        mangle(user_ptr.as_ptr() as usize, KEY_GUARD)
+    }
+
+    /// Get the expected heap canary of the allocation at `user_ptr`.
+    #[inline]
+    pub fn canary(user_ptr: NonNull<u8>) -> usize {
+        mangle(user_ptr.as_ptr() as usize, KEY_CANARY)
+    }
+}
+
+#[cfg(feature = "compact-meta")]
+mod layout {
+    use core::{mem::size_of, ptr::NonNull, sync::atomic::AtomicU32};
+
+    use super::*;
+
+    /// The size of the metadata guard word, whose low half holds the guard
+    /// and high half (in memory order) holds the checksum. The guard only
+    /// catches writes reaching it, e.g., a long underflow.
+    pub const GUARD_SIZE: usize = 8;
+
+    /// The size of the heap canary, which occupies the last 32 bits before
+    /// `user_ptr`
+    pub const CANARY_SIZE: usize = 4;
+
+    /// The number of words the fields occupy at the start of the metadata:
+    /// the granted size, then the packed word (`PACKED_*`) and the poisoned
+    /// tail in 32 bits each. The nodes follow them.
+    pub const HEADER_WORDS: usize = 1 + 8 / size_of::<usize>();
+
+    /// The distance from `user_ptr` back to the bytes covered by the
+    /// checksum, the checksum included
+    pub const CHECKED_OFFSET: usize = META_OFFSET + 4;
+
+    /// The number of bytes covered by the checksum, the checksum included
+    pub const CHECKED_LEN: usize = 4 + HEADER_WORDS * size_of::<usize>();
+
+    /// The type of the checksum
+    pub type AtomicChecksum = AtomicU32;
+
+    /// The packed word: The bit position of the alignment's base-2
+    /// logarithm. The flags word occupies the bits below it.
+    const PACKED_ALIGN_SHIFT: u32 = 20;
+    /// The packed word: The bit position of the margin's base-2 logarithm
+    const PACKED_MARGIN_SHIFT: u32 = 26;
+    /// The packed word: The mask of each logarithm
+    const PACKED_LOG2_MASK: u32 = 0x3f;
+
+    /// Obfuscate a 32-bit metadata field the way [`mangle`] does a word.
+    #[inline]
+    fn mangle32(x: u32, key: usize) -> u32 {
+        x.rotate_left(13) ^ fold32(key ^ crate::entropy::seed())
+    }
+
+    #[inline]
+    fn demangle32(x: u32, key: usize) -> u32 {
+        (x ^ fold32(key ^ crate::entropy::seed())).rotate_right(13)
+    }
+
+    /// Fold a word into 32 bits.
+    #[inline]
+    fn fold32(x: usize) -> u32 {
+        (x as u64 ^ (x as u64 >> 32)) as u32
+    }
+
+    /// Get the location of the packed word, followed by the poisoned tail.
+    #[inline]
+    fn packed_ptr(user_ptr: NonNull<u8>) -> *mut u32 {
+        meta_ptr(user_ptr).wrapping_add(1).cast()
+    }
+
+    /// Read and demangle the metadata field `i` (`FIELD_*`) of the
+    /// allocation at `user_ptr`.
+    #[inline]
+    pub unsafe fn read_field(user_ptr: NonNull<u8>, i: usize) -> usize {
+        let key = user_ptr.as_ptr() as usize;
+        if i == FIELD_SIZE {
+            return demangle(meta_ptr(user_ptr).read(), key ^ KEY_SIZE);
+        }
+        if i == FIELD_POISONED_TAIL {
+            let tail = packed_ptr(user_ptr).wrapping_add(1).read();
+            return demangle32(tail, key ^ KEY_POISONED_TAIL) as usize;
+        }
+        let packed = demangle32(packed_ptr(user_ptr).read(), key ^ KEY_MARGIN);
+        match i {
+            FIELD_MARGIN => 1 << (packed >> PACKED_MARGIN_SHIFT & PACKED_LOG2_MASK),
+            FIELD_ALIGN => 1 << (packed >> PACKED_ALIGN_SHIFT & PACKED_LOG2_MASK),
+            _ => (packed & !(!0 << PACKED_ALIGN_SHIFT)) as usize,
+        }
+    }
+
+    /// Write the metadata fields of the allocation at `user_ptr` and their
+    /// checksum. The margin and the alignment must be powers of two, and
+    /// the flags word must fit in `PACKED_ALIGN_SHIFT` bits. A poisoned tail
+    /// too long for 32 bits is recorded short, which only means that less of
+    /// it is known to be poisoned.
+    #[inline]
+    pub unsafe fn write_fields(user_ptr: NonNull<u8>, fields: &Fields) {
+        let margin = fields[FIELD_MARGIN];
+        let align = fields[FIELD_ALIGN];
+        let flags = fields[FIELD_FLAGS];
+        debug_assert!(margin.is_power_of_two() && align.is_power_of_two());
+        debug_assert!(flags >> PACKED_ALIGN_SHIFT == 0);
+        let packed = margin.trailing_zeros() << PACKED_MARGIN_SHIFT
+            | align.trailing_zeros() << PACKED_ALIGN_SHIFT
+            | flags as u32;
+        let tail = fields[FIELD_POISONED_TAIL].min(u32::MAX as usize) as u32;
+
+        let key = user_ptr.as_ptr() as usize;
+        meta_ptr(user_ptr).write(mangle(fields[FIELD_SIZE], key ^ KEY_SIZE));
+        packed_ptr(user_ptr).write(mangle32(packed, key ^ KEY_MARGIN));
+        packed_ptr(user_ptr)
+            .wrapping_add(1)
+            .write(mangle32(tail, key ^ KEY_POISONED_TAIL));
+        checksum_ptr(user_ptr).write(mangle32(fold32(checksum_of(user_ptr)), key ^ KEY_CHECKSUM));
+    }
+
+    /// Check the checksum of the metadata fields of a supposed allocation at
+    /// `user_ptr`.
+    #[inline]
+    pub unsafe fn has_intact_fields(user_ptr: NonNull<u8>) -> bool {
+        let expected = demangle32(
+            checksum_ptr(user_ptr).read(),
+            user_ptr.as_ptr() as usize ^ KEY_CHECKSUM,
+        );
+        expected == fold32(checksum_of(user_ptr))
+    }
+
+    /// Calculate the full-width checksum of the (mangled) fields of the
+    /// allocation at `user_ptr`.
+    #[inline]
+    unsafe fn checksum_of(user_ptr: NonNull<u8>) -> usize {
+        let mut words = [0; HEADER_WORDS];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = meta_ptr(user_ptr).wrapping_add(i).read();
+        }
+        checksum(&words)
+    }
+
+    /// Get the location of the (mangled) checksum of the allocation at
+    /// `user_ptr`.
+    #[inline]
+    pub fn checksum_ptr(user_ptr: NonNull<u8>) -> *mut u32 {
+        guard_ptr(user_ptr).wrapping_add(1)
+    }
+
+    /// Get the location of the metadata guard of the allocation at
+    /// `user_ptr`.
+    #[inline]
+    pub fn guard_ptr(user_ptr: NonNull<u8>) -> *mut u32 {
+        user_ptr.as_ptr().wrapping_sub(MIN_MARGIN).cast()
+    }
+
+    /// Get the location of the heap canary of the allocation at `user_ptr`.
+    #[inline]
+    pub fn canary_ptr(user_ptr: NonNull<u8>) -> *mut u32 {
+        user_ptr.as_ptr().cast::<u32>().wrapping_sub(1)
+    }
+
+    /// Get the expected metadata guard of the allocation at `user_ptr`.
+    #[inline]
+    pub fn guard(user_ptr: NonNull<u8>) -> u32 {
+        mangle32(fold32(user_ptr.as_ptr() as usize), KEY_GUARD)
+    }
+
+    /// Get the expected heap canary of the allocation at `user_ptr`.
+    #[inline]
+    pub fn canary(user_ptr: NonNull<u8>) -> u32 {
+        mangle32(fold32(user_ptr.as_ptr() as usize), KEY_CANARY)
+    }
+}
+
+/// Read and demangle the metadata fields of the allocation at `user_ptr`.
+#[inline]
+pub unsafe fn read_fields(user_ptr: NonNull<u8>) -> Fields {
+    let mut fields = [0; NUM_FIELDS];
+    for (i, field) in fields.iter_mut().enumerate() {
+        *field = read_field(user_ptr, i);
+    }
+    fields
+}
+
+/// Check the metadata guard of a supposed allocation at `user_ptr`.
+#[inline]
+pub unsafe fn has_intact_guard(user_ptr: NonNull<u8>) -> bool {
+    guard_ptr(user_ptr).read() == guard(user_ptr)
+}
+
+/// Check the heap canary of a supposed allocation at `user_ptr`.
+#[inline]
+pub unsafe fn has_intact_canary(user_ptr: NonNull<u8>) -> bool {
+    canary_ptr(user_ptr).read() == canary(user_ptr)
+}
+
+/// Place the metadata guard and the heap canary of the allocation at
+/// `user_ptr`.
+#[inline]
+pub unsafe fn write_guard_and_canary(user_ptr: NonNull<u8>) {
+    guard_ptr(user_ptr).write(guard(user_ptr));
+    canary_ptr(user_ptr).write(canary(user_ptr));
+}
@@ -0,0 +1,163 @@
+//! Writes allocator statistics to `FATALLOC_METRICS_FILE` in the Prometheus
+//! text exposition format, e.g., for node exporter's textfile collector
+//! (with the `stats` feature)
+//!
+//! The file is written under a temporary name and renamed so that a reader
+//! never sees a partial file. So is `FATALLOC_FOLDED_FILE`.
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "stats")]
+use crate::{config, quarantine, stats};
+
+/// The longest path accepted for `FATALLOC_METRICS_FILE` (or another file
+/// written by [`write_file`]), including the temporary suffix and the
+/// terminator
+const PATH_LEN: usize = 4096;
+
+/// Numbers the temporary files of a process. With the process ID, it keeps
+/// concurrent writers of the same file from clobbering each other's
+/// temporary file.
+static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Write the metrics file. Returns `false` if `FATALLOC_METRICS_FILE` is
+/// unset or the file couldn't be written.
+#[cfg(feature = "stats")]
+pub fn export() -> bool {
+    match config::get().metrics_file {
+        Some(path) => write_file(path, "FATALLOC_METRICS_FILE", write_metrics),
+        None => false,
+    }
+}
+
+/// Replace the file at `path`, which the environment variable `var` names,
+/// with what `write` writes. Returns `false` if it couldn't be written.
+pub fn write_file(path: &[u8], var: &str, write: impl FnOnce(&mut File) -> fmt::Result) -> bool {
+    // Null-terminated copies of the paths. The temporary one is
+    // `<path>.<pid>.<n>.tmp`.
+    let mut final_path = [0u8; PATH_LEN];
+    let mut temp_path = [0u8; PATH_LEN];
+    let fits = path.len() < PATH_LEN && {
+        final_path[..path.len()].copy_from_slice(path);
+        temp_path = final_path;
+        let mut suffix = Cursor {
+            buf: &mut temp_path[..PATH_LEN - 1],
+            len: path.len(),
+        };
+        let pid = unsafe { libc::getpid() };
+        let id = NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed);
+        write!(suffix, ".{pid}.{id}.tmp").is_ok()
+    };
+    if !fits {
+        warn!("{var} is too long");
+        return false;
+    }
+
+    let mode: libc::mode_t = 0o644;
+    let fd = unsafe {
+        libc::open(
+            temp_path.as_ptr().cast(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+            mode,
+        )
+    };
+    if fd < 0 {
+        warn!("failed to create the temporary file for {var}");
+        return false;
+    }
+    let mut file = File { fd, ok: true };
+    let _ = write(&mut file);
+    let ok = file.ok && unsafe { libc::close(fd) } == 0;
+
+    if !ok || unsafe { libc::rename(temp_path.as_ptr().cast(), final_path.as_ptr().cast()) } != 0 {
+        warn!("failed to write {var}");
+        unsafe { libc::unlink(temp_path.as_ptr().cast()) };
+        return false;
+    }
+    true
+}
+
+#[cfg(feature = "stats")]
+fn write_metrics(out: &mut File) -> fmt::Result {
+    let stats = stats::snapshot();
+    let metrics: [(&str, &str, &str, usize); 5] = [
+        (
+            "fatalloc_live_bytes",
+            "gauge",
+            "The total size of live allocations, excluding margins",
+            stats.bytes_live,
+        ),
+        (
+            "fatalloc_overhead_bytes",
+            "gauge",
+            "The total size of the margins of live allocations",
+            stats.overhead_bytes,
+        ),
+        (
+            "fatalloc_quarantined_bytes",
+            "gauge",
+            "The total size of freed blocks kept in the quarantine",
+            quarantine::total_bytes(),
+        ),
+        (
+            "fatalloc_allocations_total",
+            "counter",
+            "The number of allocations made",
+            stats.num_allocations,
+        ),
+        (
+            "fatalloc_events_total",
+            "counter",
+            "The number of heap errors and other notable events detected",
+            stats.num_events,
+        ),
+    ];
+    for (name, ty, help, value) in metrics {
+        write!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} {ty}\n{name} {value}\n"
+        )?;
+    }
+    Ok(())
+}
+
+pub struct File {
+    fd: libc::c_int,
+    /// Cleared on a write error
+    ok: bool,
+}
+
+impl Write for File {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut s = s.as_bytes();
+        while !s.is_empty() {
+            let written = unsafe { libc::write(self.fd, s.as_ptr().cast(), s.len()) };
+            if written < 0 {
+                self.ok = false;
+                return Err(fmt::Error);
+            }
+            s = &s[written as usize..];
+        }
+        Ok(())
+    }
+}
+
+/// Writes into a byte buffer, failing if it doesn't fit
+struct Cursor<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len.checked_add(s.len()).ok_or(fmt::Error)?;
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
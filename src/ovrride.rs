@@ -1,4 +1,5 @@
-//! Overrides C memory allocation functions with [`::rlsf`].
+//! Overrides C memory allocation functions with fatalloc backed by
+//! [`::rlsf`] or the system allocator.
 use core::{
     alloc::Layout,
     ptr::{null_mut, NonNull},
@@ -6,7 +7,8 @@ use core::{
 use libc::{c_int, c_void};
 use rlsf::CAlloc;
 
-static ALLOC: crate::FatAlloc<rlsf::GlobalTlsf> = crate::FatAlloc::new(rlsf::GlobalTlsf::INIT);
+pub(crate) static ALLOC: crate::FatAlloc<crate::backend::Backend> =
+    crate::FatAlloc::new(crate::backend::Backend::INIT);
 
 /// The alignment guaranteed by `malloc`.
 const MIN_ALIGN: usize = match () {
@@ -36,52 +38,90 @@ const MIN_ALIGN: usize = match () {
 
 #[no_mangle]
 pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    if let Some(ptr) = crate::forward::malloc(size) {
+        return ptr;
+    }
     aligned_alloc(MIN_ALIGN, size)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn malloc_usable_size(p: *mut c_void) -> usize {
     if let Some(p) = NonNull::new(p) {
+        #[cfg(feature = "pointer-tag")]
+        let (p, _) = untag(p);
         crate::CAllocUsableSize::allocation_usable_size(&ALLOC, p.cast())
     } else {
         0
     }
 }
 
+/// Strip the pointer tag of `ptr` and check it against the allocation's.
+/// Returns the untagged pointer and the allocation's tag. This reads the
+/// metadata of an allocation that a racing deallocation might be releasing,
+/// so the deallocating entry points check the tag later, once they own it.
+#[cfg(feature = "pointer-tag")]
+pub(crate) unsafe fn untag(ptr: NonNull<c_void>) -> (NonNull<u8>, u8) {
+    let (ptr, tag) = crate::ptrtag::split(ptr.cast());
+    match ALLOC.tag(ptr) {
+        Ok(expected) if tag != 0 && tag != expected => {
+            crate::logger::report(crate::logger::Event::TypeConfusion {
+                ptr: ptr.as_ptr(),
+                tag,
+                expected,
+            });
+            (ptr, expected)
+        }
+        Ok(expected) => (ptr, expected),
+        // Invalid pointers are reported by the caller
+        Err(_) => (ptr, 0),
+    }
+}
+
 #[inline]
-fn page_size() -> usize {
-    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+fn set_errno(value: c_int) {
+    unsafe { *libc::__errno_location() = value };
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn valloc(size: usize) -> *mut c_void {
-    aligned_alloc(page_size(), size)
+    aligned_alloc(crate::page_size(), size)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn pvalloc(size: usize) -> *mut c_void {
-    let page_size = page_size();
+    let page_size = crate::page_size();
     if let Some(size) = size
         .checked_add(page_size - 1)
         .map(|x| x & !(page_size - 1))
     {
         aligned_alloc(page_size, size)
     } else {
+        set_errno(libc::ENOMEM);
         null_mut()
     }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn calloc(number: usize, size: usize) -> *mut c_void {
-    let layout = size
-        .checked_mul(number)
-        .and_then(|len| Layout::from_size_align(len, MIN_ALIGN).ok());
-    if let Some((ptr, size)) =
-        layout.and_then(|layout| CAlloc::allocate(&ALLOC, layout).map(|p| (p, layout.size())))
-    {
-        ptr.as_ptr().write_bytes(0, size);
+    if let Some(ptr) = crate::forward::calloc(number, size) {
+        return ptr;
+    }
+
+    // A huge request is merely unsatisfiable, but an overflowing one means
+    // the caller's size arithmetic is broken
+    let len = match number.checked_mul(size) {
+        Some(len) => len,
+        None => {
+            crate::logger::report(crate::logger::Event::CallocOverflow { number, size });
+            set_errno(libc::ENOMEM);
+            return null_mut();
+        }
+    };
+    let layout = Layout::from_size_align(len, MIN_ALIGN).ok();
+    if let Some(ptr) = layout.and_then(|layout| ALLOC.allocate_zeroed(layout)) {
         ptr.as_ptr() as *mut c_void
     } else {
+        set_errno(libc::ENOMEM);
         null_mut()
     }
 }
@@ -92,58 +132,268 @@ pub unsafe extern "C" fn posix_memalign(
     alignment: usize,
     size: usize,
 ) -> c_int {
-    let ptr = aligned_alloc(alignment, size);
-    *out_ptr = ptr as *mut c_void;
-    if ptr.is_null() {
-        libc::ENOMEM
-    } else {
-        0
+    // `posix_memalign` reports errors by the return value and leaves `errno`
+    // and `*out_ptr` untouched
+    if alignment % core::mem::size_of::<*mut c_void>() != 0 {
+        return libc::EINVAL;
+    }
+    match aligned_alloc_inner(alignment, size) {
+        Ok(ptr) => {
+            *out_ptr = ptr;
+            0
+        }
+        Err(e) => e,
     }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn aligned_alloc(alignment: usize, size: usize) -> *mut c_void {
-    let layout = Layout::from_size_align(size, alignment).ok();
-    if let Some(ptr) = layout.and_then(|layout| CAlloc::allocate(&ALLOC, layout)) {
-        ptr.as_ptr() as *mut c_void
-    } else {
+    aligned_alloc_inner(alignment, size).unwrap_or_else(|e| {
+        set_errno(e);
         null_mut()
+    })
+}
+
+/// The implementation of [`aligned_alloc`] that returns an `errno` value on
+/// failure.
+fn aligned_alloc_inner(alignment: usize, size: usize) -> Result<*mut c_void, c_int> {
+    if !alignment.is_power_of_two() {
+        return Err(libc::EINVAL);
     }
+    let layout = Layout::from_size_align(size, alignment).map_err(|_| libc::ENOMEM)?;
+    CAlloc::allocate(&ALLOC, layout)
+        .map(|ptr| ptr.as_ptr() as *mut c_void)
+        .ok_or(libc::ENOMEM)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn memalign(alignment: usize, size: usize) -> *mut c_void {
-    aligned_alloc(alignment, size)
+    // Unlike `aligned_alloc`, glibc's `memalign` rounds a non-power-of-two
+    // alignment up to the next power of two
+    match alignment.checked_next_power_of_two() {
+        Some(alignment) => aligned_alloc(alignment, size),
+        None => {
+            set_errno(libc::EINVAL);
+            null_mut()
+        }
+    }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    if let Some(ptr) = crate::forward::realloc(ptr, size) {
+        return ptr;
+    }
+
     if let Some(ptr) = NonNull::new(ptr) {
+        #[cfg(feature = "pointer-tag")]
+        let (ptr, tag) = crate::ptrtag::split(ptr.cast());
+
         // `realloc` doesn't preserve the allocation's original alignment
         // <https://stackoverflow.com/a/9078627>
         Layout::from_size_align(size, MIN_ALIGN)
             .ok()
-            .and_then(|layout| CAlloc::reallocate(&ALLOC, ptr.cast(), layout))
-            .map(|ptr| ptr.as_ptr() as *mut c_void)
-            .unwrap_or(null_mut())
+            .and_then(|layout| {
+                ALLOC.reallocate_tagged(
+                    ptr.cast(),
+                    layout,
+                    #[cfg(feature = "pointer-tag")]
+                    tag,
+                )
+            })
+            .map(|ptr| {
+                // The tag is carried over to the new allocation, which is the
+                // caller's alone now
+                #[cfg(feature = "pointer-tag")]
+                let ptr = crate::ptrtag::apply(ptr, ALLOC.tag(ptr).unwrap_or(0));
+                ptr.as_ptr() as *mut c_void
+            })
+            .unwrap_or_else(|| {
+                set_errno(libc::ENOMEM);
+                null_mut()
+            })
     } else {
         malloc(size)
     }
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn reallocarray(ptr: *mut c_void, number: usize, size: usize) -> *mut c_void {
+    match number.checked_mul(size) {
+        Some(len) => realloc(ptr, len),
+        None => {
+            set_errno(libc::ENOMEM);
+            null_mut()
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn free(ptr: *mut c_void) {
     if let Some(ptr) = NonNull::new(ptr) {
-        CAlloc::deallocate(&ALLOC, ptr.cast());
+        #[cfg(feature = "pointer-tag")]
+        let (ptr, tag) = crate::ptrtag::split(ptr.cast());
+        ALLOC.deallocate_tagged(
+            ptr.cast(),
+            #[cfg(feature = "pointer-tag")]
+            tag,
+        );
+    } else if crate::config::get().warn_free_null {
+        // A no-op by the standard, but a lot of them may be a sign of
+        // confused ownership
+        warn!("free(NULL) called");
     }
 }
 
+// glibc's internal aliases, which some programs call directly
+/// `__libc_malloc`
+#[no_mangle]
+pub unsafe extern "C" fn __libc_malloc(size: usize) -> *mut c_void {
+    malloc(size)
+}
+
+/// `__libc_calloc`
+#[no_mangle]
+pub unsafe extern "C" fn __libc_calloc(number: usize, size: usize) -> *mut c_void {
+    calloc(number, size)
+}
+
+/// `__libc_realloc`
+#[no_mangle]
+pub unsafe extern "C" fn __libc_realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    realloc(ptr, size)
+}
+
+/// `__libc_free`
+#[no_mangle]
+pub unsafe extern "C" fn __libc_free(ptr: *mut c_void) {
+    free(ptr)
+}
+
+/// `__libc_memalign`
+#[no_mangle]
+pub unsafe extern "C" fn __libc_memalign(alignment: usize, size: usize) -> *mut c_void {
+    memalign(alignment, size)
+}
+
+/// `__libc_valloc`
+#[no_mangle]
+pub unsafe extern "C" fn __libc_valloc(size: usize) -> *mut c_void {
+    valloc(size)
+}
+
+/// `__libc_pvalloc`
+#[no_mangle]
+pub unsafe extern "C" fn __libc_pvalloc(size: usize) -> *mut c_void {
+    pvalloc(size)
+}
+
+// jemalloc's and tcmalloc's non-standard API, which programs built against
+// them call directly
+/// `MALLOCX_ZERO`
+const MALLOCX_ZERO: c_int = 0x40;
+
+/// Get the alignment `MALLOCX_ALIGN` or `MALLOCX_LG_ALIGN` encodes in
+/// `flags`, or `MIN_ALIGN` if none.
+fn mallocx_align(flags: c_int) -> usize {
+    match flags & 0x3f {
+        0 => MIN_ALIGN,
+        lg_align => 1usize.checked_shl(lg_align as u32).unwrap_or(0),
+    }
+}
+
+/// `mallocx`. The arena and tcache flags are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn mallocx(size: usize, flags: c_int) -> *mut c_void {
+    let layout = match Layout::from_size_align(size, mallocx_align(flags).max(MIN_ALIGN)) {
+        Ok(layout) => layout,
+        Err(_) => return null_mut(),
+    };
+    let ptr = if flags & MALLOCX_ZERO != 0 {
+        ALLOC.allocate_zeroed(layout)
+    } else {
+        CAlloc::allocate(&ALLOC, layout)
+    };
+    ptr.map_or(null_mut(), |ptr| ptr.as_ptr() as *mut c_void)
+}
+
+/// `dallocx`
+#[no_mangle]
+pub unsafe extern "C" fn dallocx(ptr: *mut c_void, _: c_int) {
+    free(ptr)
+}
+
+/// `sdallocx`: A sized deallocation. A size other than the requested one or
+/// up to the usable size is reported, and the allocation is left allocated
+/// with `FATALLOC_STRICT_SIZED_FREE`. C++'s sized `operator delete` goes
+/// through here, too.
+#[no_mangle]
+pub unsafe extern "C" fn sdallocx(ptr: *mut c_void, size: usize, _: c_int) {
+    if let Some(ptr) = NonNull::new(ptr) {
+        #[cfg(feature = "pointer-tag")]
+        let (ptr, tag) = crate::ptrtag::split(ptr.cast());
+        ALLOC.deallocate_sized(
+            ptr.cast(),
+            size,
+            #[cfg(feature = "pointer-tag")]
+            tag,
+        );
+    }
+}
+
+/// `nallocx`: Get the usable size `mallocx` would give for `size` bytes, or
+/// `0` if it would fail for sure. With `FATALLOC_ROUND_SIZES`, it's the
+/// rounded size, which `mallocx` may exceed if the backend's block is larger.
+#[no_mangle]
+pub extern "C" fn nallocx(size: usize, flags: c_int) -> usize {
+    match Layout::from_size_align(size, mallocx_align(flags).max(MIN_ALIGN)) {
+        Ok(_) => crate::user_size_for(size).unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// `sallocx`
+#[no_mangle]
+pub unsafe extern "C" fn sallocx(ptr: *const c_void, _: c_int) -> usize {
+    malloc_usable_size(ptr as *mut c_void)
+}
+
+/// `tc_malloc`
+#[no_mangle]
+pub unsafe extern "C" fn tc_malloc(size: usize) -> *mut c_void {
+    malloc(size)
+}
+
+/// `tc_free`
+#[no_mangle]
+pub unsafe extern "C" fn tc_free(ptr: *mut c_void) {
+    free(ptr)
+}
+
+/// `tc_free_sized`
+#[no_mangle]
+pub unsafe extern "C" fn tc_free_sized(ptr: *mut c_void, size: usize) {
+    sdallocx(ptr, size, 0)
+}
+
+/// `tc_nallocx`
+#[no_mangle]
+pub extern "C" fn tc_nallocx(size: usize, flags: c_int) -> usize {
+    nallocx(size, flags)
+}
+
+/// `tc_malloc_size`
+#[no_mangle]
+pub unsafe extern "C" fn tc_malloc_size(ptr: *mut c_void) -> usize {
+    malloc_usable_size(ptr)
+}
+
 // TODO: Find a way to define these in a C++ source file and make sure the
 //       symbols are exported by the final cdylib file
 /// `operator delete[](void*, unsigned long, std::align_val_t)`
 #[no_mangle]
-pub unsafe extern "C" fn _ZdaPvmSt11align_val_t(p: *mut c_void, _: usize, _: usize) {
-    free(p);
+pub unsafe extern "C" fn _ZdaPvmSt11align_val_t(p: *mut c_void, size: usize, _: usize) {
+    sdallocx(p, size, 0);
 }
 
 /// `operator delete[](void*, std::align_val_t, std::nothrow_t const&)`
@@ -160,8 +410,8 @@ pub unsafe extern "C" fn _ZdaPvSt11align_val_t(p: *mut c_void, _: usize) {
 
 /// `operator delete(void*, unsigned long, std::align_val_t)`
 #[no_mangle]
-pub unsafe extern "C" fn _ZdlPvmSt11align_val_t(p: *mut c_void, _: usize, _: usize) {
-    free(p);
+pub unsafe extern "C" fn _ZdlPvmSt11align_val_t(p: *mut c_void, size: usize, _: usize) {
+    sdallocx(p, size, 0);
 }
 
 /// `operator delete(void*, std::align_val_t, std::nothrow_t const&)`
@@ -210,14 +460,14 @@ pub unsafe extern "C" fn _ZnwmSt11align_val_t(size: usize, align: usize) -> *mut
 
 /// `operator delete[](void*, unsigned long)`
 #[no_mangle]
-pub unsafe extern "C" fn _ZdaPvm(p: *mut c_void, _: usize) {
-    free(p);
+pub unsafe extern "C" fn _ZdaPvm(p: *mut c_void, size: usize) {
+    sdallocx(p, size, 0);
 }
 
 /// `operator delete(void*, unsigned long)`
 #[no_mangle]
-pub unsafe extern "C" fn _ZdlPvm(p: *mut c_void, _: usize) {
-    free(p);
+pub unsafe extern "C" fn _ZdlPvm(p: *mut c_void, size: usize) {
+    sdallocx(p, size, 0);
 }
 
 /// `operator delete[](void*, std::nothrow_t const&)`
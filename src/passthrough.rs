@@ -0,0 +1,93 @@
+//! Forwards deallocation requests for foreign allocations (e.g., ones made
+//! before `LD_PRELOAD` took effect, or by a `malloc` that wasn't interposed)
+//! to the next `free` in the symbol lookup order instead of leaking them.
+//! Reallocations move them into fatalloc.
+//!
+//! Handing a pointer to the real `free` is only sound if it was actually
+//! allocated by the real allocator. Sending it one of our own allocations
+//! (e.g., on a double free) would corrupt the real allocator's heap, so we
+//! err on the side of leaking: a pointer is only forwarded if it's absent
+//! from the `AllocMap`, doesn't have intact fatalloc metadata or canary in
+//! front of it, *and* has a plausible glibc chunk header.
+use core::{
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use crate::{alloc_map, is_mapped, meta, AllocInfo, MIN_ALIGN};
+
+type FreeFn = unsafe extern "C" fn(*mut libc::c_void);
+type UsableSizeFn = unsafe extern "C" fn(*mut libc::c_void) -> usize;
+
+/// Check if `ptr` is known not to be a fatalloc allocation.
+pub unsafe fn is_foreign(ptr: NonNull<u8>) -> bool {
+    let addr = ptr.as_ptr() as usize;
+    if addr % MIN_ALIGN != 0 || alloc_map().get(addr / MIN_ALIGN) {
+        return false;
+    }
+
+    // A live glibc allocation is preceded by a chunk header whose size field
+    // is a non-zero multiple of two words (the low three bits are flags).
+    // Anything else may be one of ours released by a racing `free` or
+    // `realloc`, with its memory unmapped or reused since. Allocations of
+    // other allocators are leaked, which is safe.
+    let word = core::mem::size_of::<usize>();
+    let header_start = addr.wrapping_sub(word * 2);
+    if header_start > addr || !is_mapped(header_start, addr + 1) {
+        return false;
+    }
+    let chunk_size = (addr as *const usize).wrapping_sub(1).read() & !0b111;
+    if chunk_size < word * 4 || chunk_size % (word * 2) != 0 {
+        return false;
+    }
+
+    // The metadata of a foreign allocation might not even be mapped. If it
+    // isn't, it certainly isn't ours.
+    let meta_start = addr.wrapping_sub(meta::CHECKED_OFFSET);
+    let meta_end = meta_start + meta::CHECKED_LEN;
+    if meta_start > addr || !is_mapped(meta_start, meta_end) {
+        return true;
+    }
+
+    // A racing in-place `realloc` may be rewriting the metadata of one of
+    // ours, but it leaves the canary as it is
+    !AllocInfo::has_intact_metadata(ptr) && !AllocInfo::has_intact_canary(ptr)
+}
+
+/// Release `ptr` by the next `free`.
+pub unsafe fn deallocate(ptr: NonNull<u8>) {
+    if let Some(free) = real_free() {
+        free(ptr.as_ptr().cast());
+    } else {
+        warn!("leaking the foreign allocation {ptr:p}: the real `free` is unavailable");
+    }
+}
+
+/// Get the usable size of `ptr` by the next `malloc_usable_size`.
+pub unsafe fn usable_size(ptr: NonNull<u8>) -> Option<usize> {
+    static REAL_MALLOC_USABLE_SIZE: AtomicPtr<libc::c_void> = AtomicPtr::new(null_mut());
+
+    let p = next_symbol(&REAL_MALLOC_USABLE_SIZE, b"malloc_usable_size\0")?;
+    let malloc_usable_size = core::mem::transmute::<*mut libc::c_void, UsableSizeFn>(p);
+    Some(malloc_usable_size(ptr.as_ptr().cast()))
+}
+
+fn real_free() -> Option<FreeFn> {
+    static REAL_FREE: AtomicPtr<libc::c_void> = AtomicPtr::new(null_mut());
+
+    let p = next_symbol(&REAL_FREE, b"free\0")?;
+    Some(unsafe { core::mem::transmute::<*mut libc::c_void, FreeFn>(p) })
+}
+
+/// Look up `name` (null-terminated) by `dlsym(RTLD_NEXT, ...)`, caching the
+/// result in `cache`.
+fn next_symbol(cache: &AtomicPtr<libc::c_void>, name: &[u8]) -> Option<*mut libc::c_void> {
+    let mut p = cache.load(Ordering::Relaxed);
+    if p.is_null() {
+        // `dlsym` might call `calloc`, which is fine because we aren't
+        // holding any locks here
+        p = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr().cast()) };
+        cache.store(p, Ordering::Relaxed);
+    }
+    (!p.is_null()).then(|| p)
+}
@@ -0,0 +1,69 @@
+//! The freed pinned allocations, retained until they are unpinned
+//!
+//! The allocation map no longer has them, so this is how
+//! `fatalloc_unpin_allocation` tells them from other pointers before
+//! trusting what the metadata says. The links are stored in the allocations'
+//! metadata areas, in the space the `quarantine::Node` takes after
+//! deallocation; a retained allocation never enters the quarantine.
+use core::{cell::UnsafeCell, ptr::null_mut, ptr::NonNull};
+
+#[repr(C)]
+pub struct Node {
+    next: *mut Node,
+}
+
+struct Pinned {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    head: UnsafeCell<*mut Node>,
+}
+
+unsafe impl Sync for Pinned {}
+
+static PINNED: Pinned = Pinned {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    head: UnsafeCell::new(null_mut()),
+};
+
+/// Get the list's lock for the `fork` handlers.
+pub fn fork_mutex() -> *mut libc::pthread_mutex_t {
+    PINNED.mutex.get()
+}
+
+/// Add the freed allocation having `node`.
+///
+/// # Safety
+///
+/// `node` must point to a pointer-aligned word in the metadata area of a
+/// freed allocation not in the list, which must stay mapped until it's
+/// removed.
+pub unsafe fn insert(node: NonNull<u8>) {
+    let node = node.as_ptr().cast::<Node>();
+    libc::pthread_mutex_lock(PINNED.mutex.get());
+    let head = &mut *PINNED.head.get();
+    node.write(Node { next: *head });
+    *head = node;
+    libc::pthread_mutex_unlock(PINNED.mutex.get());
+}
+
+/// Remove the allocation having `node` from the list. Returns `false` if
+/// it's not in the list. This scans the whole list.
+pub fn remove(node: NonNull<u8>) -> bool {
+    let node = node.as_ptr().cast::<Node>();
+    let mut found = false;
+
+    unsafe {
+        libc::pthread_mutex_lock(PINNED.mutex.get());
+        let mut link = &mut *PINNED.head.get();
+        while !link.is_null() {
+            if *link == node {
+                *link = (*node).next;
+                found = true;
+                break;
+            }
+            link = &mut (**link).next;
+        }
+        libc::pthread_mutex_unlock(PINNED.mutex.get());
+    }
+
+    found
+}
@@ -0,0 +1,194 @@
+//! Reads `/proc/self/maps` to find the mapping an address belongs to
+#[cfg(feature = "backtrace")]
+use core::fmt;
+
+/// The longest file name [`Location`] holds. Longer ones are truncated.
+#[cfg(feature = "backtrace")]
+const NAME_LEN: usize = 64;
+
+/// The longest line read from `/proc/self/maps`. The rest of a longer line is
+/// discarded, which might truncate the path name.
+const LINE_LEN: usize = 512;
+
+/// An address expressed as a mapped file and an address in it. For an ELF
+/// object, it's the virtual address `addr2line` takes; for anything else,
+/// it's the file offset.
+#[cfg(feature = "backtrace")]
+pub struct Location {
+    name: [u8; NAME_LEN],
+    name_len: usize,
+    offset: usize,
+}
+
+#[cfg(feature = "backtrace")]
+impl fmt::Display for Location {
+    /// Format as `name+0xoffset`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = &self.name[..self.name_len];
+        // Truncation might have split a character
+        let name = match core::str::from_utf8(name) {
+            Ok(name) => name,
+            Err(e) => core::str::from_utf8(&name[..e.valid_up_to()]).unwrap(),
+        };
+        write!(f, "{name}+{:#x}", self.offset)
+    }
+}
+
+/// Find the mapping containing `addr`. Returns `None` if it's not found or
+/// `/proc/self/maps` is unavailable.
+///
+/// This doesn't allocate memory.
+#[cfg(feature = "backtrace")]
+pub fn locate(addr: usize) -> Option<Location> {
+    let mut location = scan(|line| parse_line(line, addr))?;
+    if let Some(bias) = load_bias(addr) {
+        location.offset = addr - bias;
+    }
+    Some(location)
+}
+
+/// Find the load bias of the loaded ELF object containing `addr`, i.e., how
+/// far it's moved from the virtual addresses in the file. That's `0` for a
+/// non-PIE executable, and where a PIE executable or DSO is loaded otherwise,
+/// which may differ from the start of its first mapping minus the file
+/// offset.
+#[cfg(feature = "backtrace")]
+fn load_bias(addr: usize) -> Option<usize> {
+    unsafe extern "C" fn visit(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut libc::c_void,
+    ) -> libc::c_int {
+        let (addr, bias) = &mut *data.cast::<(usize, Option<usize>)>();
+        let info = &*info;
+        let base = info.dlpi_addr as usize;
+        let phdrs = core::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+        // `Elf32_Phdr` or `Elf64_Phdr`
+        let contains = phdrs.iter().any(|phdr| {
+            let start = base.wrapping_add(phdr.p_vaddr as usize);
+            phdr.p_type == libc::PT_LOAD
+                && (start..start.wrapping_add(phdr.p_memsz as usize)).contains(addr)
+        });
+        if contains {
+            *bias = Some(base);
+            1
+        } else {
+            0
+        }
+    }
+
+    let mut data = (addr, None);
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(visit),
+            (&mut data as *mut (usize, Option<usize>)).cast(),
+        )
+    };
+    data.1
+}
+
+/// Find the address range of the mapping containing `addr`. Adjacent
+/// mappings with identical attributes are reported as one by the kernel.
+///
+/// This doesn't allocate memory.
+pub fn range(addr: usize) -> Option<(usize, usize)> {
+    scan(|line| parse_range(line).filter(|&(start, end)| (start..end).contains(&addr)))
+}
+
+/// Call `parse` on each line of `/proc/self/maps` until it returns `Some(_)`.
+fn scan<T>(mut parse: impl FnMut(&[u8]) -> Option<T>) -> Option<T> {
+    let fd = unsafe {
+        libc::open(
+            b"/proc/self/maps\0".as_ptr().cast(),
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+
+    let mut found = None;
+    let mut buf = [0u8; 256];
+    let mut line = [0u8; LINE_LEN];
+    let mut line_len = 0;
+    'read: loop {
+        let num_read = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        if num_read <= 0 {
+            break;
+        }
+        for &ch in &buf[..num_read as usize] {
+            if ch != b'\n' {
+                if line_len < LINE_LEN {
+                    line[line_len] = ch;
+                    line_len += 1;
+                }
+                continue;
+            }
+            found = parse(&line[..line_len]);
+            line_len = 0;
+            if found.is_some() {
+                break 'read;
+            }
+        }
+    }
+
+    unsafe { libc::close(fd) };
+    found
+}
+
+/// Parse the `start-end` field of a line of `/proc/self/maps`.
+fn parse_range(line: &[u8]) -> Option<(usize, usize)> {
+    let field = line.split(|&ch| ch == b' ').next()?;
+    let mut range = field.split(|&ch| ch == b'-');
+    Some((parse_hex(range.next()?)?, parse_hex(range.next()?)?))
+}
+
+/// Parse a line of `/proc/self/maps` (`start-end perms offset dev inode
+/// path`) and return `addr`'s location if it's in the mapping.
+#[cfg(feature = "backtrace")]
+fn parse_line(line: &[u8], addr: usize) -> Option<Location> {
+    let mut fields = line
+        .split(|&ch| ch == b' ')
+        .filter(|field| !field.is_empty());
+    let (start, end) = parse_range(fields.next()?)?;
+    if !(start..end).contains(&addr) {
+        return None;
+    }
+    let _perms = fields.next()?;
+    let file_offset = parse_hex(fields.next()?)?;
+    let _dev = fields.next()?;
+    let _inode = fields.next()?;
+
+    // Use the base name. The path may contain spaces.
+    let path_start = fields.next().map_or(line.len(), |field| {
+        field.as_ptr() as usize - line.as_ptr() as usize
+    });
+    let path = &line[path_start..];
+    let base_name = match path.iter().rposition(|&ch| ch == b'/') {
+        Some(i) => &path[i + 1..],
+        None => path,
+    };
+    let base_name = if base_name.is_empty() {
+        &b"(anonymous)"[..]
+    } else {
+        base_name
+    };
+
+    let mut location = Location {
+        name: [0; NAME_LEN],
+        name_len: base_name.len().min(NAME_LEN),
+        offset: addr - start + file_offset,
+    };
+    location.name[..location.name_len].copy_from_slice(&base_name[..location.name_len]);
+    Some(location)
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter().try_fold(0usize, |acc, &ch| {
+        let digit = (ch as char).to_digit(16)?;
+        acc.checked_mul(16)?.checked_add(digit as usize)
+    })
+}
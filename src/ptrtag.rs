@@ -0,0 +1,37 @@
+//! Pointer tags for detecting type confusion
+//!
+//! A tag is a caller-chosen non-zero byte placed in the top byte of a
+//! pointer, identifying the type (or any other class) of the allocation. The
+//! allocation remembers its tag, and the entry points receiving a pointer
+//! strip the tag and report if it doesn't match.
+//!
+//! Tagged pointers can only be dereferenced where the hardware ignores the
+//! top byte of addresses, i.e., on AArch64 (Linux enables Top Byte Ignore for
+//! user space). Elsewhere, they are only good for passing back to fatalloc.
+use core::ptr::NonNull;
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("the `pointer-tag` feature requires a 64-bit target");
+
+const TAG_SHIFT: u32 = usize::BITS - 8;
+
+/// Split `ptr` into the untagged pointer and the tag (`0` = untagged).
+#[inline]
+pub fn split(ptr: NonNull<u8>) -> (NonNull<u8>, u8) {
+    let addr = ptr.as_ptr() as usize;
+    let tag = (addr >> TAG_SHIFT) as u8;
+    let untagged = (addr & !(0xff << TAG_SHIFT)) as *mut u8;
+    match NonNull::new(untagged) {
+        Some(untagged) => (untagged, tag),
+        // A tag on a null pointer; leave it to the caller to reject
+        None => (ptr, 0),
+    }
+}
+
+/// Put `tag` in the top byte of the untagged pointer `ptr`.
+#[inline]
+pub fn apply(ptr: NonNull<u8>, tag: u8) -> NonNull<u8> {
+    let addr = ptr.as_ptr() as usize | (tag as usize) << TAG_SHIFT;
+    // Safety: `addr` includes `ptr`'s bits, which aren't all zero
+    unsafe { NonNull::new_unchecked(addr as *mut u8) }
+}
@@ -0,0 +1,257 @@
+//! Delays deallocation to nullify brief use-after-free
+//!
+//! Freed outer allocations are kept here, intact, until they are evicted to
+//! stay within the byte budget. The bookkeeping is stored in the freed blocks
+//! themselves (in the metadata area), so the quarantine itself doesn't
+//! allocate.
+//!
+//! Blocks are bucketed by power-of-two size classes, each of which is a FIFO
+//! queue. Eviction takes the oldest block of the largest class so that the
+//! most memory is released with the fewest backend calls. Eviction is
+//! amortized over deallocations (at most [`EVICT_BATCH`] blocks per call)
+//! unless the quarantine overshoots its budget by a large factor, keeping the
+//! deallocation latency predictable.
+use core::{cell::UnsafeCell, ptr::null_mut, ptr::NonNull};
+
+/// The default value of `Config::quarantine_bytes`
+pub const QUARANTINE_BYTES: usize = 4 << 20;
+
+/// The default cap on the budget sized by `FATALLOC_QUARANTINE_FRACTION`
+pub const QUARANTINE_MAX_BYTES: usize = 1 << 30;
+
+/// The number of blocks [`insert`] evicts at most in the amortized mode
+const EVICT_BATCH: usize = 2;
+
+const NUM_CLASSES: usize = usize::BITS as usize;
+
+#[repr(C)]
+pub struct Node {
+    next: *mut Node,
+    outer_ptr: NonNull<u8>,
+    size: usize,
+    /// Where the block was freed
+    #[cfg(feature = "backtrace")]
+    free_site: crate::backtrace::Backtrace,
+}
+
+/// The size of [`Node`] in words
+pub const NODE_WORDS: usize = core::mem::size_of::<Node>() / core::mem::size_of::<usize>();
+
+#[derive(Clone, Copy)]
+struct Class {
+    head: *mut Node,
+    tail: *mut Node,
+}
+
+struct Inner {
+    classes: [Class; NUM_CLASSES],
+    /// Bit `i` is set iff `classes[i]` is non-empty.
+    nonempty: usize,
+    total_bytes: usize,
+}
+
+struct Quarantine {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    inner: UnsafeCell<Inner>,
+}
+
+unsafe impl Sync for Quarantine {}
+
+static QUARANTINE: Quarantine = Quarantine {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    inner: UnsafeCell::new(Inner {
+        classes: [Class {
+            head: null_mut(),
+            tail: null_mut(),
+        }; NUM_CLASSES],
+        nonempty: 0,
+        total_bytes: 0,
+    }),
+};
+
+/// Get the quarantine's lock for the `fork` handlers.
+pub fn fork_mutex() -> *mut libc::pthread_mutex_t {
+    QUARANTINE.mutex.get()
+}
+
+/// A chain of blocks evicted from the quarantine, which the caller must
+/// return to the backend.
+pub struct Evicted {
+    head: *mut Node,
+}
+
+impl Iterator for Evicted {
+    type Item = NonNull<u8>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = unsafe { self.head.as_ref()? };
+        // Read the node before the caller frees the block
+        self.head = node.next;
+        Some(node.outer_ptr)
+    }
+}
+
+impl Evicted {
+    /// Iterate over the blocks along with their sizes.
+    pub fn with_sizes(mut self) -> impl Iterator<Item = (NonNull<u8>, usize)> {
+        core::iter::from_fn(move || {
+            let node = unsafe { self.head.as_ref()? };
+            self.head = node.next;
+            Some((node.outer_ptr, node.size))
+        })
+    }
+}
+
+#[inline]
+fn class_of(size: usize) -> usize {
+    (usize::BITS - 1 - size.leading_zeros()) as usize
+}
+
+/// Check if [`insert`] keeps a block of `size` bytes rather than returning
+/// it right away.
+#[inline]
+pub fn admits(size: usize, budget: usize) -> bool {
+    // Blocks too large for the quarantine would just push out everything else
+    size <= budget / 4
+}
+
+/// Put the freed block `outer_ptr` of `size` bytes in the quarantine, and
+/// evict blocks as needed to stay within `budget` bytes.
+///
+/// # Safety
+///
+/// `outer_ptr` must be an outer allocation that is no longer in use. `node`
+/// must point to [`NODE_WORDS`] pointer-aligned words inside it.
+pub unsafe fn insert(
+    node: NonNull<u8>,
+    outer_ptr: NonNull<u8>,
+    size: usize,
+    budget: usize,
+    #[cfg(feature = "backtrace")] free_site: crate::backtrace::Backtrace,
+) -> Evicted {
+    let node = node.as_ptr().cast::<Node>();
+    node.write(Node {
+        next: null_mut(),
+        outer_ptr,
+        size,
+        #[cfg(feature = "backtrace")]
+        free_site,
+    });
+
+    if !admits(size, budget) {
+        return Evicted { head: node };
+    }
+
+    libc::pthread_mutex_lock(QUARANTINE.mutex.get());
+    let inner = &mut *QUARANTINE.inner.get();
+
+    // Append the block to its class's queue
+    let class_i = class_of(size);
+    let class = &mut inner.classes[class_i];
+    if class.tail.is_null() {
+        class.head = node;
+    } else {
+        (*class.tail).next = node;
+    }
+    class.tail = node;
+    inner.nonempty |= 1 << class_i;
+    inner.total_bytes += size;
+
+    // Evict blocks. Stop at `EVICT_BATCH` blocks unless we are way over the
+    // budget.
+    let mut evicted = Evicted { head: null_mut() };
+    let mut num_evicted = 0;
+    while inner.total_bytes > budget
+        && (num_evicted < EVICT_BATCH || inner.total_bytes > budget * 2)
+    {
+        let class_i = class_of(inner.nonempty);
+        let class = &mut inner.classes[class_i];
+        let victim = class.head;
+        class.head = (*victim).next;
+        if class.head.is_null() {
+            class.tail = null_mut();
+            inner.nonempty &= !(1 << class_i);
+        }
+        inner.total_bytes -= (*victim).size;
+
+        (*victim).next = evicted.head;
+        evicted.head = victim;
+        num_evicted += 1;
+    }
+
+    libc::pthread_mutex_unlock(QUARANTINE.mutex.get());
+    evicted
+}
+
+/// Find where the block having `node` was freed if it's still in the
+/// quarantine. This scans the whole quarantine.
+#[cfg(feature = "backtrace")]
+pub fn find_free_site(node: NonNull<u8>) -> Option<crate::backtrace::Backtrace> {
+    find(node, |n| n.free_site)
+}
+
+/// Check if the block having `node` is in the quarantine. This scans the
+/// whole quarantine.
+pub fn contains(node: NonNull<u8>) -> bool {
+    find(node, |_| ()).is_some()
+}
+
+/// Find the node `node` in the quarantine and call `f` on it with the lock
+/// held.
+fn find<R>(node: NonNull<u8>, f: impl FnOnce(&Node) -> R) -> Option<R> {
+    let node = node.as_ptr().cast::<Node>();
+    let mut found = None;
+
+    unsafe {
+        libc::pthread_mutex_lock(QUARANTINE.mutex.get());
+        let inner = &*QUARANTINE.inner.get();
+        'outer: for class in inner.classes.iter() {
+            let mut p = class.head;
+            while let Some(n) = p.as_ref() {
+                if p == node {
+                    found = Some(f(n));
+                    break 'outer;
+                }
+                p = n.next;
+            }
+        }
+        libc::pthread_mutex_unlock(QUARANTINE.mutex.get());
+    }
+
+    found
+}
+
+/// Get the total size of the blocks in the quarantine.
+#[cfg(feature = "stats")]
+pub fn total_bytes() -> usize {
+    unsafe {
+        libc::pthread_mutex_lock(QUARANTINE.mutex.get());
+        let total_bytes = (*QUARANTINE.inner.get()).total_bytes;
+        libc::pthread_mutex_unlock(QUARANTINE.mutex.get());
+        total_bytes
+    }
+}
+
+/// Remove every block from the quarantine, e.g., to free memory under
+/// pressure.
+pub fn drain() -> Evicted {
+    let mut evicted = Evicted { head: null_mut() };
+    unsafe {
+        libc::pthread_mutex_lock(QUARANTINE.mutex.get());
+        let inner = &mut *QUARANTINE.inner.get();
+        for class in inner.classes.iter_mut() {
+            // Prepend the class's queue to the chain
+            if !class.tail.is_null() {
+                (*class.tail).next = evicted.head;
+                evicted.head = class.head;
+            }
+            class.head = null_mut();
+            class.tail = null_mut();
+        }
+        inner.nonempty = 0;
+        inner.total_bytes = 0;
+        libc::pthread_mutex_unlock(QUARANTINE.mutex.get());
+    }
+    evicted
+}
@@ -0,0 +1,39 @@
+//! Detects the allocator being reentered by the same thread, e.g., from a
+//! signal handler or a broken backend. Proceeding normally in such a case
+//! could deadlock on the `AllocMap` lock or corrupt the tracking state.
+//!
+//! This is the `reentrancy-check` feature, which costs every request a
+//! thread-local read and two writes. Without it, [`Guard::enter`] always
+//! succeeds.
+#[cfg(feature = "reentrancy-check")]
+static IN_ALLOCATOR: crate::tls::Key = crate::tls::Key::INIT;
+
+/// Marks the current thread as being inside the allocator.
+pub struct Guard {
+    _private: (),
+}
+
+impl Guard {
+    /// Enter the allocator. Returns `None` if the current thread is already
+    /// inside it. Reentry goes unnoticed if the thread-local storage is
+    /// unavailable.
+    #[inline]
+    pub fn enter() -> Option<Self> {
+        #[cfg(feature = "reentrancy-check")]
+        {
+            if IN_ALLOCATOR.get() != 0 {
+                return None;
+            }
+            IN_ALLOCATOR.set(1);
+        }
+        Some(Self { _private: () })
+    }
+}
+
+impl Drop for Guard {
+    #[inline]
+    fn drop(&mut self) {
+        #[cfg(feature = "reentrancy-check")]
+        IN_ALLOCATOR.set(0);
+    }
+}
@@ -0,0 +1,149 @@
+//! An optional list of live allocations in the allocation order
+//!
+//! The links are stored in the allocations' metadata areas, in the space the
+//! `quarantine::Node` takes after deallocation. Every operation takes a
+//! global lock, which is why this is opt-in (`FATALLOC_REGISTRY`).
+use core::{cell::UnsafeCell, ptr::null_mut, ptr::NonNull};
+
+/// The number of frames of [`Origin::site`], limited by the room for
+/// [`Node`] in the metadata area
+#[cfg(feature = "backtrace")]
+pub const SITE_DEPTH: usize = 4;
+
+#[repr(C)]
+pub struct Node {
+    /// The next older allocation
+    prev: *mut Node,
+    /// The next newer allocation
+    next: *mut Node,
+    origin: Origin,
+}
+
+/// Where and when an allocation was made
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Origin {
+    /// In [`now`] seconds
+    pub time: usize,
+    /// The innermost return addresses outside fatalloc, outermost last, if
+    /// `FATALLOC_FOLDED_FILE` is set. Unused entries are zero.
+    #[cfg(feature = "backtrace")]
+    pub site: [usize; SITE_DEPTH],
+}
+
+/// The size of [`Node`] in words
+pub const NODE_WORDS: usize = core::mem::size_of::<Node>() / core::mem::size_of::<usize>();
+
+struct Inner {
+    /// The oldest allocation
+    head: *mut Node,
+    /// The newest allocation
+    tail: *mut Node,
+}
+
+struct Registry {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    inner: UnsafeCell<Inner>,
+}
+
+unsafe impl Sync for Registry {}
+
+static REGISTRY: Registry = Registry {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    inner: UnsafeCell::new(Inner {
+        head: null_mut(),
+        tail: null_mut(),
+    }),
+};
+
+/// Get the registry's lock for the `fork` handlers.
+pub fn fork_mutex() -> *mut libc::pthread_mutex_t {
+    REGISTRY.mutex.get()
+}
+
+/// Get the current time in seconds of `CLOCK_MONOTONIC`.
+pub fn now() -> usize {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as usize
+}
+
+/// Append the allocation having `node`, made at `origin`, as the newest one.
+///
+/// # Safety
+///
+/// `node` must point to [`NODE_WORDS`] pointer-aligned words in the metadata
+/// area of a live allocation not in the list.
+pub unsafe fn insert(node: NonNull<u8>, origin: Origin) {
+    let node = node.as_ptr().cast::<Node>();
+    libc::pthread_mutex_lock(REGISTRY.mutex.get());
+    let inner = &mut *REGISTRY.inner.get();
+
+    node.write(Node {
+        prev: inner.tail,
+        next: null_mut(),
+        origin,
+    });
+    if inner.tail.is_null() {
+        inner.head = node;
+    } else {
+        (*inner.tail).next = node;
+    }
+    inner.tail = node;
+
+    libc::pthread_mutex_unlock(REGISTRY.mutex.get());
+}
+
+/// Remove the allocation having `node` from the list. Returns where and when
+/// it was made.
+///
+/// # Safety
+///
+/// `node` must be in the list.
+pub unsafe fn remove(node: NonNull<u8>) -> Origin {
+    let node = node.as_ptr().cast::<Node>();
+    libc::pthread_mutex_lock(REGISTRY.mutex.get());
+    let inner = &mut *REGISTRY.inner.get();
+
+    let Node { prev, next, origin } = node.read();
+    if prev.is_null() {
+        inner.head = next;
+    } else {
+        (*prev).next = next;
+    }
+    if next.is_null() {
+        inner.tail = prev;
+    } else {
+        (*next).prev = prev;
+    }
+
+    libc::pthread_mutex_unlock(REGISTRY.mutex.get());
+    origin
+}
+
+/// Call `f` with the nodes of live allocations and where and when they were
+/// made from the oldest (or the newest if `newest_first` is set) until it
+/// returns `false`. A reallocated allocation counts as the newest but keeps
+/// its original [`Origin`].
+///
+/// The lock is held during the iteration, so `f` must not allocate or
+/// deallocate memory.
+pub fn for_each(newest_first: bool, mut f: impl FnMut(NonNull<u8>, &Origin) -> bool) {
+    unsafe {
+        libc::pthread_mutex_lock(REGISTRY.mutex.get());
+        let inner = &*REGISTRY.inner.get();
+
+        let mut p = if newest_first { inner.tail } else { inner.head };
+        while let Some(node) = NonNull::new(p) {
+            if !f(node.cast(), &(*p).origin) {
+                break;
+            }
+            p = if newest_first { (*p).prev } else { (*p).next };
+        }
+
+        libc::pthread_mutex_unlock(REGISTRY.mutex.get());
+    }
+}
@@ -0,0 +1,42 @@
+//! Sampling of the expensive heap checks (`FATALLOC_CHECK_SAMPLE`)
+//!
+//! Each thread draws from its own xorshift generator, so a decision costs a
+//! thread-local access and a few arithmetic operations. The cheap checks
+//! aren't sampled.
+use crate::{config, entropy, tls};
+
+/// The generator state of the current thread, or `0` if not seeded yet
+static STATE: tls::Key = tls::Key::INIT;
+
+/// Decide whether to run the expensive checks (`neighbor` and `redzone`) in
+/// the current call.
+#[inline]
+pub fn should_check() -> bool {
+    let (n, d) = config::get().check_sample;
+    if n >= d {
+        return true;
+    }
+
+    let mut x = STATE.get();
+    if x == 0 {
+        // The stack address tells threads apart
+        x = (entropy::seed() ^ (&x as *const usize as usize)) | 1;
+    }
+    #[cfg(target_pointer_width = "64")]
+    {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+    }
+    #[cfg(not(target_pointer_width = "64"))]
+    {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+    }
+    if !STATE.set(x) {
+        // Without the storage, the same number would be drawn every time
+        return true;
+    }
+    x % d < n
+}
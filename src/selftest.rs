@@ -0,0 +1,155 @@
+//! A startup check of the metadata handling (`FATALLOC_SELF_TEST`)
+//!
+//! The metadata layout relies on the pointer width, the alignment of the
+//! backend's blocks, and the mangling arithmetic. This plants an allocation
+//! in a scratch mapping, round-trips it through `AllocMap` and the extremes of
+//! each metadata field through their encoding (which packs them with the
+//! `compact-meta` feature), and corrupts each checked word, the canary, and
+//! the metadata guard to confirm they are caught, so that anything off on a
+//! new platform shows up before the heap depends on it. `AllocMap`'s recovery
+//! from a corrupted root table is exercised on a scratch map, too.
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    alloc_map, config, meta, AllocInfo, MAX_SLACK, META_SLACK_SHIFT, MIN_ALIGN, MIN_MARGIN,
+};
+
+static DONE: AtomicBool = AtomicBool::new(false);
+
+/// Run [`run`] if `FATALLOC_SELF_TEST` is set and it hasn't run yet.
+#[inline]
+pub fn poll() {
+    if config::get().self_test
+        && !DONE.load(Ordering::Relaxed)
+        && !DONE.swap(true, Ordering::Relaxed)
+    {
+        run();
+    }
+}
+
+/// Run the self-test and log the result. Returns `true` if it passed.
+pub fn run() -> bool {
+    let len = crate::page_size();
+    let scratch = unsafe {
+        libc::mmap(
+            core::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if scratch == libc::MAP_FAILED {
+        warn!("self-test failed: couldn't map a scratch page");
+        return false;
+    }
+
+    let result = unsafe { run_in(NonNull::new_unchecked(scratch.cast()), len) };
+    unsafe { libc::munmap(scratch, len) };
+
+    match result {
+        Ok(()) => {
+            info!("self-test passed");
+            true
+        }
+        Err(e) => {
+            warn!("self-test failed: {e}");
+            false
+        }
+    }
+}
+
+unsafe fn run_in(scratch: NonNull<u8>, len: usize) -> Result<(), &'static str> {
+    let alloc = AllocInfo {
+        margin: MIN_MARGIN,
+        outer_ptr: scratch,
+        user_size: len - MIN_MARGIN * 2,
+        requested_size: len - MIN_MARGIN * 2 - 1,
+        align: MIN_ALIGN,
+        flags: 0,
+        poisoned_tail: 0,
+    };
+    let user_ptr = NonNull::new_unchecked(alloc.user_ptr());
+
+    // Round-trip through `AllocMap`
+    alloc
+        .mark(usize::MAX)
+        .map_err(|_| "couldn't mark the test allocation")?;
+    let found = AllocInfo::from_user_ptr(user_ptr);
+    let unmarked = AllocInfo::from_user_ptr_and_unmark(user_ptr);
+    if found.as_ref() != Ok(&alloc) || unmarked.as_ref() != Ok(&alloc) {
+        return Err("the metadata doesn't round-trip");
+    }
+    if alloc_map().get(user_ptr.as_ptr() as usize / MIN_ALIGN) {
+        return Err("the test allocation is still marked after unmarking");
+    }
+
+    // Round-trip the extremes of the metadata fields
+    let extremes = [
+        1 << (usize::BITS - 2),
+        usize::MAX,
+        1 << (usize::BITS - 2),
+        (MAX_SLACK << META_SLACK_SHIFT) | ((1 << META_SLACK_SHIFT) - 1),
+        u32::MAX as usize,
+    ];
+    meta::write_fields(user_ptr, &extremes);
+    let intact = AllocInfo::has_intact_metadata(user_ptr);
+    let read = meta::read_fields(user_ptr);
+    alloc.write_meta();
+    if !intact || read != extremes {
+        return Err("the extremes of the metadata fields don't round-trip");
+    }
+
+    // Corrupt each 32 bits covered by the checksum, the checksum included
+    if !AllocInfo::has_intact_metadata(user_ptr) {
+        return Err("intact metadata fails the checksum");
+    }
+    let checked_ptr = user_ptr
+        .as_ptr()
+        .wrapping_sub(meta::CHECKED_OFFSET)
+        .cast::<u32>();
+    for i in 0..meta::CHECKED_LEN / 4 {
+        let word = checked_ptr.wrapping_add(i);
+        let original = word.read();
+        for bit in [0, u32::BITS - 1] {
+            word.write(original ^ (1 << bit));
+            let caught = !AllocInfo::has_intact_metadata(user_ptr);
+            word.write(original);
+            if !caught {
+                return Err("a corrupted metadata word passes the checksum");
+            }
+        }
+    }
+
+    // Corrupt the canary
+    if !AllocInfo::has_intact_canary(user_ptr) {
+        return Err("an intact canary is rejected");
+    }
+    let canary = meta::canary_ptr(user_ptr);
+    let original = canary.read();
+    canary.write(original ^ 1);
+    let caught = !AllocInfo::has_intact_canary(user_ptr);
+    canary.write(original);
+    if !caught {
+        return Err("a corrupted canary is accepted");
+    }
+
+    // Corrupt the metadata guard
+    if !AllocInfo::has_intact_guard(user_ptr) {
+        return Err("an intact metadata guard is rejected");
+    }
+    let guard = meta::guard_ptr(user_ptr);
+    let original = guard.read();
+    guard.write(original ^ 1);
+    let caught = !AllocInfo::has_intact_guard(user_ptr);
+    guard.write(original);
+    if !caught {
+        return Err("a corrupted metadata guard is accepted");
+    }
+
+    crate::allocmap::self_test()
+}
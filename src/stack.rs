@@ -0,0 +1,40 @@
+//! Recognizes pointers into the current thread's stack, whose deallocation
+//! is a common and dangerous bug worth telling apart from other invalid
+//! pointers
+//!
+//! The bounds are queried on a thread's first check and cached. This only
+//! happens for pointers already found invalid, so valid deallocations don't
+//! pay for it.
+use crate::{procmaps, tls};
+
+/// The current thread's stack bounds, or `0` if not queried yet
+static START: tls::Key = tls::Key::INIT;
+static END: tls::Key = tls::Key::INIT;
+
+/// Check if `ptr` points into the current thread's stack.
+pub fn contains(ptr: *const u8) -> bool {
+    let here = 0u8;
+    let here = &here as *const u8 as usize;
+    // The main thread's stack may have grown since the bounds were cached,
+    // but the live frames are all above this one
+    bounds(here).map_or(false, |(start, end)| {
+        (start.min(here)..end).contains(&(ptr as usize))
+    })
+}
+
+/// Get the bounds of the mapping containing `here`, an address in the
+/// current stack frame.
+fn bounds(here: usize) -> Option<(usize, usize)> {
+    let end = END.get();
+    if end != 0 {
+        return Some((START.get(), end));
+    }
+
+    // `pthread_getattr_np` would be simpler, but glibc allocates memory and
+    // takes locks in it, which our callers may be in the middle of. A guard
+    // page keeps the stack from merging with mappings below it.
+    let (start, end) = procmaps::range(here)?;
+    START.set(start);
+    END.set(end);
+    Some((start, end))
+}
@@ -0,0 +1,391 @@
+//! Global allocation statistics
+//!
+//! The byte counts change on every allocation, so each thread keeps its own
+//! [`Counters`] to avoid contending on shared atomics, and the readers sum
+//! them up. A thread's counters are flushed into the shared ones when it
+//! exits, and the block is reused by a later thread.
+//!
+//! The allocation rate is derived from the allocation count sampled by its
+//! readers, so allocations aren't timestamped.
+use core::{
+    cell::UnsafeCell,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::tls;
+
+/// The per-thread counters. Only the owning thread writes them, so an update
+/// is a plain load and store. They wrap around when the thread frees more
+/// than it allocated, which cancels out in the sum.
+///
+/// A block is never unmapped once it's linked into [`BLOCKS`].
+#[repr(align(64))] // Keep it from sharing a cache line with another thread's
+struct Counters {
+    bytes_live: AtomicUsize,
+    overhead_bytes: AtomicUsize,
+    num_allocations: AtomicUsize,
+    num_live_allocations: AtomicUsize,
+    /// Owned by a thread
+    in_use: AtomicBool,
+    /// The next block in [`BLOCKS`], set before the block is linked
+    next: *const Counters,
+}
+
+/// The list of every [`Counters`] ever created
+static BLOCKS: AtomicPtr<Counters> = AtomicPtr::new(null_mut());
+
+/// The calling thread's [`Counters`]
+static LOCAL: tls::Key = tls::Key::with_destructor(release_counters);
+
+/// The IDs of the threads that most recently ran [`release_counters`], for
+/// [`has_exited`]
+static EXITED: [AtomicUsize; 64] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; 64]
+};
+
+/// The next element of [`EXITED`] to overwrite
+static NEXT_EXITED: AtomicUsize = AtomicUsize::new(0);
+
+/// The shared part of the total size of live allocations, excluding margins.
+/// It's where exited threads' counts go, and with `FATALLOC_MEMORY_LIMIT`,
+/// it's the only part so that the limit is checked against the exact total.
+static BYTES_LIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// The shared part of the total size of the margins of live allocations
+static OVERHEAD_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The shared part of the number of allocations made so far
+static NUM_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The shared part of the number of live allocations
+static NUM_LIVE_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of events passed to `logger::report`, including suppressed
+/// ones. They are rare enough to be counted in one place.
+static NUM_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the calling thread's [`Counters`], creating them on its first call.
+/// Returns `None` if thread-local storage or memory is unavailable, in which
+/// case the shared counters are updated instead.
+#[inline]
+fn local() -> Option<&'static Counters> {
+    match LOCAL.get() {
+        0 => register(),
+        p => Some(unsafe { &*(p as *const Counters) }),
+    }
+}
+
+#[cold]
+fn register() -> Option<&'static Counters> {
+    // Don't claim a block only to give it back on every call
+    if !LOCAL.is_available() || has_exited() {
+        return None;
+    }
+    let counters = reuse_counters().or_else(new_counters)?;
+    if !LOCAL.set(counters as *const Counters as usize) {
+        counters.in_use.store(false, Ordering::Release);
+        return None;
+    }
+    Some(counters)
+}
+
+/// Check if the calling thread has run [`release_counters`], i.e., it's
+/// exiting and calling us from a later destructor or glibc's cleanup. A block
+/// it claimed now would never be released because the destructor doesn't run
+/// again.
+///
+/// Only the latest exits are remembered, so a thread that many others have
+/// exited after is missed. A new thread reusing the ID of a recently exited
+/// one is taken for it, and only uses the shared counters.
+fn has_exited() -> bool {
+    let tid = crate::current_thread_id();
+    EXITED.iter().any(|x| x.load(Ordering::Relaxed) == tid)
+}
+
+/// Claim a block released by an exited thread.
+fn reuse_counters() -> Option<&'static Counters> {
+    let mut p = BLOCKS.load(Ordering::Acquire);
+    while let Some(counters) = unsafe { p.as_ref() } {
+        if !counters.in_use.load(Ordering::Relaxed)
+            && !counters.in_use.swap(true, Ordering::Acquire)
+        {
+            return Some(counters);
+        }
+        p = counters.next as *mut Counters;
+    }
+    None
+}
+
+/// Map a new block and link it into [`BLOCKS`].
+fn new_counters() -> Option<&'static Counters> {
+    let p = unsafe {
+        libc::mmap(
+            null_mut(),
+            core::mem::size_of::<Counters>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if p == libc::MAP_FAILED {
+        return None;
+    }
+    let p: *mut Counters = p.cast();
+
+    let mut head = BLOCKS.load(Ordering::Relaxed);
+    loop {
+        unsafe {
+            p.write(Counters {
+                bytes_live: AtomicUsize::new(0),
+                overhead_bytes: AtomicUsize::new(0),
+                num_allocations: AtomicUsize::new(0),
+                num_live_allocations: AtomicUsize::new(0),
+                in_use: AtomicBool::new(true),
+                next: head,
+            })
+        };
+        match BLOCKS.compare_exchange_weak(head, p, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return Some(unsafe { &*p }),
+            Err(x) => head = x,
+        }
+    }
+}
+
+/// The destructor of [`LOCAL`]: flush the exiting thread's counts into the
+/// shared counters and release the block.
+///
+/// A reader summing the counters at the same time may miss or double count
+/// the flushed amount.
+unsafe extern "C" fn release_counters(p: *mut libc::c_void) {
+    let counters = &*(p as *const Counters);
+    BYTES_LIVE.fetch_add(
+        counters.bytes_live.swap(0, Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+    OVERHEAD_BYTES.fetch_add(
+        counters.overhead_bytes.swap(0, Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+    NUM_ALLOCATIONS.fetch_add(
+        counters.num_allocations.swap(0, Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+    NUM_LIVE_ALLOCATIONS.fetch_add(
+        counters.num_live_allocations.swap(0, Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+    counters.in_use.store(false, Ordering::Release);
+
+    let i = NEXT_EXITED.fetch_add(1, Ordering::Relaxed) % EXITED.len();
+    EXITED[i].store(crate::current_thread_id(), Ordering::Relaxed);
+}
+
+/// Add `delta` (wrapping) to the calling thread's counter chosen by `select`,
+/// or to `shared` if it has no counters.
+#[inline]
+fn add(shared: &AtomicUsize, select: impl FnOnce(&Counters) -> &AtomicUsize, delta: usize) {
+    match local() {
+        Some(counters) => {
+            let counter = select(counters);
+            counter.store(
+                counter.load(Ordering::Relaxed).wrapping_add(delta),
+                Ordering::Relaxed,
+            );
+        }
+        None => {
+            shared.fetch_add(delta, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sum `shared` and every thread's counter chosen by `select`.
+fn sum(shared: &AtomicUsize, select: impl Fn(&Counters) -> &AtomicUsize) -> usize {
+    let mut total = shared.load(Ordering::Relaxed);
+    let mut p = BLOCKS.load(Ordering::Acquire);
+    while let Some(counters) = unsafe { p.as_ref() } {
+        total = total.wrapping_add(select(counters).load(Ordering::Relaxed));
+        p = counters.next as *mut Counters;
+    }
+    total
+}
+
+/// Check if live bytes are only counted in [`BYTES_LIVE`].
+#[inline]
+fn is_live_shared() -> bool {
+    crate::config::get().memory_limit != usize::MAX
+}
+
+/// The statistics at a point in time. Updates made by other threads while
+/// it's taken may or may not be included.
+#[cfg(feature = "stats")]
+pub struct Snapshot {
+    pub bytes_live: usize,
+    pub overhead_bytes: usize,
+    pub num_allocations: usize,
+    pub num_events: usize,
+}
+
+#[cfg(feature = "stats")]
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        bytes_live: bytes_live(),
+        overhead_bytes: overhead_bytes(),
+        num_allocations: num_allocations(),
+        num_events: num_events(),
+    }
+}
+
+pub fn bytes_live() -> usize {
+    sum(&BYTES_LIVE, |counters| &counters.bytes_live)
+}
+
+/// Account for `size` more live bytes unless it would make [`bytes_live`]
+/// exceed `limit`. Returns `false` if it would.
+#[inline]
+pub fn try_add_live(size: usize, limit: usize) -> bool {
+    if !is_live_shared() {
+        add(&BYTES_LIVE, |counters| &counters.bytes_live, size);
+        return true;
+    }
+    BYTES_LIVE
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+            live.checked_add(size).filter(|&new_live| new_live <= limit)
+        })
+        .is_ok()
+}
+
+/// Account for `size` fewer live bytes.
+#[inline]
+pub fn sub_live(size: usize) {
+    if is_live_shared() {
+        BYTES_LIVE.fetch_sub(size, Ordering::Relaxed);
+    } else {
+        add(
+            &BYTES_LIVE,
+            |counters| &counters.bytes_live,
+            size.wrapping_neg(),
+        );
+    }
+}
+
+pub fn overhead_bytes() -> usize {
+    sum(&OVERHEAD_BYTES, |counters| &counters.overhead_bytes)
+}
+
+#[inline]
+pub fn add_overhead(size: usize) {
+    add(&OVERHEAD_BYTES, |counters| &counters.overhead_bytes, size);
+}
+
+#[inline]
+pub fn sub_overhead(size: usize) {
+    add(
+        &OVERHEAD_BYTES,
+        |counters| &counters.overhead_bytes,
+        size.wrapping_neg(),
+    );
+}
+
+pub fn num_allocations() -> usize {
+    sum(&NUM_ALLOCATIONS, |counters| &counters.num_allocations)
+}
+
+#[inline]
+pub fn count_allocation() {
+    add(&NUM_ALLOCATIONS, |counters| &counters.num_allocations, 1);
+    add(
+        &NUM_LIVE_ALLOCATIONS,
+        |counters| &counters.num_live_allocations,
+        1,
+    );
+}
+
+pub fn num_live_allocations() -> usize {
+    sum(&NUM_LIVE_ALLOCATIONS, |counters| {
+        &counters.num_live_allocations
+    })
+}
+
+#[inline]
+pub fn count_deallocation() {
+    add(
+        &NUM_LIVE_ALLOCATIONS,
+        |counters| &counters.num_live_allocations,
+        1usize.wrapping_neg(),
+    );
+}
+
+/// The interval over which [`churn_rate`] measures the allocation rate, in
+/// milliseconds
+const CHURN_INTERVAL_MS: usize = 1000;
+
+/// The state of [`churn_rate`]
+struct Churn {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    /// The time (in milliseconds of `CLOCK_MONOTONIC`) and
+    /// [`num_allocations`] when the current interval started, if any
+    start: UnsafeCell<Option<(usize, usize)>>,
+    /// The rate over the last full interval
+    rate: UnsafeCell<usize>,
+}
+
+unsafe impl Sync for Churn {}
+
+static CHURN: Churn = Churn {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    start: UnsafeCell::new(None),
+    rate: UnsafeCell::new(0),
+};
+
+/// Get [`churn_rate`]'s lock for the `fork` handlers.
+pub fn churn_fork_mutex() -> *mut libc::pthread_mutex_t {
+    CHURN.mutex.get()
+}
+
+/// Get the number of allocations per second over the last full interval of
+/// at least [`CHURN_INTERVAL_MS`], which ends at the call that completes it.
+/// Returns `0` until the first interval is complete; the first call starts
+/// it.
+pub fn churn_rate() -> usize {
+    unsafe {
+        libc::pthread_mutex_lock(CHURN.mutex.get());
+        // Sampled in the lock so that the samples are in order
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+        let now = ts.tv_sec as usize * 1000 + ts.tv_nsec as usize / 1_000_000;
+        let count = num_allocations();
+
+        let start = &mut *CHURN.start.get();
+        let rate = &mut *CHURN.rate.get();
+        match *start {
+            Some((start_time, start_count)) if now - start_time >= CHURN_INTERVAL_MS => {
+                let num = count.wrapping_sub(start_count) as u64 * 1000;
+                *rate = (num / (now - start_time) as u64) as usize;
+                *start = Some((now, count));
+            }
+            Some(_) => {}
+            None => *start = Some((now, count)),
+        }
+        let rate = *rate;
+        libc::pthread_mutex_unlock(CHURN.mutex.get());
+        rate
+    }
+}
+
+#[cfg(feature = "stats")]
+#[inline]
+pub fn num_events() -> usize {
+    NUM_EVENTS.load(Ordering::Relaxed)
+}
+
+#[inline]
+pub fn count_event() {
+    NUM_EVENTS.fetch_add(1, Ordering::Relaxed);
+}
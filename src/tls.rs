@@ -0,0 +1,94 @@
+//! Thread-local storage backed by `pthread_key_t`, which doesn't need
+//! `#[thread_local]` (unstable) or `std`
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A thread-local `usize`, initially zero
+pub struct Key {
+    /// `UNINIT`, `BUSY`, `FAILED`, or `READY + key`
+    state: AtomicUsize,
+    /// Called with the value on thread exit if it's non-zero
+    destructor: Option<unsafe extern "C" fn(*mut libc::c_void)>,
+}
+
+const UNINIT: usize = 0;
+const BUSY: usize = 1;
+const FAILED: usize = 2;
+const READY: usize = 3;
+
+/// glibc allocates storage for keys beyond this limit by `calloc`, which
+/// would recurse into us
+const MAX_KEYS: usize = 32;
+
+impl Key {
+    pub const INIT: Self = Self {
+        state: AtomicUsize::new(UNINIT),
+        destructor: None,
+    };
+
+    /// A key calling `destructor` with the value when a thread having a
+    /// non-zero value exits. The value is reset to zero before the call.
+    pub const fn with_destructor(destructor: unsafe extern "C" fn(*mut libc::c_void)) -> Self {
+        Self {
+            state: AtomicUsize::new(UNINIT),
+            destructor: Some(destructor),
+        }
+    }
+
+    #[inline]
+    fn key(&self) -> Option<libc::pthread_key_t> {
+        match self.state.load(Ordering::Acquire) {
+            UNINIT => self.init(),
+            state if state >= READY => Some((state - READY) as libc::pthread_key_t),
+            _ => None,
+        }
+    }
+
+    #[cold]
+    fn init(&self) -> Option<libc::pthread_key_t> {
+        // If another thread is creating the key, pretend the storage is
+        // unavailable for now instead of waiting
+        self.state
+            .compare_exchange(UNINIT, BUSY, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+
+        let mut key = 0;
+        let key = if unsafe { libc::pthread_key_create(&mut key, self.destructor) } != 0 {
+            None
+        } else if key as usize >= MAX_KEYS {
+            unsafe { libc::pthread_key_delete(key) };
+            None
+        } else {
+            Some(key)
+        };
+
+        self.state.store(
+            key.map_or(FAILED, |key| key as usize + READY),
+            Ordering::Release,
+        );
+        key
+    }
+
+    /// Check if the storage is available. Failing to create the key is
+    /// remembered, so this is cheap to call again afterwards.
+    #[inline]
+    pub fn is_available(&self) -> bool {
+        self.key().is_some()
+    }
+
+    /// Get the current thread's value. Returns zero if the storage is
+    /// unavailable.
+    #[inline]
+    pub fn get(&self) -> usize {
+        self.key()
+            .map_or(0, |key| unsafe { libc::pthread_getspecific(key) } as usize)
+    }
+
+    /// Set the current thread's value. Returns `false` if the storage is
+    /// unavailable.
+    #[inline]
+    pub fn set(&self, value: usize) -> bool {
+        self.key().map_or(false, |key| unsafe {
+            libc::pthread_setspecific(key, value as *const libc::c_void) == 0
+        })
+    }
+}
@@ -0,0 +1,372 @@
+//! Reports every access to a watched allocation (`fatalloc_watch`)
+//!
+//! The pages of a watched allocation are mapped `PROT_NONE`, so that an
+//! access faults. The `SIGSEGV` handler reports the access, unprotects the
+//! pages, and sets the trap flag to single-step the faulting instruction, and
+//! the `SIGTRAP` handler that follows protects them again. The pages stay
+//! accessible while any thread is stepping, so an access by another thread in
+//! the meantime goes unreported. Other architectures can't single-step without
+//! a debugger, so a watch is lifted on the first access instead.
+//!
+//! A system call doesn't fault on a watched page but fails with `EFAULT`, so
+//! passing a watched buffer to, e.g., `read` isn't reported and breaks the
+//! call.
+//!
+//! An access to a single-threaded allocation (`fatalloc_mark_single_threaded`)
+//! by another thread is reported as a cross-thread access, too.
+//!
+//! `FATALLOC_DETECT_UNWRITTEN` tracks new allocations the same way, but maps
+//! them `PROT_READ` and lifts the tracking on the first write, so an
+//! allocation still tracked when it's freed was never written.
+//!
+//! A signal that isn't from a watchpoint is passed to the handler that was
+//! installed before, or gets the default action.
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The number of allocations watched at once at most
+const CAPACITY: usize = 16;
+
+/// The number of allocations tracked by `FATALLOC_DETECT_UNWRITTEN` at once
+/// at most
+const UNWRITTEN_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct Watch {
+    /// The watched allocation, or `0` if vacant
+    ptr: usize,
+    size: usize,
+    /// The protected pages
+    start: usize,
+    end: usize,
+}
+
+const VACANT: Watch = Watch {
+    ptr: 0,
+    size: 0,
+    start: 0,
+    end: 0,
+};
+
+struct Inner {
+    watches: [Watch; CAPACITY],
+    /// The allocations not written yet
+    unwritten: [Watch; UNWRITTEN_CAPACITY],
+    /// The number of threads single-stepping an access
+    num_stepping: usize,
+}
+
+struct Table {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    inner: UnsafeCell<Inner>,
+}
+
+unsafe impl Sync for Table {}
+
+static TABLE: Table = Table {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    inner: UnsafeCell::new(Inner {
+        watches: [VACANT; CAPACITY],
+        unwritten: [VACANT; UNWRITTEN_CAPACITY],
+        num_stepping: 0,
+    }),
+};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The handlers replaced by ours, for `SIGSEGV` and `SIGTRAP`. Written once
+/// before any page is watched.
+struct OldActions(UnsafeCell<MaybeUninit<[libc::sigaction; 2]>>);
+
+unsafe impl Sync for OldActions {}
+
+static OLD_ACTIONS: OldActions = OldActions(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// Run `f` with the table locked.
+///
+/// The signal handlers lock it, too. They can't interrupt a thread holding
+/// it because an access to a watched page never happens under the lock.
+fn with_inner<R>(f: impl FnOnce(&mut Inner) -> R) -> R {
+    unsafe {
+        libc::pthread_mutex_lock(TABLE.mutex.get());
+        let result = f(&mut *TABLE.inner.get());
+        libc::pthread_mutex_unlock(TABLE.mutex.get());
+        result
+    }
+}
+
+/// Get the table's lock for the `fork` handlers.
+pub fn fork_mutex() -> *mut libc::pthread_mutex_t {
+    TABLE.mutex.get()
+}
+
+/// Start watching the allocation `ptr` of `size` bytes by protecting the
+/// pages `start..end`.
+pub unsafe fn add(ptr: usize, size: usize, start: usize, end: usize) -> Result<(), &'static str> {
+    install()?;
+    with_inner(|inner| {
+        let watch = inner
+            .watches
+            .iter_mut()
+            .find(|watch| watch.ptr == 0)
+            .ok_or("too many watched allocations")?;
+        let ret = libc::mprotect(start as *mut libc::c_void, end - start, libc::PROT_NONE);
+        if ret != 0 {
+            return Err("mprotect failed");
+        }
+        *watch = Watch {
+            ptr,
+            size,
+            start,
+            end,
+        };
+        Ok(())
+    })
+}
+
+/// Stop watching the allocation `ptr` and make its pages accessible again.
+/// Does nothing if it's not watched.
+pub unsafe fn remove(ptr: usize) {
+    with_inner(|inner| {
+        if let Some(watch) = inner.watches.iter_mut().find(|watch| watch.ptr == ptr) {
+            unprotect(watch);
+            watch.ptr = 0;
+        }
+    })
+}
+
+/// Start tracking the new allocation `ptr` of `size` bytes until it's first
+/// written by write-protecting the pages `start..end`.
+pub unsafe fn add_unwritten(
+    ptr: usize,
+    size: usize,
+    start: usize,
+    end: usize,
+) -> Result<(), &'static str> {
+    install()?;
+    with_inner(|inner| {
+        let watch = inner
+            .unwritten
+            .iter_mut()
+            .find(|watch| watch.ptr == 0)
+            .ok_or("too many allocations tracked until written")?;
+        let ret = libc::mprotect(start as *mut libc::c_void, end - start, libc::PROT_READ);
+        if ret != 0 {
+            return Err("mprotect failed");
+        }
+        *watch = Watch {
+            ptr,
+            size,
+            start,
+            end,
+        };
+        Ok(())
+    })
+}
+
+/// Stop tracking the allocation `ptr` until it's written and make its pages
+/// writable again. Returns `false` if it's not tracked, e.g., because it was
+/// written.
+pub unsafe fn remove_unwritten(ptr: usize) -> bool {
+    with_inner(
+        |inner| match inner.unwritten.iter_mut().find(|watch| watch.ptr == ptr) {
+            Some(watch) => {
+                unprotect(watch);
+                watch.ptr = 0;
+                true
+            }
+            None => false,
+        },
+    )
+}
+
+unsafe fn unprotect(watch: &Watch) {
+    let ret = libc::mprotect(
+        watch.start as *mut libc::c_void,
+        watch.end - watch.start,
+        libc::PROT_READ | libc::PROT_WRITE,
+    );
+    assert_eq!(ret, 0, "failed to unprotect a watched allocation");
+}
+
+#[cold]
+fn install() -> Result<(), &'static str> {
+    if INSTALLED.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+    unsafe {
+        let old_actions = (*OLD_ACTIONS.0.get()).as_mut_ptr() as *mut libc::sigaction;
+        let handlers = [
+            (libc::SIGSEGV, handle_segv as libc::sighandler_t),
+            (libc::SIGTRAP, handle_trap as libc::sighandler_t),
+        ];
+        for (i, (signal, handler)) in handlers.into_iter().enumerate() {
+            let mut action: libc::sigaction = core::mem::zeroed();
+            action.sa_sigaction = handler;
+            action.sa_flags = libc::SA_SIGINFO;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, old_actions.add(i)) != 0 {
+                INSTALLED.store(false, Ordering::Relaxed);
+                return Err("failed to install the signal handlers");
+            }
+        }
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn handle_segv(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let addr = (*info).si_addr() as usize;
+    let handled = with_inner(|inner| {
+        let is_hit =
+            |watch: &&mut Watch| watch.ptr != 0 && (watch.start..watch.end).contains(&addr);
+
+        // Only a write faults on these pages
+        if let Some(watch) = inner.unwritten.iter_mut().find(is_hit) {
+            let (ptr, size, offset) = (watch.ptr as *const u8, watch.size, addr - watch.ptr);
+            unprotect(watch);
+            watch.ptr = 0;
+            info!("allocation {ptr:p} of {size} bytes is first written at offset {offset}");
+            return true;
+        }
+
+        let watch = match inner.watches.iter_mut().find(is_hit) {
+            Some(watch) => watch,
+            None => return false,
+        };
+        let (ptr, size, offset) = (watch.ptr as *const u8, watch.size, addr - watch.ptr);
+        unprotect(watch);
+        crate::check_owner(NonNull::new_unchecked(ptr as *mut u8), "access");
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let (is_write, ip) = arch::describe_fault(context);
+            let kind = if is_write { "written" } else { "read" };
+            warn!(
+                "watched allocation {ptr:p} of {size} bytes {kind} at offset {offset} \
+                by the instruction at {ip:#x}"
+            );
+            // An instruction touching two watched allocations faults twice
+            // but is stepped once
+            if arch::start_step(context) {
+                inner.num_stepping += 1;
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            let _ = context;
+            warn!(
+                "watched allocation {ptr:p} of {size} bytes accessed at offset {offset}; \
+                it's no longer watched"
+            );
+            watch.ptr = 0;
+        }
+        true
+    });
+    if !handled {
+        chain(0, signal, info, context);
+    }
+}
+
+unsafe extern "C" fn handle_trap(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    #[cfg(target_arch = "x86_64")]
+    if arch::is_stepping(context) {
+        let handled = with_inner(|inner| {
+            if inner.num_stepping == 0 {
+                return false;
+            }
+            inner.num_stepping -= 1;
+            if inner.num_stepping == 0 {
+                for watch in inner.watches.iter().filter(|watch| watch.ptr != 0) {
+                    libc::mprotect(
+                        watch.start as *mut libc::c_void,
+                        watch.end - watch.start,
+                        libc::PROT_NONE,
+                    );
+                }
+            }
+            true
+        });
+        if handled {
+            arch::finish_step(context);
+            return;
+        }
+    }
+    chain(1, signal, info, context);
+}
+
+/// Pass a signal to the handler `OLD_ACTIONS[i]`.
+unsafe fn chain(
+    i: usize,
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let old_action = &(*(*OLD_ACTIONS.0.get()).as_ptr())[i];
+    if old_action.sa_flags & libc::SA_SIGINFO != 0 {
+        let handler: unsafe extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+            core::mem::transmute(old_action.sa_sigaction);
+        handler(signal, info, context);
+    } else if old_action.sa_sigaction == libc::SIG_DFL || old_action.sa_sigaction == libc::SIG_IGN {
+        // Restore it and raise the signal again. It's delivered when this
+        // handler returns.
+        libc::sigaction(signal, old_action, core::ptr::null_mut());
+        libc::raise(signal);
+    } else {
+        let handler: unsafe extern "C" fn(libc::c_int) =
+            core::mem::transmute(old_action.sa_sigaction);
+        handler(signal);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    /// `EFLAGS.TF`
+    const TRAP_FLAG: libc::greg_t = 1 << 8;
+    /// The write bit of a page fault's error code
+    const ERR_WRITE: libc::greg_t = 1 << 1;
+
+    unsafe fn gregs<'a>(context: *mut libc::c_void) -> &'a mut [libc::greg_t; 23] {
+        &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs
+    }
+
+    /// Get whether the faulting access is a write and the address of the
+    /// faulting instruction.
+    pub unsafe fn describe_fault(context: *mut libc::c_void) -> (bool, usize) {
+        let gregs = gregs(context);
+        (
+            gregs[libc::REG_ERR as usize] & ERR_WRITE != 0,
+            gregs[libc::REG_RIP as usize] as usize,
+        )
+    }
+
+    /// Set the trap flag so that a `SIGTRAP` follows the instruction. Returns
+    /// `false` if it's already set.
+    pub unsafe fn start_step(context: *mut libc::c_void) -> bool {
+        let flags = &mut gregs(context)[libc::REG_EFL as usize];
+        let was_stepping = *flags & TRAP_FLAG != 0;
+        *flags |= TRAP_FLAG;
+        !was_stepping
+    }
+
+    /// Get whether the trap flag is set.
+    pub unsafe fn is_stepping(context: *mut libc::c_void) -> bool {
+        gregs(context)[libc::REG_EFL as usize] & TRAP_FLAG != 0
+    }
+
+    /// Clear the trap flag.
+    pub unsafe fn finish_step(context: *mut libc::c_void) {
+        gregs(context)[libc::REG_EFL as usize] &= !TRAP_FLAG;
+    }
+}
@@ -0,0 +1,25 @@
+// A `dlsym` that allocates a block when `malloc` is first looked up and
+// keeps it in `dlsym_block`, like a C library whose `dlsym` needs memory for
+// its error state. Preloaded after fatalloc, it's called by fatalloc's
+// lookup of the system allocator, so the block is allocated in the middle
+// of it.
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <stdlib.h>
+#include <string.h>
+
+void *dlsym_block;
+
+void *dlsym(void *handle, const char *name) {
+    static void *(*real_dlsym)(void *, const char *);
+    if (!real_dlsym) {
+        real_dlsym = (void *(*)(void *, const char *))dlvsym(RTLD_NEXT, "dlsym", "GLIBC_2.34");
+        if (!real_dlsym) {
+            real_dlsym = (void *(*)(void *, const char *))dlvsym(RTLD_NEXT, "dlsym", "GLIBC_2.2.5");
+        }
+    }
+    if (!dlsym_block && strcmp(name, "malloc") == 0) {
+        dlsym_block = malloc(100);
+    }
+    return real_dlsym(handle, name);
+}
@@ -0,0 +1,16 @@
+// A faulty `realloc` that flips a bit in the middle of every block it
+// returns. Preloaded after fatalloc, it stands in for the system allocator's
+// with `FATALLOC_BACKEND=system`.
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <stddef.h>
+
+void *realloc(void *ptr, size_t size) {
+    void *(*real_realloc)(void *, size_t) =
+        (void *(*)(void *, size_t))dlsym(RTLD_NEXT, "realloc");
+    char *p = real_realloc(ptr, size);
+    if (p) {
+        p[size / 2] ^= 1;
+    }
+    return p;
+}
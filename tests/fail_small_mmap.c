@@ -0,0 +1,24 @@
+// An `mmap` that fails for mappings of up to a page while `FAIL_SMALL_MMAP`
+// is set, so that the allocation map can't map a new leaf and a reallocation
+// returns an allocation it doesn't track. If it's set to `once`, only the
+// first such mapping fails. Preloaded after fatalloc, it stands in for the C
+// library's.
+#include <errno.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mman.h>
+
+void *__mmap(void *addr, size_t len, int prot, int flags, int fd, off_t offset);
+
+// The number of mappings failed so far
+int num_failed_mmaps;
+
+void *mmap(void *addr, size_t len, int prot, int flags, int fd, off_t offset) {
+    const char *fail = getenv("FAIL_SMALL_MMAP");
+    if (len <= 4096 && fail && (strcmp(fail, "once") != 0 || num_failed_mmaps == 0)) {
+        ++num_failed_mmaps;
+        errno = ENOMEM;
+        return MAP_FAILED;
+    }
+    return __mmap(addr, len, prot, flags, fd, offset);
+}
@@ -0,0 +1,21 @@
+// A faulty `posix_memalign` that returns a block 8 bytes off the requested
+// alignment while `MISALIGN_MEMALIGN` is set. Preloaded after fatalloc, it
+// stands in for the system allocator's with `FATALLOC_BACKEND=system`.
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <stdlib.h>
+
+int posix_memalign(void **out, size_t align, size_t size) {
+    int (*real_posix_memalign)(void **, size_t, size_t) =
+        (int (*)(void **, size_t, size_t))dlsym(RTLD_NEXT, "posix_memalign");
+    if (!getenv("MISALIGN_MEMALIGN")) {
+        return real_posix_memalign(out, align, size);
+    }
+    // Leaked, as the block can't be freed by the pointer returned
+    char *p;
+    int result = real_posix_memalign((void **)&p, align, size + 8);
+    if (result == 0) {
+        *out = p + 8;
+    }
+    return result;
+}
@@ -0,0 +1,49 @@
+// Entropy sources that fail while `NO_GETRANDOM`, `NO_URANDOM`, or
+// `NO_AT_RANDOM` is set, so that the metadata keys are seeded from the next
+// source. Preloaded after fatalloc, they stand in for the C library's.
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <stdarg.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/auxv.h>
+#include <sys/syscall.h>
+
+int __open(const char *path, int flags, ...);
+unsigned long __getauxval(unsigned long type);
+
+long syscall(long number, ...) {
+    if (number == SYS_getrandom && getenv("NO_GETRANDOM")) {
+        errno = ENOSYS;
+        return -1;
+    }
+    va_list ap;
+    va_start(ap, number);
+    long a = va_arg(ap, long), b = va_arg(ap, long), c = va_arg(ap, long);
+    long d = va_arg(ap, long), e = va_arg(ap, long), f = va_arg(ap, long);
+    va_end(ap);
+    long (*real_syscall)(long, ...) = (long (*)(long, ...))dlsym(RTLD_NEXT, "syscall");
+    return real_syscall(number, a, b, c, d, e, f);
+}
+
+int open(const char *path, int flags, ...) {
+    if (strcmp(path, "/dev/urandom") == 0 && getenv("NO_URANDOM")) {
+        errno = ENOENT;
+        return -1;
+    }
+    va_list ap;
+    va_start(ap, flags);
+    int mode = (flags & (O_CREAT | O_TMPFILE)) ? va_arg(ap, int) : 0;
+    va_end(ap);
+    return __open(path, flags, mode);
+}
+
+unsigned long getauxval(unsigned long type) {
+    if (type == AT_RANDOM && getenv("NO_AT_RANDOM")) {
+        errno = ENOENT;
+        return 0;
+    }
+    return __getauxval(type);
+}
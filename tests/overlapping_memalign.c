@@ -0,0 +1,24 @@
+// A faulty `posix_memalign` that returns a block overlapping with the previous
+// one while `OVERLAP_MEMALIGN` is set. Preloaded after fatalloc, it stands in
+// for the system allocator's with `FATALLOC_BACKEND=system`.
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+static char *last_ptr;
+
+int posix_memalign(void **out, size_t align, size_t size) {
+    if (last_ptr && getenv("OVERLAP_MEMALIGN")) {
+        // The first half of the previous block is the second half of this
+        *out = last_ptr - (size / 2 & ~(align - 1));
+        return 0;
+    }
+    int (*real_posix_memalign)(void **, size_t, size_t) =
+        (int (*)(void **, size_t, size_t))dlsym(RTLD_NEXT, "posix_memalign");
+    int result = real_posix_memalign(out, align, size);
+    if (result == 0) {
+        last_ptr = *out;
+    }
+    return result;
+}
@@ -0,0 +1,21 @@
+// A `sysconf` that reports `PHYS_PAGES` (from the environment) for
+// `_SC_PHYS_PAGES`, or fails if it's unset, so that the quarantine budget
+// sized by `FATALLOC_QUARANTINE_FRACTION` doesn't depend on the machine.
+// Preloaded after fatalloc, it stands in for the C library's.
+#include <errno.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+long __sysconf(int name);
+
+long sysconf(int name) {
+    if (name != _SC_PHYS_PAGES) {
+        return __sysconf(name);
+    }
+    const char *pages = getenv("PHYS_PAGES");
+    if (!pages) {
+        errno = EINVAL;
+        return -1;
+    }
+    return strtol(pages, NULL, 10);
+}
@@ -0,0 +1,26 @@
+// A faulty `posix_memalign` that returns the previous block again, without it
+// being freed, when the same size is requested twice in a row. Preloaded after
+// fatalloc, it stands in for the system allocator's with
+// `FATALLOC_BACKEND=system`.
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <stddef.h>
+
+static void *last_ptr;
+static size_t last_size;
+
+int posix_memalign(void **out, size_t align, size_t size) {
+    if (last_ptr && size == last_size) {
+        *out = last_ptr;
+        last_ptr = NULL;
+        return 0;
+    }
+    int (*real_posix_memalign)(void **, size_t, size_t) =
+        (int (*)(void **, size_t, size_t))dlsym(RTLD_NEXT, "posix_memalign");
+    int result = real_posix_memalign(out, align, size);
+    if (result == 0) {
+        last_ptr = *out;
+        last_size = size;
+    }
+    return result;
+}
@@ -0,0 +1,367 @@
+#!/bin/sh
+# Runs `smoke.c` under `LD_PRELOAD` and checks fatalloc's diagnostics.
+# Usage: tests/run.sh path/to/libfatalloc.so
+#
+# Set `PASSTHROUGH=1` if the library is built with `backend-passthrough`,
+# `REENTRANCY_CHECK=1` if with `reentrancy-check`, `MAP_STATS=1` if with
+# `map-stats`, `BUMP_BACKEND=1` if with `bump-backend`, `COMPACT_META=1` if
+# with `compact-meta`, `POINTER_TAG=1` if with `pointer-tag`, `STATS=1` if with
+# `stats`, and `BACKTRACE=1` if with `backtrace` and unwind tables
+# (`RUSTFLAGS="-C force-unwind-tables"`), without which no call stack can be
+# captured.
+#
+# `smoke bench` times allocation paths instead and isn't run here.
+set -eu
+
+lib=$(realpath "${1:?usage: $0 path/to/libfatalloc.so}")
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+${CC:-cc} -O0 -o "$tmp/smoke" "$(dirname "$0")/smoke.c" -ldl -pthread
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/buggy_realloc.so" "$(dirname "$0")/buggy_realloc.c" -ldl
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/short_usable_size.so" "$(dirname "$0")/short_usable_size.c" -ldl
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/recycling_memalign.so" "$(dirname "$0")/recycling_memalign.c" -ldl
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/misaligning_memalign.so" "$(dirname "$0")/misaligning_memalign.c" -ldl
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/overlapping_memalign.so" "$(dirname "$0")/overlapping_memalign.c" -ldl
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/phys_pages.so" "$(dirname "$0")/phys_pages.c"
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/fail_small_mmap.so" "$(dirname "$0")/fail_small_mmap.c"
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/allocating_dlsym.so" "$(dirname "$0")/allocating_dlsym.c" -ldl
+${CC:-cc} -O0 -shared -fPIC -o "$tmp/no_entropy.so" "$(dirname "$0")/no_entropy.c" -ldl
+
+# What `LD_PRELOAD` is set to
+preload=$lib
+
+num_failed=0
+
+# check_status <expected exit status> <scenario> <expected stderr pattern or
+#     empty> [VAR=value ...]
+check_status() {
+    expected_status=$1
+    scenario=$2
+    pattern=$3
+    shift 3
+    status=0
+    env "$@" LD_PRELOAD="$preload" "$tmp/smoke" "$scenario" 2> "$tmp/stderr" || status=$?
+    if [ "$status" -ne "$expected_status" ]; then
+        echo "FAIL $scenario: exited with $status"
+        num_failed=$((num_failed + 1))
+    elif [ -z "$pattern" ] && [ -s "$tmp/stderr" ]; then
+        echo "FAIL $scenario: unexpected output"
+        num_failed=$((num_failed + 1))
+    elif [ -n "$pattern" ] && ! grep -q -- "$pattern" "$tmp/stderr"; then
+        echo "FAIL $scenario: no match for \"$pattern\""
+        num_failed=$((num_failed + 1))
+    else
+        echo "ok   $scenario"
+    fi
+    # Deduplicate repeated messages
+    sed 's/0x[0-9a-f]*/0x.../g' "$tmp/stderr" | sort | uniq -c | sed "s/^/     | /"
+}
+
+# check <scenario> <expected stderr pattern or empty> [VAR=value ...]
+check() {
+    check_status 0 "$@"
+}
+
+# check_count <scenario> <stderr pattern> <min> <max> [VAR=value ...]: Like
+#     `check`, but also require <min> to <max> lines matching the pattern
+check_count() {
+    scenario=$1
+    pattern=$2
+    min=$3
+    max=$4
+    shift 4
+    check "$scenario" "$pattern" "$@"
+    count=$(grep -c -- "$pattern" "$tmp/stderr" || true)
+    if [ "$count" -lt "$min" ] || [ "$count" -gt "$max" ]; then
+        echo "FAIL $scenario: $count lines match \"$pattern\" (expected $min to $max)"
+        num_failed=$((num_failed + 1))
+    fi
+}
+
+check basic ""
+check basic "" FATALLOC_BACKEND=system
+check huge-align "" FATALLOC_BACKEND=system
+check shrink "" FATALLOC_CHECKS=all
+check_count shrunk-tail "heap overrun detected at offset 950 of allocation 0x[0-9a-f]*, in the part released" 2 2
+check shrunk-tail "" FATALLOC_CHECKS=none
+check shrink-grow "" FATALLOC_CHECKS=all
+check_count max-leaves "allocation denied: FATALLOC_MAX_LEAVES reached" 1 1 FATALLOC_MAX_LEAVES=8
+check_count max-leaves "FATALLOC_MAX_LEAVES reached" 1 1 FATALLOC_MAX_LEAVES=8 FATALLOC_BACKEND=system
+check_count max-leaves-batch "allocation denied: FATALLOC_MAX_LEAVES reached" 1 1 \
+    FATALLOC_MAX_LEAVES=8 FATALLOC_BACKEND=system
+check huge-align ""
+check double-free "ignoring the deallocation request for 0x[0-9a-f]*: not a known valid allocation"
+check double-free "freed twice before reuse" FATALLOC_CLASSIFY_DOUBLE_FREE=1
+check stale-free "stale pointer into memory reused by the allocation 0x[0-9a-f]* of 16384 bytes" \
+    FATALLOC_CLASSIFY_DOUBLE_FREE=1 FATALLOC_QUARANTINE_BYTES=0 FATALLOC_BACKEND=system
+check stale-free "not a known valid allocation" FATALLOC_QUARANTINE_BYTES=0 FATALLOC_BACKEND=system
+check_status 134 double-free "freed twice before reuse" FATALLOC_DOUBLE_FREE_GRACE=1 \
+    FATALLOC_ON_CORRUPTION=abort
+check stale-free "" FATALLOC_DOUBLE_FREE_GRACE=1 FATALLOC_ON_INVALID_POINTER=abort \
+    FATALLOC_QUARANTINE_BYTES=0 FATALLOC_BACKEND=system
+check stale-free "double free grace: 0x[0-9a-f]* is a stale pointer into memory reused by the allocation" \
+    FATALLOC_DOUBLE_FREE_GRACE=1 FATALLOC_QUARANTINE_BYTES=0 FATALLOC_BACKEND=system FATALLOC_LOG_LEVEL=info
+check misaligned-free "0x[0-9a-f]*: not aligned to 64, the alignment of the allocation 0x[0-9a-f]* it" \
+    FATALLOC_STRICT_ALIGN=1
+check misaligned-free "not a known valid allocation"
+check underflow "heap overrun detected at allocation 0x"
+check metadata-overwrite "metadata region overwritten"
+check overflow-culprit "likely overflow by [0-9]* or more bytes from the allocation at 0x"
+# The neighbor check is sampled, but the canary check isn't. A pair of
+# allocations may straddle two backend pools, so a few misses are allowed.
+check_count sample "from the allocation freed at" 990 1000 FATALLOC_CHECKS=all
+check_count sample "from the allocation freed at" 50 150 FATALLOC_CHECKS=all \
+    FATALLOC_CHECK_SAMPLE=1/10
+check_count sample "heap overrun" 1050 1150 FATALLOC_CHECKS=all FATALLOC_CHECK_SAMPLE=1/10
+check_count sample "from the allocation freed at" 990 1000 FATALLOC_CHECK_NEIGHBORS=1
+# Only the canary check of each allocation itself otherwise
+check_count sample "heap overrun" 1000 1000 FATALLOC_CHECKS=all FATALLOC_CHECK_NEIGHBORS=0
+check_count sample "heap overrun" 1000 1000
+check invalid-free "ignoring the deallocation request for 0x[0-9a-f]*: "
+check_count stack-free "free of a pointer into the current thread's stack" 2 2
+check static-free "ignoring the deallocation request for 0x[0-9a-f]*: not a known valid allocation"
+check invalid-realloc "rejecting the reallocation request for 0x"
+check invalid-realloc "rejecting the reallocation request for 0x" FATALLOC_REALLOC_ERROR=null
+# ... or stops the caller before it uses the invalid pointer
+check_status 134 invalid-realloc "rejecting the reallocation request for 0x" FATALLOC_REALLOC_ERROR=abort
+check out-of-memory "allocation denied: memory limit reached" FATALLOC_MEMORY_LIMIT=65536
+check calloc-overflow "calloc integer overflow: [0-9]* \\* 32"
+check_status 134 calloc-overflow "calloc integer overflow" FATALLOC_ON_INVALID_POINTER=abort
+check calloc-huge ""
+check calloc-large "" FATALLOC_CHECKS=all
+check calloc-large "" FATALLOC_CHECKS=all FATALLOC_QUARANTINE_BYTES=0
+check calloc-large "" FATALLOC_STRICT_SIZED_FREE=1 FATALLOC_BACKEND=system
+check huge-sizes ""
+check huge-sizes "" FATALLOC_BACKEND=system
+# Fewer than 100 requests are made before the scenario's loop
+check fail-nth "" FATALLOC_FAIL_NTH=100 EXPECTED_FAILURES=1
+check fail-nth "" FATALLOC_FAIL_NTH=100000 EXPECTED_FAILURES=0
+check fail-nth "" FATALLOC_FAIL_NTH=10 FATALLOC_FAIL_REPEAT=1 EXPECTED_FAILURES=100
+check fail-nth "" FATALLOC_FAIL_NTH=1 FATALLOC_FAIL_REPEAT=1 EXPECTED_FAILURES=1000
+check min-align ""
+check min-align "" FATALLOC_MIN_ALIGN=64
+check min-align "" FATALLOC_MIN_ALIGN=4096
+check min-align "" FATALLOC_MIN_ALIGN=64 FATALLOC_BACKEND=system
+check odd-align ""
+check odd-align "" FATALLOC_BACKEND=system
+# The loser of each race is rejected cleanly
+check race ": not a known valid allocation" FATALLOC_CHECKS=all
+# The child of a fork doesn't inherit a lock held by another thread
+check fork ": not a known valid allocation"
+check usable-size "" FATALLOC_ROUND_SIZES=1
+check usable-size "" FATALLOC_ROUND_SIZES=1 FATALLOC_BACKEND=system FATALLOC_CHECKS=all
+check sizes "" FATALLOC_ROUND_SIZES=1 FATALLOC_BACKEND=system FATALLOC_CHECKS=all
+check leak "of 12345 bytes possibly leaked (live for [0-9]* seconds)" \
+    FATALLOC_LEAK_CHECK_SIGNAL=10 FATALLOC_LEAK_AGE=1
+check log-level "allocate size=54321 "
+check log-level "allocate size=54321 .* margin=16384 " FATALLOC_MARGIN=10000
+check scoped-log-level "allocate size=54321 "
+check scoped-log-level "allocate size=34567 "
+check log-fd ""
+check log-fd "" FATALLOC_LOG_FORMAT=json
+check basic "FATALLOC_MARGIN=16 is too small for the metadata and the canary; using [0-9]*" \
+    FATALLOC_MARGIN=16
+check basic "" FATALLOC_MARGIN=4096
+if [ "${COMPACT_META:-0}" = 1 ]; then
+    check outer-pointer "" COMPACT_META=1
+else
+    check outer-pointer ""
+fi
+check overhead ""
+check map-memory ""
+check count-tracked "not a known valid allocation"
+check thread-stats ""
+check thread-stats "" FATALLOC_MEMORY_LIMIT=1000000000
+if [ "${STATS:-0}" = 1 ]; then
+    check metrics "" FATALLOC_METRICS_FILE="$tmp/metrics.prom"
+fi
+if [ "${BACKTRACE:-0}" = 1 ]; then
+    check folded "" FATALLOC_FOLDED_FILE="$tmp/folded"
+    check realloc-leak "of 100000 bytes possibly leaked (live for [0-9]* seconds); allocated at \[0x" \
+        FATALLOC_LEAK_SITES=1 FATALLOC_SYMBOLIZE=0
+    check realloc-leak "of 100000 bytes possibly leaked (live for [0-9]* seconds); reallocated at \[0x" \
+        FATALLOC_LEAK_SITES=1 FATALLOC_SYMBOLIZE=0 FATALLOC_REALLOC_SITE=last
+fi
+check churn ""
+check identity-fill "" FATALLOC_IDENTITY_FILL=1
+check_status 1 identity-fill ""
+check consistency ""
+check corruptions "" FATALLOC_ON_CORRUPTION=ignore
+check_count report-once "heap overrun detected" 1 1 FATALLOC_REPORT_ONCE=1
+check_count report-once "heap overrun detected" 100 100
+check madv-free "" FATALLOC_QUARANTINE_MADV_FREE=1
+check secure ""
+check secure "" FATALLOC_PROTECT_METADATA=1
+check compact ""
+if [ "${MAP_STATS:-0}" = 1 ]; then
+    check map-stats "allocation map leaf 0x[0-9a-f]*\.\.0x[0-9a-f]*: [0-9]* sub-leaves, 1 allocations"
+fi
+check_count freeze "allocation denied: the allocator is frozen" 1 1
+check realloc-hash "deallocate size=120 ptr=0x[0-9a-f]* hash=[0-9a-f]*$" \
+    FATALLOC_CONTENT_HASH=1 FATALLOC_LOG_LEVEL=info
+check realloc-hash "" FATALLOC_CONTENT_HASH=1 FATALLOC_BACKEND=system
+# A faulty system allocator is caught
+preload="$lib $tmp/buggy_realloc.so"
+check realloc-hash "the first 100 bytes of allocation 0x[0-9a-f]* changed while being reallocated" \
+    FATALLOC_CONTENT_HASH=1 FATALLOC_BACKEND=system
+preload="$lib $tmp/short_usable_size.so"
+check basic "leaking allocation 0x[0-9a-f]*: the backend reports only [0-9]* usable bytes" \
+    FATALLOC_BACKEND=system
+preload="$lib $tmp/recycling_memalign.so"
+check_count recycled-batch "the backend returned an already-tracked address 0x" 1 1 FATALLOC_BACKEND=system
+# ... and nothing else, as the first allocation is intact
+check_count recycled-batch "." 1 1 FATALLOC_BACKEND=system FATALLOC_CHECKS=all
+check_count recycled-batch "." 1 1 FATALLOC_BACKEND=system FATALLOC_ROUND_SIZES=1
+check_status 134 recycled-batch "already-tracked address" FATALLOC_BACKEND=system \
+    FATALLOC_ON_CORRUPTION=abort
+preload="$lib $tmp/misaligning_memalign.so"
+check_count misaligned-backend \
+    "the backend returned a bad allocation 0x[0-9a-f]*8 for Layout .*: misaligned" 1 1 \
+    FATALLOC_BACKEND=system
+check_count misaligned-backend "." 1 1 FATALLOC_BACKEND=system FATALLOC_CHECKS=all
+preload="$lib $tmp/overlapping_memalign.so"
+check_count overlapping-backend \
+    "the backend returned a bad allocation 0x[0-9a-f]* for Layout .*: overlapping with a live allocation" \
+    1 1 FATALLOC_BACKEND=system
+check_count overlapping-backend "." 1 1 FATALLOC_BACKEND=system FATALLOC_CHECKS=all
+preload="$lib $tmp/phys_pages.so"
+page_size=$(getconf PAGESIZE)
+check quarantine-budget "" QUARANTINE_BUDGET=$((1000000 * page_size / 100)) PHYS_PAGES=1000000 \
+    FATALLOC_QUARANTINE_FRACTION=1/100
+# Capped at 1 GiB by default
+check quarantine-budget "" QUARANTINE_BUDGET=1073741824 PHYS_PAGES=100000000 \
+    FATALLOC_QUARANTINE_FRACTION=1/100
+check quarantine-budget "" QUARANTINE_BUDGET=$((1000000 * page_size / 4)) PHYS_PAGES=1000000 \
+    FATALLOC_QUARANTINE_FRACTION=1/4 FATALLOC_QUARANTINE_MAX_BYTES=$((1 << 40))
+# Falls back to `FATALLOC_QUARANTINE_BYTES`
+check quarantine-budget "couldn't get the size of the physical memory" \
+    QUARANTINE_BUDGET=12345 FATALLOC_QUARANTINE_FRACTION=1/100 FATALLOC_QUARANTINE_BYTES=12345
+check quarantine-budget "isn't in the form \`n/d\`" QUARANTINE_BUDGET=4194304 PHYS_PAGES=1000000 \
+    FATALLOC_QUARANTINE_FRACTION=1%
+# The quarantine keeps the freed block's metadata mapped for the second `free`
+# A block allocated by `dlsym` while the system allocator is looked up
+preload="$lib $tmp/allocating_dlsym.so"
+check dlsym-alloc "" FATALLOC_BACKEND=system
+preload="$lib $tmp/fail_small_mmap.so"
+check_status 1 untracked-free "reallocated 0x[0-9a-f]* without tracking it"
+check_count untracked-free "0x[0-9a-f]*: not a known valid allocation" 1 1 \
+    FATALLOC_METADATA_FREE=1 FATALLOC_QUARANTINE_BYTES=1073741824
+check_count untracked-free "freeing 0x[0-9a-f]*, which the allocation map misses, by its metadata" \
+    1 1 FATALLOC_METADATA_FREE=1 FATALLOC_QUARANTINE_BYTES=1073741824 FATALLOC_LOG_LEVEL=info
+check mmap-retry ""
+# Each unavailable entropy source falls back to the next
+preload="$lib $tmp/no_entropy.so"
+check basic "seeded the metadata keys from getrandom" FATALLOC_LOG_LEVEL=info
+check basic "seeded the metadata keys from /dev/urandom" FATALLOC_LOG_LEVEL=info NO_GETRANDOM=1
+check basic "seeded the metadata keys from AT_RANDOM" FATALLOC_LOG_LEVEL=info NO_GETRANDOM=1 \
+    NO_URANDOM=1
+check basic "no entropy source is available; the metadata keys are predictable" NO_GETRANDOM=1 \
+    NO_URANDOM=1 NO_AT_RANDOM=1
+check basic "seeded the metadata keys from the clock" FATALLOC_LOG_LEVEL=info NO_GETRANDOM=1 \
+    NO_URANDOM=1 NO_AT_RANDOM=1
+preload=$lib
+check sizes "" FATALLOC_ROUND_SIZES=1
+check_count realloc-same "ignoring the deallocation request for 0x[0-9a-f]*: not a known valid allocation" 3 3
+check_count realloc-same "not a known valid allocation" 3 3 FATALLOC_BACKEND=system
+check realloc-align ""
+check protect-metadata "" FATALLOC_PROTECT_METADATA=1
+check allocations-in-range ""
+check allocations-in-range "" FATALLOC_BACKEND=system
+check_count pin-free "use after free detected at offset 10 of the pinned allocation 0x" 1 1
+check_count pin-free "rejecting the unpin request for 0x[0-9a-f]*: not a known valid allocation" 2 2
+check_count pin-free "rejecting the unpin request for 0x[0-9a-f]*: not pinned" 1 1
+check_count pin-free "." 4 4 FATALLOC_BACKEND=system
+check_count readonly "rejecting the write-protection request for 0x[0-9a-f]*: no whole pages to protect" 1 1
+check_count readonly "no whole pages to protect" 1 1 FATALLOC_BACKEND=system
+check_count readonly-registry "rejecting the .* request for .*: read-only" 2 3 FATALLOC_REGISTRY=1
+check realloc-align "" FATALLOC_PROTECT_METADATA=1
+check huge-align "" FATALLOC_PROTECT_METADATA=1
+# Needs a kernel with NUMA support
+if [ -d /sys/devices/system/node/node0 ]; then
+    check numa-node ""
+fi
+check verify ""
+check verify "" FATALLOC_CHECKS=none
+check min-size "" FATALLOC_MIN_SIZE=64 FATALLOC_BACKEND=system
+check_count jemalloc-api "allocation 0x[0-9a-f]* of 13 bytes deallocated with a different size 100$" \
+    1 1 FATALLOC_ROUND_SIZES=1
+check_count sized-free "of 13 bytes deallocated with a different size 100$" 2 2
+check_count sized-free "of 13 bytes deallocated with a different size 100; leaving it allocated$" \
+    2 2 FATALLOC_STRICT_SIZED_FREE=1
+check_count rust-dealloc "allocation 0x[0-9a-f]* (size 24, align [0-9]*) deallocated with a different layout Layout" \
+    2 2
+check_count rust-dealloc "deallocated with a different layout Layout { size_: 24, align_: 8 }; leaving it allocated$" \
+    1 1 FATALLOC_STRICT_SIZED_FREE=1
+check_count checked-free "the token doesn't match the allocation$" 4 4 \
+    FATALLOC_QUARANTINE_BYTES=0 FATALLOC_BACKEND=system
+check checked-free "ignoring the deallocation request for 0x[0-9a-f]*: not a known valid allocation" \
+    FATALLOC_QUARANTINE_BYTES=0 FATALLOC_BACKEND=system
+# Three accesses are reported until unwatched
+check_count watch "watched allocation 0x[0-9a-f]* of 4096 bytes" 3 3
+check watch "written at offset 8 by the instruction at 0x"
+check watch "read at offset 100 by the instruction at 0x"
+check_count single-threaded "cross-thread access to single-threaded allocation" 3 3
+check single-threaded "0x[0-9a-f]*: free by thread [0-9]*, but it's owned by thread [0-9]*$"
+check single-threaded ": access by thread [0-9]*, but it's owned by thread [0-9]*$"
+check single-threaded "rejecting the single-thread marking request for 0x[0-9a-f]*: it has a token"
+check_count unwritten "allocated but never written" 1 1 FATALLOC_DETECT_UNWRITTEN=1
+check unwritten "freeing 0x[0-9a-f]*: allocated but never written 5000 bytes$" \
+    FATALLOC_DETECT_UNWRITTEN=1
+check unwritten "first written at offset 4500$" FATALLOC_DETECT_UNWRITTEN=1 FATALLOC_LOG_LEVEL=info
+check unwritten ""
+check basic "" FATALLOC_MIN_SIZE=64
+check realloc-hash "" FATALLOC_MIN_SIZE=256 FATALLOC_CONTENT_HASH=1
+if [ "${BUMP_BACKEND:-0}" = 1 ]; then
+    check fixed-base "" FATALLOC_BACKEND=bump FATALLOC_FIXED_BASE=0x200000000000
+    check basic "" FATALLOC_BACKEND=bump
+    check huge-sizes "" FATALLOC_BACKEND=bump
+    check realloc-hash "" FATALLOC_BACKEND=bump FATALLOC_CONTENT_HASH=1
+    check distant-overrun "heap overrun detected at allocation 0x[0-9a-f]*$" FATALLOC_BACKEND=bump
+fi
+check_count wild-free "ignoring the deallocation request for 0x[0-9a-f]*: not a known valid allocation" \
+    100 100 FATALLOC_CLASSIFY_DOUBLE_FREE=1
+check wild-free "" FATALLOC_CLASSIFY_DOUBLE_FREE=1 FATALLOC_ON_INVALID_POINTER=ignore
+check owns ""
+check_count libc-aliases "ignoring the deallocation request for 0x[0-9a-f]*: " 1 1
+check_count self-test "the allocation map's root table was corrupted (.*); repaired it" 3 3
+check self-test "the leaf count exceeds the capacity"
+check self-test "a leaf is past the count"
+check self-test "the leaves are out of order"
+check basic "self-test passed" FATALLOC_SELF_TEST=1 FATALLOC_LOG_LEVEL=info
+check batch "" FATALLOC_CHECKS=all
+check_count free-batch "ignoring the deallocation request for 0x[0-9a-f]*: not a known valid allocation" \
+    1001 1001 FATALLOC_CHECKS=all
+if [ "${POINTER_TAG:-0}" = 1 ]; then
+    check_count tag-pointer "accessed through a pointer tagged 0x[0-9a-f]*: possible type confusion" 2 2
+    check_count tag-pointer "rejecting the tag request for 0x[0-9a-f]*: not a known valid allocation" \
+        1 1
+    check_count tag-pointer "." 3 3 FATALLOC_BACKEND=system
+fi
+if [ "${PASSTHROUGH:-0}" = 1 ]; then
+    check mixed ""
+    check passthrough-realloc "" FATALLOC_PASSTHROUGH=realloc
+    check passthrough-malloc "" FATALLOC_PASSTHROUGH=malloc,calloc
+else
+    # Forwarded allocations can't be freed without `backend-passthrough`
+    check passthrough-realloc "ignoring the deallocation request for 0x[0-9a-f]*: " \
+        FATALLOC_PASSTHROUGH=realloc
+    check passthrough-malloc "ignoring the deallocation request for 0x[0-9a-f]*: " \
+        FATALLOC_PASSTHROUGH=malloc,calloc
+fi
+if [ "${REENTRANCY_CHECK:-0}" = 1 ]; then
+    # Interrupted allocations are served by `mmap`, and their blocks are
+    # released without complaint
+    check signal-alloc "allocator reentered; mapping Layout"
+    check signal-secure "allocation denied: can't secure 0x[0-9a-f]*: "
+fi
+check basic "free(NULL) called" FATALLOC_WARN_FREE_NULL=1
+check basic "seeded the metadata keys from getrandom" FATALLOC_LOG_LEVEL=info
+check underflow "^fatalloc\[[0-9]*/[0-9]* smoke\]: heap overrun" FATALLOC_LOG_THREAD=1
+check underflow '"event":"overrun"' FATALLOC_LOG_FORMAT=json
+# Corruption aborts while a bad pointer only warns. 134 = 128 + SIGABRT.
+check_status 134 underflow "heap overrun detected" FATALLOC_ON_CORRUPTION=abort
+check double-free "not a known valid allocation" FATALLOC_ON_CORRUPTION=abort
+check double-free "" FATALLOC_ON_INVALID_POINTER=ignore
+
+[ "$num_failed" -eq 0 ]
@@ -0,0 +1,12 @@
+// A `malloc_usable_size` that reports half the actual size, as if blocks
+// came from a smaller size class. Preloaded after fatalloc, it stands in for
+// the system allocator's with `FATALLOC_BACKEND=system`.
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <stddef.h>
+
+size_t malloc_usable_size(void *ptr) {
+    size_t (*real_malloc_usable_size)(void *) =
+        (size_t(*)(void *))dlsym(RTLD_NEXT, "malloc_usable_size");
+    return real_malloc_usable_size(ptr) / 2;
+}
@@ -0,0 +1,2724 @@
+// A program exercising the allocator in ways `tests/run.sh` checks for.
+// Usage: smoke <scenario>
+#include <dlfcn.h>
+#include <errno.h>
+#include <malloc.h>
+#include <pthread.h>
+#include <signal.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mman.h>
+#include <sys/syscall.h>
+#include <sys/time.h>
+#include <sys/wait.h>
+#include <time.h>
+#include <unistd.h>
+
+// Hide pointers from the compiler, which would otherwise warn about (or
+// optimize out) the deliberate misuses
+static void *volatile sink;
+
+static void *launder(void *p) {
+    sink = p;
+    return sink;
+}
+
+// The smallest margin, which a small allocation gets. It depends on the
+// metadata layout (`compact-meta`). The metadata guard is always located
+// this far before an allocation.
+static ptrdiff_t min_margin(void) {
+    void *(*get_outer_pointer)(void *, size_t *) =
+        (void *(*)(void *, size_t *))dlsym(RTLD_DEFAULT, "fatalloc_outer_pointer");
+    if (!get_outer_pointer) {
+        return 0;
+    }
+    void *p = malloc(1);
+    size_t margin = 0;
+    get_outer_pointer(p, &margin);
+    free(p);
+    return (ptrdiff_t)margin;
+}
+
+static int basic(void) {
+    char *p = malloc(100);
+    memset(p, 1, 100);
+    p = realloc(p, 10000);
+    for (int i = 0; i < 100; ++i) {
+        if (p[i] != 1) {
+            return 1;
+        }
+    }
+    p = realloc(p, 10);
+    free(p);
+
+    char *z = calloc(1000, 10);
+    for (int i = 0; i < 10000; ++i) {
+        if (z[i] != 0) {
+            return 1;
+        }
+    }
+    free(z);
+
+    void *a;
+    if (posix_memalign(&a, 4096, 100) != 0 || (size_t)a % 4096 != 0) {
+        return 1;
+    }
+    free(a);
+
+    free(launder(NULL));
+    return 0;
+}
+
+static int shrink(void) {
+    // The metadata and the canary must survive shrinking a block to almost
+    // nothing, whatever its alignment
+    static const size_t aligns[] = {16, 4096, 65536};
+    for (size_t i = 0; i < sizeof aligns / sizeof aligns[0]; ++i) {
+        char *p;
+        if (posix_memalign((void **)&p, aligns[i], 1 << 20) != 0) {
+            return 1;
+        }
+        memset(p, 1, 1 << 20);
+        p = realloc(p, 1);
+        if ((size_t)p % aligns[i] != 0 || malloc_usable_size(p) != 1 || p[0] != 1) {
+            return 1;
+        }
+        p = realloc(p, 100);
+        p[99] = 0;
+        free(p);
+    }
+    return 0;
+}
+
+static int shrunk_tail(void) {
+    // Shrinking poisons the released tail, which growing checks. Write past
+    // the new end, then grow in place and by moving.
+    size_t grown_sizes[] = {1000, 100000};
+    for (size_t i = 0; i < sizeof grown_sizes / sizeof grown_sizes[0]; ++i) {
+        char *p = malloc(1000);
+        memset(p, 1, 1000);
+        p = realloc(p, 900);
+        ((char *)launder(p))[950] = 2;
+        p = realloc(p, grown_sizes[i]);
+        if (!p || p[899] != 1) {
+            return 1;
+        }
+        free(p);
+    }
+    return 0;
+}
+
+static int shrink_grow(void) {
+    // Without an overrun, shrinking and growing back only keep the contents
+    char *p = malloc(1000);
+    memset(p, 1, 1000);
+    for (size_t size = 900; size > 0; size /= 2) {
+        p = realloc(p, size);
+        p = realloc(p, size + 50);
+        for (size_t i = 0; i < size; ++i) {
+            if (p[i] != 1) {
+                return 1;
+            }
+        }
+        memset(p, 1, size + 50);
+    }
+    free(p);
+    return 0;
+}
+
+static int huge_align(void) {
+    static const size_t aligns[] = {64 << 10, 2 << 20};
+    for (size_t i = 0; i < sizeof aligns / sizeof aligns[0]; ++i) {
+        char *p = aligned_alloc(aligns[i], 3 << 20);
+        if (!p || (size_t)p % aligns[i] != 0) {
+            return 1;
+        }
+        memset(p, 1, 3 << 20);
+        p = realloc(p, 5 << 20);
+        if ((size_t)p % aligns[i] != 0 || p[(3 << 20) - 1] != 1) {
+            return 1;
+        }
+        free(p);
+    }
+    return 0;
+}
+
+static int double_free(void) {
+    void *p = malloc(16);
+    free(p);
+    free(launder(p));
+    return 0;
+}
+
+static int stale_free(void) {
+    // Without a quarantine, the block is released right away, and glibc hands
+    // out its start again. Margins grow with the size, so the smaller
+    // allocation covers the freed pointer without starting there.
+    char *p = malloc(65536);
+    free(p);
+    char *q = malloc(16384);
+    if (!(q < p && p < q + 16384)) {
+        return 1;
+    }
+    free(launder(p));
+    free(q);
+    return 0;
+}
+
+static int misaligned_free(void) {
+    // `sizeof(void *)`-aligned like any allocation, but not like this one
+    char *p = aligned_alloc(64, 256);
+    free(launder(p + 16));
+    free(p);
+    return 0;
+}
+
+static int underflow(void) {
+    char *p = malloc(16);
+    ((char *)launder(p))[-1] = 0;
+    free(p);
+    return 0;
+}
+
+static int overflow_culprit(void) {
+    char *a = malloc(16), *b = malloc(16);
+    char *lo = a < b ? a : b, *hi = a < b ? b : a;
+    // A stray write from `lo` that lands on the canary of `hi` only
+    ((char *)launder(lo))[hi - lo - 1] = 0;
+    free(hi);
+    free(lo);
+    return 0;
+}
+
+static int sample(void) {
+    // Run with `FATALLOC_CHECKS=all`. Every broken canary is caught when
+    // freed, and also when the allocation in front of it is freed if the
+    // neighbor check is sampled.
+    for (int i = 0; i < 1000; i++) {
+        char *a = malloc(16), *b = malloc(16);
+        char *lo = a < b ? a : b, *hi = a < b ? b : a;
+        ((char *)launder(hi))[-1] ^= 1;
+        free(lo);
+        free(hi);
+    }
+    return 0;
+}
+
+static int metadata_overwrite(void) {
+    char *p = malloc(16);
+    ((char *)launder(p))[-min_margin()] ^= 1;
+    free(p);
+    return 0;
+}
+
+static int invalid_free(void) {
+    int x;
+    free(launder(&x));
+    return 0;
+}
+
+static void *free_local(void *arg) {
+    (void)arg;
+    int x;
+    free(launder(&x));
+    return NULL;
+}
+
+static int stack_free(void) {
+    // In the main thread, whose stack glibc finds by `/proc/self/maps`, and
+    // in another
+    free_local(NULL);
+    pthread_t thread;
+    pthread_create(&thread, NULL, free_local, NULL);
+    pthread_join(thread, NULL);
+    return 0;
+}
+
+static int static_free(void) {
+    static void *x;
+    free(launder(&x));
+    return 0;
+}
+
+static int invalid_realloc(void) {
+    void *p = malloc(16);
+    free(p);
+    return realloc(launder(p), 32) == NULL ? 0 : 1;
+}
+
+static int out_of_memory(void) {
+    // Run with `FATALLOC_MEMORY_LIMIT`
+    errno = 0;
+    void *p = malloc(1 << 20);
+    return p == NULL && errno == ENOMEM ? 0 : 1;
+}
+
+static int calloc_overflow(void) {
+    // `volatile` keeps the compiler from flagging the overflow
+    volatile size_t number = SIZE_MAX / 16 + 1;
+    errno = 0;
+    void *p = calloc(number, 32);
+    return p == NULL && errno == ENOMEM ? 0 : 1;
+}
+
+static int calloc_huge(void) {
+    // Doesn't overflow, but can't be satisfied either
+    errno = 0;
+    void *p = calloc(SIZE_MAX / 64, 16);
+    return p == NULL && errno == ENOMEM ? 0 : 1;
+}
+
+static bool is_zero(const char *p, size_t size) {
+    for (size_t i = 0; i < size; ++i) {
+        if (p[i] != 0) {
+            return false;
+        }
+    }
+    return true;
+}
+
+static int calloc_large(void) {
+    // Large zeroed allocations are cleared even when recycled (run with
+    // `FATALLOC_QUARANTINE_BYTES=0` as well), keep their requested alignment,
+    // grow by `realloc`, and free with their layout silently
+    void *(*rust_alloc_zeroed)(size_t, size_t) =
+        (void *(*)(size_t, size_t))dlsym(RTLD_DEFAULT, "fatalloc_rust_alloc_zeroed");
+    void (*rust_dealloc)(void *, size_t, size_t) =
+        (void (*)(void *, size_t, size_t))dlsym(RTLD_DEFAULT, "fatalloc_rust_dealloc");
+    bool (*owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    if (!rust_alloc_zeroed || !rust_dealloc || !owns) {
+        return 1;
+    }
+    for (int round = 0; round < 2; ++round) {
+        size_t size = (1 << 20) + 100;
+        char *p = calloc(1, size);
+        if (!p || !is_zero(p, size)) {
+            return 1;
+        }
+        memset(p, 1, size);
+        for (size_t new_size = 2 << 20; new_size <= 16 << 20; new_size *= 2) {
+            p = realloc(p, new_size);
+            if (!p || p[0] != 1 || p[size - 1] != 1) {
+                return 1;
+            }
+        }
+        free(p);
+
+        char *q = rust_alloc_zeroed(200000, 64);
+        if (!q || (uintptr_t)q % 64 != 0 || !is_zero(q, 200000)) {
+            return 1;
+        }
+        memset(q, 1, 200000);
+        rust_dealloc(q, 200000, 64);
+        if (owns(launder(q))) {
+            return 1;
+        }
+    }
+    return 0;
+}
+
+static int huge_sizes(void) {
+    // Sizes that would wrap around once margins are added fail cleanly
+    int ok = 1;
+    size_t sizes[] = {SIZE_MAX, SIZE_MAX - 1, SIZE_MAX - 4095, SIZE_MAX / 2 + 1, PTRDIFF_MAX};
+    char *p = malloc(16);
+    for (size_t i = 0; i < sizeof sizes / sizeof sizes[0]; ++i) {
+        void *q;
+        errno = 0;
+        ok &= launder(malloc(sizes[i])) == NULL && errno == ENOMEM;
+        errno = 0;
+        ok &= launder(calloc(1, sizes[i])) == NULL && errno == ENOMEM;
+        errno = 0;
+        ok &= launder(aligned_alloc(4096, sizes[i])) == NULL && errno == ENOMEM;
+        ok &= posix_memalign(&q, 4096, sizes[i]) == ENOMEM;
+        // The original allocation is left intact
+        errno = 0;
+        ok &= launder(realloc(p, sizes[i])) == NULL && errno == ENOMEM;
+    }
+    memset(p, 1, 16);
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static int fail_nth(void) {
+    // Run with `FATALLOC_FAIL_NTH=<n>` and `EXPECTED_FAILURES`. Of 1000
+    // requests in a row, the failed ones are `n` apart, and the rest are
+    // served and tracked as usual.
+    size_t (*live_allocations)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_live_allocations");
+    const char *expected = getenv("EXPECTED_FAILURES");
+    if (!live_allocations || !expected) {
+        return 1;
+    }
+    long n = strtol(getenv("FATALLOC_FAIL_NTH"), NULL, 10);
+    static void *ps[1000];
+    size_t before = live_allocations();
+    long num_failed = 0, last_failed = -1;
+    int ok = 1;
+    for (long i = 0; i < 1000; ++i) {
+        errno = 0;
+        ps[i] = malloc(100);
+        if (!ps[i]) {
+            ok &= errno == ENOMEM && (last_failed < 0 || i - last_failed == n);
+            last_failed = i;
+            ++num_failed;
+        }
+    }
+    ok &= live_allocations() == before + 1000 - num_failed;
+    for (int i = 0; i < 1000; ++i) {
+        free(ps[i]);
+    }
+    ok &= live_allocations() == before;
+    return ok && num_failed == strtol(expected, NULL, 10) ? 0 : 1;
+}
+
+static int min_align(void) {
+    // Every pointer is aligned to 16 bytes like glibc's, or to
+    // `FATALLOC_MIN_ALIGN` if larger, whichever way it's allocated
+    const char *env = getenv("FATALLOC_MIN_ALIGN");
+    uintptr_t align = env ? strtoul(env, NULL, 10) : 1;
+    align = align > 16 ? align : 16;
+    int ok = 1;
+    for (size_t size = 1; size < 100000; size = size * 3 + 1) {
+        void *ps[6] = {malloc(size), calloc(1, size), realloc(NULL, size), aligned_alloc(8, size),
+                       memalign(4, size), NULL};
+        ok &= posix_memalign(&ps[5], 8, size) == 0;
+        for (int i = 0; i < 6; ++i) {
+            ok &= ps[i] && (uintptr_t)ps[i] % align == 0;
+            // Both in place and moved
+            ps[i] = realloc(ps[i], i % 2 ? size / 2 + 1 : size * 5);
+            ok &= ps[i] && (uintptr_t)ps[i] % align == 0;
+            free(ps[i]);
+        }
+    }
+    return ok ? 0 : 1;
+}
+
+static int misaligned_backend(void) {
+    // Run with `misaligning_memalign.so` and `FATALLOC_BACKEND=system`. The
+    // misaligned block is rejected and the allocation fails.
+    setenv("MISALIGN_MEMALIGN", "1", 1);
+    errno = 0;
+    void *p = malloc(100);
+    int ok = p == NULL && errno == ENOMEM;
+    unsetenv("MISALIGN_MEMALIGN");
+    p = malloc(100);
+    ok &= p != NULL;
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static int overlapping_backend(void) {
+    // Run with `overlapping_memalign.so` and `FATALLOC_BACKEND=system`. The
+    // block overlapping with `p` is rejected before touching `p`, and the
+    // allocation fails.
+    char *p = malloc(1000);
+    memset(p, 1, 1000);
+    setenv("OVERLAP_MEMALIGN", "1", 1);
+    errno = 0;
+    void *q = malloc(1000);
+    int ok = q == NULL && errno == ENOMEM;
+    unsetenv("OVERLAP_MEMALIGN");
+    for (int i = 0; i < 1000; ++i) {
+        ok &= p[i] == 1;
+    }
+    free(p);
+    q = malloc(1000);
+    ok &= q != NULL;
+    free(q);
+    return ok ? 0 : 1;
+}
+
+static int odd_align(void) {
+    // `memalign` rounds the alignment up to a power of two like glibc's;
+    // `aligned_alloc` and `posix_memalign` reject it
+    int ok = 1;
+    size_t aligns[] = {24, 48, 3000};
+    for (size_t i = 0; i < sizeof aligns / sizeof aligns[0]; ++i) {
+        size_t rounded = 1;
+        while (rounded < aligns[i]) {
+            rounded <<= 1;
+        }
+        char *p = memalign(aligns[i], 100);
+        ok &= p && (uintptr_t)p % rounded == 0;
+        free(p);
+    }
+    errno = 0;
+    ok &= memalign(SIZE_MAX / 2 + 2, 100) == NULL && errno == EINVAL;
+    errno = 0;
+    ok &= aligned_alloc(24, 100) == NULL && errno == EINVAL;
+    void *p;
+    ok &= posix_memalign(&p, 24, 100) == EINVAL;
+    return ok ? 0 : 1;
+}
+
+static void *volatile race_ptr;
+static pthread_barrier_t race_barrier;
+
+static void *race_free(void *arg) {
+    (void)arg;
+    for (int i = 0; i < 10000; ++i) {
+        pthread_barrier_wait(&race_barrier);
+        free(race_ptr);
+        pthread_barrier_wait(&race_barrier);
+    }
+    return NULL;
+}
+
+static int race(void) {
+    // Free and reallocate the same pointer simultaneously. Exactly one of
+    // them must win; the other is rejected.
+    pthread_t thread;
+    pthread_barrier_init(&race_barrier, NULL, 2);
+    pthread_create(&thread, NULL, race_free, NULL);
+    for (int i = 0; i < 10000; ++i) {
+        race_ptr = malloc(100);
+        pthread_barrier_wait(&race_barrier);
+        // Alternate between in-place and moving reallocation
+        char *p = realloc(race_ptr, i % 2 ? 50 : 100000);
+        pthread_barrier_wait(&race_barrier);
+        if (p) {
+            memset(p, 1, i % 2 ? 50 : 100000);
+            free(p);
+        }
+    }
+    pthread_join(thread, NULL);
+    return 0;
+}
+
+static volatile bool fork_done;
+
+static void *fork_churn(void *arg) {
+    (void)arg;
+    for (unsigned i = 0; !fork_done; ++i) {
+        char *p = malloc(i % 1000 + 1);
+        p = realloc(p, i % 3000 + 1);
+        if (i % 1024 == 0) {
+            // Keeps the logger busy, too
+            free(launder(p + 16));
+        }
+        free(p);
+    }
+    return NULL;
+}
+
+static int fork_while_allocating(void) {
+    // Fork while other threads hold the allocator's locks. The child must
+    // be able to allocate.
+    pthread_t threads[4];
+    for (int i = 0; i < 4; ++i) {
+        pthread_create(&threads[i], NULL, fork_churn, NULL);
+    }
+    int ok = 1;
+    for (int i = 0; i < 20 && ok; ++i) {
+        char *p = malloc(100);
+        pid_t pid = fork();
+        if (pid == 0) {
+            // Fail instead of hanging
+            alarm(10);
+            for (int k = 0; k < 1000; ++k) {
+                free(realloc(malloc(k + 1), k * 3 + 1));
+            }
+            free(p);
+            _exit(0);
+        }
+        int status;
+        ok = pid > 0 && waitpid(pid, &status, 0) == pid && WIFEXITED(status) &&
+             WEXITSTATUS(status) == 0;
+        free(p);
+    }
+    fork_done = true;
+    for (int i = 0; i < 4; ++i) {
+        pthread_join(threads[i], NULL);
+    }
+    return ok ? 0 : 1;
+}
+
+static int usable_size(void) {
+    // Run with `FATALLOC_ROUND_SIZES=1`
+    // Writing the granted bytes doesn't trip the canary of the next one
+    char *p = malloc(13);
+    char *q = malloc(13);
+    size_t size = malloc_usable_size(p);
+    memset(p, 1, size);
+    p = realloc(p, 14);
+    int ok = size >= 16 && malloc_usable_size(p) >= 14;
+    memset(p, 2, malloc_usable_size(p));
+    free(q);
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static volatile sig_atomic_t num_handled;
+
+static void allocate_in_handler(int sig) {
+    (void)sig;
+    char *p = malloc(100);
+    if (p) {
+        memset(p, 1, 100);
+        char *q = realloc(p, 5000);
+        if (q) {
+            memset(q, 2, 5000);
+            p = q;
+        }
+        free(p);
+    }
+    num_handled++;
+}
+
+static void *(*handler_malloc_secure)(size_t);
+static volatile sig_atomic_t num_secured;
+
+static void allocate_secure_in_handler(int sig) {
+    (void)sig;
+    char *p = handler_malloc_secure(100);
+    if (p) {
+        if ((size_t)p % 4096 != 0) {
+            abort();
+        }
+        memset(p, 1, 100);
+        free(p);
+        num_secured++;
+    }
+    num_handled++;
+}
+
+static int signal_alloc(void) {
+    // Run with the `reentrancy-check` feature. A frequent timer interrupts
+    // allocations with a handler that allocates, as a crash handler might.
+    struct sigaction action = {.sa_handler = allocate_in_handler};
+    sigaction(SIGALRM, &action, NULL);
+    struct itimerval timer = {{0, 100}, {0, 100}};
+    setitimer(ITIMER_REAL, &timer, NULL);
+    while (num_handled < 2000) {
+        free(realloc(malloc(100), 200));
+    }
+    timer = (struct itimerval){{0, 0}, {0, 0}};
+    setitimer(ITIMER_REAL, &timer, NULL);
+    return 0;
+}
+
+static int signal_secure(void) {
+    // Run with the `reentrancy-check` feature. An interrupted allocation
+    // leaves only an emergency block, which can't be secured.
+    handler_malloc_secure = (void *(*)(size_t))dlsym(RTLD_DEFAULT, "fatalloc_malloc_secure");
+    if (!handler_malloc_secure) {
+        return 1;
+    }
+    num_handled = 0;
+    struct sigaction action = {.sa_handler = allocate_secure_in_handler};
+    sigaction(SIGALRM, &action, NULL);
+    struct itimerval timer = {{0, 1000}, {0, 1000}};
+    setitimer(ITIMER_REAL, &timer, NULL);
+    while (num_handled < 200) {
+        free(realloc(malloc(100), 200));
+    }
+    timer = (struct itimerval){{0, 0}, {0, 0}};
+    setitimer(ITIMER_REAL, &timer, NULL);
+    return num_secured < num_handled ? 0 : 1;
+}
+
+static int leak(void) {
+    // Run with `FATALLOC_LEAK_CHECK_SIGNAL=<n> FATALLOC_LEAK_AGE=1`
+    const char *signal = getenv("FATALLOC_LEAK_CHECK_SIGNAL");
+    if (!signal) {
+        return 1;
+    }
+    sink = malloc(12345);
+    sleep(2);
+    raise(atoi(signal));
+    // The report is made here
+    free(malloc(1));
+    return 0;
+}
+
+// In this order, so that a return address in one is below the next one
+static __attribute__((noinline)) void *leak_origin_site(void) { return launder(malloc(100)); }
+static __attribute__((noinline)) void *leak_growth_site(void *p, size_t size) {
+    return launder(realloc(p, size));
+}
+static __attribute__((noinline)) void leak_sites_end(void) {}
+
+static int realloc_leak(void) {
+    // Run with `FATALLOC_LEAK_SITES=1 FATALLOC_SYMBOLIZE=0`. The leak must be
+    // attributed to the site `FATALLOC_REALLOC_SITE` says.
+    size_t (*report)(size_t) =
+        (size_t(*)(size_t))dlsym(RTLD_DEFAULT, "fatalloc_report_old_allocations");
+    if (!report) {
+        return 1;
+    }
+    const char *realloc_site = getenv("FATALLOC_REALLOC_SITE");
+    bool last = realloc_site && strcmp(realloc_site, "last") == 0;
+    void *p = leak_origin_site();
+    for (size_t size = 1000; size <= 100000; size *= 10) {
+        p = leak_growth_site(p, size);
+    }
+
+    // Capture the report, passing it on
+    FILE *log = tmpfile();
+    if (!log) {
+        return 1;
+    }
+    int saved_stderr = dup(2);
+    dup2(fileno(log), 2);
+    report(0);
+    dup2(saved_stderr, 2);
+    close(saved_stderr);
+    rewind(log);
+    char line[1024], prefix[64];
+    snprintf(prefix, sizeof prefix, "allocation %p ", p);
+    uintptr_t ip = 0;
+    bool reallocated = false;
+    while (fgets(line, sizeof line, log)) {
+        fputs(line, stderr);
+        char *at = strstr(line, "allocated at [");
+        if (strstr(line, prefix) && at) {
+            reallocated = at - line >= 2 && strncmp(at - 2, "re", 2) == 0;
+            ip = strtoull(at + strlen("allocated at ["), NULL, 16);
+        }
+    }
+    fclose(log);
+
+    uintptr_t start = (uintptr_t)(last ? (void *)leak_growth_site : (void *)leak_origin_site);
+    uintptr_t end = (uintptr_t)(last ? (void *)leak_sites_end : (void *)leak_growth_site);
+    return reallocated == last && start < ip && ip < end ? 0 : 1;
+}
+
+static int log_level(void) {
+    int (*set_log_level)(int) = (int (*)(int))dlsym(RTLD_DEFAULT, "fatalloc_set_log_level");
+    if (!set_log_level) {
+        return 1;
+    }
+    int old = set_log_level(2);
+    free(malloc(54321));
+    if (set_log_level(old) != 2 || old != 1) {
+        return 1;
+    }
+    free(malloc(12345));
+    return 0;
+}
+
+static int scoped_log_level_inside;
+
+static void scoped_log_level_body(void *arg) {
+    int (*set_log_level)(int) = arg;
+    free(malloc(54321));
+    // The previous level is restored even if the body changes it
+    scoped_log_level_inside = set_log_level(0);
+}
+
+static void scoped_log_level_exit(void *arg) {
+    (void)arg;
+    free(malloc(34567));
+    pthread_exit(NULL);
+}
+
+static void *scoped_log_level_thread(void *arg) {
+    int (*with_log_level)(int, void (*)(void *), void *) = arg;
+    with_log_level(2, scoped_log_level_exit, NULL);
+    return NULL;
+}
+
+static int scoped_log_level(void) {
+    int (*set_log_level)(int) = (int (*)(int))dlsym(RTLD_DEFAULT, "fatalloc_set_log_level");
+    int (*with_log_level)(int, void (*)(void *), void *) =
+        (int (*)(int, void (*)(void *), void *))dlsym(RTLD_DEFAULT, "fatalloc_with_log_level");
+    if (!set_log_level || !with_log_level || with_log_level(3, scoped_log_level_body, NULL) != -1) {
+        return 1;
+    }
+    if (with_log_level(2, scoped_log_level_body, (void *)set_log_level) != 0 ||
+        scoped_log_level_inside != 2) {
+        return 1;
+    }
+    // Back to `warn`, the default
+    int level = set_log_level(1);
+
+    // Restored when the thread exits in the scope
+    pthread_t thread;
+    pthread_create(&thread, NULL, scoped_log_level_thread, (void *)with_log_level);
+    pthread_join(thread, NULL);
+    free(malloc(12345));
+    return level == 1 && set_log_level(1) == 1 ? 0 : 1;
+}
+
+static volatile int log_fd_stop;
+
+static void *log_fd_churn(void *arg) {
+    (void)arg;
+    while (!log_fd_stop) {
+        free(malloc(100));
+    }
+    return NULL;
+}
+
+// Check that every line in `log` is a whole message. Returns whether
+// `pattern` appears.
+static bool log_fd_check(FILE *log, const char *pattern, bool *whole) {
+    rewind(log);
+    char line[1024];
+    bool found = false;
+    while (fgets(line, sizeof line, log)) {
+        size_t len = strlen(line);
+        *whole &= len > 0 && line[len - 1] == '\n' &&
+                  (strncmp(line, "fatalloc", 8) == 0 || strncmp(line, "{\"lvl\"", 6) == 0);
+        found |= strstr(line, pattern) != NULL;
+    }
+    return found;
+}
+
+static int log_fd(void) {
+    int (*set_log_fd)(int) = (int (*)(int))dlsym(RTLD_DEFAULT, "fatalloc_set_log_fd");
+    int (*set_log_level)(int) = (int (*)(int))dlsym(RTLD_DEFAULT, "fatalloc_set_log_level");
+    if (!set_log_fd || !set_log_level) {
+        return 1;
+    }
+    FILE *first = tmpfile(), *second = tmpfile();
+    if (!first || !second || set_log_fd(-1) != -1) {
+        return 1;
+    }
+
+    // Swap the destination while another thread is logging its allocations
+    int ok = set_log_fd(fileno(first)) == 2;
+    int old_level = set_log_level(2);
+    pthread_t thread;
+    pthread_create(&thread, NULL, log_fd_churn, NULL);
+    usleep(20000);
+    ok &= set_log_fd(fileno(second)) == fileno(first);
+    free(malloc(54321));
+    usleep(20000);
+    log_fd_stop = 1;
+    pthread_join(thread, NULL);
+    set_log_level(old_level);
+    ok &= set_log_fd(2) == fileno(second);
+
+    bool whole = true;
+    ok &= log_fd_check(first, "size=100 ", &whole);
+    ok &= !log_fd_check(first, "size=54321 ", &whole);
+    ok &= log_fd_check(second, "size=54321 ", &whole);
+    fclose(first);
+    fclose(second);
+    return ok && whole ? 0 : 1;
+}
+
+// Run with `allocating_dlsym.so` preloaded
+static int dlsym_alloc(void) {
+    free(malloc(1));
+    void **block = (void **)dlsym(RTLD_DEFAULT, "dlsym_block");
+    if (!block || !*block || malloc_usable_size(*block) < 100) {
+        return 1;
+    }
+    char *p = realloc(*block, 1000);
+    if (!p) {
+        return 1;
+    }
+    free(p);
+    return 0;
+}
+
+static int outer_pointer(void) {
+    void *(*get_outer_pointer)(void *, size_t *) =
+        (void *(*)(void *, size_t *))dlsym(RTLD_DEFAULT, "fatalloc_outer_pointer");
+    if (!get_outer_pointer) {
+        return 1;
+    }
+    char *p = malloc(100);
+    size_t margin = 0;
+    char *outer = get_outer_pointer(p, &margin);
+    // Set `COMPACT_META=1` with the `compact-meta` feature, which halves the
+    // smallest margin on a 64-bit target
+    size_t min_words = getenv("COMPACT_META") ? 8 : 16;
+    int ok = margin >= min_words * sizeof(void *) && outer == p - margin;
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static void *allocate_and_exit(void *arg) {
+    (void)arg;
+    return malloc(12345);
+}
+
+static int untracked_free(void) {
+    // Returns 0 if the allocation that `realloc` failed to track is freed
+    size_t (*bytes_live)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_bytes_live");
+    if (!bytes_live) {
+        return 1;
+    }
+    char *p = malloc(100);
+    setenv("FAIL_SMALL_MMAP", "1", 1);
+    char *q = realloc(p, 200 << 20);
+    unsetenv("FAIL_SMALL_MMAP");
+    if (!q) {
+        return 1;
+    }
+    memset(q, 1, 200 << 20);
+    size_t before = bytes_live();
+    free(q);
+    int ok = bytes_live() + (200 << 20) <= before;
+    // The metadata is left behind, but marked freed
+    free(q);
+    return ok ? 0 : 1;
+}
+
+static int max_leaves(void) {
+    // Run with `FATALLOC_MAX_LEAVES=8`. Each allocation aligned to 64 MiB,
+    // the address range of a leaf, needs a leaf of its own.
+    char *p = malloc(100);
+    static void *ps[16];
+    size_t num_allocated = 0;
+    while (num_allocated < 16 && (ps[num_allocated] = aligned_alloc(64 << 20, 64))) {
+        ++num_allocated;
+    }
+    if (!p || num_allocated == 0 || num_allocated > 8 || errno != ENOMEM) {
+        return 1;
+    }
+
+    memset(p, 1, 100);
+    free(p);
+
+    // A leaf emptied by deallocation is recycled
+    free(ps[0]);
+    ps[0] = aligned_alloc(64 << 20, 64);
+    if (!ps[0]) {
+        return 1;
+    }
+    for (size_t i = 0; i < num_allocated; ++i) {
+        free(ps[i]);
+    }
+    return 0;
+}
+
+static int mmap_retry(void) {
+    // Run with `fail_small_mmap.so`. A new leaf of the allocation map fails
+    // to map once, which is retried after flushing the quarantine.
+    bool (*owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    int *num_failed_mmaps = (int *)dlsym(RTLD_DEFAULT, "num_failed_mmaps");
+    if (!owns || !num_failed_mmaps) {
+        return 1;
+    }
+    for (int i = 0; i < 16; ++i) {
+        free(malloc(1000));
+    }
+    setenv("FAIL_SMALL_MMAP", "once", 1);
+    char *p = malloc(200 << 20);
+    unsetenv("FAIL_SMALL_MMAP");
+    int ok = p && owns(p) && *num_failed_mmaps == 1;
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static int thread_stats(void) {
+    // The counts of an exited thread are kept, and memory freed by another
+    // thread is subtracted from the total
+    size_t (*bytes_live)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_bytes_live");
+    if (!bytes_live) {
+        return 1;
+    }
+    // The first thread makes glibc allocate bookkeeping that it keeps
+    pthread_t thread;
+    void *p;
+    pthread_create(&thread, NULL, allocate_and_exit, NULL);
+    pthread_join(thread, &p);
+    free(p);
+
+    int ok = 1;
+    for (int i = 0; i < 4; ++i) {
+        size_t before = bytes_live();
+        pthread_create(&thread, NULL, allocate_and_exit, NULL);
+        pthread_join(thread, &p);
+        ok &= bytes_live() == before + 12345;
+        free(p);
+        ok &= bytes_live() == before;
+    }
+    return ok ? 0 : 1;
+}
+
+static int overhead(void) {
+    size_t (*overhead_bytes)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_overhead_bytes");
+    void *(*get_outer_pointer)(void *, size_t *) =
+        (void *(*)(void *, size_t *))dlsym(RTLD_DEFAULT, "fatalloc_outer_pointer");
+    if (!overhead_bytes || !get_outer_pointer) {
+        return 1;
+    }
+
+    static const size_t sizes[] = {1, 1000, 100000};
+    void *ps[3];
+    size_t expected = overhead_bytes();
+    for (int i = 0; i < 3; ++i) {
+        size_t margin;
+        ps[i] = malloc(sizes[i]);
+        get_outer_pointer(ps[i], &margin);
+        expected += margin * 2;
+    }
+    if (overhead_bytes() != expected) {
+        return 1;
+    }
+
+    size_t margin;
+    get_outer_pointer(ps[0], &margin);
+    expected -= margin * 2;
+    ps[0] = realloc(ps[0], 1000000);
+    get_outer_pointer(ps[0], &margin);
+    expected += margin * 2;
+    if (overhead_bytes() != expected) {
+        return 1;
+    }
+
+    for (int i = 0; i < 3; ++i) {
+        get_outer_pointer(ps[i], &margin);
+        expected -= margin * 2;
+        free(ps[i]);
+    }
+    return overhead_bytes() == expected ? 0 : 1;
+}
+
+static int count_tracked(void) {
+    size_t (*count_tracked)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_count_tracked");
+    size_t (*live_allocations)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_live_allocations");
+    void (*free_batch)(void **, size_t) =
+        (void (*)(void **, size_t))dlsym(RTLD_DEFAULT, "fatalloc_free_batch");
+    if (!count_tracked || !live_allocations || !free_batch) {
+        return 1;
+    }
+    size_t base = count_tracked();
+    if (live_allocations() != base) {
+        return 1;
+    }
+
+    static void *ps[1000];
+    for (int i = 0; i < 1000; ++i) {
+        ps[i] = i % 2 ? malloc(i * 10) : calloc(i, 3);
+    }
+    for (int i = 0; i < 300; ++i) {
+        ps[i] = realloc(ps[i], i * 100 + 1);
+    }
+    for (int i = 0; i < 250; ++i) {
+        free(ps[i]);
+    }
+    free_batch(ps + 250, 250);
+    // An invalid free changes nothing
+    free(launder(ps[0]));
+    if (count_tracked() != base + 500 || live_allocations() != base + 500) {
+        return 1;
+    }
+    for (int i = 500; i < 1000; ++i) {
+        free(ps[i]);
+    }
+    return count_tracked() == base && live_allocations() == base ? 0 : 1;
+}
+
+static int map_memory(void) {
+    size_t (*map_memory_bytes)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_map_memory_bytes");
+    if (!map_memory_bytes) {
+        return 1;
+    }
+
+    // Each allocation is in a range of its own, which needs at least a leaf
+    // (2 KiB) and a sub-leaf (4 KiB) on a 64-bit target. They're not freed
+    // because that would fill them.
+    size_t last = map_memory_bytes();
+    for (int i = 0; i < 3; ++i) {
+        if (!launder(malloc(100 << 20))) {
+            return 1;
+        }
+        size_t bytes = map_memory_bytes();
+        if (bytes < last + 6144 || bytes > last + (1 << 20)) {
+            return 1;
+        }
+        last = bytes;
+    }
+    return 0;
+}
+
+static int quarantine_budget(void) {
+    // Run with `QUARANTINE_BUDGET=<expected budget>`
+    size_t (*budget)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_quarantine_budget");
+    if (!budget) {
+        return 1;
+    }
+    return budget() == strtoull(getenv("QUARANTINE_BUDGET"), NULL, 10) ? 0 : 1;
+}
+
+static int metrics(void) {
+    // Run with `FATALLOC_METRICS_FILE=<path>`
+    bool (*export_metrics)(void) = (bool (*)(void))dlsym(RTLD_DEFAULT, "fatalloc_export_metrics");
+    if (!export_metrics) {
+        return 1;
+    }
+    sink = malloc(100000);
+    if (!export_metrics()) {
+        return 1;
+    }
+
+    FILE *f = fopen(getenv("FATALLOC_METRICS_FILE"), "r");
+    if (!f) {
+        return 1;
+    }
+    char line[256];
+    size_t live_bytes = 0, num_allocations = 0;
+    while (fgets(line, sizeof line, f)) {
+        sscanf(line, "fatalloc_live_bytes %zu", &live_bytes);
+        sscanf(line, "fatalloc_allocations_total %zu", &num_allocations);
+    }
+    fclose(f);
+    if (live_bytes < 100000 || num_allocations < 1) {
+        return 1;
+    }
+
+    // Processes exporting to the same file at once don't get in each other's
+    // way
+    pid_t pids[4];
+    for (int i = 0; i < 4; ++i) {
+        if ((pids[i] = fork()) == 0) {
+            for (int k = 0; k < 200; ++k) {
+                if (!export_metrics()) {
+                    _exit(1);
+                }
+            }
+            _exit(0);
+        }
+    }
+    int ok = 1;
+    for (int i = 0; i < 4; ++i) {
+        int status;
+        ok &= waitpid(pids[i], &status, 0) == pids[i] && WIFEXITED(status) &&
+              WEXITSTATUS(status) == 0;
+    }
+    return ok ? 0 : 1;
+}
+
+static __attribute__((noinline)) void *folded_site(size_t size) { return launder(malloc(size)); }
+
+static int folded(void) {
+    // Run with `FATALLOC_FOLDED_FILE=<path>`
+    bool (*dump_folded)(void) = (bool (*)(void))dlsym(RTLD_DEFAULT, "fatalloc_dump_folded");
+    if (!dump_folded) {
+        return 1;
+    }
+    // The first two share a stack
+    void *p[3];
+    for (int i = 0; i < 2; ++i) {
+        p[i] = folded_site(1000);
+    }
+    p[2] = folded_site(300);
+    if (!dump_folded()) {
+        return 1;
+    }
+
+    FILE *f = fopen(getenv("FATALLOC_FOLDED_FILE"), "r");
+    if (!f) {
+        return 1;
+    }
+    char line[1024];
+    bool found_shared = false, found_single = false;
+    while (fgets(line, sizeof line, f)) {
+        char *bytes = strrchr(line, ' ');
+        if (!bytes || !strchr(line, ';')) {
+            return 1;
+        }
+        found_shared |= strcmp(bytes, " 2000\n") == 0;
+        found_single |= strcmp(bytes, " 300\n") == 0;
+    }
+    fclose(f);
+    for (int i = 0; i < 3; ++i) {
+        free(p[i]);
+    }
+    return found_shared && found_single ? 0 : 1;
+}
+
+// Run with `FATALLOC_IDENTITY_FILL=1`
+static int identity_fill(void) {
+    void *(*decode_fill)(uintptr_t) =
+        (void *(*)(uintptr_t))dlsym(RTLD_DEFAULT, "fatalloc_decode_fill");
+    if (!decode_fill) {
+        return 1;
+    }
+    // Every word, and the bytes of the partial one at the end, identify the
+    // block while it's in the quarantine
+    unsigned char *p = malloc(100);
+    memset(p, 0, 100);
+    free(p);
+    const unsigned char *freed = launder(p);
+    uintptr_t word;
+    for (size_t i = 0; i + sizeof word <= 100; i += sizeof word) {
+        memcpy(&word, freed + i, sizeof word);
+        if (decode_fill(word) != p) {
+            return 1;
+        }
+    }
+    memcpy(&word, freed, sizeof word);
+    if (memcmp(freed + 100 / sizeof word * sizeof word, &word, 100 % sizeof word) != 0) {
+        return 1;
+    }
+    // Other values don't decode
+    return decode_fill(0) == NULL && decode_fill((uintptr_t)p) == NULL ? 0 : 1;
+}
+
+static int churn(void) {
+    size_t (*churn_rate)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_churn_rate");
+    if (!churn_rate) {
+        return 1;
+    }
+    // Starts the first interval
+    if (churn_rate() != 0) {
+        return 1;
+    }
+
+    // Up to 100000 allocations in a little over a second
+    struct timespec start, now;
+    clock_gettime(CLOCK_MONOTONIC, &start);
+    do {
+        for (int i = 0; i < 1000; ++i) {
+            sink = malloc(24);
+            free(sink);
+        }
+        usleep(10000);
+        clock_gettime(CLOCK_MONOTONIC, &now);
+    } while ((now.tv_sec - start.tv_sec) * 1000 + (now.tv_nsec - start.tv_nsec) / 1000000 < 1050);
+    size_t rate = churn_rate();
+    // Within the interval just completed
+    return rate >= 1000 && rate <= 1000000 && churn_rate() == rate ? 0 : 1;
+}
+
+static int consistency(void) {
+    size_t (*check)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_check_consistency");
+    if (!check) {
+        return 1;
+    }
+    void *ps[100];
+    for (int i = 0; i < 100; ++i) {
+        ps[i] = malloc(i * 37);
+    }
+    for (int i = 0; i < 100; i += 2) {
+        free(ps[i]);
+    }
+    size_t num_collisions = check();
+    for (int i = 1; i < 100; i += 2) {
+        free(ps[i]);
+    }
+    return num_collisions == 0 ? 0 : 1;
+}
+
+struct corruption_event {
+    uint32_t kind;
+    void *ptr;
+    size_t size;
+    size_t time;
+};
+
+static int corruptions(void) {
+    // Run with `FATALLOC_ON_CORRUPTION=ignore`
+    size_t (*recent)(struct corruption_event *, size_t) =
+        (size_t(*)(struct corruption_event *, size_t))dlsym(RTLD_DEFAULT,
+                                                               "fatalloc_recent_corruptions");
+    size_t (*dropped)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_dropped_corruptions");
+    if (!recent || !dropped) {
+        return 1;
+    }
+
+    // Three underflows, drained in two calls
+    char *ps[3];
+    for (int i = 0; i < 3; ++i) {
+        ps[i] = malloc(16);
+        ((char *)launder(ps[i]))[-1] ^= 1;
+        free(ps[i]);
+    }
+    struct corruption_event events[70];
+    if (recent(events, 2) != 2 || recent(events + 2, 70) != 1 || recent(events, 70) != 0) {
+        return 1;
+    }
+    for (int i = 0; i < 3; ++i) {
+        if (events[i].kind != 1 || events[i].ptr != ps[i]) {
+            return 1;
+        }
+    }
+
+    // Overflow the ring buffer, which keeps the newest 64
+    for (int i = 0; i < 70; ++i) {
+        char *p = malloc(16);
+        ((char *)launder(p))[-1] ^= 1;
+        free(p);
+    }
+    return recent(events, 70) == 64 && dropped() == 6 ? 0 : 1;
+}
+
+// The total size of lazily freed pages in KiB
+static long lazy_free_kib(void) {
+    FILE *f = fopen("/proc/self/smaps_rollup", "r");
+    if (!f) {
+        return -1;
+    }
+    char line[256];
+    long kib = -1;
+    while (fgets(line, sizeof line, f)) {
+        sscanf(line, "LazyFree: %ld kB", &kib);
+    }
+    fclose(f);
+    return kib;
+}
+
+static int report_once(void) {
+    // The same broken canary is found on every query
+    size_t (*suppressed)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_suppressed_corruptions");
+    if (!suppressed) {
+        return 1;
+    }
+    char *p = malloc(16);
+    ((char *)launder(p))[-1] ^= 1;
+    for (int i = 0; i < 100; ++i) {
+        sink = (void *)malloc_usable_size(p);
+    }
+    size_t expected = getenv("FATALLOC_REPORT_ONCE") ? 99 : 0;
+    return suppressed() == expected ? 0 : 1;
+}
+
+static int madv_free(void) {
+    // Run with `FATALLOC_QUARANTINE_MADV_FREE=1`
+    long before = lazy_free_kib();
+    char *p = malloc(256 << 10);
+    memset(p, 0xab, 256 << 10);
+    free(p);
+    // Without memory pressure, the quarantined contents are still there
+    volatile char *freed = launder(p);
+    return lazy_free_kib() >= before + 128 && freed[128 << 10] == (char)0xab ? 0 : 1;
+}
+
+// The resident set size in KiB
+static long rss_kib(void) {
+    FILE *f = fopen("/proc/self/statm", "r");
+    if (!f) {
+        return -1;
+    }
+    long pages = -1;
+    if (fscanf(f, "%*ld %ld", &pages) != 1) {
+        pages = -1;
+    }
+    fclose(f);
+    return pages * (sysconf(_SC_PAGESIZE) / 1024);
+}
+
+// The size of memory locked by `mlock` in KiB
+static long locked_kib(void) {
+    FILE *f = fopen("/proc/self/status", "r");
+    if (!f) {
+        return -1;
+    }
+    char line[256];
+    long kib = -1;
+    while (fgets(line, sizeof line, f)) {
+        sscanf(line, "VmLck: %ld kB", &kib);
+    }
+    fclose(f);
+    return kib;
+}
+
+static int secure(void) {
+    void *(*malloc_secure)(size_t) = (void *(*)(size_t))dlsym(RTLD_DEFAULT, "fatalloc_malloc_secure");
+    if (!malloc_secure) {
+        return 1;
+    }
+    char *p = malloc_secure(100);
+    if (!p) {
+        return 1;
+    }
+    int ok = (size_t)p % 4096 == 0 && locked_kib() > 0;
+    memset(p, 0xaa, 100);
+
+    // Freed blocks stay readable in the quarantine, where both the original
+    // block and the moved one must be zeroed
+    char *q = realloc(p, 5000);
+    ok &= q != p && q[99] == (char)0xaa;
+    volatile char *freed = launder(p);
+    for (int i = 0; i < 100; ++i) {
+        ok &= freed[i] == 0;
+    }
+    free(q);
+    freed = launder(q);
+    for (int i = 0; i < 5000; ++i) {
+        ok &= freed[i] == 0;
+    }
+    return ok && locked_kib() == 0 ? 0 : 1;
+}
+
+static int compact(void) {
+    size_t (*fatalloc_compact)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_compact");
+    if (!fatalloc_compact) {
+        return 1;
+    }
+    // Fill the quarantine (4 MiB by default) with resident pages
+    enum { COUNT = 48, SIZE = 64 << 10 };
+    char *ps[COUNT];
+    for (int i = 0; i < COUNT; i++) {
+        ps[i] = malloc(SIZE);
+        memset(ps[i], 0xab, SIZE);
+    }
+    for (int i = 0; i < COUNT; i++) {
+        free(ps[i]);
+    }
+    long before = rss_kib();
+    size_t released = fatalloc_compact();
+    long after = rss_kib();
+    return released >= COUNT * SIZE && after <= before - COUNT * SIZE / 1024 / 2 ? 0 : 1;
+}
+
+static int map_stats(void) {
+    size_t (*fatalloc_dump_map_stats)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_dump_map_stats");
+    size_t (*fatalloc_compact)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_compact");
+    if (!fatalloc_dump_map_stats || !fatalloc_compact) {
+        return 1;
+    }
+    // An allocation aligned this much has margins of the same size, which
+    // keep everything else out of its leaf
+    enum { COUNT = 4, ALIGN = 1 << 27 };
+    void *ps[COUNT];
+    size_t before = fatalloc_dump_map_stats();
+    for (int i = 0; i < COUNT; i++) {
+        ps[i] = memalign(ALIGN, 16);
+    }
+    size_t during = fatalloc_dump_map_stats();
+    for (int i = 0; i < COUNT; i++) {
+        free(ps[i]);
+    }
+    fatalloc_compact();
+    size_t after = fatalloc_dump_map_stats();
+    return during == before + COUNT && after <= before ? 0 : 1;
+}
+
+static int freeze(void) {
+    void (*fatalloc_freeze)(void) = (void (*)(void))dlsym(RTLD_DEFAULT, "fatalloc_freeze");
+    void (*fatalloc_unfreeze)(void) = (void (*)(void))dlsym(RTLD_DEFAULT, "fatalloc_unfreeze");
+    if (!fatalloc_freeze || !fatalloc_unfreeze) {
+        return 1;
+    }
+    char *p = malloc(100), *q = malloc(100);
+    fatalloc_freeze();
+    int ok = 1;
+    errno = 0;
+    ok &= malloc(16) == NULL && errno == ENOMEM;
+    ok &= calloc(1, 16) == NULL;
+    ok &= realloc(p, 1000) == NULL;
+    // Shrinking and freeing still work
+    p = realloc(p, 10);
+    ok &= p != NULL;
+    free(q);
+    fatalloc_unfreeze();
+    q = malloc(16);
+    ok &= q != NULL;
+    free(q);
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static int realloc_hash(void) {
+    // Run with `FATALLOC_CONTENT_HASH=1`
+    char *p = malloc(100);
+    memset(p, 1, 100);
+    p = realloc(p, 120);
+    free(p);
+    return 0;
+}
+
+static int sizes(void) {
+    // Run with `FATALLOC_ROUND_SIZES=1`
+    bool (*allocation_sizes)(void *, size_t *, size_t *) =
+        (bool (*)(void *, size_t *, size_t *))dlsym(RTLD_DEFAULT, "fatalloc_allocation_sizes");
+    if (!allocation_sizes) {
+        return 1;
+    }
+    size_t requested, granted;
+    char *p = malloc(13);
+    int ok = allocation_sizes(p, &requested, &granted) && requested == 13 &&
+             granted == malloc_usable_size(p) && granted >= 16 && granted % 8 == 0;
+    // Resized within the granted size
+    p = realloc(p, 15);
+    ok &= allocation_sizes(p, &requested, NULL) && requested == 15;
+    p = realloc(p, 1001);
+    ok &= allocation_sizes(p, &requested, &granted) && requested == 1001 && granted > 1001;
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static int realloc_same(void) {
+    // Reallocating to the same size keeps the allocation where it is, intact
+    // and tracked
+    bool (*owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    size_t (*live_allocations)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_live_allocations");
+    size_t (*bytes_live)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_bytes_live");
+    if (!owns || !live_allocations || !bytes_live) {
+        return 1;
+    }
+    static const size_t sizes[] = {1, 1000, 100000};
+    for (int i = 0; i < 3; ++i) {
+        char *p = malloc(sizes[i]);
+        memset(p, 1, sizes[i]);
+        size_t allocations = live_allocations(), bytes = bytes_live();
+        for (int k = 0; k < 1000; ++k) {
+            if (realloc(p, sizes[i]) != p) {
+                return 1;
+            }
+        }
+        if (!owns(p) || live_allocations() != allocations || bytes_live() != bytes ||
+            p[sizes[i] - 1] != 1) {
+            return 1;
+        }
+        free(p);
+        // A double free is still caught
+        free(launder(p));
+    }
+    return 0;
+}
+
+static int realloc_align(void) {
+    // The alignment outlives reallocation
+    void *p;
+    if (posix_memalign(&p, 4096, 100) != 0) {
+        return 1;
+    }
+    int ok = 1;
+    static const size_t sizes[] = {10000, 10, 200000};
+    for (int i = 0; i < 3; ++i) {
+        p = realloc(p, sizes[i]);
+        ok &= p && (size_t)p % 4096 == 0 && malloc_usable_size(p) == sizes[i];
+    }
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static int protect_metadata(void) {
+    // Run with `FATALLOC_PROTECT_METADATA=1`. The metadata of a page-aligned
+    // allocation is in the page before it.
+    void *p;
+    if (posix_memalign(&p, 4096, 100) != 0) {
+        return 1;
+    }
+    pid_t pid = fork();
+    if (pid == 0) {
+        ((char *)launder(p))[-16] = 0;
+        _exit(0);
+    }
+    int status;
+    if (waitpid(pid, &status, 0) != pid || !WIFSIGNALED(status) || WTERMSIG(status) != SIGSEGV) {
+        return 1;
+    }
+
+    // It's lifted while the allocator updates the metadata
+    bool (*pin)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_pin_allocation");
+    bool (*unpin)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_unpin_allocation");
+    if (!pin || !unpin || !pin(p) || !unpin(p)) {
+        return 1;
+    }
+    p = realloc(p, 10000);
+    free(p);
+    return 0;
+}
+
+static int tag_pointer(void) {
+    // Run with the `pointer-tag` feature
+    void *(*tag_pointer)(void *, unsigned char) =
+        (void *(*)(void *, unsigned char))dlsym(RTLD_DEFAULT, "fatalloc_tag_pointer");
+    void (*sdallocx)(void *, size_t, int) =
+        (void (*)(void *, size_t, int))dlsym(RTLD_DEFAULT, "sdallocx");
+    if (!tag_pointer || !sdallocx) {
+        return 1;
+    }
+    const uintptr_t mask = ~((uintptr_t)0xff << 56);
+    int ok = 1;
+    // The tag goes in the top byte, and the tagged pointer is accepted
+    char *p = malloc(100);
+    char *t = tag_pointer(p, 0x2a);
+    ok &= (uintptr_t)t >> 56 == 0x2a && ((uintptr_t)t & mask) == (uintptr_t)p;
+    ok &= malloc_usable_size(t) >= 100;
+    // ... and carried over by moving reallocation
+    t = realloc(t, 100000);
+    ok &= t && (uintptr_t)t >> 56 == 0x2a;
+    // A different tag is reported, but the allocation is freed anyway
+    free((void *)(((uintptr_t)t & mask) | (uintptr_t)0x17 << 56));
+    // An untagged pointer is accepted
+    p = malloc(100);
+    ok &= tag_pointer(p, 5) != NULL;
+    free(p);
+    ok &= tag_pointer(launder(p), 6) == NULL;
+    p = tag_pointer(malloc(100), 7);
+    sdallocx((void *)(((uintptr_t)p & mask) | (uintptr_t)8 << 56), 100, 0);
+    return ok ? 0 : 1;
+}
+
+struct found_allocations {
+    size_t num_found;
+    void *ptrs[64];
+    size_t sizes[64];
+};
+
+static void collect_allocation(void *ptr, size_t size, void *ctx) {
+    struct found_allocations *found = ctx;
+    if (found->num_found < 64) {
+        found->ptrs[found->num_found] = ptr;
+        found->sizes[found->num_found] = size;
+    }
+    ++found->num_found;
+}
+
+static int allocations_in_range(void) {
+    void (*in_range)(uintptr_t, uintptr_t, void (*)(void *, size_t, void *), void *) =
+        (void (*)(uintptr_t, uintptr_t, void (*)(void *, size_t, void *), void *))dlsym(
+            RTLD_DEFAULT, "fatalloc_allocations_in_range");
+    if (!in_range) {
+        return 1;
+    }
+    int ok = 1;
+    char *ps[8];
+    uintptr_t lowest = UINTPTR_MAX, highest = 0;
+    for (int i = 0; i < 8; ++i) {
+        ps[i] = malloc(100 << i);
+        lowest = (uintptr_t)ps[i] < lowest ? (uintptr_t)ps[i] : lowest;
+        highest = (uintptr_t)ps[i] > highest ? (uintptr_t)ps[i] : highest;
+    }
+    free(ps[3]);
+
+    // Every live one is found once with its usable size, in the address
+    // order, along with any other allocations in between
+    struct found_allocations found = {0};
+    in_range(lowest, highest + 1, collect_allocation, &found);
+    ok &= found.num_found <= 64;
+    for (size_t j = 1; j < found.num_found && j < 64; ++j) {
+        ok &= found.ptrs[j - 1] < found.ptrs[j];
+    }
+    for (int i = 0; i < 8; ++i) {
+        int num_matches = 0;
+        for (size_t j = 0; j < found.num_found && j < 64; ++j) {
+            if (found.ptrs[j] == ps[i]) {
+                ++num_matches;
+                ok &= found.sizes[j] == malloc_usable_size(ps[i]);
+            }
+        }
+        ok &= num_matches == (i == 3 ? 0 : 1);
+    }
+
+    // The end is exclusive, and an unaligned start skips the allocation
+    memset(&found, 0, sizeof found);
+    in_range((uintptr_t)ps[0], (uintptr_t)ps[0] + 1, collect_allocation, &found);
+    ok &= found.num_found == 1 && found.ptrs[0] == ps[0];
+    memset(&found, 0, sizeof found);
+    in_range((uintptr_t)ps[0] + 1, (uintptr_t)ps[0] + 16, collect_allocation, &found);
+    in_range((uintptr_t)ps[0], (uintptr_t)ps[0], collect_allocation, &found);
+    ok &= found.num_found == 0;
+
+    for (int i = 0; i < 8; ++i) {
+        if (i != 3) {
+            free(ps[i]);
+        }
+    }
+    return ok ? 0 : 1;
+}
+
+static int pin_free(void) {
+    bool (*pin)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_pin_allocation");
+    bool (*unpin)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_unpin_allocation");
+    if (!pin || !unpin) {
+        return 1;
+    }
+    int ok = 1;
+    // A freed pinned allocation is retained and checked when unpinned, once
+    char *p = malloc(100);
+    ok &= pin(p);
+    free(p);
+    ((char *)launder(p))[10] = 1;
+    ok &= unpin(p);
+    ok &= !unpin(launder(p));
+    // A freed allocation that isn't pinned is rejected without being read
+    char *q = malloc(100000);
+    free(q);
+    ok &= !unpin(launder(q));
+    char *r = malloc(100);
+    ok &= !unpin(r);
+    free(r);
+    return ok ? 0 : 1;
+}
+
+static int readonly(void) {
+    bool (*make_readonly)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_make_readonly");
+    if (!make_readonly) {
+        return 1;
+    }
+    char *p = valloc(8192);
+    memset(p, 1, 8192);
+    if (!make_readonly(p) || !make_readonly(p) || p[8191] != 1) {
+        return 1;
+    }
+
+    // Writing faults
+    for (size_t offset = 0; offset < 8192; offset += 8191) {
+        pid_t pid = fork();
+        if (pid == 0) {
+            ((char *)launder(p))[offset] = 0;
+            _exit(0);
+        }
+        int status;
+        if (waitpid(pid, &status, 0) != pid || !WIFSIGNALED(status) ||
+            WTERMSIG(status) != SIGSEGV) {
+            return 1;
+        }
+    }
+
+    // Reallocating and freeing lift the protection
+    p = realloc(p, 16384);
+    if (!p || p[8191] != 1) {
+        return 1;
+    }
+    memset(p, 2, 16384);
+    if (!make_readonly(p)) {
+        return 1;
+    }
+    free(p);
+
+    // A small allocation has no whole pages to protect
+    char *q = malloc(100);
+    if (make_readonly(q)) {
+        return 1;
+    }
+    q[0] = 0;
+    free(q);
+    return 0;
+}
+
+static int readonly_registry(void) {
+    // Run with `FATALLOC_REGISTRY=1`. Inserting and removing neighbors in
+    // the registry updates the metadata of a read-only allocation.
+    bool (*make_readonly)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_make_readonly");
+    bool (*pin)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_pin_allocation");
+    bool (*unpin)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_unpin_allocation");
+    size_t (*registered)(void **, size_t, bool) =
+        (size_t (*)(void **, size_t, bool))dlsym(RTLD_DEFAULT, "fatalloc_registered_allocations");
+    void *(*tag_pointer)(void *, unsigned char) =
+        (void *(*)(void *, unsigned char))dlsym(RTLD_DEFAULT, "fatalloc_tag_pointer");
+    if (!make_readonly || !pin || !unpin || !registered) {
+        return 1;
+    }
+    void *p;
+    if (posix_memalign(&p, 4096, 8192) != 0) {
+        return 1;
+    }
+    void *before = malloc(100);
+    if (!pin(p) || !make_readonly(p)) {
+        return 1;
+    }
+    void *after = malloc(100);
+    free(before);
+
+    // These are rejected instead of writing to the protected pages
+    if (pin(p) || unpin(p) || (tag_pointer && tag_pointer(p, 1))) {
+        return 1;
+    }
+    pid_t pid = fork();
+    if (pid == 0) {
+        ((char *)launder(p))[0] = 0;
+        _exit(0);
+    }
+    int status;
+    if (waitpid(pid, &status, 0) != pid || !WIFSIGNALED(status) || WTERMSIG(status) != SIGSEGV) {
+        return 1;
+    }
+
+    // `p` is still linked between its neighbors
+    void *ptrs[64];
+    size_t n = registered(ptrs, 64, true);
+    if (n < 2 || ptrs[0] != after || ptrs[1] != p) {
+        return 1;
+    }
+    free(p);
+    free(after);
+    if (!unpin(p)) {
+        return 1;
+    }
+    n = registered(ptrs, 64, true);
+    for (size_t i = 0; i < n; ++i) {
+        if (ptrs[i] == p || ptrs[i] == after) {
+            return 1;
+        }
+    }
+    return 0;
+}
+
+static int numa_node(void) {
+    void *(*alloc_on_node)(size_t, size_t, int) =
+        (void *(*)(size_t, size_t, int))dlsym(RTLD_DEFAULT, "fatalloc_aligned_alloc_on_node");
+    if (!alloc_on_node) {
+        return 1;
+    }
+    char *p = alloc_on_node(16, 100000, 0);
+    if (!p || (size_t)p % 8192 != 0) {
+        return 1;
+    }
+    memset(p, 1, 100000);
+
+    // Check the policy unless the kernel lacks NUMA support. 1 =
+    // `MPOL_PREFERRED`, 2 = `MPOL_F_ADDR`.
+    int mode;
+    unsigned long nodes = 0;
+    if (syscall(SYS_get_mempolicy, &mode, &nodes, sizeof nodes * 8 + 1, p + 50000, 2) == 0 &&
+        (mode != 1 || nodes != 1)) {
+        return 1;
+    }
+    free(p);
+    return 0;
+}
+
+static int min_size(void) {
+    // Run with `FATALLOC_MIN_SIZE=64 FATALLOC_BACKEND=system`
+    void *(*get_outer_pointer)(void *, size_t *) =
+        (void *(*)(void *, size_t *))dlsym(RTLD_DEFAULT, "fatalloc_outer_pointer");
+    int (*verify)(void *) = (int (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_verify");
+    void *libc = dlopen("libc.so.6", RTLD_LAZY | RTLD_NOLOAD);
+    size_t (*libc_usable_size)(void *) =
+        libc ? (size_t(*)(void *))dlsym(libc, "malloc_usable_size") : NULL;
+    if (!get_outer_pointer || !verify || !libc_usable_size) {
+        return 1;
+    }
+    char *p = malloc(1);
+    size_t margin = 0;
+    char *outer = get_outer_pointer(p, &margin);
+    // The block is padded, but the allocation ends where requested
+    int ok = libc_usable_size(outer) >= 64 + margin * 2 && malloc_usable_size(p) == 1;
+    // followed by the poisoned padding
+    for (int i = 1; i < 64; ++i) {
+        ok &= ((unsigned char *)launder(p))[i] == 0xdf;
+    }
+    ok &= verify(p) == 0;
+    ((char *)launder(p))[1] = 0;
+    ok &= verify(p) == 5;
+    ((char *)launder(p))[1] = (char)0xdf;
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static int jemalloc_api(void) {
+    // Run with `FATALLOC_ROUND_SIZES=1`, which rounds 13 bytes up to 16
+    void *(*mallocx)(size_t, int) = (void *(*)(size_t, int))dlsym(RTLD_DEFAULT, "mallocx");
+    void (*sdallocx)(void *, size_t, int) =
+        (void (*)(void *, size_t, int))dlsym(RTLD_DEFAULT, "sdallocx");
+    size_t (*nallocx)(size_t, int) = (size_t(*)(size_t, int))dlsym(RTLD_DEFAULT, "nallocx");
+    if (!mallocx || !sdallocx || !nallocx) {
+        return 1;
+    }
+    int ok = nallocx(13, 0) == 16 && nallocx(13, 6) == 16;
+
+    // `MALLOCX_LG_ALIGN(6) | MALLOCX_ZERO`
+    unsigned char *p = mallocx(13, 6 | 0x40);
+    ok &= p && (uintptr_t)p % 64 == 0 && malloc_usable_size(p) == 16;
+    for (int i = 0; i < 16; ++i) {
+        ok &= p[i] == 0;
+    }
+    // Any size from the requested one to the usable one is right
+    sdallocx(p, 16, 0);
+    sdallocx(mallocx(13, 0), 13, 0);
+    // but not this one
+    sdallocx(mallocx(13, 0), 100, 0);
+    return ok ? 0 : 1;
+}
+
+static int checked_free(void) {
+    // Run with `FATALLOC_QUARANTINE_BYTES=0 FATALLOC_BACKEND=system` so that
+    // freed addresses are reused right away
+    void *(*malloc_checked)(size_t, uint64_t *) =
+        (void *(*)(size_t, uint64_t *))dlsym(RTLD_DEFAULT, "fatalloc_malloc_checked");
+    bool (*free_checked)(void *, uint64_t) =
+        (bool (*)(void *, uint64_t))dlsym(RTLD_DEFAULT, "fatalloc_free_checked");
+    bool (*owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    if (!malloc_checked || !free_checked || !owns) {
+        return 1;
+    }
+
+    // Nothing is allocated without somewhere to put the token
+    if (malloc_checked(100, NULL)) {
+        return 1;
+    }
+
+    // A wrong token leaves it allocated
+    uint64_t token;
+    char *p = malloc_checked(100, &token);
+    if (!p || free_checked(p, token + 1) || !owns(p)) {
+        return 1;
+    }
+    // The right one frees it, once
+    if (!free_checked(p, token) || free_checked(launder(p), token)) {
+        return 1;
+    }
+
+    // The stale token doesn't free the next allocation at the same address,
+    // whether it has a token of its own or not
+    for (int i = 0; i < 100; ++i) {
+        uint64_t new_token;
+        char *q = malloc_checked(100, &new_token);
+        if (q == p) {
+            if (free_checked(q, token) || !free_checked(q, new_token)) {
+                return 1;
+            }
+            q = malloc(100);
+            if (q != p || free_checked(q, token) || free_checked(q, new_token)) {
+                return 1;
+            }
+            free(q);
+            return 0;
+        }
+        free(q);
+    }
+    return 1;
+}
+
+static int sized_free(void) {
+    // Mismatched sizes are refused with `FATALLOC_STRICT_SIZED_FREE=1`
+    void (*sdallocx)(void *, size_t, int) =
+        (void (*)(void *, size_t, int))dlsym(RTLD_DEFAULT, "sdallocx");
+    void *(*cpp_new)(size_t) = (void *(*)(size_t))dlsym(RTLD_DEFAULT, "_Znwm");
+    void (*cpp_sized_delete)(void *, size_t) =
+        (void (*)(void *, size_t))dlsym(RTLD_DEFAULT, "_ZdlPvm");
+    bool (*owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    if (!sdallocx || !cpp_new || !cpp_sized_delete || !owns) {
+        return 1;
+    }
+    const char *strict = getenv("FATALLOC_STRICT_SIZED_FREE");
+    bool refuses = strict && strcmp(strict, "1") == 0;
+
+    // A matching size always frees it
+    void *p = malloc(13);
+    sdallocx(p, 13, 0);
+    void *q = cpp_new(13);
+    cpp_sized_delete(q, 13);
+    if (owns(launder(p)) || owns(launder(q))) {
+        return 1;
+    }
+
+    // A mismatched one leaves it intact and allocated if refused
+    p = malloc(13);
+    memset(p, 1, 13);
+    sdallocx(p, 100, 0);
+    q = cpp_new(13);
+    cpp_sized_delete(q, 100);
+    if (owns(launder(p)) != refuses || owns(launder(q)) != refuses) {
+        return 1;
+    }
+    if (refuses) {
+        if (((unsigned char *)p)[12] != 1) {
+            return 1;
+        }
+        free(p);
+        cpp_sized_delete(q, 13);
+    }
+    return 0;
+}
+
+static int rust_dealloc(void) {
+    // Mismatched layouts are refused with `FATALLOC_STRICT_SIZED_FREE=1`
+    void *(*rust_alloc)(size_t, size_t) =
+        (void *(*)(size_t, size_t))dlsym(RTLD_DEFAULT, "fatalloc_rust_alloc");
+    void (*rust_dealloc)(void *, size_t, size_t) =
+        (void (*)(void *, size_t, size_t))dlsym(RTLD_DEFAULT, "fatalloc_rust_dealloc");
+    bool (*owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    if (!rust_alloc || !rust_dealloc || !owns || rust_alloc(8, 3)) {
+        return 1;
+    }
+    const char *strict = getenv("FATALLOC_STRICT_SIZED_FREE");
+    bool refuses = strict && strcmp(strict, "1") == 0;
+
+    // The matching layout frees it silently
+    void *p = rust_alloc(24, 8);
+    rust_dealloc(p, 24, 8);
+    if (owns(launder(p))) {
+        return 1;
+    }
+
+    // A wrong size or alignment is reported
+    p = rust_alloc(24, 8);
+    void *q = rust_alloc(24, 64);
+    if (!p || !q || (size_t)q % 64 != 0) {
+        return 1;
+    }
+    rust_dealloc(p, 32, 8);
+    rust_dealloc(q, 24, 8);
+    if (owns(launder(p)) != refuses || owns(launder(q)) != refuses) {
+        return 1;
+    }
+    if (refuses) {
+        rust_dealloc(p, 24, 8);
+        rust_dealloc(q, 24, 64);
+    }
+    return 0;
+}
+
+static int (*verify_fn)(void *);
+
+static void *touch_from_other_thread(void *p) {
+    if (verify_fn(p) != 0) {
+        return NULL;
+    }
+    free(p);
+    return p;
+}
+
+static void *write_from_other_thread(void *p) {
+    *(volatile char *)p = 1;
+    return p;
+}
+
+static int single_threaded(void) {
+    bool (*mark)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_mark_single_threaded");
+    bool (*watch)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_watch");
+    bool (*unwatch)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_unwatch");
+    void *(*malloc_checked)(size_t, uint64_t *) =
+        (void *(*)(size_t, uint64_t *))dlsym(RTLD_DEFAULT, "fatalloc_malloc_checked");
+    verify_fn = (int (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_verify");
+    if (!mark || !watch || !unwatch || !malloc_checked || !verify_fn) {
+        return 1;
+    }
+    // The owner may do anything, and a reallocation keeps the owner. The
+    // other thread's verification and `free` are reported.
+    void *p = malloc(100);
+    if (!mark(p)) {
+        return 1;
+    }
+    p = realloc(p, 5000);
+    if (verify_fn(p) != 0) {
+        return 1;
+    }
+    pthread_t thread;
+    void *result;
+    pthread_create(&thread, NULL, touch_from_other_thread, p);
+    pthread_join(thread, &result);
+    if (!result) {
+        return 1;
+    }
+
+    // An access to a watched one is reported, too
+    void *w;
+    if (posix_memalign(&w, 4096, 4096) != 0 || !mark(w) || !watch(w)) {
+        return 1;
+    }
+    pthread_create(&thread, NULL, write_from_other_thread, w);
+    pthread_join(thread, &result);
+    unwatch(w);
+    free(w);
+
+    // The room for the owner is taken
+    uint64_t token;
+    void *c = malloc_checked(100, &token);
+    if (mark(c)) {
+        return 1;
+    }
+    free(c);
+    return 0;
+}
+
+static int watch(void) {
+    bool (*watch)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_watch");
+    bool (*unwatch)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_unwatch");
+    if (!watch || !unwatch) {
+        return 1;
+    }
+    void *p;
+    if (posix_memalign(&p, 4096, 4096) != 0 || !watch(p)) {
+        return 1;
+    }
+    // Each access is reported and then proceeds
+    volatile char *q = launder(p);
+    q[8] = 42;
+    if (q[100] != 0 || q[8] != 42) {
+        return 1;
+    }
+
+    // Other faults still kill the process
+    pid_t pid = fork();
+    if (pid == 0) {
+        *(volatile char *)launder(NULL) = 0;
+        _exit(0);
+    }
+    int status;
+    if (waitpid(pid, &status, 0) != pid || !WIFSIGNALED(status) || WTERMSIG(status) != SIGSEGV) {
+        return 1;
+    }
+
+    if (!unwatch(p)) {
+        return 1;
+    }
+    q[200] = 1;
+    // Freeing lifts the watch
+    if (!watch(p)) {
+        return 1;
+    }
+    free(p);
+    return 0;
+}
+
+static int unwritten(void) {
+    // Run with `FATALLOC_DETECT_UNWRITTEN=1`. Only the first is never written.
+    void *p[3];
+    for (int i = 0; i < 3; ++i) {
+        if (posix_memalign(&p[i], 4096, 5000) != 0) {
+            return 1;
+        }
+    }
+    volatile char *q = launder(p[0]);
+    if (q[4999] != 0) {
+        return 1;
+    }
+    q = launder(p[1]);
+    q[4500] = 1;
+    q[0] = 2;
+    if (q[4500] != 1) {
+        return 1;
+    }
+    // Moving it counts as using it
+    p[2] = realloc(p[2], 6000);
+    for (int i = 0; i < 3; ++i) {
+        free(p[i]);
+    }
+    return 0;
+}
+
+static int verify(void) {
+    int (*verify)(void *) = (int (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_verify");
+    if (!verify) {
+        return 1;
+    }
+    int ok = 1;
+    int x;
+    ok &= verify(launder(&x)) == 1;
+
+    // Each breakage is undone so that `free` succeeds silently. The guard is
+    // at the start of the smallest margin, followed by the metadata covered
+    // by the checksum, and the canary ends right before the allocation.
+    char *p = malloc(100);
+    ok &= verify(p) == 0;
+    ptrdiff_t guard = -min_margin();
+    const struct {
+        ptrdiff_t offset;
+        int kind;
+    } cases[] = {{guard + (ptrdiff_t)sizeof(void *), 2}, {guard, 3}, {-1, 4}};
+    for (size_t i = 0; i < sizeof cases / sizeof cases[0]; ++i) {
+        ((char *)launder(p))[cases[i].offset] ^= 1;
+        ok &= verify(p) == cases[i].kind;
+        ((char *)launder(p))[cases[i].offset] ^= 1;
+    }
+    ok &= verify(p) == 0;
+
+    // Shrinking poisons the released tail
+    char *q = realloc(p, 10);
+    ((char *)launder(q))[10] ^= 1;
+    ok &= verify(q) == 5;
+    ((char *)launder(q))[10] ^= 1;
+    ok &= verify(q) == 0;
+    free(q);
+    return ok ? 0 : 1;
+}
+
+// Run with `FATALLOC_BACKEND=bump` and `FATALLOC_FIXED_BASE`
+static int fixed_base(void) {
+    uintptr_t base = strtoull(getenv("FATALLOC_FIXED_BASE"), NULL, 16);
+    // The first block starts after its 8-byte size, aligned to 16 bytes, and
+    // the user region after the smallest margin
+    char *p = malloc(100);
+    if ((uintptr_t)p != base + 16 + (uintptr_t)min_margin()) {
+        return 1;
+    }
+    char *q = malloc(100);
+    free(p);
+    free(q);
+    return 0;
+}
+
+static int distant_overrun(void) {
+    // Run with `FATALLOC_BACKEND=bump`, which places `p` and `q` far apart
+    // with only freed blocks in between
+    size_t (*recent)(struct corruption_event *, size_t) =
+        (size_t(*)(struct corruption_event *, size_t))dlsym(RTLD_DEFAULT,
+                                                               "fatalloc_recent_corruptions");
+    if (!recent) {
+        return 1;
+    }
+    char *p = malloc(100);
+    for (int i = 0; i < 1000; ++i) {
+        free(malloc(100));
+    }
+    char *q = malloc(100);
+    if (q - p < 256 * 1024) {
+        return 1;
+    }
+
+    // No allocation made so far can reach `q`'s canary, so `p` isn't blamed
+    ((char *)launder(q))[-1] ^= 1;
+    free(q);
+    struct corruption_event event;
+    int ok = recent(&event, 1) == 1 && event.ptr == q && event.size == 0;
+    free(p);
+    return ok ? 0 : 1;
+}
+
+static int wild_free(void) {
+    // Run with `FATALLOC_CLASSIFY_DOUBLE_FREE=1`. A pointer into memory that
+    // fatalloc doesn't own, with no allocation within the longest backward
+    // scan, is rejected without an owner and without scanning far.
+    size_t len = 4 << 20;
+    char *region = mmap(NULL, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (region == MAP_FAILED) {
+        return 1;
+    }
+    struct timespec start, end;
+    clock_gettime(CLOCK_MONOTONIC, &start);
+    for (int i = 0; i < 100; ++i) {
+        free(launder(region + (3 << 20) + i * 16));
+    }
+    clock_gettime(CLOCK_MONOTONIC, &end);
+    long elapsed_ms =
+        (end.tv_sec - start.tv_sec) * 1000 + (end.tv_nsec - start.tv_nsec) / 1000000;
+    munmap(region, len);
+    return elapsed_ms < 100 ? 0 : 1;
+}
+
+static int owns(void) {
+    bool (*fatalloc_owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    void *libc = dlopen("libc.so.6", RTLD_LAZY | RTLD_NOLOAD);
+    void *(*libc_malloc)(size_t) = libc ? (void *(*)(size_t))dlsym(libc, "malloc") : NULL;
+    if (!fatalloc_owns || !libc_malloc) {
+        return 1;
+    }
+    int ok = 1;
+    char *p = malloc(100);
+    ok &= fatalloc_owns(p);
+    ok &= !fatalloc_owns(p + 16);
+    free(p);
+    ok &= !fatalloc_owns(launder(p));
+    char *foreign = libc_malloc(100);
+    ok &= !fatalloc_owns(foreign);
+    int local;
+    ok &= !fatalloc_owns(&local) && !fatalloc_owns(NULL);
+    dlclose(libc);
+    return ok ? 0 : 1;
+}
+
+void *__libc_malloc(size_t size);
+void *__libc_calloc(size_t number, size_t size);
+void *__libc_realloc(void *ptr, size_t size);
+void __libc_free(void *ptr);
+void *__libc_memalign(size_t alignment, size_t size);
+
+static int libc_aliases(void) {
+    // glibc's internal aliases are fatalloc's too, so their blocks can be
+    // mixed with the public functions'
+    bool (*fatalloc_owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    if (!fatalloc_owns) {
+        return 1;
+    }
+    int ok = 1;
+    char *p = __libc_malloc(100);
+    ok &= fatalloc_owns(p);
+    p = __libc_realloc(p, 10000);
+    ok &= fatalloc_owns(p);
+    free(p);
+    unsigned char *q = __libc_calloc(10, 10);
+    ok &= fatalloc_owns(q) && q[0] == 0 && q[99] == 0;
+    __libc_free(q);
+    char *r = __libc_memalign(4096, 100);
+    ok &= fatalloc_owns(r) && (uintptr_t)r % 4096 == 0;
+    __libc_free(r);
+    p = malloc(100);
+    __libc_free(p);
+    // ... including the validation
+    __libc_free(launder(p));
+    return ok ? 0 : 1;
+}
+
+static int passthrough_realloc(void) {
+    // Run with `FATALLOC_PASSTHROUGH=realloc`
+    bool (*fatalloc_owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    if (!fatalloc_owns) {
+        return 1;
+    }
+    int ok = 1;
+    // Ours stay ours
+    char *p = malloc(100);
+    memset(p, 0xab, 100);
+    p = realloc(p, 5000);
+    ok &= fatalloc_owns(p) && p[99] == (char)0xab;
+    // Anything else goes to the system allocator
+    char *q = realloc(launder(NULL), 100);
+    ok &= q != NULL && !fatalloc_owns(q);
+    memset(q, 0xcd, 100);
+    q = realloc(q, 5000);
+    ok &= q != NULL && !fatalloc_owns(q) && q[99] == (char)0xcd;
+    free(p);
+    free(q);
+    return ok ? 0 : 1;
+}
+
+static int passthrough_malloc(void) {
+    // Run with `FATALLOC_PASSTHROUGH=malloc,calloc`
+    bool (*fatalloc_owns)(void *) = (bool (*)(void *))dlsym(RTLD_DEFAULT, "fatalloc_owns");
+    if (!fatalloc_owns) {
+        return 1;
+    }
+    int ok = 1;
+    char *p = malloc(100);
+    ok &= p != NULL && !fatalloc_owns(p);
+    char *z = calloc(100, 10);
+    ok &= z != NULL && !fatalloc_owns(z);
+    for (int i = 0; i < 1000; i++) {
+        ok &= z[i] == 0;
+    }
+    // Not forwarded
+    void *a = memalign(64, 100);
+    ok &= fatalloc_owns(a);
+    free(p);
+    free(z);
+    free(a);
+    return ok ? 0 : 1;
+}
+
+static int self_test(void) {
+    bool (*run_self_test)(void) = (bool (*)(void))dlsym(RTLD_DEFAULT, "fatalloc_self_test");
+    return run_self_test && run_self_test() ? 0 : 1;
+}
+
+// Requires the `backend-passthrough` feature
+static int mixed(void) {
+    // Bypass `LD_PRELOAD` to get libc's own functions, as if they weren't
+    // interposed
+    void *libc = dlopen("libc.so.6", RTLD_LAZY | RTLD_NOLOAD);
+    if (!libc) {
+        return 1;
+    }
+    void *(*libc_malloc)(size_t) = (void *(*)(size_t))dlsym(libc, "malloc");
+    void *(*libc_calloc)(size_t, size_t) = (void *(*)(size_t, size_t))dlsym(libc, "calloc");
+    if (!libc_malloc || !libc_calloc || libc_malloc == malloc) {
+        return 1;
+    }
+
+    int ok = 1;
+    char *p = libc_malloc(100);
+    ok &= malloc_usable_size(p) >= 100;
+    free(p);
+
+    // Moved into fatalloc with the contents
+    p = libc_calloc(1, 100);
+    p[0] = 42;
+    p = realloc(p, 1000);
+    ok &= p && p[0] == 42 && p[99] == 0 && malloc_usable_size(p) == 1000;
+    free(p);
+
+    dlclose(libc);
+    return ok ? 0 : 1;
+}
+
+static size_t (*get_malloc_batch(void))(size_t, size_t, void **) {
+    return (size_t(*)(size_t, size_t, void **))dlsym(RTLD_DEFAULT, "fatalloc_malloc_batch");
+}
+
+static int batch(void) {
+    size_t (*malloc_batch)(size_t, size_t, void **) = get_malloc_batch();
+    if (!malloc_batch) {
+        return 1;
+    }
+    void *ps[1000];
+    if (malloc_batch(24, 1000, ps) != 1000) {
+        return 1;
+    }
+    int ok = 1;
+    for (int i = 0; i < 1000; ++i) {
+        ok &= malloc_usable_size(ps[i]) >= 24;
+        memset(ps[i], 0xaa, 24);
+    }
+    // Freed like any other allocation
+    for (int i = 0; i < 1000; ++i) {
+        free(ps[i]);
+    }
+    return ok ? 0 : 1;
+}
+
+static void (*get_free_batch(void))(void *const *, size_t) {
+    return (void (*)(void *const *, size_t))dlsym(RTLD_DEFAULT, "fatalloc_free_batch");
+}
+
+static int free_batch(void) {
+    size_t (*malloc_batch)(size_t, size_t, void **) = get_malloc_batch();
+    void (*free_batch)(void *const *, size_t) = get_free_batch();
+    if (!malloc_batch || !free_batch) {
+        return 1;
+    }
+    // More than one chunk of 64, with a null pointer and a repeated one
+    static void *ps[1002];
+    if (malloc_batch(24, 1000, ps) != 1000) {
+        return 1;
+    }
+    ps[1000] = NULL;
+    ps[1001] = ps[500];
+    free_batch(ps, 1002);
+    // All of them are gone
+    for (int i = 0; i < 1000; ++i) {
+        free(ps[i]);
+    }
+    return 0;
+}
+
+// Run with `recycling_memalign.so`, which hands out the first block twice
+static int recycled_batch(void) {
+    size_t (*malloc_batch)(size_t, size_t, void **) = get_malloc_batch();
+    size_t (*bytes_live)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_bytes_live");
+    if (!malloc_batch || !bytes_live) {
+        return 1;
+    }
+    // The repeated block is returned only once, and counted only once, with
+    // its granted size if sizes are rounded up
+    size_t before = bytes_live();
+    void *ps[2];
+    if (malloc_batch(1000, 2, ps) != 1) {
+        return 1;
+    }
+    free(ps[0]);
+    return bytes_live() == before ? 0 : 1;
+}
+
+static int max_leaves_batch(void) {
+    // Run with `FATALLOC_MAX_LEAVES=8` and `FATALLOC_BACKEND=system`. Each
+    // block of 64 MiB, the address range of a leaf, needs a leaf of its own,
+    // so the batch is cut short, and the blocks past it are freed and cleared.
+    size_t (*malloc_batch)(size_t, size_t, void **) = get_malloc_batch();
+    if (!malloc_batch) {
+        return 1;
+    }
+    static void *ps[16];
+    size_t num_allocated = malloc_batch(64 << 20, 16, ps);
+    if (num_allocated == 0 || num_allocated > 8) {
+        return 1;
+    }
+    for (size_t i = num_allocated; i < 16; ++i) {
+        if (ps[i]) {
+            return 1;
+        }
+    }
+    for (size_t i = 0; i < num_allocated; ++i) {
+        free(ps[i]);
+    }
+    return 0;
+}
+
+static double elapsed_ns(struct timespec start) {
+    struct timespec end;
+    clock_gettime(CLOCK_MONOTONIC, &end);
+    return (end.tv_sec - start.tv_sec) * 1e9 + (end.tv_nsec - start.tv_nsec);
+}
+
+enum { BENCH_ROUNDS = 100, BENCH_LEN = 10000, BENCH_THREADS = 8 };
+
+static void *bench_thread(void *arg) {
+    (void)arg;
+    void **ps = calloc(BENCH_LEN, sizeof *ps);
+    for (int round = 0; round < BENCH_ROUNDS; ++round) {
+        for (int i = 0; i < BENCH_LEN; ++i) {
+            ps[i] = malloc(24);
+        }
+        for (int i = 0; i < BENCH_LEN; ++i) {
+            free(ps[i]);
+        }
+    }
+    free(ps);
+    return NULL;
+}
+
+// Not run by `run.sh`. Prints the time per allocation of a tight loop.
+static int bench(void) {
+    size_t (*malloc_batch)(size_t, size_t, void **) = get_malloc_batch();
+    void (*free_batch)(void *const *, size_t) = get_free_batch();
+    if (!malloc_batch || !free_batch) {
+        return 1;
+    }
+    enum { ROUNDS = 100, LEN = 10000 };
+    static void *ps[LEN];
+    struct timespec start;
+
+    clock_gettime(CLOCK_MONOTONIC, &start);
+    for (int round = 0; round < ROUNDS; ++round) {
+        for (int i = 0; i < LEN; ++i) {
+            ps[i] = malloc(24);
+        }
+        for (int i = 0; i < LEN; ++i) {
+            free(ps[i]);
+        }
+    }
+    printf("malloc:                %6.1f ns/allocation\n", elapsed_ns(start) / (ROUNDS * LEN));
+
+    clock_gettime(CLOCK_MONOTONIC, &start);
+    for (int round = 0; round < ROUNDS; ++round) {
+        if (malloc_batch(24, LEN, ps) != LEN) {
+            return 1;
+        }
+        for (int i = 0; i < LEN; ++i) {
+            free(ps[i]);
+        }
+    }
+    printf("fatalloc_malloc_batch: %6.1f ns/allocation\n", elapsed_ns(start) / (ROUNDS * LEN));
+
+    // Freeing only
+    double free_ns = 0, free_batch_ns = 0;
+    for (int round = 0; round < ROUNDS; ++round) {
+        if (malloc_batch(24, LEN, ps) != LEN) {
+            return 1;
+        }
+        clock_gettime(CLOCK_MONOTONIC, &start);
+        for (int i = 0; i < LEN; ++i) {
+            free(ps[i]);
+        }
+        free_ns += elapsed_ns(start);
+
+        if (malloc_batch(24, LEN, ps) != LEN) {
+            return 1;
+        }
+        clock_gettime(CLOCK_MONOTONIC, &start);
+        free_batch(ps, LEN);
+        free_batch_ns += elapsed_ns(start);
+    }
+    printf("free:                  %6.1f ns/allocation\n", free_ns / (ROUNDS * LEN));
+    printf("fatalloc_free_batch:   %6.1f ns/allocation\n", free_batch_ns / (ROUNDS * LEN));
+
+    // The wall time spent per allocation with all threads allocating at once.
+    // Up to the number of CPUs, it stays flat unless the threads contend, e.g.,
+    // on a shared counter or lock.
+    long num_cpus = sysconf(_SC_NPROCESSORS_ONLN);
+    pthread_t threads[BENCH_THREADS];
+    for (int num_threads = 1; num_threads <= BENCH_THREADS; num_threads *= 2) {
+        clock_gettime(CLOCK_MONOTONIC, &start);
+        for (int i = 0; i < num_threads; ++i) {
+            pthread_create(&threads[i], NULL, bench_thread, NULL);
+        }
+        for (int i = 0; i < num_threads; ++i) {
+            pthread_join(threads[i], NULL);
+        }
+        printf("malloc, %d threads:     %6.1f ns/allocation (%ld CPUs)\n", num_threads,
+               elapsed_ns(start) / ((double)num_threads * BENCH_ROUNDS * BENCH_LEN), num_cpus);
+    }
+
+    // Large zeroed allocations, whose whole pages are discarded instead of
+    // cleared. Touching one page of each shows the cost of clearing, not
+    // faulting in.
+    static const size_t calloc_sizes[] = {64 << 10, 1 << 20, 16 << 20};
+    for (size_t k = 0; k < sizeof calloc_sizes / sizeof calloc_sizes[0]; ++k) {
+        clock_gettime(CLOCK_MONOTONIC, &start);
+        for (int round = 0; round < ROUNDS; ++round) {
+            char *p = calloc(1, calloc_sizes[k]);
+            if (!p) {
+                return 1;
+            }
+            p[0] = 1;
+            free(p);
+        }
+        printf("calloc, %5zu KiB:      %6.1f us/allocation\n", calloc_sizes[k] >> 10,
+               elapsed_ns(start) / ROUNDS / 1000);
+    }
+
+    // The margins of small allocations, whose smallest size depends on the
+    // metadata layout (`compact-meta`)
+    size_t (*overhead_bytes)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_overhead_bytes");
+    if (!overhead_bytes) {
+        return 1;
+    }
+    static const size_t small_sizes[] = {8, 24, 100};
+    for (size_t k = 0; k < sizeof small_sizes / sizeof small_sizes[0]; ++k) {
+        size_t before = overhead_bytes();
+        for (int i = 0; i < LEN; ++i) {
+            ps[i] = malloc(small_sizes[k]);
+        }
+        printf("margins, %3zu bytes:    %6.1f bytes/allocation\n", small_sizes[k],
+               (double)(overhead_bytes() - before) / LEN);
+        for (int i = 0; i < LEN; ++i) {
+            free(ps[i]);
+        }
+    }
+
+    // The margins of sizes distributed log-uniformly from 8 bytes to 64 KiB,
+    // against the requested sizes
+    size_t before = overhead_bytes(), requested = 0;
+    unsigned seed = 1;
+    for (int i = 0; i < LEN; ++i) {
+        seed = seed * 1103515245 + 12345;
+        size_t size = (size_t)8 << (seed >> 16) % 13;
+        size += (seed >> 8) % size;
+        requested += size;
+        ps[i] = malloc(size);
+    }
+    size_t margins = overhead_bytes() - before;
+    printf("margins, mixed sizes:  %6.1f bytes/allocation (%.1f%% of %zu bytes/allocation)\n",
+           (double)margins / LEN, 100.0 * margins / requested, requested / LEN);
+    for (int i = 0; i < LEN; ++i) {
+        free(ps[i]);
+    }
+
+    // The worst-case latency of `free` with the quarantine full, where each
+    // call evicts a few blocks, against flushing it whenever it fills up,
+    // which is what evicting without amortization amounts to
+    size_t (*quarantine_budget)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_quarantine_budget");
+    size_t (*compact)(void) = (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_compact");
+    if (!quarantine_budget || !compact) {
+        return 1;
+    }
+    for (int flush = 0; flush < 2; ++flush) {
+        double max_ns = 0, total_ns = 0;
+        size_t freed = 0;
+        for (int round = 0; round < ROUNDS; ++round) {
+            for (int i = 0; i < LEN; ++i) {
+                ps[i] = malloc(1000);
+            }
+            for (int i = 0; i < LEN; ++i) {
+                clock_gettime(CLOCK_MONOTONIC, &start);
+                free(ps[i]);
+                if (flush && (freed += 1000) >= quarantine_budget()) {
+                    compact();
+                    freed = 0;
+                }
+                double ns = elapsed_ns(start);
+                total_ns += ns;
+                max_ns = ns > max_ns ? ns : max_ns;
+            }
+        }
+        printf("free, %s:       %6.1f us max, %6.1f ns mean\n", flush ? " flushing" : "amortized",
+               max_ns / 1000, total_ns / (ROUNDS * LEN));
+    }
+
+    // The bitmap tracking allocations in disjoint address ranges, each of
+    // which is 64 MiB (the range of a leaf) away from the others. The
+    // resident set includes the page of each allocation touched.
+    size_t (*map_memory_bytes)(void) =
+        (size_t(*)(void))dlsym(RTLD_DEFAULT, "fatalloc_map_memory_bytes");
+    if (!map_memory_bytes) {
+        return 1;
+    }
+    enum { NUM_RANGES = 64 };
+    size_t map_before = map_memory_bytes();
+    long rss_before = rss_kib();
+    for (int i = 0; i < NUM_RANGES; ++i) {
+        if (!(ps[i] = aligned_alloc(64 << 20, 64))) {
+            return 1;
+        }
+    }
+    printf("map, scattered:        %6.1f KiB/range (resident: %.1f KiB/range)\n",
+           (double)(map_memory_bytes() - map_before) / 1024 / NUM_RANGES,
+           (double)(rss_kib() - rss_before) / NUM_RANGES);
+    for (int i = 0; i < NUM_RANGES; ++i) {
+        free(ps[i]);
+    }
+    return 0;
+}
+
+int main(int argc, char **argv) {
+    static const struct {
+        const char *name;
+        int (*fn)(void);
+    } scenarios[] = {
+        {"basic", basic},
+        {"shrink", shrink},
+        {"shrunk-tail", shrunk_tail},
+        {"shrink-grow", shrink_grow},
+        {"huge-align", huge_align},
+        {"double-free", double_free},
+        {"stale-free", stale_free},
+        {"misaligned-free", misaligned_free},
+        {"underflow", underflow},
+        {"metadata-overwrite", metadata_overwrite},
+        {"overflow-culprit", overflow_culprit},
+        {"sample", sample},
+        {"invalid-free", invalid_free},
+        {"stack-free", stack_free},
+        {"static-free", static_free},
+        {"invalid-realloc", invalid_realloc},
+        {"out-of-memory", out_of_memory},
+        {"calloc-overflow", calloc_overflow},
+        {"calloc-huge", calloc_huge},
+        {"calloc-large", calloc_large},
+        {"huge-sizes", huge_sizes},
+        {"fail-nth", fail_nth},
+        {"min-align", min_align},
+        {"misaligned-backend", misaligned_backend},
+        {"overlapping-backend", overlapping_backend},
+        {"odd-align", odd_align},
+        {"race", race},
+        {"fork", fork_while_allocating},
+        {"usable-size", usable_size},
+        {"leak", leak},
+        {"realloc-leak", realloc_leak},
+        {"log-level", log_level},
+        {"log-fd", log_fd},
+        {"scoped-log-level", scoped_log_level},
+        {"dlsym-alloc", dlsym_alloc},
+        {"outer-pointer", outer_pointer},
+        {"overhead", overhead},
+        {"map-memory", map_memory},
+        {"count-tracked", count_tracked},
+        {"quarantine-budget", quarantine_budget},
+        {"thread-stats", thread_stats},
+        {"untracked-free", untracked_free},
+        {"max-leaves", max_leaves},
+        {"max-leaves-batch", max_leaves_batch},
+        {"mmap-retry", mmap_retry},
+        {"metrics", metrics},
+        {"folded", folded},
+        {"churn", churn},
+        {"identity-fill", identity_fill},
+        {"consistency", consistency},
+        {"corruptions", corruptions},
+        {"report-once", report_once},
+        {"madv-free", madv_free},
+        {"secure", secure},
+        {"compact", compact},
+        {"map-stats", map_stats},
+        {"signal-alloc", signal_alloc},
+        {"signal-secure", signal_secure},
+        {"freeze", freeze},
+        {"realloc-hash", realloc_hash},
+        {"sizes", sizes},
+        {"realloc-same", realloc_same},
+        {"realloc-align", realloc_align},
+        {"protect-metadata", protect_metadata},
+        {"tag-pointer", tag_pointer},
+        {"allocations-in-range", allocations_in_range},
+        {"pin-free", pin_free},
+        {"readonly", readonly},
+        {"readonly-registry", readonly_registry},
+        {"numa-node", numa_node},
+        {"min-size", min_size},
+        {"jemalloc-api", jemalloc_api},
+        {"sized-free", sized_free},
+        {"rust-dealloc", rust_dealloc},
+        {"checked-free", checked_free},
+        {"watch", watch},
+        {"single-threaded", single_threaded},
+        {"unwritten", unwritten},
+        {"verify", verify},
+        {"fixed-base", fixed_base},
+        {"distant-overrun", distant_overrun},
+        {"wild-free", wild_free},
+        {"owns", owns},
+        {"libc-aliases", libc_aliases},
+        {"passthrough-realloc", passthrough_realloc},
+        {"passthrough-malloc", passthrough_malloc},
+        {"self-test", self_test},
+        {"mixed", mixed},
+        {"batch", batch},
+        {"free-batch", free_batch},
+        {"recycled-batch", recycled_batch},
+        {"bench", bench},
+    };
+
+    for (size_t i = 0; argc == 2 && i < sizeof scenarios / sizeof scenarios[0]; ++i) {
+        if (strcmp(argv[1], scenarios[i].name) == 0) {
+            return scenarios[i].fn();
+        }
+    }
+    fprintf(stderr, "usage: %s <scenario>\n", argv[0]);
+    return 2;
+}